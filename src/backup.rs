@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use log::info;
+use rocksdb::{DB, checkpoint::Checkpoint};
+
+use crate::database::ResultDatabase;
+
+impl ResultDatabase {
+    /// `backup_to` with the path as a string, like `ResultDatabase::new`
+    pub fn backup(&self, dest: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.backup_to(Path::new(dest))
+    }
+
+    /// `restore_from` with the paths as strings, like `ResultDatabase::new`
+    pub fn restore(src: &str, dest: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Self::restore_from(Path::new(src), Path::new(dest))
+    }
+
+    /// Write a consistent copy of the database to `dest`, which must not
+    /// exist or be an empty directory. Safe to run while rows are being saved.
+    /// Files are hard linked when `dest` is on the same filesystem.
+    pub fn backup_to(&self, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        prepare_destination(dest)?;
+
+        let db = self.db()?;
+        Checkpoint::new(&db)?.create_checkpoint(dest)?;
+
+        info!("Backed up {} to {}", self.path, dest.display());
+
+        Ok(())
+    }
+
+    /// Copy the backup at `src` to a new database at `dest`. `src` is checked
+    /// to be a scan database first, and the copy is checked again afterwards. `dest` must not exist or be an empty directory.
+    pub fn restore_from(src: &Path, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let database = ResultDatabase::open_read_only(&src.to_string_lossy());
+        let columns = check_columns(&database, src)?;
+        prepare_destination(dest)?;
+
+        // Open with the column families the backup has, older backups may
+        // not have every non-host column family yet
+        let db = DB::open_cf_for_read_only(&database.options, src, &columns, false)?;
+        Checkpoint::new(&db)?.create_checkpoint(dest)?;
+        check_columns(&database, dest)?;
+
+        info!("Restored {} to {}", src.display(), dest.display());
+
+        Ok(())
+    }
+}
+
+// The checkpoint API creates the directory itself and refuses to write into
+// an existing one. Remove an empty destination, reject anything else.
+fn prepare_destination(dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !dest.exists() {
+        return Ok(());
+    }
+
+    if !dest.is_dir() || dest.read_dir()?.next().is_some() {
+        return Err(format!("{} already exists and is not empty", dest.display()).into());
+    }
+    std::fs::remove_dir(dest)?;
+
+    Ok(())
+}
+
+// Column families at `path`, or an error if it isn't a scan database
+fn check_columns(
+    database: &ResultDatabase,
+    path: &Path,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let existing = DB::list_cf(&database.options, path)?;
+    // Every version of the database has these, column families added since
+    // are created when the restored copy is first opened
+    let missing: Vec<&str> = database
+        .host_columns()
+        .take(4)
+        .filter(|column| !existing.iter().any(|e| e == column))
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} is not a scan database (missing column families: {})",
+            path.display(),
+            missing.join(", ")
+        )
+        .into());
+    }
+
+    Ok(existing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::row;
+
+    #[test]
+    fn restores_a_backup_over_a_wiped_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan.db");
+        let backup = dir.path().join("backup");
+        let path_str = path.to_string_lossy().to_string();
+
+        let database = ResultDatabase::new(&path_str);
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22, 80], &["ssh", "http"]),
+                row("2001:db8::1", &[443], &["https"]),
+            ])
+            .unwrap();
+        database.backup(&backup.to_string_lossy()).unwrap();
+        // Saved after the backup, so not restored
+        database
+            .save_rows(vec![row("10.0.0.2", &[22], &[])])
+            .unwrap();
+        drop(database);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        ResultDatabase::restore(&backup.to_string_lossy(), &path_str).unwrap();
+        let database = ResultDatabase::new(&path_str);
+        let restored = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(restored.ports, vec![22, 80]);
+        assert_eq!(restored.services, vec!["ssh", "http"]);
+        assert!(database.get_row_by_host("2001:db8::1").unwrap().is_some());
+        assert!(database.get_row_by_host("10.0.0.2").unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_to_overwrite_or_restore_anything_else() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = ResultDatabase::new(&dir.path().join("scan.db").to_string_lossy());
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+
+        let taken = dir.path().join("taken");
+        std::fs::create_dir(&taken).unwrap();
+        std::fs::write(taken.join("file"), "").unwrap();
+        assert!(database.backup(&taken.to_string_lossy()).is_err());
+
+        let restored = dir.path().join("restored");
+        assert!(
+            ResultDatabase::restore(&taken.to_string_lossy(), &restored.to_string_lossy()).is_err()
+        );
+        assert!(!restored.exists());
+    }
+}
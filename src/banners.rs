@@ -0,0 +1,53 @@
+use rocksdb::WriteBatch;
+
+use crate::{
+    database::{DatabaseError, ResultDatabase, host_key},
+    index::{IndexManager, banner_key},
+    service_scan::service_scan::ServiceScanResult,
+};
+
+impl ResultDatabase {
+    /// The bytes a host's port sent when it was last service scanned, NUL
+    /// bytes and invalid UTF-8 included, cut to `DatabaseOptions::banner_limit`.
+    /// The responses column only has a text preview of them.
+    pub fn get_banner(&self, host: &str, port: u16) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let db = self.db()?;
+        let banners = IndexManager::new(self, &db)?.banners;
+        let Some(key) = banner_key(&host_key(host), port as i32) else {
+            return Ok(None);
+        };
+        Ok(db.get_cf(banners, key)?)
+    }
+
+    /// Every raw banner stored for a host, in port order
+    pub fn get_banners(&self, host: &str) -> Result<Vec<(u16, Vec<u8>)>, DatabaseError> {
+        let db = self.db()?;
+        Ok(IndexManager::new(self, &db)?.stored_banners(&host_key(host)))
+    }
+
+    // Store the raw banners from a service scan, called after the rows are saved
+    pub(crate) fn save_banners(
+        &self,
+        results: &[ServiceScanResult],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+
+        let mut batch = WriteBatch::default();
+        for result in results {
+            let host = result.ip.to_string();
+            for (port, banner) in &result.banners {
+                indexes.put_banner(&mut batch, &host, *port, banner);
+            }
+            if batch.len() >= self.batch_size {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if !batch.is_empty() {
+            db.write(batch)?;
+        }
+
+        Ok(())
+    }
+}
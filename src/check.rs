@@ -0,0 +1,432 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use log::{info, warn};
+use rocksdb::{DB, IteratorMode, ReadOptions, WriteBatch};
+use serde::Serialize;
+
+use crate::{
+    database::{
+        BANNER_COLUMN, DatabaseError, DatabaseResult, LEGACY_KEYS_END, LEGACY_KEYS_START,
+        PORT_INDEX_COLUMN, PREFIX_COLUMN, RUN_COLUMN, ResultDatabase, TAG_INDEX_COLUMN,
+        host_from_key,
+    },
+    index::IndexManager,
+    os_info::OsInfo,
+    path_mtu::PrefixPath,
+    runs::RunMetadata,
+};
+
+// Entries `check` decodes at the start of each column family
+const CHECK_SAMPLE_ENTRIES: usize = 1000;
+
+/// An entry `ResultDatabase::check` couldn't decode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BadKey {
+    /// Column family the entry is in
+    pub column: String,
+    /// The entry's key as stored
+    pub key: Vec<u8>,
+    /// The host the key belongs to, if it can be read
+    pub host: Option<String>,
+    /// What is wrong with it
+    pub reason: String,
+}
+
+impl fmt::Display for BadKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{} {}: {}", self.column, host, self.reason),
+            None => {
+                write!(f, "{} 0x", self.column)?;
+                for byte in &self.key {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, ": {}", self.reason)
+            }
+        }
+    }
+}
+
+/// What `ResultDatabase::check` found
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct CheckReport {
+    /// Column families this version uses that the database doesn't have.
+    /// They're created the next time it's opened for writing.
+    pub missing_columns: Vec<String>,
+    /// Entries decoded
+    pub scanned: u64,
+    /// Whether every entry was decoded, not just the first of each column
+    pub complete: bool,
+    /// Entries that couldn't be decoded, for `remove_bad_keys`
+    pub bad_keys: Vec<BadKey>,
+    /// Column families that couldn't be read to the end, with the error
+    pub read_errors: Vec<(String, String)>,
+}
+
+impl CheckReport {
+    /// Whether nothing was found wrong
+    pub fn is_healthy(&self) -> bool {
+        self.missing_columns.is_empty() && self.bad_keys.is_empty() && self.read_errors.is_empty()
+    }
+}
+
+impl ResultDatabase {
+    /// Check that every column family exists and that the first
+    /// entries of each one decode, without changing anything. Works on a
+    /// database opened read-only, or one a scan has open.
+    pub fn check(&self) -> Result<CheckReport, DatabaseError> {
+        self.check_entries(Some(CHECK_SAMPLE_ENTRIES))
+    }
+
+    /// `check` decoding every entry rather than a sample, which reads the
+    /// whole database
+    pub fn check_all(&self) -> Result<CheckReport, DatabaseError> {
+        self.check_entries(None)
+    }
+
+    fn check_entries(&self, limit: Option<usize>) -> Result<CheckReport, DatabaseError> {
+        let existing = DB::list_cf(&self.options, &self.path)?;
+        let mut report = CheckReport {
+            missing_columns: self
+                .columns
+                .iter()
+                .filter(|column| !existing.contains(column))
+                .cloned()
+                .collect(),
+            complete: limit.is_none(),
+            ..Default::default()
+        };
+
+        // Opening for writing would create the missing column families, so
+        // read what there is without them
+        let db = if report.missing_columns.is_empty() {
+            self.db()?
+        } else {
+            Arc::new(DB::open_cf_for_read_only(
+                &self.options,
+                &self.path,
+                &existing,
+                false,
+            )?)
+        };
+
+        for column in self
+            .columns
+            .iter()
+            .filter(|column| existing.contains(column))
+        {
+            let Some(cf) = db.cf_handle(column) else {
+                continue;
+            };
+            let mut read_opts = ReadOptions::default();
+            read_opts.set_total_order_seek(true);
+            let entries = db.iterator_cf_opt(cf, read_opts, IteratorMode::Start);
+            for item in entries.take(limit.unwrap_or(usize::MAX)) {
+                let (key, value) = match item {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        report.read_errors.push((column.clone(), e.to_string()));
+                        break;
+                    }
+                };
+                report.scanned += 1;
+                if let Err(reason) = check_entry(column, &key, &value) {
+                    report.bad_keys.push(BadKey {
+                        column: column.clone(),
+                        host: entry_host(column, &key),
+                        key: key.to_vec(),
+                        reason,
+                    });
+                }
+            }
+        }
+
+        if report.is_healthy() {
+            info!("Checked {} entries, all readable", report.scanned);
+        } else {
+            warn!(
+                "Checked {} entries: {} missing column families, {} bad entries, {} unreadable column families",
+                report.scanned,
+                report.missing_columns.len(),
+                report.bad_keys.len(),
+                report.read_errors.len()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Delete the entries a check found bad. A bad row in the default
+    /// column family takes the rest of its host with it, as `delete_host`
+    /// does, other entries are deleted on their own. Returns the number of
+    /// entries removed.
+    pub fn remove_bad_keys(&self, bad_keys: &[BadKey]) -> Result<u64, DatabaseError> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        let mut batch = WriteBatch::default();
+        for bad in bad_keys {
+            if bad.column == "default"
+                && let Some(host) = &bad.host
+            {
+                indexes.delete_row(&mut batch, host);
+            }
+            // Also covers keys `delete_row` wouldn't find, such as old text keys
+            if let Some(cf) = db.cf_handle(&bad.column) {
+                batch.delete_cf(cf, &bad.key);
+            }
+        }
+        db.write(batch)?;
+        indexes.collect_garbage_if_due()?;
+
+        info!("Removed {} bad entries", bad_keys.len());
+
+        Ok(bad_keys.len() as u64)
+    }
+
+    /// Try to recover a database that fails to open, e.g. after a crash
+    /// part way through a write. RocksDB rebuilds its metadata from the
+    /// files it can still read, and anything it can't read is lost. Nothing
+    /// else may have the database open.
+    pub fn repair(path: &str) -> Result<(), DatabaseError> {
+        // Repairing a path with nothing there would create an empty database
+        if !Path::new(path).exists() {
+            return Err(DatabaseError::NotFound(path.to_string()));
+        }
+        let database = ResultDatabase::new(path);
+        DB::repair(&database.options, path)?;
+        // Repairing drops column families with nothing on disk, opening
+        // creates them again
+        database.db()?;
+
+        info!("Repaired {}", path);
+
+        Ok(())
+    }
+}
+
+// Why an entry of `column` doesn't decode as what that column holds
+fn check_entry(column: &str, key: &[u8], value: &[u8]) -> Result<(), String> {
+    match column {
+        PREFIX_COLUMN => serde_json::from_slice::<PrefixPath>(value)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        RUN_COLUMN => serde_json::from_slice::<RunMetadata>(value)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        // The big-endian port, then the host key
+        PORT_INDEX_COLUMN => check_host_key(key.get(2..).unwrap_or_default()),
+        // The tag, a zero byte, then the host key
+        TAG_INDEX_COLUMN => {
+            let split = key
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or("no separator after the tag")?;
+            std::str::from_utf8(&key[..split]).map_err(|_| "tag is not UTF-8")?;
+            check_host_key(&key[split + 1..])
+        }
+        // The host key, then the big-endian port
+        BANNER_COLUMN => check_host_key(&key[..key.len().saturating_sub(2)]),
+        _ => {
+            check_host_key(key)?;
+            check_host_value(column, value)
+        }
+    }
+}
+
+fn check_host_value(column: &str, value: &[u8]) -> Result<(), String> {
+    match column {
+        "default" => DatabaseResult::decode(value)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        "ports" => {
+            let binary = value.get(..4).is_some_and(|count| {
+                let count = u32::from_le_bytes([count[0], count[1], count[2], count[3]]);
+                value.len() as u64 == 4 + count as u64 * 2
+            });
+            // Older versions stored the ports as comma separated text
+            let text = std::str::from_utf8(value).is_ok_and(|text| {
+                text.split(',')
+                    .all(|port| port.trim().is_empty() || port.trim().parse::<i32>().is_ok())
+            });
+            if binary || text {
+                Ok(())
+            } else {
+                Err("not a port list".to_string())
+            }
+        }
+        "timestamps" if value.len() != 16 => Err(format!(
+            "{} bytes, expected two 8 byte timestamps",
+            value.len()
+        )),
+        "rtt" if value.len() != 8 => {
+            Err(format!("{} bytes, expected an 8 byte float", value.len()))
+        }
+        "os" if OsInfo::decode(value).is_none() => Err("not an OS guess".to_string()),
+        "timestamps" | "rtt" | "os" => Ok(()),
+        _ => std::str::from_utf8(value)
+            .map(drop)
+            .map_err(|_| "not UTF-8".to_string()),
+    }
+}
+
+// Keys from versions that stored hosts as text are fine, `migrate_keys`
+// moves them
+fn check_host_key(key: &[u8]) -> Result<(), String> {
+    let legacy = key.first().is_some_and(|first| {
+        (LEGACY_KEYS_START[0]..LEGACY_KEYS_END[0]).contains(first)
+            && std::str::from_utf8(key).is_ok()
+    });
+    if host_from_key(key).is_some() || legacy {
+        Ok(())
+    } else {
+        Err("not a host key".to_string())
+    }
+}
+
+// The host an entry of `column` belongs to, None if its key doesn't say
+fn entry_host(column: &str, key: &[u8]) -> Option<String> {
+    let host_key = match column {
+        PREFIX_COLUMN | RUN_COLUMN => return None,
+        PORT_INDEX_COLUMN => key.get(2..)?,
+        TAG_INDEX_COLUMN => &key[key.iter().position(|b| *b == 0)? + 1..],
+        BANNER_COLUMN => &key[..key.len().checked_sub(2)?],
+        _ => key,
+    };
+    host_from_key(host_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::host_key,
+        testing::{self, row},
+    };
+
+    fn fill(database: &ResultDatabase) {
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &["ssh"]),
+                row("10.0.0.2", &[80, 443], &["http"]),
+                row("10.0.0.3", &[8080], &[]),
+            ])
+            .unwrap();
+        database.add_tag("10.0.0.1", "lab").unwrap();
+    }
+
+    #[test]
+    fn healthy_database_checks_clean() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+
+        let report = database.check().unwrap();
+        assert!(report.is_healthy(), "{:?}", report);
+        assert!(!report.complete);
+        assert!(report.scanned > 0);
+
+        let report = database.check_all().unwrap();
+        assert!(report.is_healthy(), "{:?}", report);
+        assert!(report.complete);
+    }
+
+    #[test]
+    fn corrupted_entries_are_found_and_removed() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        db.put_cf(cfs[0], host_key("10.0.0.1"), b"\x07not a row")
+            .unwrap();
+        db.put_cf(cfs[1], host_key("10.0.0.2"), b"80,abc").unwrap();
+        db.put_cf(cfs[4], host_key("10.0.0.2"), [0u8; 3]).unwrap();
+        let port_index = db.cf_handle(PORT_INDEX_COLUMN).unwrap();
+        db.put_cf(port_index, [0, 22, 4, 10, 0], []).unwrap();
+        drop(db);
+
+        let report = database.check_all().unwrap();
+        assert!(!report.is_healthy());
+        assert!(report.missing_columns.is_empty());
+        assert!(report.read_errors.is_empty());
+        let found: Vec<(&str, Option<&str>)> = report
+            .bad_keys
+            .iter()
+            .map(|bad| (bad.column.as_str(), bad.host.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("default", Some("10.0.0.1")),
+                ("ports", Some("10.0.0.2")),
+                ("timestamps", Some("10.0.0.2")),
+                (PORT_INDEX_COLUMN, None),
+            ]
+        );
+        assert_eq!(report.bad_keys[1].reason, "not a port list");
+        assert_eq!(
+            report.bad_keys[2].to_string(),
+            "timestamps 10.0.0.2: 3 bytes, expected two 8 byte timestamps"
+        );
+        assert_eq!(
+            report.bad_keys[3].to_string(),
+            "port_index 0x0016040a00: not a host key"
+        );
+
+        assert_eq!(database.remove_bad_keys(&report.bad_keys).unwrap(), 4);
+        let report = database.check_all().unwrap();
+        assert!(report.is_healthy(), "{:?}", report);
+
+        // The bad row took the rest of its host with it, index entries too
+        assert!(database.get_row_by_host("10.0.0.1").unwrap().is_none());
+        assert!(database.get_rows_by_tag("lab").unwrap().is_empty());
+        assert!(database.get_rows_by_port(22).unwrap().is_empty());
+        assert_eq!(
+            database.get_row_by_host("10.0.0.3").unwrap().unwrap().ports,
+            vec![8080]
+        );
+    }
+
+    #[test]
+    fn missing_column_families_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        // A database from before any of the other column families
+        DB::open_default(&path).unwrap();
+
+        let report = ResultDatabase::open_read_only(&path).check().unwrap();
+        assert!(!report.is_healthy());
+        assert_eq!(
+            report.missing_columns.len(),
+            ResultDatabase::new(&path).columns.len() - 1
+        );
+        assert!(
+            report
+                .missing_columns
+                .iter()
+                .all(|column| column != "default")
+        );
+    }
+
+    #[test]
+    fn repair_keeps_what_it_can_read() {
+        let (dir, database) = testing::temp_database();
+        fill(&database);
+        let path = database.path.clone();
+        drop(database);
+
+        ResultDatabase::repair(&path).unwrap();
+        let database = ResultDatabase::new(&path);
+        assert!(database.check_all().unwrap().is_healthy());
+        assert_eq!(
+            database.get_row_by_host("10.0.0.2").unwrap().unwrap().ports,
+            vec![80, 443]
+        );
+
+        let gone = dir.path().join("nothing here");
+        assert!(matches!(
+            ResultDatabase::repair(&gone.to_string_lossy()),
+            Err(DatabaseError::NotFound(_))
+        ));
+        assert!(!gone.exists());
+    }
+}
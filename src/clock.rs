@@ -0,0 +1,133 @@
+use std::{
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Seconds between the NTP epoch (1900) and the unix epoch (1970)
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+
+/// Source of wall-clock time (for stored timestamps) and monotonic time
+/// (for durations). Wall time may jump when NTP steps the clock, monotonic
+/// time never goes backwards.
+pub trait Clock: Send + Sync {
+    fn wall(&self) -> SystemTime;
+    fn monotonic(&self) -> Instant;
+
+    /// Wall time as unix seconds, 0 if the clock is before 1970
+    fn unix_seconds(&self) -> u64 {
+        self.wall()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// The operating system clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when told to. The wall time can be stepped
+/// backwards to simulate an NTP correction.
+#[derive(Debug)]
+pub struct FakeClock {
+    wall: Mutex<SystemTime>,
+    monotonic: Mutex<Instant>,
+}
+
+impl FakeClock {
+    pub fn new(wall: SystemTime) -> Self {
+        FakeClock {
+            wall: Mutex::new(wall),
+            monotonic: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Move both clocks forward
+    pub fn advance(&self, by: Duration) {
+        *self.wall.lock().unwrap() += by;
+        *self.monotonic.lock().unwrap() += by;
+    }
+
+    /// Step only the wall clock, as NTP would
+    pub fn set_wall(&self, wall: SystemTime) {
+        *self.wall.lock().unwrap() = wall;
+    }
+}
+
+impl Clock for FakeClock {
+    fn wall(&self) -> SystemTime {
+        *self.wall.lock().unwrap()
+    }
+
+    fn monotonic(&self) -> Instant {
+        *self.monotonic.lock().unwrap()
+    }
+}
+
+/// Offset of `clock` from an NTP server in milliseconds, positive when the
+/// local clock is behind. Uses a single SNTP exchange.
+pub fn ntp_offset(
+    clock: &dyn Clock,
+    server: &str,
+    timeout: Duration,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let server = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:123", server)
+    };
+
+    // LI 0, version 3, client mode
+    let mut request = [0u8; 48];
+    request[0] = 0x1b;
+
+    let sent = unix_f64(clock.wall());
+    let sent_at = clock.monotonic();
+    socket.send_to(&request, &server)?;
+
+    let mut reply = [0u8; 48];
+    let (len, _) = socket.recv_from(&mut reply)?;
+    if len < 48 {
+        return Err(format!("Short NTP reply from {} ({} bytes)", server, len).into());
+    }
+    // Use monotonic time for the round trip so a clock step can't skew it
+    let received = sent + sent_at.elapsed().as_secs_f64();
+
+    let server_received = ntp_timestamp(&reply[32..40]);
+    let server_sent = ntp_timestamp(&reply[40..48]);
+    let offset = ((server_received - sent) + (server_sent - received)) / 2.0;
+
+    Ok((offset * 1000.0).round() as i64)
+}
+
+fn unix_f64(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+// NTP 64 bit timestamp as unix seconds
+fn ntp_timestamp(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64;
+    seconds - NTP_UNIX_OFFSET + fraction / u32::MAX as f64
+}
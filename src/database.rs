@@ -1,60 +1,697 @@
-use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Instant};
-
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::ControlFlow,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use memchr::memmem;
 use regex::Regex;
-use rocksdb::{Cache, ColumnFamily, DB, IteratorMode, Options, WriteBatch};
-use serde::{Deserialize, Serialize};
-
+use rocksdb::{
+    AsColumnFamilyRef, Cache, ColumnFamily, ColumnFamilyDescriptor, ColumnFamilyTtl, DB,
+    DBIteratorWithThreadMode, Direction, ErrorKind, IteratorMode, Options, ReadOptions,
+    SliceTransform, WriteBatch, WriteOptions,
+};
+// For `DatabaseOptions::with_compression`
+pub use rocksdb::DBCompressionType;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use log::{info, warn};
 use rayon::prelude::*;
 
-use crate::{port_scan::port_scan::PortScanResult, service_scan::service_scan::ServiceScanResult};
-
-// Global settings for optimal performance
+use crate::{
+    clock::{self, Clock},
+    index::{GcCursor, IndexManager, decode_ports, decode_services, decode_timestamps},
+    metrics::Counters,
+    online_scan::PingResult,
+    os_info::OsInfo,
+    parse_ip_range::IpPrefix,
+    port_scan::port_scan::PortScanResult,
+    progress::{Operation, Progress, ProgressEvent, ProgressHook},
+    service_scan::{
+        fingerprint::{ServiceMatch, identify_service},
+        service_scan::ServiceScanResult,
+    },
+    subscriptions::Subscribers,
+    util::humanize_duration,
+};
+
+// Defaults for `DatabaseOptions`
 const BLOCK_CACHE_SIZE_MB: usize = 512; // 512MB block cache
 const WRITE_BUFFER_SIZE_MB: usize = 64; // 64MB write buffer
 const NUM_PARALLEL_THREADS: usize = 8; // Number of threads for parallel operations
 const BATCH_SIZE: usize = 1000; // Batch size for writes
+const BULK_BATCH_SIZE: usize = 50_000; // Smallest batch size for writes in bulk load mode
+pub(crate) const GC_AFTER_DELETES: u64 = 10_000; // Hosts deleted between automatic garbage collections
+pub(crate) const GC_STEP_ENTRIES: u64 = 20_000; // Most entries one delete's share of automatic garbage collection scans
+const BANNER_LIMIT_BYTES: usize = 4096; // Most bytes of a raw banner kept
+pub(crate) const PREFIX_COLUMN: &str = "prefixes"; // Per-network metadata, not keyed by host
+pub(crate) const RUN_COLUMN: &str = "runs"; // Per-scan metadata, not keyed by host
+pub(crate) const PORT_INDEX_COLUMN: &str = "port_index"; // Port -> host index, keyed by port then host
+pub(crate) const TAG_INDEX_COLUMN: &str = "tag_index"; // Tag -> host index, keyed by tag then host
+pub(crate) const BANNER_COLUMN: &str = "banners"; // Raw service banners, keyed by host then port
+pub(crate) const PORT_INDEX_PREFIX_LEN: usize = 2; // The big-endian port at the start of each port index key
+const HOST_KEY_PREFIX_LEN: usize = 3; // A host key's family byte and first two address bytes
+// First byte of a host key, saying what follows
+const KEY_IPV4: u8 = 4;
+const KEY_IPV6: u8 = 6;
+const KEY_NAME: u8 = 0xff;
+// Start of every row in the default column family, before its format version
+const ROW_MAGIC: &[u8] = b"RS";
+// Versions of the row encoding in the default column family. Rows written
+// before the marker existed start with the version instead.
+const ROW_FORMAT_LEGACY: u8 = 0; // Empty value, the fields are only in the other columns
+const ROW_FORMAT_JSON: u8 = 1; // The row as JSON, after the version byte with no marker
+const ROW_FORMAT_MARKED_JSON: u8 = 2; // The row as JSON, after the marker and version
+const ROW_FORMAT_KEYED_RESPONSES: u8 = 3; // As 2, with responses keyed by port and protocol
+pub(crate) const ROW_FORMAT: u8 = ROW_FORMAT_KEYED_RESPONSES; // What `DatabaseResult::encode` writes
+
+// Column families that aren't keyed by host
+const NON_HOST_COLUMNS: [&str; 5] = [
+    PREFIX_COLUMN,
+    RUN_COLUMN,
+    PORT_INDEX_COLUMN,
+    TAG_INDEX_COLUMN,
+    BANNER_COLUMN,
+];
+
+/// RocksDB tuning for `ResultDatabase::with_options`. The defaults suit a
+/// machine with a few GB of memory to spare.
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    /// Size of the LRU cache for uncompressed blocks, in MB
+    pub block_cache_mb: usize,
+    /// Size of each memtable before it's flushed to disk, in MB
+    pub write_buffer_mb: usize,
+    /// Background threads for flushes and compactions
+    pub parallelism: usize,
+    /// Rows per write batch, and rows held in memory when walking the database
+    pub batch_size: usize,
+    /// How SST files below the two smallest levels are compressed
+    pub compression: DBCompressionType,
+    /// Save rows without the write-ahead log, in batches of at least 50000
+    pub bulk_load: bool,
+    /// Most bytes of each raw service banner to store, the rest is dropped
+    pub banner_limit: usize,
+    /// Key ranges a full column search scans at once, 1 to scan serially
+    pub search_threads: usize,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            block_cache_mb: BLOCK_CACHE_SIZE_MB,
+            write_buffer_mb: WRITE_BUFFER_SIZE_MB,
+            parallelism: NUM_PARALLEL_THREADS,
+            batch_size: BATCH_SIZE,
+            compression: DBCompressionType::Lz4,
+            bulk_load: false,
+            banner_limit: BANNER_LIMIT_BYTES,
+            search_threads: NUM_PARALLEL_THREADS,
+        }
+    }
+}
+
+impl DatabaseOptions {
+    pub fn with_block_cache_mb(mut self, mb: usize) -> Self {
+        self.block_cache_mb = mb;
+        self
+    }
+
+    pub fn with_write_buffer_mb(mut self, mb: usize) -> Self {
+        self.write_buffer_mb = mb;
+        self
+    }
+
+    pub fn with_parallelism(mut self, threads: usize) -> Self {
+        self.parallelism = threads;
+        self
+    }
+
+    pub fn with_batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: DBCompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Speed up large imports by having `save_rows` skip the write-ahead log
+    /// and write bigger batches. The rows are flushed to disk when
+    /// `save_rows` returns, but if the process crashes or loses power before
+    /// then, any of the rows it was saving may be lost, not just the last
+    /// batch. Other writes, such as tags, still go through the log.
+    pub fn with_bulk_load(mut self, bulk_load: bool) -> Self {
+        self.bulk_load = bulk_load;
+        self
+    }
+
+    pub fn with_banner_limit(mut self, bytes: usize) -> Self {
+        self.banner_limit = bytes;
+        self
+    }
+
+    pub fn with_search_threads(mut self, threads: usize) -> Self {
+        self.search_threads = threads;
+        self
+    }
+}
 
 pub struct ResultDatabase {
     pub path: String,
-    options: Options,
-    columns: Vec<String>,
+    pub(crate) options: Options,
+    // Rows per write batch, from `DatabaseOptions::batch_size`
+    pub(crate) batch_size: usize,
+    bulk_load: bool,
+    // Most bytes of a raw banner stored, from `DatabaseOptions::banner_limit`
+    pub(crate) banner_limit: usize,
+    // Key ranges scanned in parallel, from `DatabaseOptions::search_threads`
+    search_threads: usize,
+    pub(crate) columns: Vec<String>,
+    // Hosts deleted since garbage was last collected
+    pub(crate) deletes_since_gc: AtomicU64,
+    // How far the automatic garbage collection in progress has got
+    pub(crate) gc_cursor: Mutex<Option<GcCursor>>,
+    pub(crate) clock: Arc<dyn Clock>,
+    // Told how long operations are getting on, from `with_progress`
+    pub(crate) progress: Option<ProgressHook>,
+    // Opened on first use and kept open for every operation after that
+    handle: OnceLock<Arc<DB>>,
+    // Held while opening, so two threads don't both open the files
+    opening: Mutex<()>,
+    // Held from reading what a save merges with until it's written, so two
+    // threads saving the same host don't lose each other's ports
+    pub(crate) writing: Mutex<()>,
+    read_only: bool,
+    pub(crate) ttl: Option<Duration>,
+    // Rows written and read, reported by `metrics`
+    pub(crate) counters: Counters,
+    // Sent each row once it's saved, from `subscribe`
+    pub(crate) subscribers: Subscribers,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Everything saved about one host. Serializes as a flat JSON object, with
+/// `responses` as the JSON it holds rather than a string of it when it's a
+/// valid object or array.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct DatabaseResult {
     pub id: String,
     pub ports: Vec<i32>,
     pub services: Vec<String>,
+    /// `Responses` as a JSON object, for hosts whose services were identified
+    #[serde(
+        serialize_with = "serialize_responses",
+        deserialize_with = "deserialize_responses"
+    )]
     pub responses: String,
+    /// The identified services, sorted by port, as stored in the services
+    /// column family. Kept in step with `responses` by `set_response_map`.
+    #[serde(default)]
+    pub service_entries: Vec<ServiceEntry>,
+    /// Unix time in seconds the host was first saved, if recorded
+    #[serde(default)]
+    pub first_seen: Option<u64>,
+    /// Unix time in seconds the host was last saved, if recorded
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    /// Id of the scan run that last saved the host, if it was saved by one
+    #[serde(default)]
+    pub last_run: Option<String>,
+    /// Labels added with `ResultDatabase::add_tag`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Name from the host's PTR record, or set with `ResultDatabase::set_hostname`
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Round trip time of the last ICMP echo the host answered, in milliseconds
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+    /// Operating system guessed for the host, set with `ResultDatabase::set_os_info`
+    #[serde(default)]
+    pub os_guess: Option<String>,
 }
 
-impl DatabaseResult {
-    pub fn to_string(&self) -> String {
-        let mut str = "".to_string();
+/// One identified service on a host
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ServiceEntry {
+    pub port: u16,
+    pub protocol: String,
+    pub name: String,
+    /// Raw banner or response body
+    pub banner: String,
+    /// Software and version, when the banner names them
+    pub version: Option<String>,
+}
+
+impl ServiceEntry {
+    /// Whether `query` is part of the service name or banner, ignoring case
+    /// as a `SearchOptions::case_insensitive` service search does
+    pub fn matches(&self, query: &str) -> bool {
+        SearchOptions::all_of([query])
+            .case_insensitive()
+            .matcher()
+            .matches_services(std::slice::from_ref(self))
+    }
+
+    // Whether `term` is part of the name or banner
+    fn contains(&self, term: &SearchTerm) -> bool {
+        term.is_match(self.name.as_bytes()) || term.is_match(self.banner.as_bytes())
+    }
+}
+
+// Software named by the banner's fingerprint, an SSH identification string
+// or an HTTP Server header
+fn version_from_banner(port: u16, banner: &str) -> Option<String> {
+    version_from_match(identify_service(port, banner.as_bytes()).as_ref(), banner)
+}
+
+/// Software named by `found`, the fingerprint the banner matched, or failing
+/// that by an SSH identification string or HTTP Server header in the banner
+pub(crate) fn version_from_match(found: Option<&ServiceMatch>, banner: &str) -> Option<String> {
+    if let Some(found) = found
+        && let Some(version) = &found.version
+    {
+        return Some(match &found.product {
+            Some(product) => format!("{} {}", product, version),
+            None => version.clone(),
+        });
+    }
+
+    if let Some(ident) = banner.strip_prefix("SSH-") {
+        return ident
+            .split_once('-')
+            .map(|(_, software)| {
+                software
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .filter(|software| !software.is_empty());
+    }
+
+    banner
+        .lines()
+        .find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            header
+                .eq_ignore_ascii_case("server")
+                .then(|| value.trim().to_string())
+        })
+        .filter(|server| !server.is_empty())
+}
+
+// `DatabaseResult::responses` as the JSON object or array it holds, or as a
+// string if it holds anything else
+fn serialize_responses<S: Serializer>(responses: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match serde_json::from_str::<serde_json::Value>(responses) {
+        Ok(value) if value.is_object() || value.is_array() => value.serialize(serializer),
+        _ => serializer.serialize_str(responses),
+    }
+}
 
-        str += format!(
-            "\n{}\n- ports: [{}]\n- services: [{}]\n- responses: [{}]",
+// Read `responses` back from either form `serialize_responses` writes, and
+// from rows saved before it, which always held a string
+fn deserialize_responses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(responses) => Ok(responses),
+        value => Ok(value.to_string()),
+    }
+}
+
+impl fmt::Display for DatabaseResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\n{}\n- ports: [{}]\n- services: [{}]\n- responses: [",
             self.id,
             join_nums(&self.ports, ","),
-            self.services.join(", "),
-            if let Ok(data) =
-                serde_json::from_str::<HashMap<i32, (String, String)>>(self.responses.as_str())
-            {
-                format!("{:?}", data)
-            } else {
-                self.responses.clone()
+            self.services.join(", ")
+        )?;
+        let responses = self.response_map();
+        let mut responses: Vec<_> = responses.iter().collect();
+        responses.sort_by_key(|(key, _)| parse_service_key(key));
+        for (i, (key, service)) in responses.into_iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}: {:?}", separator, key, service)?;
+        }
+        write!(f, "]")?;
+        if let Some(rtt_ms) = self.rtt_ms {
+            write!(f, "\n- rtt: {:.2}ms", rtt_ms)?;
+        }
+        if let Some(os_guess) = &self.os_guess {
+            write!(f, "\n- os: {}", os_guess)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why `DatabaseResult::decode` couldn't read a row
+#[derive(Debug)]
+pub enum RowDecodeError {
+    /// Neither the row marker nor a version from before it, so not a row
+    NotARow,
+    /// Written with a format version this build doesn't know, by a newer one
+    UnknownVersion(u8),
+    /// The version is known but the row after it doesn't parse
+    Corrupt(serde_json::Error),
+}
+
+impl fmt::Display for RowDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowDecodeError::NotARow => write!(f, "not a row, no format marker"),
+            RowDecodeError::UnknownVersion(version) => {
+                write!(f, "unknown row format version {}", version)
             }
-        )
-        .as_str();
+            RowDecodeError::Corrupt(e) => write!(f, "corrupt row: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RowDecodeError {}
+
+/// Why a database operation failed, so callers can tell a database they
+/// couldn't open from one that doesn't have what they asked for. Each
+/// variant holds the underlying message.
+#[derive(Debug)]
+pub enum DatabaseError {
+    /// Another process has the database open for writing
+    Locked(String),
+    /// RocksDB found its files damaged
+    Corrupted(String),
+    /// There's no database at the path, e.g. when opening read-only, or it
+    /// has no column family of the name asked for
+    NotFound(String),
+    /// The database was opened read-only and asked to write
+    ReadOnly(String),
+    /// Reading or writing the files failed some other way
+    Io(String),
+    /// A stored value couldn't be decoded, or a value encoded to be stored
+    Encoding(String),
+}
+
+impl From<rocksdb::Error> for DatabaseError {
+    fn from(e: rocksdb::Error) -> Self {
+        let message = e.to_string();
+        match e.kind() {
+            // The lock file held by another process, or another handle in
+            // this one
+            ErrorKind::IOError if message.contains("LOCK:") => DatabaseError::Locked(message),
+            ErrorKind::IOError if message.contains("No such file or directory") => {
+                DatabaseError::NotFound(message)
+            }
+            ErrorKind::NotFound => DatabaseError::NotFound(message),
+            ErrorKind::Corruption => DatabaseError::Corrupted(message),
+            _ => DatabaseError::Io(message),
+        }
+    }
+}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(e: std::io::Error) -> Self {
+        DatabaseError::Io(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for DatabaseError {
+    fn from(e: serde_json::Error) -> Self {
+        DatabaseError::Encoding(e.to_string())
+    }
+}
 
-        str
+impl From<RowDecodeError> for DatabaseError {
+    fn from(e: RowDecodeError) -> Self {
+        DatabaseError::Encoding(e.to_string())
     }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Locked(e) => {
+                write!(f, "database is in use by another process: {}", e)
+            }
+            DatabaseError::Corrupted(e) => write!(f, "database is corrupted: {}", e),
+            DatabaseError::NotFound(e) => write!(f, "database not found: {}", e),
+            DatabaseError::ReadOnly(path) => write!(f, "database {} is opened read-only", path),
+            DatabaseError::Io(e) => write!(f, "database I/O error: {}", e),
+            DatabaseError::Encoding(e) => write!(f, "bad stored value: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl DatabaseResult {
+    /// The whole row as stored in the default column family: a marker, the
+    /// format version byte, then the row in that format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = ROW_MAGIC.to_vec();
+        data.push(ROW_FORMAT);
+        // Only strings, numbers and the responses JSON, none of which can
+        // fail to serialize
+        data.extend(serde_json::to_vec(self).expect("rows always serialize to JSON"));
+        data
+    }
+
+    /// Format version of a value from the default column family, and the
+    /// row after it. The empty values versions before the encoding stored
+    /// are version 0, and rows from before the marker are only recognised
+    /// as version 1. None for anything else.
+    fn split_version(data: &[u8]) -> Option<(u8, &[u8])> {
+        match data.strip_prefix(ROW_MAGIC) {
+            Some([version, row @ ..]) => Some((*version, row)),
+            Some([]) => None,
+            None => match data {
+                [] => Some((ROW_FORMAT_LEGACY, data)),
+                [ROW_FORMAT_JSON, row @ ..] => Some((ROW_FORMAT_JSON, row)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Format version of a value from the default column family, None if
+    /// it isn't a row
+    pub fn format_version(data: &[u8]) -> Option<u8> {
+        Self::split_version(data).map(|(version, _)| version)
+    }
+
+    /// Read a row from the default column family. Ok(None) for a version 0
+    /// row, whose fields have to be read from the other columns instead.
+    /// Rows from a newer version, or that aren't rows at all, are an error
+    /// rather than a guess, and can still be read from the other columns.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>, RowDecodeError> {
+        match Self::split_version(data).ok_or(RowDecodeError::NotARow)? {
+            (ROW_FORMAT_LEGACY, _) => Ok(None),
+            (ROW_FORMAT_JSON | ROW_FORMAT_MARKED_JSON, row) => {
+                let mut row: Self = serde_json::from_slice(row).map_err(RowDecodeError::Corrupt)?;
+                row.upgrade_responses();
+                Ok(Some(row))
+            }
+            (ROW_FORMAT_KEYED_RESPONSES, row) => {
+                let mut row: Self = serde_json::from_slice(row).map_err(RowDecodeError::Corrupt)?;
+                // Saved before the service entries were stored
+                if row.service_entries.is_empty() {
+                    row.upgrade_responses();
+                }
+                Ok(Some(row))
+            }
+            (version, _) => Err(RowDecodeError::UnknownVersion(version)),
+        }
+    }
+
+    /// The responses column as a map, whatever version saved it. Keys that
+    /// are only a port, as rows from before the protocol was added have,
+    /// are TCP. Responses that aren't a JSON object are kept whole under
+    /// `UNKNOWN_SERVICE_KEY`, named after the row's services.
+    pub fn response_map(&self) -> Responses {
+        if self.responses.is_empty() {
+            return Responses::new();
+        }
+        if let Ok(responses) = serde_json::from_str::<Responses>(&self.responses) {
+            return responses
+                .into_iter()
+                .map(|(key, service)| match key.parse::<i32>() {
+                    Ok(port) => (service_key(port, "tcp"), service),
+                    Err(_) => (key, service),
+                })
+                .collect();
+        }
+
+        let name = if self.services.is_empty() {
+            UNKNOWN_SERVICE_KEY.to_string()
+        } else {
+            self.services.join(",")
+        };
+        Responses::from([(
+            UNKNOWN_SERVICE_KEY.to_string(),
+            (name, self.responses.clone()),
+        )])
+    }
+
+    /// Replace the responses column with `responses`, and the service
+    /// entries with one for each response and a portless one for each
+    /// service name no response has. Responses kept under
+    /// `UNKNOWN_SERVICE_KEY` are port 0 too. Entries the row already has
+    /// for a response are kept as they are, the others get their version
+    /// from the banner.
+    pub fn set_response_map(&mut self, responses: &Responses) {
+        self.responses = if responses.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(responses).unwrap_or_default()
+        };
+
+        let mut entries: Vec<ServiceEntry> = responses
+            .iter()
+            .map(|(key, (name, banner))| {
+                let (port, protocol) = parse_service_key(key).unwrap_or((0, "tcp"));
+                self.service_entries
+                    .iter()
+                    .find(|entry| {
+                        entry.port == port
+                            && entry.protocol == protocol
+                            && &entry.name == name
+                            && &entry.banner == banner
+                    })
+                    .cloned()
+                    .unwrap_or_else(|| ServiceEntry {
+                        port,
+                        protocol: protocol.to_string(),
+                        name: name.clone(),
+                        banner: banner.clone(),
+                        version: version_from_banner(port, banner),
+                    })
+            })
+            .collect();
+        for name in &self.services {
+            if !name.is_empty() && !entries.iter().any(|entry| &entry.name == name) {
+                entries.push(ServiceEntry {
+                    port: 0,
+                    protocol: "tcp".to_string(),
+                    name: name.clone(),
+                    banner: String::new(),
+                    version: None,
+                });
+            }
+        }
+        entries.sort_by(|a, b| (a.port, &a.protocol).cmp(&(b.port, &b.protocol)));
+        self.service_entries = entries;
+    }
+
+    // Rewrite responses saved by an older version with the current keys,
+    // and make the service entries from them if they weren't stored
+    fn upgrade_responses(&mut self) {
+        let responses = self.response_map();
+        self.set_response_map(&responses);
+    }
+
+    /// The row as a single line of JSON, as `export_json` writes it without
+    /// the banners
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
     pub fn ports_to_string(&self) -> String {
-        return join_nums(&self.ports, ",");
+        join_nums(&self.ports, ",")
+    }
+
+    /// Fold an earlier row for the same host into this one. Ports and
+    /// services are unioned without duplicates, and responses are merged
+    /// with this row's response winning when both have the same port.
+    pub fn merge(&mut self, existing: DatabaseResult) {
+        for service in &existing.services {
+            if !self.services.contains(service) {
+                self.services.push(service.clone());
+            }
+        }
+
+        let mut responses = existing.response_map();
+        responses.extend(self.response_map());
+        // Either row's entry for a response that's kept is reused as is
+        self.service_entries.extend(existing.service_entries);
+        self.set_response_map(&responses);
+
+        self.ports.extend(existing.ports);
+        self.ports.sort();
+        self.ports.dedup();
+
+        if self.last_run.is_none() {
+            self.last_run = existing.last_run;
+        }
+
+        if self.hostname.is_none() {
+            self.hostname = existing.hostname;
+        }
+        if self.rtt_ms.is_none() {
+            self.rtt_ms = existing.rtt_ms;
+        }
+        if self.os_guess.is_none() {
+            self.os_guess = existing.os_guess;
+        }
+
+        for tag in existing.tags {
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+    }
+
+    /// Remove `ports` and their responses from the row, and any service
+    /// name that was only seen on those ports
+    pub fn retire_ports(&mut self, ports: &[i32]) {
+        self.ports.retain(|port| !ports.contains(port));
+
+        let mut responses = self.response_map();
+        let mut retired = Vec::new();
+        responses.retain(|key, (name, _)| {
+            let keep = !matches!(parse_service_key(key),
+                Some((port, "tcp")) if ports.contains(&(port as i32)));
+            if !keep {
+                retired.push(name.clone());
+            }
+            keep
+        });
+        if retired.is_empty() {
+            return;
+        }
+
+        self.services.retain(|service| {
+            !retired.contains(service) || responses.values().any(|(name, _)| name == service)
+        });
+        self.set_response_map(&responses);
     }
 }
 
-pub fn join_nums(nums: &Vec<i32>, sep: &str) -> String {
+/// Service key to (service name, banner), as stored in the responses
+/// column. Keys are "port/protocol", e.g. "22/tcp", made by `service_key`.
+pub type Responses = BTreeMap<String, (String, String)>;
+
+/// Key of responses saved before they were kept by port, which can't be
+/// put under one
+pub const UNKNOWN_SERVICE_KEY: &str = "unknown";
+
+/// Key of the service on `port` in `Responses`, e.g. "22/tcp"
+pub fn service_key(port: i32, protocol: &str) -> String {
+    format!("{}/{}", port, protocol)
+}
+
+/// Port and protocol of a `service_key` key. None for `UNKNOWN_SERVICE_KEY`
+/// and anything else.
+pub fn parse_service_key(key: &str) -> Option<(u16, &str)> {
+    let (port, protocol) = key.split_once('/')?;
+    Some((port.parse().ok()?, protocol))
+}
+
+pub fn join_nums(nums: &[i32], sep: &str) -> String {
     // 1. Convert numbers to strings
     let str_nums: Vec<String> = nums
         .iter()
@@ -65,32 +702,210 @@ pub fn join_nums(nums: &Vec<i32>, sep: &str) -> String {
     str_nums.join(sep)
 }
 
-pub fn split_nums(str: &str, sep: &str) -> Vec<i32> {
-    if str.is_empty() {
-        return vec![];
+/// A host as it's shown in `DatabaseResult::id`. IP addresses are written
+/// in their canonical form, so "0:0:0:0:0:0:0:1" and "::1" are the same
+/// host. Anything else is used as is.
+pub fn canonical_host(host: &str) -> String {
+    match host.trim().parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_string(),
+    }
+}
+
+/// The key a host is stored under: a byte for the address family and then
+/// the address in network byte order, so hosts sort numerically with IPv4
+/// before IPv6 and every way of writing an address finds the same row.
+/// Anything that isn't an address is 0xff followed by the text.
+pub fn host_key(host: &str) -> Vec<u8> {
+    match host.trim().parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[KEY_IPV4][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[KEY_IPV6][..], &ip.octets()].concat(),
+        Err(_) => [&[KEY_NAME], host.as_bytes()].concat(),
+    }
+}
+
+/// The host a `host_key` key was made from, as `canonical_host` writes it.
+/// None for anything else, such as the text keys older versions used.
+pub fn host_from_key(key: &[u8]) -> Option<String> {
+    match key.split_first()? {
+        (&KEY_IPV4, address) => {
+            Some(Ipv4Addr::from(<[u8; 4]>::try_from(address).ok()?).to_string())
+        }
+        (&KEY_IPV6, address) => {
+            Some(Ipv6Addr::from(<[u8; 16]>::try_from(address).ok()?).to_string())
+        }
+        (&KEY_NAME, name) => std::str::from_utf8(name).ok().map(String::from),
+        _ => None,
+    }
+}
+
+// Text keys from older versions start with a printable character, so they
+// all sort between the IPv6 keys and the name keys
+pub(crate) const LEGACY_KEYS_START: [u8; 1] = [KEY_IPV6 + 1];
+pub(crate) const LEGACY_KEYS_END: [u8; 1] = [KEY_NAME];
+
+// Whether any host is still stored under a text key, which lookups by
+// host no longer find until `ResultDatabase::migrate_keys` moves it
+fn has_legacy_keys(db: &DB, cf: &impl AsColumnFamilyRef) -> bool {
+    let mut read_opts = ReadOptions::default();
+    read_opts.set_total_order_seek(true);
+    read_opts.set_iterate_upper_bound(LEGACY_KEYS_END);
+    db.iterator_cf_opt(
+        cf,
+        read_opts,
+        IteratorMode::From(&LEGACY_KEYS_START, Direction::Forward),
+    )
+    .next()
+    .is_some_and(|item| item.is_ok())
+}
+
+/// The handle for `column`, an error rather than a panic if the database
+/// has no column family of that name
+pub(crate) fn column_handle<'a>(
+    db: &'a DB,
+    column: &str,
+) -> Result<&'a ColumnFamily, DatabaseError> {
+    db.cf_handle(column)
+        .ok_or_else(|| DatabaseError::NotFound(format!("no column family \"{}\"", column)))
+}
+
+/// Iterate the entries of `cf` whose keys start with `prefix`, stopping at
+/// the first key past it instead of reading on to the end. `extractor_len`
+/// is the length of the column family's prefix extractor, 0 if it has none.
+pub(crate) fn seek_prefix<'a>(
+    db: &'a DB,
+    cf: &impl AsColumnFamilyRef,
+    prefix: &[u8],
+    extractor_len: usize,
+) -> DBIteratorWithThreadMode<'a, DB> {
+    db.iterator_cf_opt(
+        cf,
+        prefix_read_opts(prefix, extractor_len),
+        IteratorMode::From(prefix, Direction::Forward),
+    )
+}
+
+/// Read options for `seek_prefix`, for iterating a prefix of a snapshot
+pub(crate) fn prefix_read_opts(prefix: &[u8], extractor_len: usize) -> ReadOptions {
+    let mut read_opts = ReadOptions::default();
+    // Only a prefix at least as long as the extractor's can use the prefix
+    // bloom filters, a shorter one may span several extracted prefixes
+    if extractor_len > 0 && prefix.len() >= extractor_len {
+        read_opts.set_prefix_same_as_start(true);
+    } else {
+        read_opts.set_total_order_seek(true);
+    }
+    if let Some(upper) = prefix_successor(prefix) {
+        read_opts.set_iterate_upper_bound(upper);
+    }
+    read_opts
+}
+
+// Inclusive start and exclusive end of a range of keys, None if unbounded
+type KeyRange = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+// Split the keys of `cf` into up to `count` consecutive ranges. The split
+// points are spread evenly between the first and last keys, by their first
+// 8 bytes, so hosts clustered in one network are still split up.
+fn key_ranges(db: &DB, cf: &ColumnFamily, count: usize) -> Vec<KeyRange> {
+    let whole = vec![(None, None)];
+    if count <= 1 {
+        return whole;
+    }
+
+    let mut read_opts = ReadOptions::default();
+    read_opts.set_total_order_seek(true);
+    let mut iter = db.raw_iterator_cf_opt(cf, read_opts);
+    iter.seek_to_first();
+    let Some(first) = iter.key().map(key_position) else {
+        return whole;
+    };
+    iter.seek_to_last();
+    let Some(last) = iter.key().map(key_position) else {
+        return whole;
     };
 
-    return str
-        .split(sep)
-        .map(|n| {
-            if let Ok(num) = n.parse::<i32>() {
-                return num;
-            } else {
-                return 0;
-            }
-        })
+    let step = (last - first) / count as u64;
+    if step == 0 {
+        return whole;
+    }
+
+    let splits: Vec<Vec<u8>> = (1..count as u64)
+        .map(|i| (first + step * i).to_be_bytes().to_vec())
         .collect();
+    let mut ranges = Vec::with_capacity(count);
+    let mut lower = None;
+    for split in splits {
+        ranges.push((lower, Some(split.clone())));
+        lower = Some(split);
+    }
+    ranges.push((lower, None));
+    ranges
+}
+
+// A key's first 8 bytes as a number, zero padded, for `key_ranges`
+fn key_position(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = key.len().min(8);
+    bytes[..len].copy_from_slice(&key[..len]);
+    u64::from_be_bytes(bytes)
+}
+
+// The smallest key after every key starting with `prefix`, None if there is
+// none because the prefix is empty or all 0xff
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
+// Start of the key shared by every host in `prefix`: the family byte and
+// the network's whole bytes. Bits of a partly covered byte are left for the
+// caller to check.
+fn cidr_key_prefix(prefix: &IpPrefix) -> Vec<u8> {
+    let whole = prefix.len as usize / 8;
+    match prefix.network {
+        IpAddr::V4(network) => [&[KEY_IPV4][..], &network.octets()[..whole]].concat(),
+        IpAddr::V6(network) => [&[KEY_IPV6][..], &network.octets()[..whole]].concat(),
+    }
+}
+
+/// Tags from their stored form, a comma separated list
+pub(crate) fn split_tags(str: &str) -> Vec<String> {
+    str.split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
+/// Numbers in a separated list. Entries that aren't numbers are skipped
+pub fn split_nums(str: &str, sep: &str) -> Vec<i32> {
+    str.split(sep)
+        .filter_map(|n| n.trim().parse::<i32>().ok())
+        .collect()
 }
 
 impl ResultDatabase {
     pub fn new(path: &str) -> Self {
+        Self::with_options(path, DatabaseOptions::default())
+    }
+
+    /// A database at `path` tuned with `opts`. The options only affect how
+    /// this process uses the files, so a path can be reopened with others.
+    pub fn with_options(path: &str, opts: DatabaseOptions) -> Self {
+        let write_buffer_bytes = opts.write_buffer_mb.max(1) * 1024 * 1024;
         let mut options = Options::default();
 
         options.create_if_missing(true);
         options.create_missing_column_families(true);
-        options.increase_parallelism(NUM_PARALLEL_THREADS as i32); // Use multiple background threads
+        options.increase_parallelism(opts.parallelism.max(1) as i32); // Use multiple background threads
         options.set_max_background_jobs(4);
-        options.set_write_buffer_size(WRITE_BUFFER_SIZE_MB * 1024 * 1024); // Larger write buffer
+        options.set_write_buffer_size(write_buffer_bytes); // Larger write buffer
         options.set_max_write_buffer_number(3); // Allow more write buffers
         options.set_target_file_size_base(64 * 1024 * 1024); // 64MB per SST file
         options.set_level_zero_file_num_compaction_trigger(4); // Start compaction after 4 L0 files
@@ -98,14 +913,19 @@ impl ResultDatabase {
         options.set_level_zero_stop_writes_trigger(24); // Stop writes after 24 L0 files
         options.set_max_bytes_for_level_base(512 * 1024 * 1024); // 512MB for base level
         options.set_disable_auto_compactions(false); // Enable auto compactions
-        options.optimize_level_style_compaction(WRITE_BUFFER_SIZE_MB * 1024 * 1024);
+        options.optimize_level_style_compaction(write_buffer_bytes);
+        // Like optimize_level_style_compaction, leave the two smallest levels
+        // uncompressed, they are rewritten too often to be worth it
+        let mut per_level = [opts.compression; 7];
+        per_level[..2].fill(DBCompressionType::None);
+        options.set_compression_per_level(&per_level);
         options.set_max_total_wal_size(256 * 1024 * 1024); // 256MB max for WAL files
         options.set_keep_log_file_num(5); // Keep 5 log files
         options.set_log_level(rocksdb::LogLevel::Warn); // Minimal logging
 
         // Set up block cache for improved read performance
         let mut block_opts = rocksdb::BlockBasedOptions::default();
-        block_opts.set_block_cache(&Cache::new_lru_cache(BLOCK_CACHE_SIZE_MB * 1024 * 1024));
+        block_opts.set_block_cache(&Cache::new_lru_cache(opts.block_cache_mb * 1024 * 1024));
         block_opts.set_bloom_filter(10.0, false);
         block_opts.set_whole_key_filtering(true);
         block_opts.set_cache_index_and_filter_blocks(true);
@@ -119,302 +939,1061 @@ impl ResultDatabase {
             "ports".to_string(),
             "services".to_string(),
             "responses".to_string(),
+            "timestamps".to_string(),
+            "last_run".to_string(),
+            "tags".to_string(),
+            "hostnames".to_string(),
+            "rtt".to_string(),
+            "os".to_string(),
+            PREFIX_COLUMN.to_string(),
+            RUN_COLUMN.to_string(),
+            PORT_INDEX_COLUMN.to_string(),
+            TAG_INDEX_COLUMN.to_string(),
+            BANNER_COLUMN.to_string(),
         ];
 
         Self {
             path: path.to_string(),
             options,
+            batch_size: opts.batch_size.max(1),
+            bulk_load: opts.bulk_load,
+            banner_limit: opts.banner_limit,
+            search_threads: opts.search_threads.max(1),
             columns: column_families,
+            deletes_since_gc: AtomicU64::new(0),
+            gc_cursor: Mutex::new(None),
+            clock: clock::system(),
+            progress: None,
+            handle: OnceLock::new(),
+            opening: Mutex::new(()),
+            writing: Mutex::new(()),
+            read_only: false,
+            ttl: None,
+            counters: Counters::default(),
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    /// Open an existing database for queries only. Several read-only
+    /// instances can share a path with one writer, e.g. to query while a
+    /// scan is running. Rows saved after the first query are not seen.
+    pub fn open_read_only(path: &str) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(path)
+        }
+    }
+
+    /// The database handle, opening it if this is the first use
+    pub(crate) fn db(&self) -> Result<Arc<DB>, DatabaseError> {
+        self.db_ref().map(Arc::clone)
+    }
+
+    /// `db` borrowed for as long as this struct, which keeps it open until
+    /// it is dropped
+    pub(crate) fn db_ref(&self) -> Result<&Arc<DB>, DatabaseError> {
+        if let Some(db) = self.handle.get() {
+            return Ok(db);
+        }
+        let _opening = self.opening.lock().unwrap();
+        if let Some(db) = self.handle.get() {
+            return Ok(db);
+        }
+
+        let db = Arc::new(match self.ttl {
+            _ if self.read_only => DB::open_cf_descriptors_read_only(
+                &self.options,
+                &self.path,
+                self.column_descriptors(),
+                false,
+            )?,
+            Some(ttl) => {
+                // Only host rows and their index entries expire, scan runs
+                // and prefix paths are kept
+                let descriptors = self.columns.iter().map(|column| {
+                    let ttl = if [PREFIX_COLUMN, RUN_COLUMN].contains(&column.as_str()) {
+                        ColumnFamilyTtl::Disabled
+                    } else {
+                        ColumnFamilyTtl::SameAsDb
+                    };
+                    ColumnFamilyDescriptor::new_with_ttl(column, self.column_options(column), ttl)
+                });
+                DB::open_cf_descriptors_with_ttl(&self.options, &self.path, descriptors, ttl)?
+            }
+            None => DB::open_cf_descriptors(&self.options, &self.path, self.column_descriptors())?,
+        });
+        if has_legacy_keys(&db, column_handle(&db, "default")?) {
+            warn!(
+                "Database {} stores hosts under text keys from an older version, run `migrate` to convert them",
+                self.path
+            );
+        }
+
+        Ok(self.handle.get_or_init(|| db))
+    }
+
+    fn column_descriptors(&self) -> Vec<ColumnFamilyDescriptor> {
+        self.columns
+            .iter()
+            .map(|column| ColumnFamilyDescriptor::new(column, self.column_options(column)))
+            .collect()
+    }
+
+    // The port index gets a prefix extractor on its port, and rows one on
+    // the start of the host key, a /16 for both IPv4 and IPv6. The prefix
+    // bloom filters then let a seek to one port's hosts or one network skip
+    // files without it. Reads that cross prefixes must set total_order_seek.
+    fn column_options(&self, column: &str) -> Options {
+        let prefix_len = match column {
+            PORT_INDEX_COLUMN => PORT_INDEX_PREFIX_LEN,
+            "default" => HOST_KEY_PREFIX_LEN,
+            _ => return self.options.clone(),
+        };
+        let mut options = self.options.clone();
+        options.set_prefix_extractor(SliceTransform::create_fixed_prefix(prefix_len));
+        options.set_memtable_prefix_bloom_ratio(0.1);
+        options
+    }
+
+    /// `db`, or an error if the database was opened read-only
+    pub(crate) fn writable_db(&self) -> Result<Arc<DB>, DatabaseError> {
+        if self.read_only {
+            return Err(DatabaseError::ReadOnly(self.path.clone()));
         }
+
+        self.db()
+    }
+
+    /// Let compaction drop rows that haven't been saved again within `ttl`.
+    /// Rows are kept forever by default.
+    ///
+    /// The age is measured by RocksDB from the time a row was last written,
+    /// using the system clock rather than `clock`. That is the same moment
+    /// recorded as last_seen in the timestamps column family, so rows expire
+    /// roughly `ttl` after their last_seen, but only once compaction reaches
+    /// them. Use `purge_older_than` to remove them immediately.
+    ///
+    /// Every column family of a host expires on its own, so each save writes
+    /// them all again, along with the host's tag index entries. That
+    /// includes the timestamps, which is what keeps first_seen from being
+    /// lost while the host is still being saved. Tags, hostnames and OS
+    /// guesses set after a host's last save can outlive its row by up to the
+    /// time between them, until garbage collection removes them.
+    ///
+    /// RocksDB stores an expiry time with every value written with a TTL, so
+    /// a database written this way must always be opened with a TTL, and
+    /// can't be opened read-only.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
     }
 
+    /// Use `clock` instead of the system clock for timestamps and timings
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Call `hook` as exports, merges, index rebuilds and full column
+    /// searches work through the database, see `ProgressEvent`
+    pub fn with_progress(mut self, hook: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(hook));
+        self
+    }
+
+    /// Save the hosts that answered, with their round trip times. Hosts that
+    /// didn't answer are skipped.
     pub fn add_ping_results(
         &self,
-        results: &Vec<IpAddr>,
+        results: &[PingResult],
+        run_id: Option<&str>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut string_rows = Vec::with_capacity(results.len()); // Pre-allocate capacity
 
-        for result in results {
-            string_rows.push(DatabaseResult {
-                id: result.to_string(),
-                ports: vec![],
-                services: Vec::new(),
-                responses: String::new(),
-            });
+        for result in results.iter().filter(|result| result.is_up) {
+            let mut row = result.to_database();
+            row.last_run = run_id.map(|id| id.to_string());
+            string_rows.push(row);
         }
 
-        return self.save_rows(string_rows);
+        Ok(self.upsert_rows(string_rows)?)
+    }
+
+    /// Save hosts known to be up, without round trip times
+    pub fn add_ping_hosts(
+        &self,
+        hosts: &[IpAddr],
+        run_id: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let results = hosts
+            .iter()
+            .map(|host| PingResult {
+                is_up: true,
+                ..PingResult::create(*host)
+            })
+            .collect::<Vec<_>>();
+
+        self.add_ping_results(&results, run_id)
     }
 
+    /// Merge port scan results into the stored rows. Stored ports the scan
+    /// probed and found not open are removed, along with their responses.
+    /// Ports it didn't probe are left alone.
     pub fn add_tcp_results(
         &self,
         results: &Vec<PortScanResult>,
+        run_id: Option<&str>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let _writing = self.writing.lock().unwrap();
+
         let mut string_rows = Vec::with_capacity(results.len()); // Pre-allocate capacity
 
         for result in results {
-            string_rows.push(result.to_database());
+            let mut row = result.to_database();
+            row.last_run = run_id.map(|id| id.to_string());
+            let retired = result.not_open_ports();
+            if let Some(mut existing) = self.fetch_row(&db, &row.id, &cfs)? {
+                existing.retire_ports(&retired);
+                row.merge(existing);
+            }
+            string_rows.push((row, retired));
         }
 
-        return self.save_rows(string_rows);
+        Ok(self.write_rows(string_rows)?)
     }
 
     pub fn add_service_results(
         &self,
         results: &Vec<ServiceScanResult>,
+        run_id: Option<&str>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut string_rows = Vec::with_capacity(results.len()); // Pre-allocate capacity
 
         for result in results {
-            string_rows.push(result.to_database());
+            let mut row = result.to_database();
+            row.last_run = run_id.map(|id| id.to_string());
+            string_rows.push(row);
         }
 
-        return self.save_rows(string_rows);
+        self.upsert_rows(string_rows)?;
+        self.save_banners(results)
+    }
+
+    /// Like `save_rows`, but merges each row with what is already stored for
+    /// the host instead of replacing it. Saving the same rows twice is a no-op.
+    pub fn upsert_rows(&self, rows: Vec<DatabaseResult>) -> Result<(), DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let _writing = self.writing.lock().unwrap();
+
+        let rows = rows
+            .into_iter()
+            .map(|mut row| {
+                if let Some(existing) = self.fetch_row(&db, &row.id, &cfs)? {
+                    row.merge(existing);
+                }
+                Ok((row, Vec::new()))
+            })
+            .collect::<Result<_, DatabaseError>>()?;
+
+        self.write_rows(rows)
+    }
+
+    /// Save rows, replacing what is stored for each host except its ports.
+    /// Those are unioned with the stored ones, so saves of the same host
+    /// from different scans or threads never lose each other's ports, and a
+    /// row with no ports never clears them. `upsert_rows` merges the rest of
+    /// the row as well.
+    pub fn save_rows(&self, string_rows: Vec<DatabaseResult>) -> Result<(), DatabaseError> {
+        let _writing = self.writing.lock().unwrap();
+        self.write_rows(
+            string_rows
+                .into_iter()
+                .map(|row| (row, Vec::new()))
+                .collect(),
+        )
     }
 
-    pub fn save_rows(
+    // `save_rows` for callers already holding the write lock. Each row comes
+    // with ports to remove from the union rather than keep, ones a scan
+    // probed and found not open.
+    fn write_rows(
         &self,
-        string_rows: Vec<DatabaseResult>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let db = Arc::new(DB::open_cf(&self.options, &self.path, &self.columns)?);
-        let cf_default = db.cf_handle(&self.columns[0]).unwrap();
-        let cf_ports = db.cf_handle(&self.columns[1]).unwrap();
-        let cf_services = db.cf_handle(&self.columns[2]).unwrap();
-        let cf_responses = db.cf_handle(&self.columns[3]).unwrap();
+        string_rows: Vec<(DatabaseResult, Vec<i32>)>,
+    ) -> Result<(), DatabaseError> {
+        let db = self.writable_db()?;
+        let cfs = self.column_handles(&db)?;
+        let indexes = IndexManager::new(self, &db)?;
 
-        let start = Instant::now();
+        let start = self.clock.monotonic();
         let length = string_rows.len();
 
-        // Split the rows into chunks for parallel processing
-        let chunks: Vec<Vec<DatabaseResult>> = string_rows
-            .chunks(BATCH_SIZE)
-            .map(|chunk| chunk.to_vec())
-            .collect();
+        // In bulk load mode nothing is durable until the flush below, so the
+        // write-ahead log is only overhead
+        let mut write_opts = WriteOptions::default();
+        write_opts.disable_wal(self.bulk_load);
+        let batch_size = if self.bulk_load {
+            self.batch_size.max(BULK_BATCH_SIZE)
+        } else {
+            self.batch_size
+        };
 
-        // Process chunks in parallel
         let elapsed = {
             let db_ref = Arc::clone(&db);
-            let cf_default_ref = cf_default;
-
-            // Create batches in parallel but write them sequentially
-            let batches: Vec<WriteBatch> = chunks
-                .into_iter()
-                .map(|chunk| {
-                    let mut batch = WriteBatch::default();
-
-                    for row in chunk {
-                        batch.put_cf(cf_default_ref, row.id.as_bytes(), &vec![]);
-
-                        // Ports
-                        batch.put_cf(
-                            cf_ports,
-                            row.id.as_bytes(),
-                            row.ports_to_string().as_bytes(),
-                        );
-
-                        // Services
-                        batch.put_cf(
-                            cf_services,
-                            row.id.as_bytes(),
-                            row.services.join(",").into_bytes(),
-                        );
-
-                        // Responses
-                        batch.put_cf(cf_responses, row.id.as_bytes(), row.responses.into_bytes());
+            // Only kept when someone is listening
+            let publishing = self.subscribers.active();
+
+            // Each chunk is written before the next one's stored ports are
+            // read, so a host saved twice keeps the ports of both
+            for chunk in string_rows.chunks(batch_size) {
+                let keys: Vec<Vec<u8>> = chunk.iter().map(|(row, _)| host_key(&row.id)).collect();
+                let stored = db_ref.multi_get_cf(keys.iter().map(|key| (cfs[1], key)));
+                // Ports queued for hosts earlier in this chunk
+                let mut queued: HashMap<&[u8], Vec<i32>> = HashMap::new();
+                let mut saved = Vec::new();
+
+                let mut batch = WriteBatch::default();
+                for (((row, retired), key), stored) in chunk.iter().zip(&keys).zip(stored) {
+                    let mut ports = match queued.get(key.as_slice()) {
+                        Some(ports) => ports.clone(),
+                        None => stored
+                            .ok()
+                            .flatten()
+                            .map(|data| decode_ports(&data))
+                            .unwrap_or_default(),
+                    };
+                    ports.extend(&row.ports);
+                    ports.retain(|port| !retired.contains(port));
+                    ports.sort();
+                    ports.dedup();
+
+                    let row = DatabaseResult {
+                        ports,
+                        ..row.clone()
+                    };
+                    indexes.put_row(&mut batch, &row);
+                    queued.insert(key, row.ports.clone());
+                    if publishing {
+                        saved.push(row);
                     }
+                }
 
-                    batch
-                })
-                .collect();
-
-            // Write all batches to the database
-            for batch in batches {
-                db_ref.write(batch)?;
+                db_ref.write_opt(batch, &write_opts)?;
+                self.counters
+                    .rows_written
+                    .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                self.counters.write_batches.fetch_add(1, Ordering::Relaxed);
+                self.subscribers.publish(&saved);
             }
 
             // Force a flush to ensure all data is persisted
             db_ref.flush()?;
 
-            start.elapsed()
+            self.clock.monotonic().saturating_duration_since(start)
         };
 
-        println!("Saved {} rows in {}ms", length, elapsed.as_millis());
+        info!("Saved {} rows in {}", length, humanize_duration(elapsed));
 
         Ok(())
     }
 
-    pub fn get_row_by_host(&self, row: &str) -> Option<DatabaseResult> {
-        let db = DB::open_cf(&self.options, &self.path, &self.columns);
-        if db.is_err() {
-            return None;
+    /// The row saved for `host`. Ok(None) if it has none, an error if the
+    /// database couldn't be opened or read.
+    pub fn get_row_by_host(&self, row: &str) -> Result<Option<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        self.fetch_row(&db, row, &cfs)
+    }
+
+    /// What was identified on `port` of `host`, the TCP service if a UDP
+    /// one was found on it too. None if the host has no row or nothing was
+    /// identified on the port.
+    pub fn get_service(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<Option<ServiceEntry>, DatabaseError> {
+        let Some(row) = self.get_row_by_host(host)? else {
+            return Ok(None);
         };
-        let db = db.unwrap();
+        Ok(row
+            .service_entries
+            .into_iter()
+            .find(|entry| entry.port == port))
+    }
 
-        let cfs = vec![
-            db.cf_handle(&self.columns[0]).unwrap(),
-            db.cf_handle(&self.columns[1]).unwrap(),
-            db.cf_handle(&self.columns[2]).unwrap(),
-            db.cf_handle(&self.columns[3]).unwrap(),
-        ];
+    /// When `host` was last saved, read from the timestamps column alone.
+    /// None if it has no row, or was saved before timestamps were recorded.
+    pub fn last_seen(&self, host: &str) -> Result<Option<SystemTime>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let Some(data) = db.get_pinned_cf(cfs[4], host_key(host))? else {
+            return Ok(None);
+        };
+        Ok(decode_timestamps(&data)
+            .map(|(_, last_seen)| UNIX_EPOCH + Duration::from_secs(last_seen)))
+    }
+
+    /// Whether each host has a row, in the same order as `hosts`. The keys
+    /// are looked up in one batch, so checking a long target list is cheap.
+    pub fn contains_hosts(&self, hosts: &[&str]) -> Result<Vec<bool>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let keys: Vec<Vec<u8>> = hosts.iter().map(|host| host_key(host)).collect();
+        db.multi_get_cf(keys.iter().map(|key| (cfs[0], key)))
+            .into_iter()
+            .map(|value| Ok(value?.is_some()))
+            .collect()
+    }
+
+    /// Rows for many hosts at once, in the same order as `hosts` so they
+    /// can be zipped together, with None for hosts without a row. The
+    /// default column is read in one batch, and holds the whole row of any
+    /// host saved by this version. Older rows are put together from one
+    /// more batch per column, shared by all of them.
+    pub fn get_rows_by_hosts(
+        &self,
+        hosts: &[&str],
+    ) -> Result<Vec<Option<DatabaseResult>>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let keys: Vec<Vec<u8>> = hosts.iter().map(|host| host_key(host)).collect();
+        let mut rows = Vec::with_capacity(hosts.len());
+        // Indexes of the rows to put together from the other columns
+        let mut older = Vec::new();
+        for (i, value) in db
+            .multi_get_cf(keys.iter().map(|key| (cfs[0], key)))
+            .into_iter()
+            .enumerate()
+        {
+            let row = match value? {
+                Some(data) => match DatabaseResult::decode(&data) {
+                    Ok(Some(row)) => Some(row),
+                    decoded => {
+                        if let Err(e) = decoded {
+                            warn!("Reading {} from its columns: {}", hosts[i], e);
+                        }
+                        older.push(i);
+                        None
+                    }
+                },
+                None => None,
+            };
+            rows.push(row);
+        }
+        if older.is_empty() {
+            self.count_rows_read(&rows);
+            return Ok(rows);
+        }
 
-        return self.fetch_row(&db, row, &cfs);
+        // columns[cf][j] is the value in column `cf` of the j-th older row
+        let mut columns: Vec<Vec<Option<Vec<u8>>>> = vec![Vec::new()];
+        for cf in &cfs[1..] {
+            columns.push(
+                db.multi_get_cf(older.iter().map(|&i| (*cf, &keys[i])))
+                    .into_iter()
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+        for (j, &i) in older.iter().enumerate() {
+            rows[i] = Some(row_from_columns(hosts[i], |cf| columns[cf][j].take()));
+        }
+        self.count_rows_read(&rows);
+        Ok(rows)
+    }
+
+    /// Rows with exactly `port` open, looked up through the port index.
+    /// Ports are compared as numbers, so 80 doesn't find hosts with only 8080.
+    pub fn get_rows_by_port(&self, port: i32) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let hosts = IndexManager::new(self, &db)?.hosts_with_port(port)?;
+        Ok(self
+            .fetch_rows(&db, &hosts, &cfs)?
+            .into_iter()
+            // The row has the last word if an index entry was left behind
+            .filter(|row| row.ports.contains(&port))
+            .collect())
+    }
+
+    /// Rows with all or any of `ports` open, each listed once, looked up
+    /// through the port index. An empty list of ports is an error rather
+    /// than a match for every host.
+    pub fn get_rows_by_ports(
+        &self,
+        ports: &[u16],
+        mode: PortMatch,
+    ) -> Result<Vec<DatabaseResult>, Box<dyn std::error::Error>> {
+        if ports.is_empty() {
+            return Err("No ports given to match".into());
+        }
+
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let indexes = IndexManager::new(self, &db)?;
+
+        let mut hosts: Option<BTreeSet<String>> = None;
+        for port in ports {
+            let with_port: BTreeSet<String> =
+                indexes.hosts_with_port(*port as i32)?.into_iter().collect();
+            hosts = Some(match (hosts, mode) {
+                (None, _) => with_port,
+                (Some(hosts), PortMatch::All) => &hosts & &with_port,
+                (Some(hosts), PortMatch::Any) => &hosts | &with_port,
+            });
+        }
+
+        let wanted = |row: &DatabaseResult| {
+            let has = |port: &u16| row.ports.contains(&(*port as i32));
+            match mode {
+                PortMatch::All => ports.iter().all(has),
+                PortMatch::Any => ports.iter().any(has),
+            }
+        };
+
+        Ok(self
+            .fetch_rows(&db, hosts.unwrap_or_default(), &cfs)?
+            .into_iter()
+            .filter(wanted)
+            .collect())
+    }
+
+    /// Rows with at least one open port from `low` to `high` inclusive, e.g.
+    /// 1 and 1024 for hosts with any well-known port open. The bounds may be
+    /// given either way round and are clamped to 0..=65535.
+    pub fn get_rows_by_port_range(
+        &self,
+        low: i32,
+        high: i32,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let (low, high) = (low.min(high), low.max(high));
+        if high < 0 || low > 65535 {
+            return Ok(Vec::new());
+        }
+        let (low, high) = (low.max(0), high.min(65535));
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let hosts = IndexManager::new(self, &db)?.hosts_with_port_range(low as u16, high as u16)?;
+        Ok(self
+            .fetch_rows(&db, &hosts, &cfs)?
+            .into_iter()
+            .filter(|row| row.ports.iter().any(|port| (low..=high).contains(port)))
+            .collect())
+    }
+
+    /// Rows whose host is inside `cidr`, e.g. "192.168.10.0/24" or "2001:db8::/48".
+    /// Keys sort by address, so only the hosts in the network's key range are read.
+    pub fn get_rows_by_cidr(
+        &self,
+        cidr: &str,
+    ) -> Result<Vec<DatabaseResult>, Box<dyn std::error::Error>> {
+        let prefix: IpPrefix = cidr.parse()?;
+
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let mut matching_rows = Vec::new();
+
+        let key_prefix = cidr_key_prefix(&prefix);
+        for item in seek_prefix(&db, cfs[0], &key_prefix, HOST_KEY_PREFIX_LEN) {
+            let (key_bytes, _) = item?;
+            if let Some(host) = host_from_key(&key_bytes)
+                && let Ok(ip) = host.parse::<IpAddr>()
+                && prefix.contains(&ip)
+                && let Some(row) = self.fetch_row(&db, &host, &cfs)?
+            {
+                matching_rows.push(row);
+            }
+        }
+
+        Ok(matching_rows)
+    }
+
+    /// Rows with a service whose name or banner contains `service`
+    pub fn get_rows_by_service(&self, service: &str) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.get_rows_by_service_with(&SearchOptions::all_of([service]))
+    }
+
+    /// Rows whose services match `options`. A term matches if it's part of
+    /// the name or banner of any of the host's services, so "http" and "401"
+    /// can be found on different ports.
+    pub fn get_rows_by_service_with(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let matcher = options.matcher();
+        let mut rows = Vec::new();
+        self.for_each_row(|row| {
+            if matcher.matches_services(&row.service_entries) {
+                rows.push(row);
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(rows)
     }
 
-    pub fn get_rows_by_port(&self, port: &str) -> Vec<DatabaseResult> {
-        if let Ok(result) = self.search_substring_in_column_regex(
-            self.columns[1].as_str(),
-            Regex::new(&format!(r"\b{}\b", port)).unwrap(),
-        ) {
-            return result;
-        } else {
-            return Vec::new();
-        }
+    /// Rows whose guessed operating system contains `os`, e.g. "Linux"
+    /// matches "Linux 2.6.x". Hosts without a guess never match.
+    pub fn get_rows_by_os(&self, os: &str) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.get_rows_by_os_with(&SearchOptions::all_of([os]))
     }
 
-    pub fn get_rows_by_service(&self, service: &str) -> Vec<DatabaseResult> {
-        if let Ok(result) = self.search_substring_in_column(self.columns[2].as_str(), service) {
-            return result;
-        } else {
-            return Vec::new();
-        }
+    /// Rows whose guessed operating system matches `options`
+    pub fn get_rows_by_os_with(
+        &self,
+        options: &SearchOptions,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let matcher = options.matcher();
+        let mut rows = Vec::new();
+        self.for_each_row(|row| {
+            if let Some(guess) = &row.os_guess
+                && matcher.matches(|term| term.is_match(guess.as_bytes()))
+            {
+                rows.push(row);
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(rows)
     }
 
-    pub fn search_substring_in_column(
+    /// Rows last saved before `timestamp` (unix seconds). Rows written before
+    /// timestamps were recorded are not included.
+    pub fn get_rows_not_seen_since(
         &self,
-        column: &str,
-        string: &str,
-    ) -> Result<Vec<DatabaseResult>, rocksdb::Error> {
-        let db = Arc::new(DB::open_cf(&self.options, &self.path, &self.columns)?);
-
-        let cf = db.cf_handle(column).unwrap();
-        let cfs = vec![
-            db.cf_handle(&self.columns[0]).unwrap(),
-            db.cf_handle(&self.columns[1]).unwrap(),
-            db.cf_handle(&self.columns[2]).unwrap(),
-            db.cf_handle(&self.columns[3]).unwrap(),
-        ];
+        timestamp: u64,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
 
-        let mut matching_keys: Vec<DatabaseResult> = Vec::new();
+        let mut rows = Vec::new();
 
-        let iter = db.iterator_cf(cf, IteratorMode::Start);
-        for item in iter {
+        for item in db.iterator_cf(cfs[4], IteratorMode::Start) {
             let (key_bytes, value_bytes) = item?;
-            if let Ok(value_str) = std::str::from_utf8(&value_bytes) {
-                // Check if the value contains the substring
-                if value_str.contains(string) {
-                    // Convert key to string and add to results
-                    if let Ok(key_str) = std::str::from_utf8(&key_bytes) {
-                        if let Some(row) = self.fetch_row(&db, key_str, &cfs) {
-                            matching_keys.push(row);
-                        }
+            if let Some((_, last_seen)) = decode_timestamps(&value_bytes)
+                && last_seen < timestamp
+                && let Some(key_str) = host_from_key(&key_bytes)
+                && let Some(row) = self.fetch_row(&db, &key_str, &cfs)?
+            {
+                rows.push(row);
+            }
+        }
+
+        Ok(rows)
+    }
+
+    /// Rows last saved more than `age` ago by the database's clock, in key
+    /// order, to find hosts due a rescan. Rows written before timestamps
+    /// were recorded have no age, and are only included if
+    /// `include_untimed` is set.
+    pub fn hosts_older_than(
+        &self,
+        age: Duration,
+        include_untimed: bool,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let cutoff = self.clock.unix_seconds().saturating_sub(age.as_secs());
+
+        // Untimed rows are the keys of the default column missing from the
+        // timestamps column, found by walking both in step
+        let mut untimed = include_untimed.then(|| {
+            let mut read_opts = ReadOptions::default();
+            read_opts.set_total_order_seek(true);
+            let mut iter = db.raw_iterator_cf_opt(cfs[0], read_opts);
+            iter.seek_to_first();
+            iter
+        });
+        let mut untimed_before =
+            |key: Option<&[u8]>, rows: &mut Vec<DatabaseResult>| -> Result<(), DatabaseError> {
+                let Some(iter) = untimed.as_mut() else {
+                    return Ok(());
+                };
+                while let Some(row_key) = iter.key()
+                    && key.is_none_or(|key| row_key < key)
+                {
+                    if let Some(host) = host_from_key(row_key)
+                        && let Some(row) = self.fetch_row(&db, &host, &cfs)?
+                    {
+                        rows.push(row);
                     }
+                    iter.next();
+                }
+                if key.is_some() && iter.key() == key {
+                    iter.next();
                 }
+                Ok(())
+            };
+
+        let mut rows = Vec::new();
+        for item in db.iterator_cf(cfs[4], IteratorMode::Start) {
+            let (key, value) = item?;
+            untimed_before(Some(&key), &mut rows)?;
+
+            let old = match decode_timestamps(&value) {
+                Some((_, last_seen)) => last_seen < cutoff,
+                None => include_untimed,
+            };
+            if old
+                && let Some(host) = host_from_key(&key)
+                && let Some(row) = self.fetch_row(&db, &host, &cfs)?
+            {
+                rows.push(row);
             }
         }
+        untimed_before(None, &mut rows)?;
+
+        Ok(rows)
+    }
+
+    pub fn search_substring_in_column(
+        &self,
+        column: &str,
+        string: &str,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.search_column(column, &SearchOptions::all_of([string]))
+    }
 
-        Ok(matching_keys)
+    /// Rows whose value in `column` matches `options`
+    pub fn search_column(
+        &self,
+        column: &str,
+        options: &SearchOptions,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let matcher = options.matcher();
+        self.scan_column(column, |value| matcher.matches(|term| term.is_match(value)))
     }
 
     pub fn search_substring_in_column_regex(
         &self,
         column: &str,
         regex: Regex,
-    ) -> Result<Vec<DatabaseResult>, rocksdb::Error> {
-        let db = Arc::new(DB::open_cf(&self.options, &self.path, &self.columns)?);
-
-        let cf = db.cf_handle(column).unwrap();
-        let cfs = vec![
-            db.cf_handle(&self.columns[0]).unwrap(),
-            db.cf_handle(&self.columns[1]).unwrap(),
-            db.cf_handle(&self.columns[2]).unwrap(),
-            db.cf_handle(&self.columns[3]).unwrap(),
-        ];
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.scan_column(column, |value| {
+            std::str::from_utf8(value).is_ok_and(|value| regex.is_match(value))
+        })
+    }
 
-        let mut matching_keys: Vec<DatabaseResult> = Vec::new();
+    // Rows whose value in `column` passes `filter`, in key order. The column
+    // is split into `search_threads` key ranges that are scanned in parallel
+    fn scan_column(
+        &self,
+        column: &str,
+        filter: impl Fn(&[u8]) -> bool + Sync,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+
+        let cf = column_handle(&db, column)?;
+        let cfs = self.column_handles(&db)?;
+        let progress = Progress::new(self.progress.as_ref(), Operation::Search, &db, cf);
+
+        let ranges: Vec<Vec<DatabaseResult>> = key_ranges(&db, cf, self.search_threads)
+            .into_par_iter()
+            .map(|(lower, upper)| {
+                let mut read_opts = ReadOptions::default();
+                read_opts.set_total_order_seek(true);
+                if let Some(upper) = upper {
+                    read_opts.set_iterate_upper_bound(upper);
+                }
+                let mode = match &lower {
+                    Some(lower) => IteratorMode::From(lower, Direction::Forward),
+                    None => IteratorMode::Start,
+                };
 
-        let iter = db.iterator_cf(cf, IteratorMode::Start);
-        for item in iter {
-            let (key_bytes, value_bytes) = item?;
-            if let Ok(value_str) = std::str::from_utf8(&value_bytes) {
-                // Check if the value contains the substring
-                if regex.is_match(value_str) {
-                    // Convert key to string and add to results
-                    if let Ok(key_str) = std::str::from_utf8(&key_bytes) {
-                        if let Some(row) = self.fetch_row(&db, key_str, &cfs) {
-                            matching_keys.push(row);
-                        }
+                let mut rows = Vec::new();
+                for item in db.iterator_cf_opt(cf, read_opts, mode) {
+                    let (key_bytes, value_bytes) = item?;
+                    let matched = filter(&value_bytes);
+                    progress.key(matched);
+                    if matched
+                        && let Some(key_str) = host_from_key(&key_bytes)
+                        && let Some(row) = self.fetch_row(&db, &key_str, &cfs)?
+                    {
+                        rows.push(row);
                     }
                 }
-            }
-        }
+                Ok(rows)
+            })
+            .collect::<Result<_, DatabaseError>>()?;
+        progress.finish();
 
-        Ok(matching_keys)
+        Ok(ranges.into_iter().flatten().collect())
     }
 
     pub fn search(
         &self,
         queries: Vec<QueryDataType>,
-    ) -> Result<Vec<DatabaseResult>, rocksdb::Error> {
-        if queries.len() == 0 {
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        if queries.is_empty() {
             return Ok(Vec::new());
         }
         if queries.len() == 1 {
             // Return host if results include host
-            match queries[0] {
-                QueryDataType::Host(row) => {
-                    return Ok(vec![
-                        self.get_row_by_host(row.to_string().as_str())
-                            .expect("Host Not Found"),
-                    ]);
-                }
-                _ => {}
+            if let QueryDataType::Host(row) = queries[0] {
+                return Ok(self
+                    .get_row_by_host(row.to_string().as_str())?
+                    .into_iter()
+                    .collect());
             }
         }
 
-        let db = Arc::new(DB::open_cf(&self.options, &self.path, &self.columns)?);
+        let db = self.db()?;
 
-        let cfs = vec![
-            db.cf_handle(&self.columns[0]).unwrap(),
-            db.cf_handle(&self.columns[1]).unwrap(),
-            db.cf_handle(&self.columns[2]).unwrap(),
-            db.cf_handle(&self.columns[3]).unwrap(),
-        ];
+        let cfs = self.column_handles(&db)?;
 
         let matching_key_bytes = search_parallel(&db, queries, &cfs);
         let mut matching_rows = Vec::new();
 
         for key_bytes in matching_key_bytes {
-            if let Ok(key_str) = std::str::from_utf8(&key_bytes) {
-                if let Some(row) = self.fetch_row(&db, &key_str, &cfs) {
-                    matching_rows.push(row);
-                }
+            if let Some(key_str) = host_from_key(&key_bytes)
+                && let Some(row) = self.fetch_row(&db, &key_str, &cfs)?
+            {
+                matching_rows.push(row);
             }
         }
 
         Ok(matching_rows)
     }
 
-    fn fetch_row(&self, db: &DB, row_id: &str, cfs: &Vec<&ColumnFamily>) -> Option<DatabaseResult> {
-        match db.get_cf(&cfs[0], row_id.as_bytes()) {
-            Ok(Some(_)) => Some(DatabaseResult {
-                id: row_id.to_string(),
-                ports: split_nums(&self.row_to_string(db, row_id, &cfs[1]), ","),
-                services: self
-                    .row_to_string(db, row_id, &cfs[2])
-                    .split(",")
-                    .map(|a| a.to_string())
-                    .collect(),
-                responses: self.row_to_string(db, row_id, &cfs[3]),
-            }),
-            _ => None,
+    /// Names of the per-host column families, `default` first
+    pub(crate) fn host_columns(&self) -> impl Iterator<Item = &str> {
+        self.columns
+            .iter()
+            .map(|column| column.as_str())
+            .filter(|column| !NON_HOST_COLUMNS.contains(column))
+    }
+
+    /// Handles for the per-host column families, `default` first
+    pub(crate) fn column_handles<'a>(
+        &self,
+        db: &'a DB,
+    ) -> Result<Vec<&'a ColumnFamily>, DatabaseError> {
+        self.host_columns()
+            .map(|column| column_handle(db, column))
+            .collect()
+    }
+
+    /// The row saved for `row_id`, Ok(None) if it has none
+    pub(crate) fn fetch_row(
+        &self,
+        db: &DB,
+        row_id: &str,
+        cfs: &[&ColumnFamily],
+    ) -> Result<Option<DatabaseResult>, DatabaseError> {
+        self.fetch_row_opt(db, row_id, cfs, &ReadOptions::default())
+    }
+
+    // The rows of whichever of `hosts` have one, in the same order
+    pub(crate) fn fetch_rows(
+        &self,
+        db: &DB,
+        hosts: impl IntoIterator<Item = impl AsRef<str>>,
+        cfs: &[&ColumnFamily],
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let mut rows = Vec::new();
+        for host in hosts {
+            rows.extend(self.fetch_row(db, host.as_ref(), cfs)?);
         }
+        Ok(rows)
     }
 
-    fn row_to_string(&self, db: &DB, row_id: &str, cf: &ColumnFamily) -> String {
-        if let Ok(Some(data)) = db.get_cf(cf, row_id) {
-            String::from_utf8_lossy(&*data).to_string()
-        } else {
-            String::new()
+    /// `fetch_row` with explicit read options, e.g. to read from a snapshot
+    pub(crate) fn fetch_row_opt(
+        &self,
+        db: &DB,
+        row_id: &str,
+        cfs: &[&ColumnFamily],
+        read_opts: &ReadOptions,
+    ) -> Result<Option<DatabaseResult>, DatabaseError> {
+        let key = host_key(row_id);
+        let Some(data) = db.get_cf_opt(cfs[0], &key, read_opts)? else {
+            return Ok(None);
+        };
+        let row = match DatabaseResult::decode(&data) {
+            Ok(Some(row)) => row,
+            // Rows saved before the default column held the encoded row are
+            // put together from the other columns until they're next saved.
+            // Every version writes those too, so a row this build can't
+            // decode is read from them as well.
+            decoded => {
+                if let Err(e) = decoded {
+                    warn!("Reading {} from its columns: {}", row_id, e);
+                }
+                let mut columns = vec![None];
+                for cf in &cfs[1..] {
+                    columns.push(db.get_cf_opt(cf, &key, read_opts)?);
+                }
+                row_from_columns(row_id, |cf| columns[cf].take())
+            }
+        };
+        self.counters.rows_read.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(row))
+    }
+
+    // Add the rows found by a batch lookup to the read counter
+    fn count_rows_read(&self, rows: &[Option<DatabaseResult>]) {
+        let found = rows.iter().filter(|row| row.is_some()).count();
+        self.counters
+            .rows_read
+            .fetch_add(found as u64, Ordering::Relaxed);
+    }
+}
+
+// A row put together from its value in each column, read by `column` from
+// the column's index in `COLUMN_FAMILIES`
+pub(crate) fn row_from_columns(
+    row_id: &str,
+    mut column: impl FnMut(usize) -> Option<Vec<u8>>,
+) -> DatabaseResult {
+    let text = |data: Option<Vec<u8>>| data.map(|data| String::from_utf8_lossy(&data).to_string());
+    let timestamps = column(4).and_then(|data| decode_timestamps(&data));
+
+    let services = column(2).unwrap_or_default();
+    let (services, service_entries) = match decode_services(&services) {
+        Some(entries) => {
+            let mut names: Vec<String> = Vec::new();
+            for entry in &entries {
+                if !names.contains(&entry.name) {
+                    names.push(entry.name.clone());
+                }
+            }
+            (names, entries)
+        }
+        // An empty value means no services, not one empty name
+        None => (
+            String::from_utf8_lossy(&services)
+                .split(",")
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_string())
+                .collect(),
+            Vec::new(),
+        ),
+    };
+
+    let mut row = DatabaseResult {
+        id: canonical_host(row_id),
+        ports: column(1)
+            .map(|data| decode_ports(&data))
+            .unwrap_or_default(),
+        services,
+        responses: text(column(3)).unwrap_or_default(),
+        service_entries,
+        first_seen: timestamps.map(|(first_seen, _)| first_seen),
+        last_seen: timestamps.map(|(_, last_seen)| last_seen),
+        last_run: text(column(5)),
+        tags: split_tags(&text(column(6)).unwrap_or_default()),
+        hostname: text(column(7)),
+        rtt_ms: column(8)
+            .and_then(|data| Some(f64::from_be_bytes(data.as_slice().try_into().ok()?))),
+        os_guess: column(9)
+            .and_then(|data| OsInfo::decode(&data))
+            .map(|info| info.guess),
+    };
+    row.upgrade_responses();
+    row
+}
+
+/// How `ResultDatabase::get_rows_by_ports` combines its ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMatch {
+    /// Hosts with every one of the ports open
+    All,
+    /// Hosts with at least one of the ports open
+    Any,
+}
+
+/// Terms for `ResultDatabase::search_column` and `get_rows_by_service_with`.
+/// A value matches when it contains every term in `all_of` and, unless
+/// `any_of` is empty, at least one term in `any_of`.
+#[derive(Debug, Default, Clone)]
+pub struct SearchOptions {
+    pub case_insensitive: bool,
+    pub all_of: Vec<String>,
+    pub any_of: Vec<String>,
+}
+
+impl SearchOptions {
+    /// Match values containing every one of `terms`, case sensitively
+    pub fn all_of<S: Into<String>>(terms: impl IntoIterator<Item = S>) -> Self {
+        SearchOptions {
+            all_of: terms.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Match values containing any of `terms`, case sensitively
+    pub fn any_of<S: Into<String>>(terms: impl IntoIterator<Item = S>) -> Self {
+        SearchOptions {
+            any_of: terms.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn case_insensitive(mut self) -> Self {
+        self.case_insensitive = true;
+        self
+    }
+
+    fn matcher(&self) -> SearchMatcher {
+        let term = |term: &String| {
+            if self.case_insensitive {
+                SearchTerm::Folded(
+                    regex::bytes::RegexBuilder::new(&regex::escape(term))
+                        .case_insensitive(true)
+                        .build()
+                        .expect("Escaped search term is a valid regex"),
+                )
+            } else {
+                SearchTerm::Exact(Box::new(memmem::Finder::new(term.as_bytes()).into_owned()))
+            }
+        };
+
+        SearchMatcher {
+            all_of: self.all_of.iter().map(term).collect(),
+            any_of: self.any_of.iter().map(term).collect(),
+        }
+    }
+}
+
+// `SearchOptions` with each term compiled once per search
+struct SearchMatcher {
+    all_of: Vec<SearchTerm>,
+    any_of: Vec<SearchTerm>,
+}
+
+impl SearchMatcher {
+    // Whether the terms `found` reports as present satisfy the options
+    fn matches(&self, found: impl Fn(&SearchTerm) -> bool) -> bool {
+        self.all_of.iter().all(&found) && (self.any_of.is_empty() || self.any_of.iter().any(found))
+    }
+
+    // Whether each term is part of the name or banner of one of `entries`
+    fn matches_services(&self, entries: &[ServiceEntry]) -> bool {
+        self.matches(|term| entries.iter().any(|entry| entry.contains(term)))
+    }
+}
+
+// Case sensitive terms are compared byte for byte, so values aren't copied
+// or even checked for UTF-8. Case insensitive ones fold case as they go.
+enum SearchTerm {
+    Exact(Box<memmem::Finder<'static>>),
+    Folded(regex::bytes::Regex),
+}
+
+impl SearchTerm {
+    fn is_match(&self, value: &[u8]) -> bool {
+        match self {
+            SearchTerm::Exact(finder) => finder.find(value).is_some(),
+            SearchTerm::Folded(regex) => regex.is_match(value),
         }
     }
 }
@@ -492,10 +2071,8 @@ fn collect_all_keys(db: &DB, cf: &ColumnFamily) -> Vec<Vec<u8>> {
     let mut keys = Vec::new();
     let iter = db.iterator_cf(cf, rocksdb::IteratorMode::Start);
 
-    for result in iter {
-        if let Ok((key, _)) = result {
-            keys.push(key.to_vec());
-        }
+    for (key, _) in iter.flatten() {
+        keys.push(key.to_vec());
     }
 
     keys
@@ -505,7 +2082,7 @@ fn collect_all_keys(db: &DB, cf: &ColumnFamily) -> Vec<Vec<u8>> {
 pub fn search_parallel(
     db: &DB,
     queries: Vec<QueryDataType>,
-    cfs: &Vec<&ColumnFamily>,
+    cfs: &[&ColumnFamily],
 ) -> Vec<Vec<u8>> {
     // Get column family handles
     let cf_ports = cfs[1];
@@ -518,35 +2095,17 @@ pub fn search_parallel(
     // Partition queries by type
     let port_queries: Vec<_> = queries
         .iter()
-        .filter_map(|q| {
-            if let QueryDataType::Port(_, _) = q {
-                Some(q)
-            } else {
-                None
-            }
-        })
+        .filter(|q| matches!(q, QueryDataType::Port(_, _)))
         .collect();
 
     let service_queries: Vec<_> = queries
         .iter()
-        .filter_map(|q| {
-            if let QueryDataType::Service(_, _, _) = q {
-                Some(q)
-            } else {
-                None
-            }
-        })
+        .filter(|q| matches!(q, QueryDataType::Service(_, _, _)))
         .collect();
 
     let fulltext_queries: Vec<_> = queries
         .iter()
-        .filter_map(|q| {
-            if let QueryDataType::FullTextIncludes(_) = q {
-                Some(q)
-            } else {
-                None
-            }
-        })
+        .filter(|q| matches!(q, QueryDataType::FullTextIncludes(_)))
         .collect();
 
     // Load all data for batch processing to minimize DB reads
@@ -575,27 +2134,20 @@ pub fn search_parallel(
             // Check port queries
             let ports_match = port_queries.is_empty()
                 || if let Some(ports_value) = ports_data.get(key) {
-                    if let Ok(ports_str) = std::str::from_utf8(ports_value) {
-                        let ports: Vec<i32> = ports_str
-                            .split(',')
-                            .filter_map(|p| p.trim().parse::<i32>().ok())
-                            .collect();
-
-                        port_queries.iter().all(|query| {
-                            if let QueryDataType::Port(query_type, port_num) = *query {
-                                match query_type {
-                                    QueryType::Equals => ports_str == port_num.to_string(),
-                                    QueryType::NotEquals => ports_str != port_num.to_string(),
-                                    QueryType::Includes => ports.contains(port_num),
-                                    QueryType::NotIncludes => !ports.contains(port_num),
-                                }
-                            } else {
-                                false
+                    let ports = decode_ports(ports_value);
+
+                    port_queries.iter().all(|query| {
+                        if let QueryDataType::Port(query_type, port_num) = *query {
+                            match query_type {
+                                QueryType::Equals => ports == [*port_num],
+                                QueryType::NotEquals => ports != [*port_num],
+                                QueryType::Includes => ports.contains(port_num),
+                                QueryType::NotIncludes => !ports.contains(port_num),
                             }
-                        })
-                    } else {
-                        false
-                    }
+                        } else {
+                            false
+                        }
+                    })
                 } else {
                     false
                 };
@@ -660,7 +2212,8 @@ pub fn search_parallel(
             }
 
             // Check fulltext queries
-            let fulltext_match = fulltext_queries.is_empty()
+
+            fulltext_queries.is_empty()
                 || if let Some(responses_value) = responses_data.get(key) {
                     if let Ok(responses_str) = std::str::from_utf8(responses_value) {
                         fulltext_queries.iter().all(|query| {
@@ -675,11 +2228,923 @@ pub fn search_parallel(
                     }
                 } else {
                     false
-                };
-
-            fulltext_match
+                }
         })
         .collect();
 
     matching_keys
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use super::*;
+    use crate::{
+        clock::FakeClock,
+        testing::{self, row},
+    };
+
+    const START: u64 = 1_700_000_000;
+
+    #[test]
+    fn last_seen_never_moves_backwards() {
+        let clock = Arc::new(FakeClock::new(UNIX_EPOCH + Duration::from_secs(START)));
+        let (_dir, database) = testing::temp_database();
+        let database = database.with_clock(clock.clone());
+
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+        clock.advance(Duration::from_secs(600));
+        database
+            .save_rows(vec![row("10.0.0.1", &[80], &[])])
+            .unwrap();
+
+        // NTP steps the clock back an hour
+        clock.set_wall(UNIX_EPOCH + Duration::from_secs(START - 3000));
+        database
+            .save_rows(vec![row("10.0.0.1", &[443], &[])])
+            .unwrap();
+
+        let saved = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(saved.first_seen, Some(START));
+        assert_eq!(saved.last_seen, Some(START + 600));
+        assert_eq!(saved.ports, vec![22, 80, 443]);
+
+        // A new host saved now gets the stepped clock's time
+        database
+            .save_rows(vec![row("10.0.0.2", &[22], &[])])
+            .unwrap();
+        let saved = database.get_row_by_host("10.0.0.2").unwrap().unwrap();
+        assert_eq!(saved.first_seen, Some(START - 3000));
+        assert_eq!(saved.last_seen, Some(START - 3000));
+    }
+
+    #[test]
+    fn rows_by_port_match_whole_ports() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[80], &[]),
+                row("10.0.0.2", &[8080], &[]),
+                row("10.0.0.3", &[80, 8080], &[]),
+                row("10.0.0.4", &[1080], &[]),
+            ])
+            .unwrap();
+
+        let hosts = |port| -> Vec<String> {
+            let mut hosts: Vec<String> = database
+                .get_rows_by_port(port)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect();
+            hosts.sort();
+            hosts
+        };
+        assert_eq!(hosts(80), vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(hosts(8080), vec!["10.0.0.2", "10.0.0.3"]);
+        assert_eq!(hosts(1080), vec!["10.0.0.4"]);
+        assert!(hosts(8).is_empty());
+    }
+
+    #[test]
+    fn lookups_share_one_handle() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+
+        let opened = database.db().unwrap();
+        for _ in 0..1000 {
+            assert!(database.get_row_by_host("10.0.0.1").unwrap().is_some());
+        }
+        assert!(Arc::ptr_eq(&opened, &database.db().unwrap()));
+    }
+
+    #[test]
+    fn upsert_keeps_ports_from_an_earlier_scan() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("10.0.0.1", &[22, 80], &[])])
+            .unwrap();
+
+        // A service scan saves the same host again, without its ports
+        let services = row("10.0.0.1", &[], &["ssh", "http"]);
+        database.upsert_rows(vec![services.clone()]).unwrap();
+        database.upsert_rows(vec![services]).unwrap();
+        database
+            .upsert_rows(vec![row("10.0.0.1", &[80], &["http"])])
+            .unwrap();
+
+        let saved = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(saved.ports, vec![22, 80]);
+        assert_eq!(saved.services, vec!["http", "ssh"]);
+    }
+
+    #[test]
+    fn read_only_instance_opens_alongside_a_writer() {
+        let (dir, writer) = testing::temp_database();
+        writer.save_rows(vec![row("10.0.0.1", &[22], &[])]).unwrap();
+
+        let reader = ResultDatabase::open_read_only(&dir.path().to_string_lossy());
+        let saved = reader.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(saved.ports, vec![22]);
+        assert!(matches!(
+            reader.delete_host("10.0.0.1"),
+            Err(DatabaseError::ReadOnly(_))
+        ));
+        assert!(reader.save_rows(vec![row("10.0.0.2", &[22], &[])]).is_err());
+
+        // The writer is still usable with the reader open
+        writer.save_rows(vec![row("10.0.0.2", &[80], &[])]).unwrap();
+        assert!(writer.get_row_by_host("10.0.0.2").unwrap().is_some());
+    }
+
+    #[test]
+    fn reopens_with_different_cache_and_buffer_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        let hosts: Vec<String> = (0..5000u32)
+            .map(|i| Ipv4Addr::from(0x0a00_0000 + i).to_string())
+            .collect();
+        let save = |database: &ResultDatabase, hosts: &[String], port| {
+            database
+                .save_rows(hosts.iter().map(|host| row(host, &[port], &[])).collect())
+                .unwrap();
+        };
+
+        let sizes = [
+            DatabaseOptions::default(),
+            // No block cache at all, and the smallest memtable
+            DatabaseOptions::default()
+                .with_block_cache_mb(0)
+                .with_write_buffer_mb(1),
+            DatabaseOptions::default()
+                .with_block_cache_mb(256)
+                .with_write_buffer_mb(128)
+                .with_compression(DBCompressionType::Zstd),
+        ];
+        for (i, opts) in sizes.into_iter().enumerate() {
+            let cache_mb = opts.block_cache_mb as u64;
+            let database = ResultDatabase::with_options(&path, opts);
+            save(&database, &hosts[i * 1000..(i + 1) * 1000], 22);
+            save(&database, &hosts[..1000], 80 + i as i32);
+
+            let db = database.db().unwrap();
+            assert_eq!(
+                db.property_int_value("rocksdb.block-cache-capacity")
+                    .unwrap(),
+                Some(cache_mb * 1024 * 1024)
+            );
+            assert_eq!(database.count_hosts().unwrap(), (i as u64 + 1) * 1000);
+            assert_eq!(
+                database.get_row_by_host(&hosts[0]).unwrap().unwrap().ports,
+                [22].into_iter()
+                    .chain(80..=80 + i as i32)
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        // Everything written under each size is still there
+        let database = ResultDatabase::new(&path);
+        for (i, host) in hosts[..3000].iter().enumerate() {
+            let ports = database.get_row_by_host(host).unwrap().unwrap().ports;
+            assert!(ports.contains(&22), "{} {:?}", i, ports);
+        }
+        assert!(database.check_all().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn unknown_column_is_an_error() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+
+        assert!(matches!(
+            database.search_substring_in_column("nope", "22"),
+            Err(DatabaseError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn ipv6_hosts_are_found_under_any_spelling() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("0:0:0:0:0:0:0:1", &[22], &[])])
+            .unwrap();
+        database.save_rows(vec![row("::1", &[80], &[])]).unwrap();
+
+        for spelling in ["::1", "0:0:0:0:0:0:0:1", "0000::0001", " ::1 "] {
+            let saved = database.get_row_by_host(spelling).unwrap().unwrap();
+            assert_eq!(saved.id, "::1");
+            assert_eq!(saved.ports, vec![22, 80]);
+        }
+        assert_eq!(database.iter_rows().count(), 1);
+    }
+
+    #[test]
+    fn saved_rows_read_back_unchanged() {
+        let clock = Arc::new(FakeClock::new(UNIX_EPOCH + Duration::from_secs(START)));
+        let (_dir, database) = testing::temp_database();
+        let database = database.with_clock(clock.clone());
+
+        let mut saved = row("10.0.0.1", &[22, 80], &["ssh", "http"]);
+        saved.set_response_map(&Responses::from([
+            (
+                service_key(22, "tcp"),
+                ("ssh".to_string(), "SSH-2.0-OpenSSH_9.6".to_string()),
+            ),
+            (
+                service_key(80, "tcp"),
+                ("http".to_string(), "HTTP/1.1 200 OK".to_string()),
+            ),
+        ]));
+        saved.last_run = Some("run-1".to_string());
+        saved.rtt_ms = Some(1.5);
+        database.save_rows(vec![saved.clone()]).unwrap();
+        database.add_tag("10.0.0.1", "lab").unwrap();
+        database.set_hostname("10.0.0.1", "gateway.lan").unwrap();
+
+        // A later scan finds another port
+        clock.advance(Duration::from_secs(60));
+        database
+            .upsert_rows(vec![row("10.0.0.1", &[443], &["https"])])
+            .unwrap();
+
+        // The later scan named https without a response for it
+        let mut service_entries = saved.service_entries.clone();
+        service_entries.insert(
+            0,
+            ServiceEntry {
+                port: 0,
+                protocol: "tcp".to_string(),
+                name: "https".to_string(),
+                banner: String::new(),
+                version: None,
+            },
+        );
+        let expected = DatabaseResult {
+            ports: vec![22, 80, 443],
+            services: ["https", "ssh", "http"].map(String::from).to_vec(),
+            service_entries,
+            first_seen: Some(START),
+            last_seen: Some(START + 60),
+            tags: vec!["lab".to_string()],
+            hostname: Some("gateway.lan".to_string()),
+            ..saved
+        };
+        let expected = serde_json::to_value(&expected).unwrap();
+        let read = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(serde_json::to_value(&read).unwrap(), expected);
+
+        // Rows saved before the default column held the encoded row are
+        // put together from the other columns
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        db.put_cf(cfs[0], host_key("10.0.0.1"), b"").unwrap();
+        let read = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(serde_json::to_value(&read).unwrap(), expected);
+    }
+
+    #[test]
+    fn service_entries_are_stored_and_read_from_old_rows() {
+        let (_dir, database) = testing::temp_database();
+        let mut saved = row("10.0.0.1", &[22], &["ssh"]);
+        saved.set_response_map(&Responses::from([(
+            service_key(22, "tcp"),
+            ("ssh".to_string(), "SSH-2.0-OpenSSH_8.9p1".to_string()),
+        )]));
+        // As a scan would have found it, the banner isn't matched again
+        saved.service_entries[0].version = Some("from the scan".to_string());
+        database.save_rows(vec![saved]).unwrap();
+
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        let stored = db.get_cf(cfs[2], host_key("10.0.0.1")).unwrap().unwrap();
+        let entries: Vec<ServiceEntry> = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].version.as_deref(), Some("from the scan"));
+        let read = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(read.service_entries, entries);
+
+        // A row from before the entries were stored, with the service names
+        // in the services column and the entries made from the responses
+        db.put_cf(cfs[0], host_key("10.0.0.1"), b"").unwrap();
+        db.put_cf(cfs[2], host_key("10.0.0.1"), b"ssh").unwrap();
+        let read = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(read.services, vec!["ssh"]);
+        assert_eq!(read.service_entries.len(), 1);
+        assert_eq!(read.service_entries[0].port, 22);
+        assert_eq!(
+            read.service_entries[0].version.as_deref(),
+            Some("OpenSSH 8.9p1")
+        );
+    }
+
+    #[test]
+    fn rows_by_port_range_include_both_ends() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[1024], &[]),
+                row("10.0.0.2", &[1025, 8080], &[]),
+                row("10.0.0.3", &[1], &[]),
+                row("10.0.0.4", &[65535], &[]),
+                row("10.0.0.5", &[], &[]),
+            ])
+            .unwrap();
+
+        let hosts = |low, high| -> Vec<String> {
+            let mut hosts: Vec<String> = database
+                .get_rows_by_port_range(low, high)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect();
+            hosts.sort();
+            hosts
+        };
+        assert_eq!(hosts(1, 1024), vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(hosts(1024, 1024), vec!["10.0.0.1"]);
+        assert_eq!(hosts(1025, 8079), vec!["10.0.0.2"]);
+        // Reversed bounds are swapped
+        assert_eq!(hosts(1024, 1), vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(
+            hosts(0, 65535),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]
+        );
+        assert_eq!(hosts(65535, 70000), vec!["10.0.0.4"]);
+        assert!(hosts(2, 1023).is_empty());
+        assert!(hosts(-10, -1).is_empty());
+    }
+
+    #[test]
+    fn rows_by_cidr_are_the_hosts_in_the_network() {
+        let (_dir, database) = testing::temp_database();
+        let hosts = [
+            "9.255.255.255",
+            "10.0.0.0",
+            "10.0.0.1",
+            "10.0.0.254",
+            "10.0.0.255",
+            "10.0.1.1",
+            "11.0.0.1",
+            "2001:db8::1",
+            "2001:db8:0:1::1",
+            "2001:db9::1",
+            "printer.lan",
+        ];
+        database
+            .save_rows(hosts.iter().map(|host| row(host, &[22], &[])).collect())
+            .unwrap();
+
+        let in_network = |cidr: &str| -> Vec<String> {
+            let mut hosts: Vec<String> = database
+                .get_rows_by_cidr(cidr)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect();
+            hosts.sort();
+            hosts
+        };
+
+        assert_eq!(
+            in_network("10.0.0.0/24"),
+            ["10.0.0.0", "10.0.0.1", "10.0.0.254", "10.0.0.255"]
+        );
+        // Bits past the prefix length are ignored
+        assert_eq!(in_network("10.0.0.77/24"), in_network("10.0.0.0/24"));
+        // A prefix ending part way through a byte
+        assert_eq!(
+            in_network("10.0.0.0/23"),
+            [
+                "10.0.0.0",
+                "10.0.0.1",
+                "10.0.0.254",
+                "10.0.0.255",
+                "10.0.1.1"
+            ]
+        );
+        assert_eq!(in_network("10.0.0.0/31"), ["10.0.0.0", "10.0.0.1"]);
+        assert_eq!(in_network("10.0.0.254/31"), ["10.0.0.254", "10.0.0.255"]);
+        assert_eq!(in_network("10.0.0.1/32"), ["10.0.0.1"]);
+        assert_eq!(in_network("0.0.0.0/0").len(), 7);
+
+        assert!(in_network("10.0.0.5/32").is_empty());
+        assert!(in_network("172.16.0.0/12").is_empty());
+        assert!(in_network("10.0.2.0/24").is_empty());
+
+        assert_eq!(
+            in_network("2001:db8::/32"),
+            ["2001:db8:0:1::1", "2001:db8::1"]
+        );
+        assert_eq!(in_network("2001:db8::/64"), ["2001:db8::1"]);
+        assert_eq!(in_network("2001:db8::1/128"), ["2001:db8::1"]);
+        assert_eq!(in_network("::/0").len(), 3);
+        assert!(in_network("2001:db8:ffff::/48").is_empty());
+
+        for cidr in [
+            "10.0.0.0",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "printer.lan/24",
+            "10.0.0.0/x",
+        ] {
+            assert!(database.get_rows_by_cidr(cidr).is_err(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn rows_by_ports_match_all_or_any() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22, 80, 443], &[]),
+                row("10.0.0.2", &[80, 443], &[]),
+                row("10.0.0.3", &[22], &[]),
+                row("10.0.0.4", &[8080], &[]),
+                row("10.0.0.5", &[], &[]),
+            ])
+            .unwrap();
+
+        let hosts = |ports: &[u16], mode| -> Vec<String> {
+            let mut hosts: Vec<String> = database
+                .get_rows_by_ports(ports, mode)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect();
+            hosts.sort();
+            hosts
+        };
+
+        // Overlapping sets
+        assert_eq!(hosts(&[80, 443], PortMatch::All), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(hosts(&[22, 80], PortMatch::All), ["10.0.0.1"]);
+        assert_eq!(
+            hosts(&[22, 80], PortMatch::Any),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        // Each host is listed once, however many of the ports it has
+        assert_eq!(
+            hosts(&[22, 80, 443], PortMatch::Any),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert_eq!(hosts(&[443, 443], PortMatch::All), ["10.0.0.1", "10.0.0.2"]);
+
+        // Disjoint sets
+        assert!(hosts(&[22, 8080], PortMatch::All).is_empty());
+        assert_eq!(
+            hosts(&[22, 8080], PortMatch::Any),
+            ["10.0.0.1", "10.0.0.3", "10.0.0.4"]
+        );
+        assert!(hosts(&[3389], PortMatch::Any).is_empty());
+        assert!(hosts(&[22, 3389], PortMatch::All).is_empty());
+
+        // A single port is the same either way
+        assert_eq!(hosts(&[22], PortMatch::All), hosts(&[22], PortMatch::Any));
+
+        for mode in [PortMatch::All, PortMatch::Any] {
+            assert_eq!(
+                database
+                    .get_rows_by_ports(&[], mode)
+                    .unwrap_err()
+                    .to_string(),
+                "No ports given to match"
+            );
+        }
+    }
+
+    #[test]
+    fn contains_hosts_checks_each_host() {
+        let (_dir, database) = testing::temp_database();
+        assert_eq!(database.contains_hosts(&["10.0.0.1"]).unwrap(), vec![false]);
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &[]),
+                row("::1", &[22], &[]),
+                row("printer.lan", &[631], &[]),
+            ])
+            .unwrap();
+
+        let hosts = [
+            "10.0.0.1",
+            "10.0.0.2",
+            "0:0:0:0:0:0:0:1",
+            "printer.lan",
+            "scanner.lan",
+            "10.0.0.1",
+        ];
+        assert_eq!(
+            database.contains_hosts(&hosts).unwrap(),
+            vec![true, false, true, true, false, true]
+        );
+        assert!(database.contains_hosts(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parallel_and_serial_searches_agree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_string_lossy().to_string();
+        let rows = (0..3000)
+            .map(|i| {
+                let host = match i % 3 {
+                    0 => format!("10.{}.{}.1", i / 256, i % 256),
+                    1 => format!("2001:db8::{:x}", i),
+                    _ => format!("host{}.lan", i),
+                };
+                let services: &[&str] = match i % 4 {
+                    0 => &["ssh", "http"],
+                    1 => &["http-proxy"],
+                    2 => &["ssh"],
+                    _ => &[],
+                };
+                row(&host, &[22], services)
+            })
+            .collect();
+
+        let search = |threads: usize| -> Vec<String> {
+            let database = ResultDatabase::with_options(
+                &path,
+                DatabaseOptions::default().with_search_threads(threads),
+            );
+            database
+                .search_substring_in_column("services", "http")
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect()
+        };
+        ResultDatabase::new(&path).save_rows(rows).unwrap();
+
+        let serial = search(1);
+        assert_eq!(serial.len(), 1500);
+        let keys: Vec<Vec<u8>> = serial.iter().map(|host| host_key(host)).collect();
+        assert!(keys.is_sorted());
+        for threads in [2, 3, 8] {
+            assert_eq!(search(threads), serial, "{} threads", threads);
+        }
+    }
+
+    // A row as older versions wrote it, with responses keyed by port alone
+    const OLD_ROW_JSON: &str = r#"{"id":"10.0.0.1","ports":[22],"services":["ssh"],"responses":{"22":["ssh","SSH-2.0-OpenSSH_9.6"]}}"#;
+
+    fn assert_old_row(row: DatabaseResult) {
+        assert_eq!(row.id, "10.0.0.1");
+        assert_eq!(row.ports, vec![22]);
+        assert_eq!(row.services, vec!["ssh"]);
+        assert_eq!(
+            row.response_map(),
+            Responses::from([(
+                service_key(22, "tcp"),
+                ("ssh".to_string(), "SSH-2.0-OpenSSH_9.6".to_string())
+            )])
+        );
+        assert!(row.tags.is_empty() && row.first_seen.is_none());
+    }
+
+    #[test]
+    fn decodes_every_row_format() {
+        // Version 0, the fields are in the other columns
+        assert!(DatabaseResult::decode(b"").unwrap().is_none());
+        assert_eq!(DatabaseResult::format_version(b""), Some(0));
+
+        let v1 = [&[1], OLD_ROW_JSON.as_bytes()].concat();
+        assert_eq!(DatabaseResult::format_version(&v1), Some(1));
+        assert_old_row(DatabaseResult::decode(&v1).unwrap().unwrap());
+
+        let v2 = [b"RS\x02", OLD_ROW_JSON.as_bytes()].concat();
+        assert_eq!(DatabaseResult::format_version(&v2), Some(2));
+        assert_old_row(DatabaseResult::decode(&v2).unwrap().unwrap());
+
+        let mut current = row("10.0.0.1", &[22], &["ssh"]);
+        current.set_response_map(&Responses::from([(
+            service_key(22, "tcp"),
+            ("ssh".to_string(), "SSH-2.0-OpenSSH_9.6".to_string()),
+        )]));
+        let encoded = current.encode();
+        assert!(encoded.starts_with(b"RS"));
+        assert_eq!(DatabaseResult::format_version(&encoded), Some(ROW_FORMAT));
+        assert_old_row(DatabaseResult::decode(&encoded).unwrap().unwrap());
+    }
+
+    #[test]
+    fn rejects_values_that_arent_rows() {
+        for garbage in [&b"garbage"[..], b"\xff\x00", b"RS"] {
+            assert_eq!(DatabaseResult::format_version(garbage), None);
+            assert!(matches!(
+                DatabaseResult::decode(garbage),
+                Err(RowDecodeError::NotARow)
+            ));
+        }
+
+        for corrupt in [&b"RS\x03{\"id\":"[..], b"\x01not json", b"RS\x02[]"] {
+            assert!(matches!(
+                DatabaseResult::decode(corrupt),
+                Err(RowDecodeError::Corrupt(_))
+            ));
+        }
+
+        let newer = [b"RS", &[ROW_FORMAT + 1][..], b"{}"].concat();
+        assert_eq!(DatabaseResult::format_version(&newer), Some(ROW_FORMAT + 1));
+        let e = DatabaseResult::decode(&newer).unwrap_err();
+        assert!(matches!(e, RowDecodeError::UnknownVersion(v) if v == ROW_FORMAT + 1));
+        assert_eq!(
+            e.to_string(),
+            format!("unknown row format version {}", ROW_FORMAT + 1)
+        );
+    }
+
+    #[test]
+    fn rows_by_hosts_are_only_returned_for_saved_hosts() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &["ssh"]),
+                row("10.0.0.2", &[80, 443], &["http"]),
+            ])
+            .unwrap();
+        // Written before rows were stored whole, read from the other columns
+        {
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            db.put_cf(cfs[0], host_key("10.0.0.2"), b"").unwrap();
+        }
+
+        let rows = database
+            .get_rows_by_hosts(&["10.0.0.2", "10.0.0.9", "10.0.0.1", "10.0.0.2"])
+            .unwrap();
+        let found: Vec<Option<(&str, &[i32])>> = rows
+            .iter()
+            .map(|row| {
+                row.as_ref()
+                    .map(|row| (row.id.as_str(), row.ports.as_slice()))
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                Some(("10.0.0.2", &[80, 443][..])),
+                None,
+                Some(("10.0.0.1", &[22][..])),
+                Some(("10.0.0.2", &[80, 443][..])),
+            ]
+        );
+        assert_eq!(rows[0].as_ref().unwrap().services, vec!["http"]);
+        assert!(database.get_rows_by_hosts(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn saved_rows_have_a_recent_timestamp() {
+        let (_dir, database) = testing::temp_database();
+        let before = SystemTime::now() - Duration::from_secs(1);
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &[]),
+                row("10.0.0.2", &[22], &[]),
+            ])
+            .unwrap();
+        let after = SystemTime::now() + Duration::from_secs(1);
+
+        // Timestamps are stored in whole seconds
+        let last_seen = database.last_seen("10.0.0.1").unwrap().unwrap();
+        assert!(before <= last_seen && last_seen <= after);
+
+        // Saved before timestamps were recorded
+        {
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            db.delete_cf(cfs[4], host_key("10.0.0.2")).unwrap();
+        }
+        assert_eq!(database.last_seen("10.0.0.2").unwrap(), None);
+        assert_eq!(database.last_seen("10.0.0.9").unwrap(), None);
+    }
+
+    #[test]
+    fn hosts_older_than_finds_backdated_rows() {
+        let clock = Arc::new(FakeClock::new(UNIX_EPOCH + Duration::from_secs(START)));
+        let (_dir, database) = testing::temp_database();
+        let database = database.with_clock(clock.clone());
+
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &[]),
+                row("10.0.0.3", &[22], &[]),
+                row("10.0.0.4", &[22], &[]),
+            ])
+            .unwrap();
+        clock.advance(Duration::from_secs(7200));
+        database
+            .save_rows(vec![
+                row("10.0.0.2", &[22], &[]),
+                row("10.0.0.4", &[80], &[]),
+            ])
+            .unwrap();
+        clock.advance(Duration::from_secs(600));
+        // Saved before timestamps were recorded
+        {
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            db.delete_cf(cfs[4], host_key("10.0.0.3")).unwrap();
+        }
+
+        let hosts = |secs, include_untimed| -> Vec<String> {
+            database
+                .hosts_older_than(Duration::from_secs(secs), include_untimed)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect()
+        };
+        assert_eq!(hosts(3600, false), vec!["10.0.0.1"]);
+        assert_eq!(hosts(3600, true), vec!["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(
+            hosts(60, true),
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]
+        );
+        assert!(hosts(86400, false).is_empty());
+        assert_eq!(hosts(86400, true), vec!["10.0.0.3"]);
+    }
+
+    #[test]
+    fn migrate_upgrades_older_rows_and_keeps_newer_ones() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &["ssh"]),
+                row("10.0.0.2", &[80], &["http"]),
+            ])
+            .unwrap();
+        let newer = [b"RS", &[ROW_FORMAT + 1][..], b"{}"].concat();
+        {
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            let old = [b"RS\x02", OLD_ROW_JSON.as_bytes()].concat();
+            db.put_cf(cfs[0], host_key("10.0.0.1"), old).unwrap();
+            db.put_cf(cfs[0], host_key("10.0.0.2"), &newer).unwrap();
+        }
+
+        database.migrate_keys().unwrap();
+
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        let stored = db.get_cf(cfs[0], host_key("10.0.0.1")).unwrap().unwrap();
+        assert_eq!(DatabaseResult::format_version(&stored), Some(ROW_FORMAT));
+        assert_old_row(database.get_row_by_host("10.0.0.1").unwrap().unwrap());
+
+        // Written by a later version, left alone and read from the columns
+        let stored = db.get_cf(cfs[0], host_key("10.0.0.2")).unwrap().unwrap();
+        assert_eq!(stored, newer);
+        let saved = database.get_row_by_host("10.0.0.2").unwrap().unwrap();
+        assert_eq!(saved.ports, vec![80]);
+        assert_eq!(saved.services, vec!["http"]);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn bulk_load_saves_rows_faster() {
+        const ROWS: u32 = 1_000_000;
+        let rows = || -> Vec<DatabaseResult> {
+            (0..ROWS)
+                .map(|i| {
+                    let host = Ipv4Addr::from(0x0a00_0000 + i).to_string();
+                    row(&host, &[22, 80, 1024 + (i % 60000) as i32], &[])
+                })
+                .collect()
+        };
+
+        // Seconds `save_rows` took over ROWS rows
+        let load = |bulk_load: bool| {
+            let (_dir, database) =
+                testing::temp_database_with(DatabaseOptions::default().with_bulk_load(bulk_load));
+            let rows = rows();
+            let start = Instant::now();
+            database.save_rows(rows).unwrap();
+            let elapsed = start.elapsed();
+
+            let mut saved = 0;
+            database
+                .for_each_row(|_| {
+                    saved += 1;
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
+            assert_eq!(saved, ROWS);
+            assert_eq!(
+                database
+                    .get_row_by_host("10.15.66.63")
+                    .unwrap()
+                    .unwrap()
+                    .ports,
+                vec![22, 80, 1024 + (999_999 % 60000)]
+            );
+            elapsed
+        };
+
+        let logged = load(false);
+        let bulk = load(true);
+        println!(
+            "Saved {} rows: with the WAL {:?} ({:.0} rows/s), bulk load {:?} ({:.0} rows/s)",
+            ROWS,
+            logged,
+            ROWS as f64 / logged.as_secs_f64(),
+            bulk,
+            ROWS as f64 / bulk.as_secs_f64()
+        );
+        assert!(bulk < logged, "bulk {:?}, with the WAL {:?}", bulk, logged);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn cidr_lookup_only_reads_its_prefix() {
+        // Every host in 10.0.0.0/12, sixteen /16s
+        const ROWS: u32 = 16 * 65536;
+        let (_dir, database) =
+            testing::temp_database_with(DatabaseOptions::default().with_bulk_load(true));
+        database
+            .save_rows(
+                (0..ROWS)
+                    .map(|i| row(&Ipv4Addr::from(0x0a00_0000 + i).to_string(), &[22], &[]))
+                    .collect(),
+            )
+            .unwrap();
+        database.compact().unwrap();
+        let network: IpPrefix = "10.5.0.0/16".parse().unwrap();
+
+        // Keys the seek visits, all of them in the /16
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        let mut visited = 0;
+        for item in seek_prefix(&db, cfs[0], &cidr_key_prefix(&network), HOST_KEY_PREFIX_LEN) {
+            let (key, _) = item.unwrap();
+            let ip: IpAddr = host_from_key(&key).unwrap().parse().unwrap();
+            assert!(network.contains(&ip), "{} read", ip);
+            visited += 1;
+        }
+        assert_eq!(visited, 65536);
+
+        let start = Instant::now();
+        let seeked = database.get_rows_by_cidr("10.5.0.0/16").unwrap();
+        let seek_time = start.elapsed();
+
+        // The same rows found by reading every one
+        let start = Instant::now();
+        let mut scanned = Vec::new();
+        database
+            .for_each_row(|row| {
+                if row
+                    .id
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| network.contains(&ip))
+                {
+                    scanned.push(row);
+                }
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        let scan_time = start.elapsed();
+
+        println!(
+            "Hosts in a /16 of {} rows: prefix seek {:?}, full scan {:?}",
+            ROWS, seek_time, scan_time
+        );
+        assert_eq!(seeked.len(), 65536);
+        assert_eq!(scanned.len(), 65536);
+        assert!(
+            seek_time < scan_time,
+            "seek {:?}, scan {:?}",
+            seek_time,
+            scan_time
+        );
+    }
+
+    #[test]
+    fn concurrent_saves_union_their_ports() {
+        let (_dir, database) = testing::temp_database();
+        let hosts: Vec<String> = (0..50).map(|i| format!("10.0.1.{}", i)).collect();
+
+        std::thread::scope(|s| {
+            for port in [22, 80] {
+                let (database, hosts) = (&database, &hosts);
+                s.spawn(move || {
+                    for host in hosts {
+                        database.save_rows(vec![row(host, &[port], &[])]).unwrap();
+                        // A ping result for the same host
+                        database.save_rows(vec![row(host, &[], &[])]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let rows = database
+            .get_rows_by_hosts(&hosts.iter().map(|h| h.as_str()).collect::<Vec<_>>())
+            .unwrap();
+        for (host, row) in hosts.iter().zip(rows) {
+            assert_eq!(row.unwrap().ports, vec![22, 80], "{}", host);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::{cmp::Ordering, fmt};
+
+use serde::Serialize;
+
+use crate::database::{DatabaseError, DatabaseResult, ResultDatabase, host_key};
+
+/// Changes to one host present in both databases
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct HostDiff {
+    pub host: String,
+    pub added_ports: Vec<i32>,
+    pub removed_ports: Vec<i32>,
+    pub added_services: Vec<String>,
+    pub removed_services: Vec<String>,
+}
+
+impl HostDiff {
+    fn between(old: &DatabaseResult, new: &DatabaseResult) -> Self {
+        HostDiff {
+            host: new.id.clone(),
+            added_ports: missing_from(&new.ports, &old.ports),
+            removed_ports: missing_from(&old.ports, &new.ports),
+            added_services: missing_from(&new.services, &old.services),
+            removed_services: missing_from(&old.services, &new.services),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_ports.is_empty()
+            && self.removed_ports.is_empty()
+            && self.added_services.is_empty()
+            && self.removed_services.is_empty()
+    }
+}
+
+/// What changed between an older and a newer database
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ScanDiff {
+    /// Hosts only in the newer database
+    pub new_hosts: Vec<DatabaseResult>,
+    /// Hosts only in the older database
+    pub removed_hosts: Vec<DatabaseResult>,
+    pub changed_hosts: Vec<HostDiff>,
+}
+
+impl ScanDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new_hosts.is_empty() && self.removed_hosts.is_empty() && self.changed_hosts.is_empty()
+    }
+}
+
+impl fmt::Display for ScanDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+
+        for row in &self.new_hosts {
+            writeln!(f, "+ {} ports {:?}", row.id, row.ports)?;
+        }
+        for row in &self.removed_hosts {
+            writeln!(f, "- {} ports {:?}", row.id, row.ports)?;
+        }
+        for host in &self.changed_hosts {
+            writeln!(f, "~ {}", host.host)?;
+            if !host.added_ports.is_empty() {
+                writeln!(f, "    + ports {:?}", host.added_ports)?;
+            }
+            if !host.removed_ports.is_empty() {
+                writeln!(f, "    - ports {:?}", host.removed_ports)?;
+            }
+            if !host.added_services.is_empty() {
+                writeln!(f, "    + services {}", host.added_services.join(", "))?;
+            }
+            if !host.removed_services.is_empty() {
+                writeln!(f, "    - services {}", host.removed_services.join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ResultDatabase {
+    /// Changes from `older` to this database. Both are walked in key order
+    /// side by side, so neither is loaded into memory at once.
+    pub fn diff(&self, older: &ResultDatabase) -> Result<ScanDiff, DatabaseError> {
+        let mut diff = ScanDiff::default();
+
+        let mut old_rows = older.iter_rows();
+        let mut new_rows = self.iter_rows();
+        let mut old = old_rows.next().transpose()?;
+        let mut new = new_rows.next().transpose()?;
+
+        loop {
+            let order = match (&old, &new) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(old), Some(new)) => host_key(&old.id).cmp(&host_key(&new.id)),
+            };
+
+            match order {
+                Ordering::Less => {
+                    diff.removed_hosts.extend(old.take());
+                    old = old_rows.next().transpose()?;
+                }
+                Ordering::Greater => {
+                    diff.new_hosts.extend(new.take());
+                    new = new_rows.next().transpose()?;
+                }
+                Ordering::Equal => {
+                    if let (Some(old), Some(new)) = (&old, &new) {
+                        let host = HostDiff::between(old, new);
+                        if !host.is_empty() {
+                            diff.changed_hosts.push(host);
+                        }
+                    }
+                    old = old_rows.next().transpose()?;
+                    new = new_rows.next().transpose()?;
+                }
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+// Items of `a` that aren't in `b`
+fn missing_from<T: Clone + PartialEq>(a: &[T], b: &[T]) -> Vec<T> {
+    a.iter().filter(|item| !b.contains(item)).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, row};
+
+    #[test]
+    fn ports_and_services_changed_on_a_host_in_both() {
+        let (_old_dir, older) = testing::temp_database();
+        let (_new_dir, newer) = testing::temp_database();
+        older
+            .save_rows(vec![row("10.0.0.1", &[22, 80], &["ssh", "http"])])
+            .unwrap();
+        newer
+            .save_rows(vec![row("10.0.0.1", &[22, 443], &["ssh", "https"])])
+            .unwrap();
+
+        let diff = newer.diff(&older).unwrap();
+        assert!(diff.new_hosts.is_empty() && diff.removed_hosts.is_empty());
+        let [host] = &diff.changed_hosts[..] else {
+            panic!("expected one changed host, got {:?}", diff.changed_hosts);
+        };
+        assert_eq!(host.host, "10.0.0.1");
+        assert_eq!(host.added_ports, vec![443]);
+        assert_eq!(host.removed_ports, vec![80]);
+        assert_eq!(host.added_services, vec!["https"]);
+        assert_eq!(host.removed_services, vec!["http"]);
+    }
+
+    #[test]
+    fn hosts_on_one_side_are_new_or_removed() {
+        let (_old_dir, older) = testing::temp_database();
+        let (_new_dir, newer) = testing::temp_database();
+        older
+            .save_rows(vec![
+                row("10.0.0.1", &[22], &[]),
+                row("10.0.0.3", &[22], &[]),
+            ])
+            .unwrap();
+        newer
+            .save_rows(vec![
+                row("10.0.0.2", &[80], &[]),
+                row("10.0.0.3", &[22], &[]),
+            ])
+            .unwrap();
+
+        let diff = newer.diff(&older).unwrap();
+        let ids = |rows: &[crate::database::DatabaseResult]| -> Vec<String> {
+            rows.iter().map(|row| row.id.clone()).collect()
+        };
+        assert_eq!(ids(&diff.new_hosts), vec!["10.0.0.2"]);
+        assert_eq!(ids(&diff.removed_hosts), vec!["10.0.0.1"]);
+        assert!(diff.changed_hosts.is_empty());
+    }
+
+    #[test]
+    fn identical_databases_have_no_changes() {
+        let (_old_dir, older) = testing::temp_database();
+        let (_new_dir, newer) = testing::temp_database();
+        let rows = vec![
+            row("10.0.0.1", &[22, 80], &["ssh", "http"]),
+            row("::1", &[8080], &[]),
+        ];
+        older.save_rows(rows.clone()).unwrap();
+        newer.save_rows(rows).unwrap();
+
+        let diff = newer.diff(&older).unwrap();
+        assert!(diff.is_empty(), "{:?}", diff);
+        assert_eq!(diff.to_string(), "No changes\n");
+    }
+}
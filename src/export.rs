@@ -0,0 +1,477 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+#[cfg(feature = "sqlite")]
+use std::{fs, path::Path};
+
+use rocksdb::IteratorMode;
+use serde::Serialize;
+
+#[cfg(feature = "sqlite")]
+use crate::{database::column_handle, progress::Progress};
+use crate::{
+    database::{DatabaseResult, ResultDatabase, host_from_key, join_nums},
+    progress::Operation,
+    snapshot::DbSnapshot,
+};
+
+/// Columns understood by `ResultDatabase::export_csv`. `banners` is the raw
+/// banner of each port as "port:hex", joined with ";".
+pub const CSV_COLUMNS: [&str; 9] = [
+    "host",
+    "ports",
+    "services",
+    "responses",
+    "first_seen",
+    "last_seen",
+    "tags",
+    "hostname",
+    "banners",
+];
+
+/// The stable `host,ports,services` schema written by `export_csv_file`
+pub const DEFAULT_CSV_COLUMNS: [&str; 3] = ["host", "ports", "services"];
+
+// Rows written per SQLite transaction by `export_sqlite`
+#[cfg(feature = "sqlite")]
+const SQLITE_BATCH_ROWS: usize = 5000;
+
+/// What `export_sqlite` does when the file is already there
+#[cfg(feature = "sqlite")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfExists {
+    /// Return an error and leave the file alone
+    Fail,
+    /// Delete the file and write a new one
+    Replace,
+}
+
+impl ResultDatabase {
+    /// Write every row to `path` as CSV with the default columns
+    pub fn export_csv_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.export_csv(BufWriter::new(File::create(path)?), &DEFAULT_CSV_COLUMNS)
+    }
+
+    /// Write every row as CSV (RFC 4180 quoting), with a header row first.
+    /// Ports and service names are joined with ";" so they stay in a single field.
+    pub fn export_csv(
+        &self,
+        writer: impl Write,
+        columns: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(column) = columns.iter().find(|c| !CSV_COLUMNS.contains(c)) {
+            return Err(format!(
+                "Unknown CSV column \"{}\" (expected one of {})",
+                column,
+                CSV_COLUMNS.join(", ")
+            )
+            .into());
+        }
+
+        self.snapshot()?.export_csv(writer, columns)
+    }
+}
+
+impl DbSnapshot<'_> {
+    /// `ResultDatabase::export_csv` as of the snapshot
+    pub fn export_csv(
+        &self,
+        writer: impl Write,
+        columns: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(columns)?;
+
+        let cfs = self.database.column_handles(self.db)?;
+        let read_opts = self.read_opts();
+        let with_banners = columns.contains(&"banners");
+        let progress = self.progress(Operation::Export, cfs[0]);
+
+        for item in self.snapshot.iterator_cf(cfs[0], IteratorMode::Start) {
+            let (key_bytes, _) = item?;
+            progress.key(true);
+            if let Some(key_str) = host_from_key(&key_bytes)
+                && let Some(row) = self
+                    .database
+                    .fetch_row_opt(self.db, &key_str, &cfs, &read_opts)?
+            {
+                let banners = if with_banners {
+                    self.banners_at_key(&key_bytes)
+                } else {
+                    Vec::new()
+                };
+                csv.write_record(
+                    columns
+                        .iter()
+                        .map(|column| csv_field(&row, &banners, column)),
+                )?;
+            }
+        }
+
+        csv.flush()?;
+        progress.finish();
+
+        Ok(())
+    }
+}
+
+impl ResultDatabase {
+    /// Write every row to `path` as a JSON array of `DatabaseResult`, each
+    /// with a `banners` object mapping ports to their raw banner in hex.
+    /// Rows are streamed one at a time rather than collected first.
+    pub fn export_json(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.snapshot()?.export_json(path)
+    }
+}
+
+impl DbSnapshot<'_> {
+    /// `ResultDatabase::export_json` as of the snapshot
+    pub fn export_json(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let cfs = self.database.column_handles(self.db)?;
+        let read_opts = self.read_opts();
+        let progress = self.progress(Operation::Export, cfs[0]);
+
+        writer.write_all(b"[")?;
+
+        let mut first = true;
+        for item in self.snapshot.iterator_cf(cfs[0], IteratorMode::Start) {
+            let (key_bytes, _) = item?;
+            progress.key(true);
+            if let Some(key_str) = host_from_key(&key_bytes)
+                && let Some(row) = self
+                    .database
+                    .fetch_row_opt(self.db, &key_str, &cfs, &read_opts)?
+            {
+                if !first {
+                    writer.write_all(b",")?;
+                }
+                first = false;
+
+                let banners = self
+                    .banners_at_key(&key_bytes)
+                    .into_iter()
+                    .map(|(port, banner)| (port, hex(&banner)))
+                    .collect();
+                writer.write_all(b"\n")?;
+                serde_json::to_writer(&mut writer, &JsonRow { row, banners })?;
+            }
+        }
+
+        writer.write_all(b"\n]\n")?;
+        writer.flush()?;
+        progress.finish();
+
+        Ok(())
+    }
+}
+
+// A row as `export_json` writes it
+#[derive(Serialize)]
+struct JsonRow {
+    #[serde(flatten)]
+    row: DatabaseResult,
+    banners: BTreeMap<u16, String>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn csv_field(row: &DatabaseResult, banners: &[(u16, Vec<u8>)], column: &str) -> String {
+    match column {
+        "host" => row.id.clone(),
+        "ports" => join_nums(&row.ports, ";"),
+        "services" => row.services.join(";"),
+        "responses" => row.responses.clone(),
+        "first_seen" => row.first_seen.map(|t| t.to_string()).unwrap_or_default(),
+        "last_seen" => row.last_seen.map(|t| t.to_string()).unwrap_or_default(),
+        "tags" => row.tags.join(";"),
+        "hostname" => row.hostname.clone().unwrap_or_default(),
+        "banners" => banners
+            .iter()
+            .map(|(port, banner)| format!("{}:{}", port, hex(banner)))
+            .collect::<Vec<_>>()
+            .join(";"),
+        _ => String::new(),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ResultDatabase {
+    /// Write every row to a new SQLite database at `path`, with the tables
+    ///
+    /// - `hosts(id TEXT PRIMARY KEY, last_seen INTEGER)`
+    /// - `ports(host TEXT, port INTEGER)`
+    /// - `services(host TEXT, port INTEGER, name TEXT, banner TEXT)`
+    ///
+    /// Rows are streamed in a transaction per few thousand hosts. Returns the
+    /// number of hosts written.
+    pub fn export_sqlite(
+        &self,
+        path: &Path,
+        if_exists: IfExists,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        if path.exists() {
+            match if_exists {
+                IfExists::Fail => {
+                    return Err(format!("{} already exists", path.display()).into());
+                }
+                IfExists::Replace => fs::remove_file(path)?,
+            }
+        }
+
+        let mut sqlite = rusqlite::Connection::open(path)?;
+        sqlite.execute_batch(
+            "CREATE TABLE hosts (id TEXT PRIMARY KEY, last_seen INTEGER);
+             CREATE TABLE ports (host TEXT, port INTEGER);
+             CREATE TABLE services (host TEXT, port INTEGER, name TEXT, banner TEXT);
+             CREATE INDEX ports_port ON ports (port);
+             CREATE INDEX services_name ON services (name);",
+        )?;
+
+        let db = self.db()?;
+        let progress = Progress::new(
+            self.progress.as_ref(),
+            Operation::Export,
+            &db,
+            column_handle(&db, "default")?,
+        );
+        let mut hosts = 0;
+        let mut rows = self.iter_rows().peekable();
+        while rows.peek().is_some() {
+            let transaction = sqlite.transaction()?;
+            {
+                let mut insert_host =
+                    transaction.prepare("INSERT INTO hosts (id, last_seen) VALUES (?1, ?2)")?;
+                let mut insert_port =
+                    transaction.prepare("INSERT INTO ports (host, port) VALUES (?1, ?2)")?;
+                let mut insert_service = transaction.prepare(
+                    "INSERT INTO services (host, port, name, banner) VALUES (?1, ?2, ?3, ?4)",
+                )?;
+
+                for row in rows.by_ref().take(SQLITE_BATCH_ROWS) {
+                    let row = row?;
+                    progress.key(true);
+                    insert_host.execute(rusqlite::params![row.id, row.last_seen])?;
+                    for port in &row.ports {
+                        insert_port.execute(rusqlite::params![row.id, port])?;
+                    }
+                    for service in &row.service_entries {
+                        insert_service.execute(rusqlite::params![
+                            row.id,
+                            // Services from rows without per-port responses have no port
+                            (service.port != 0).then_some(service.port),
+                            service.name,
+                            service.banner
+                        ])?;
+                    }
+                    hosts += 1;
+                }
+            }
+            transaction.commit()?;
+        }
+        progress.finish();
+
+        Ok(hosts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        database::{DatabaseResult, Responses},
+        testing,
+    };
+
+    #[test]
+    fn csv_round_trips_through_a_reader() {
+        let (_dir, database) = testing::temp_database();
+
+        let mut web = testing::row("10.0.0.2", &[80, 443], &["http", "https"]);
+        web.set_response_map(&Responses::from([(
+            "80/tcp".to_string(),
+            ("http".to_string(), "Server: a, \"quoted\" b".to_string()),
+        )]));
+        database
+            .save_rows(vec![web, testing::row("10.0.0.1", &[22], &["ssh"])])
+            .unwrap();
+        database
+            .set_hostname("10.0.0.2", "web, \"primary\"")
+            .unwrap();
+
+        let mut out = Vec::new();
+        database
+            .export_csv(
+                &mut out,
+                &["host", "ports", "services", "hostname", "responses"],
+            )
+            .unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec!["host", "ports", "services", "hostname", "responses"]
+        );
+        let records: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(&records[0][0], "10.0.0.1");
+        assert_eq!(&records[0][1], "22");
+        assert_eq!(&records[0][2], "ssh");
+        assert_eq!(&records[0][3], "");
+
+        assert_eq!(&records[1][0], "10.0.0.2");
+        assert_eq!(&records[1][1], "80;443");
+        assert_eq!(&records[1][2], "http;https");
+        assert_eq!(&records[1][3], "web, \"primary\"");
+        let responses: Responses = serde_json::from_str(&records[1][4]).unwrap();
+        assert_eq!(
+            responses["80/tcp"],
+            ("http".to_string(), "Server: a, \"quoted\" b".to_string())
+        );
+    }
+
+    #[test]
+    fn csv_rejects_unknown_columns() {
+        let (_dir, database) = testing::temp_database();
+
+        let e = database
+            .export_csv(Vec::new(), &["host", "nope"])
+            .unwrap_err();
+        assert!(e.to_string().contains("Unknown CSV column \"nope\""));
+    }
+
+    #[test]
+    fn json_round_trips_through_serde() {
+        let (dir, database) = testing::temp_database();
+        let path = dir.path().join("export.json");
+        let path = path.to_str().unwrap();
+
+        database.export_json(path).unwrap();
+        let rows: Vec<DatabaseResult> =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert!(rows.is_empty());
+
+        let mut web = testing::row("10.0.0.2", &[80], &["http"]);
+        web.set_response_map(&Responses::from([(
+            "80/tcp".to_string(),
+            ("http".to_string(), "HTTP/1.1 200 OK".to_string()),
+        )]));
+        database
+            .save_rows(vec![web, testing::row("2001:db8::1", &[22, 443], &[])])
+            .unwrap();
+        database.add_tag("10.0.0.2", "prod").unwrap();
+        database.export_json(path).unwrap();
+
+        let text = std::fs::read_to_string(path).unwrap();
+        let values: Vec<serde_json::Value> = serde_json::from_str(&text).unwrap();
+        assert_eq!(values.len(), 2);
+        for mut value in values {
+            assert!(value["banners"].is_object());
+            value.as_object_mut().unwrap().remove("banners");
+
+            let host = value["id"].as_str().unwrap().to_string();
+            let stored = database.get_row_by_host(&host).unwrap().unwrap();
+            assert_eq!(value, serde_json::to_value(&stored).unwrap());
+        }
+
+        let rows: Vec<DatabaseResult> = serde_json::from_str(&text).unwrap();
+        assert_eq!(rows[0].id, "10.0.0.2");
+        assert_eq!(rows[0].tags, vec!["prod"]);
+        assert_eq!(rows[0].response_map()["80/tcp"].1, "HTTP/1.1 200 OK");
+        assert_eq!(rows[1].ports, vec![22, 443]);
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod sqlite_tests {
+    use super::*;
+    use crate::{database::Responses, testing};
+
+    #[test]
+    fn sqlite_holds_every_host_port_and_service() {
+        let (dir, database) = testing::temp_database();
+        let path = dir.path().join("export.sqlite");
+
+        let mut web = testing::row("10.0.0.2", &[80, 443], &["http"]);
+        web.set_response_map(&Responses::from([(
+            "80/tcp".to_string(),
+            ("http".to_string(), "HTTP/1.1 200 OK".to_string()),
+        )]));
+        // Enough hosts for more than one transaction
+        let mut rows: Vec<DatabaseResult> = (0..SQLITE_BATCH_ROWS as u32 + 10)
+            .map(|i| {
+                let [_, a, b, c] = i.to_be_bytes();
+                testing::row(&format!("10.{}.{}.{}", a + 1, b, c), &[22], &[])
+            })
+            .collect();
+        rows.push(web);
+        let written = rows.len() as u64;
+        database.save_rows(rows).unwrap();
+
+        assert_eq!(
+            database.export_sqlite(&path, IfExists::Fail).unwrap(),
+            written
+        );
+
+        let sqlite = rusqlite::Connection::open(&path).unwrap();
+        let count = |sql: &str| -> u64 { sqlite.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM hosts"), written);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM ports WHERE port = 22"),
+            written - 1
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM hosts WHERE last_seen IS NULL"),
+            0
+        );
+
+        let mut statement = sqlite
+            .prepare("SELECT port FROM ports WHERE host = '10.0.0.2' ORDER BY port")
+            .unwrap();
+        let ports: Vec<i32> = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        drop(statement);
+        assert_eq!(ports, vec![80, 443]);
+
+        let stored = database.get_row_by_host("10.0.0.2").unwrap().unwrap();
+        let service: (Option<u16>, String, String) = sqlite
+            .query_row(
+                "SELECT port, name, banner FROM services WHERE host = '10.0.0.2'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            service,
+            (
+                Some(80),
+                stored.service_entries[0].name.clone(),
+                stored.service_entries[0].banner.clone()
+            )
+        );
+        assert_eq!(service.1, "http");
+        drop(sqlite);
+
+        // Only replaced when asked to
+        let e = database.export_sqlite(&path, IfExists::Fail).unwrap_err();
+        assert!(e.to_string().contains("already exists"));
+        database.delete_host("10.0.0.2").unwrap();
+        assert_eq!(
+            database.export_sqlite(&path, IfExists::Replace).unwrap(),
+            written - 1
+        );
+        let sqlite = rusqlite::Connection::open(&path).unwrap();
+        let services: u64 = sqlite
+            .query_row("SELECT COUNT(*) FROM services", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(services, 0);
+    }
+}
@@ -0,0 +1,475 @@
+use std::{
+    cmp::min,
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    online_scan::{online_scan::os_family_from_ttl, ping_scanner::ping_scan_results},
+    port_scan::{
+        port_scan::{PortScanResult, ScanConfig},
+        tcp_scan::tcp_scan,
+    },
+    service_scan::service_scan::scan_services,
+    util::humanize_duration,
+};
+
+// A single host answers quickly, so don't wait as long as a sweep does
+const PORT_TIMEOUT: Duration = Duration::from_secs(1);
+const SERVICE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_SERVICE_THREADS: usize = 16;
+// Detail column width in the terminal report
+const DETAIL_WIDTH: usize = 60;
+
+// Banner substrings that give away the operating system
+const OS_HINTS: [(&str, &str); 12] = [
+    ("ubuntu", "Ubuntu"),
+    ("debian", "Debian"),
+    ("raspbian", "Raspbian"),
+    ("centos", "CentOS"),
+    ("red hat", "Red Hat"),
+    ("fedora", "Fedora"),
+    ("alpine", "Alpine"),
+    ("freebsd", "FreeBSD"),
+    ("openbsd", "OpenBSD"),
+    ("microsoft", "Windows"),
+    ("win32", "Windows"),
+    ("win64", "Windows"),
+];
+
+/// Everything learned about one host by `scan_host`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostReport {
+    pub ip: IpAddr,
+    /// Answered an ICMP echo or any TCP probe
+    pub up: bool,
+    pub icmp_reply: bool,
+    /// IP TTL of the ICMP echo reply
+    #[serde(default)]
+    pub ttl: Option<u8>,
+    pub ports_scanned: usize,
+    pub open_ports: Vec<PortReport>,
+    pub closed_ports: Vec<i32>,
+    pub filtered_ports: Vec<i32>,
+    pub mss: Option<u16>,
+    /// Operating systems named in service banners, or guessed from the reply TTL
+    pub os_hints: Vec<String>,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortReport {
+    pub port: i32,
+    pub service: String,
+    /// Raw banner or response body
+    pub response: String,
+}
+
+/// Ping, port scan and identify services on a single host, without a database.
+/// Filtered ports are probed a second time before giving up on them. Fails
+/// like `tcp_scan`.
+pub fn scan_host(ip: IpAddr, ports: Vec<i32>) -> io::Result<HostReport> {
+    let start = Instant::now();
+
+    // The ICMP ping waits out its own timeout, so run it alongside the port scan
+    let ping = thread::spawn(move || {
+        ping_scan_results(vec![ip], None, None)
+            .ok()
+            .and_then(|up| up.into_iter().find(|reply| reply.host == ip))
+    });
+
+    let config = ScanConfig {
+        timeout: PORT_TIMEOUT,
+        ..Default::default()
+    };
+    let mut result = tcp_scan(vec![ip], ports.clone(), &config)?.remove(0);
+    if !result.filtered_ports.is_empty() {
+        let retry = tcp_scan(vec![ip], result.filtered_ports.clone(), &config)?.remove(0);
+        merge_retry(&mut result, retry, &ports);
+    }
+
+    let reply = ping.join().unwrap_or(None);
+    let icmp_reply = reply.is_some();
+    let ttl = reply.and_then(|reply| reply.ttl);
+
+    let services = if result.open_ports.is_empty() {
+        HashMap::new()
+    } else {
+        let threads = min(MAX_SERVICE_THREADS, result.open_ports.len());
+        scan_services(
+            vec![result.clone()],
+            threads,
+            SERVICE_TIMEOUT,
+            HashMap::new(),
+        )
+        .remove(0)
+        .services
+    };
+
+    let open_ports: Vec<PortReport> = result
+        .open_ports
+        .iter()
+        .map(|port| {
+            let (service, response) = services
+                .get(port)
+                .cloned()
+                .unwrap_or(("tcp".to_string(), String::new()));
+            PortReport {
+                port: *port,
+                service,
+                response,
+            }
+        })
+        .collect();
+
+    Ok(HostReport {
+        ip,
+        up: icmp_reply || !result.open_ports.is_empty() || !result.closed_ports.is_empty(),
+        icmp_reply,
+        ttl,
+        ports_scanned: ports.len(),
+        os_hints: os_hints(&open_ports, ttl),
+        open_ports,
+        closed_ports: result.closed_ports,
+        filtered_ports: result.filtered_ports,
+        mss: result.mss,
+        elapsed_ms: start.elapsed().as_millis(),
+    })
+}
+
+fn merge_retry(result: &mut PortScanResult, retry: PortScanResult, ports: &[i32]) {
+    result.open_ports.extend(retry.open_ports);
+    result.closed_ports.extend(retry.closed_ports);
+    result.mss = match (result.mss, retry.mss) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    result.finish(ports);
+}
+
+fn os_hints(ports: &[PortReport], ttl: Option<u8>) -> Vec<String> {
+    let mut hints: Vec<String> = Vec::new();
+
+    for port in ports {
+        let response = port.response.to_lowercase();
+        for (needle, os) in OS_HINTS {
+            if response.contains(needle) && !hints.iter().any(|h| h.starts_with(os)) {
+                hints.push(format!("{} ({}/{})", os, port.port, port.service));
+            }
+        }
+    }
+
+    // Banners name the system outright, so the TTL guess is only a fallback
+    if hints.is_empty()
+        && let Some(ttl) = ttl
+        && let Some(family) = os_family_from_ttl(ttl)
+    {
+        hints.push(format!("{} (TTL {})", family, ttl));
+    }
+
+    hints
+}
+
+impl HostReport {
+    /// Terminal report for the host
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out += &format!(
+            "Host {} is {}{}\n",
+            self.ip,
+            if self.up { "up" } else { "down" },
+            if self.icmp_reply {
+                " (replied to ICMP echo)"
+            } else {
+                ""
+            }
+        );
+        out += &format!(
+            "Scanned {} ports in {}: {} open, {} closed, {} filtered\n",
+            self.ports_scanned,
+            humanize_duration(Duration::from_millis(self.elapsed_ms as u64)),
+            self.open_ports.len(),
+            self.closed_ports.len(),
+            self.filtered_ports.len()
+        );
+
+        if !self.open_ports.is_empty() {
+            out += &format!("\n{:<10}{:<14}DETAIL\n", "PORT", "SERVICE");
+            for port in &self.open_ports {
+                out += &format!(
+                    "{:<10}{:<14}{}\n",
+                    format!("{}/tcp", port.port),
+                    port.service,
+                    summarize(&port.response)
+                );
+            }
+        }
+
+        if !self.os_hints.is_empty() {
+            out += &format!("\nOS hints: {}\n", self.os_hints.join(", "));
+        }
+        if let Some(ttl) = self.ttl {
+            out += &format!("ICMP reply TTL: {}\n", ttl);
+        }
+        if let Some(mss) = self.mss {
+            out += &format!("TCP MSS: {}\n", mss);
+        }
+
+        out
+    }
+}
+
+/// One line describing a response: the HTML title if there is one, otherwise the first line
+fn summarize(response: &str) -> String {
+    let lower = response.to_ascii_lowercase();
+    let line = match (lower.find("<title>"), lower.find("</title>")) {
+        (Some(start), Some(end)) if start + 7 <= end => response[start + 7..end].trim(),
+        _ => response
+            .lines()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .unwrap_or(""),
+    };
+
+    let line: String = line.chars().filter(|c| !c.is_control()).collect();
+    if line.chars().count() > DETAIL_WIDTH {
+        format!(
+            "{}...",
+            line.chars().take(DETAIL_WIDTH - 3).collect::<String>()
+        )
+    } else {
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        net::{Ipv4Addr, TcpListener},
+    };
+
+    fn port(port: i32, service: &str, response: &str) -> PortReport {
+        PortReport {
+            port,
+            service: service.to_string(),
+            response: response.to_string(),
+        }
+    }
+
+    fn report() -> HostReport {
+        HostReport {
+            ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            up: true,
+            icmp_reply: true,
+            ttl: Some(64),
+            ports_scanned: 100,
+            open_ports: vec![
+                port(22, "ssh", "SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n"),
+                port(
+                    80,
+                    "http",
+                    "HTTP/1.1 200 OK\r\n\r\n<html><TITLE> Router login </TITLE></html>",
+                ),
+            ],
+            closed_ports: vec![443],
+            filtered_ports: vec![8080, 8443],
+            mss: Some(1460),
+            os_hints: vec!["Ubuntu (22/ssh)".to_string()],
+            elapsed_ms: 1500,
+        }
+    }
+
+    #[test]
+    fn summaries_prefer_the_html_title() {
+        assert_eq!(
+            summarize("HTTP/1.1 200 OK\r\n\r\n<html><Title>\n Home </title>"),
+            "Home"
+        );
+        assert_eq!(summarize("\r\n  \r\n220 ready\r\nmore\r\n"), "220 ready");
+        assert_eq!(
+            summarize("</title> before <title>"),
+            "</title> before <title>"
+        );
+        assert_eq!(summarize(""), "");
+        assert_eq!(summarize("a\x07b\tc"), "abc");
+
+        let long = "x".repeat(DETAIL_WIDTH + 1);
+        let summary = summarize(&long);
+        assert_eq!(summary.chars().count(), DETAIL_WIDTH);
+        assert!(summary.ends_with("..."));
+        assert_eq!(summarize(&long[1..]), long[1..]);
+    }
+
+    #[test]
+    fn os_hints_come_from_banners_before_the_ttl() {
+        let ports = [
+            port(22, "ssh", "SSH-2.0-OpenSSH_8.4p1 Debian-5"),
+            port(80, "http", "Server: Apache/2.4.56 (Debian)"),
+            port(3389, "rdp", "Microsoft Terminal Services"),
+        ];
+        // One hint per system, from the first port naming it
+        assert_eq!(
+            os_hints(&ports, Some(128)),
+            vec!["Debian (22/ssh)", "Windows (3389/rdp)"]
+        );
+
+        assert_eq!(os_hints(&[], Some(64)), vec!["Linux/Unix (TTL 64)"]);
+        assert_eq!(
+            os_hints(&[port(80, "http", "nginx")], Some(120)),
+            vec!["Windows (TTL 120)"]
+        );
+        assert!(os_hints(&[], Some(0)).is_empty());
+        assert!(os_hints(&[], None).is_empty());
+    }
+
+    #[test]
+    fn retry_adds_the_ports_that_answered_second_time() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ports = [22, 80, 443, 8080];
+
+        let mut result = PortScanResult::new(ip);
+        result.open_ports = vec![22];
+        result.mss = Some(1460);
+        result.finish(&ports);
+        assert_eq!(result.filtered_ports, vec![80, 443, 8080]);
+
+        let mut retry = PortScanResult::new(ip);
+        retry.open_ports = vec![80];
+        retry.closed_ports = vec![443];
+        retry.mss = Some(1400);
+        merge_retry(&mut result, retry, &ports);
+
+        assert_eq!(result.open_ports, vec![22, 80]);
+        assert_eq!(result.closed_ports, vec![443]);
+        assert_eq!(result.filtered_ports, vec![8080]);
+        assert_eq!(result.mss, Some(1400));
+
+        // An MSS from either scan is kept
+        let mut retry = PortScanResult::new(ip);
+        retry.mss = Some(536);
+        let mut without = PortScanResult::new(ip);
+        merge_retry(&mut without, retry, &ports);
+        assert_eq!(without.mss, Some(536));
+    }
+
+    #[test]
+    fn render_lists_ports_and_hints() {
+        assert_eq!(
+            report().render(),
+            "Host 10.0.0.1 is up (replied to ICMP echo)\n\
+             Scanned 100 ports in 1s500ms: 2 open, 1 closed, 2 filtered\n\
+             \n\
+             PORT      SERVICE       DETAIL\n\
+             22/tcp    ssh           SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\n\
+             80/tcp    http          Router login\n\
+             \n\
+             OS hints: Ubuntu (22/ssh)\n\
+             ICMP reply TTL: 64\n\
+             TCP MSS: 1460\n"
+        );
+
+        let down = HostReport {
+            up: false,
+            icmp_reply: false,
+            ttl: None,
+            open_ports: Vec::new(),
+            closed_ports: Vec::new(),
+            mss: None,
+            os_hints: Vec::new(),
+            elapsed_ms: 0,
+            ..report()
+        };
+        assert_eq!(
+            down.render(),
+            "Host 10.0.0.1 is down\nScanned 100 ports in 0s: 0 open, 0 closed, 2 filtered\n"
+        );
+    }
+
+    #[test]
+    fn report_json_round_trips() {
+        let json = serde_json::to_value(report()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "ip": "10.0.0.1",
+                "up": true,
+                "icmp_reply": true,
+                "ttl": 64,
+                "ports_scanned": 100,
+                "open_ports": [
+                    {
+                        "port": 22,
+                        "service": "ssh",
+                        "response": "SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n",
+                    },
+                    {
+                        "port": 80,
+                        "service": "http",
+                        "response": "HTTP/1.1 200 OK\r\n\r\n<html><TITLE> Router login </TITLE></html>",
+                    },
+                ],
+                "closed_ports": [443],
+                "filtered_ports": [8080, 8443],
+                "mss": 1460,
+                "os_hints": ["Ubuntu (22/ssh)"],
+                "elapsed_ms": 1500,
+            })
+        );
+
+        let read: HostReport = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(read).unwrap(), json);
+
+        // Reports saved before the TTL was recorded still load
+        let mut old = json;
+        old.as_object_mut().unwrap().remove("ttl");
+        let read: HostReport = serde_json::from_value(old).unwrap();
+        assert_eq!(read.ttl, None);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn scans_a_loopback_host() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let open = listener.local_addr().unwrap().port() as i32;
+        let closed = {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap().port() as i32
+        };
+        // Greets every connection, the port scan's and the service scan's
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n");
+            }
+        });
+
+        let report = scan_host(IpAddr::V4(Ipv4Addr::LOCALHOST), vec![open, closed]).unwrap();
+
+        assert!(report.up);
+        assert!(report.icmp_reply);
+        assert_eq!(report.ttl, Some(64));
+        assert_eq!(report.ports_scanned, 2);
+        let [found] = &report.open_ports[..] else {
+            panic!("expected one open port, got {:?}", report.open_ports);
+        };
+        assert_eq!(found.port, open);
+        assert!(
+            found.response.starts_with("SSH-2.0-OpenSSH_9.6p1"),
+            "{:?}",
+            found
+        );
+        assert_eq!(report.closed_ports, vec![closed]);
+        assert!(report.filtered_ports.is_empty());
+        assert_eq!(
+            report.os_hints,
+            vec![format!("Ubuntu ({}/{})", open, found.service)]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use rocksdb::{IteratorMode, WriteBatch};
+use serde::Serialize;
+
+use crate::{
+    database::{DatabaseError, DatabaseResult, ResultDatabase, host_from_key, host_key},
+    index::IndexManager,
+    util::humanize_duration,
+};
+
+const DNS_PORT: u16 = 53;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+// Largest reply over UDP without EDNS
+const MAX_REPLY: usize = 512;
+// Compression pointers followed before a name is considered a loop
+const MAX_POINTERS: usize = 16;
+
+/// How `resolve_hostnames` looks hosts up
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    /// DNS server to ask, the first one in /etc/resolv.conf if None
+    pub nameserver: Option<SocketAddr>,
+    /// Lookups in flight at once
+    pub threads: usize,
+    /// How long to wait for each reply
+    pub timeout: Duration,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            nameserver: None,
+            threads: 32,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Counts from a `resolve_hostnames` pass
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ResolveStats {
+    pub looked_up: u64,
+    pub resolved: u64,
+}
+
+/// First nameserver in /etc/resolv.conf
+pub fn system_nameserver() -> Option<SocketAddr> {
+    fs::read_to_string("/etc/resolv.conf")
+        .ok()?
+        .lines()
+        .find_map(|line| {
+            let address = line.trim().strip_prefix("nameserver")?.trim();
+            // Drop an IPv6 zone such as "fe80::1%eth0"
+            let address = address.split('%').next()?;
+            address.parse::<IpAddr>().ok()
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+}
+
+/// Name queried for `ip`'s PTR record, e.g. "4.3.2.1.in-addr.arpa"
+pub fn ptr_name(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::with_capacity(72);
+            for byte in v6.octets().iter().rev() {
+                name += &format!("{:x}.{:x}.", byte & 0xf, byte >> 4);
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Hostname from `ip`'s PTR record, lowercased and without the trailing dot.
+/// None if the server has no record for it.
+pub fn reverse_lookup(
+    ip: &IpAddr,
+    nameserver: SocketAddr,
+    timeout: Duration,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let socket = match nameserver {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+
+    let id: u16 = rand::random();
+    socket.send_to(&build_query(id, &ptr_name(ip)), nameserver)?;
+
+    let start = Instant::now();
+    let mut reply = [0u8; MAX_REPLY];
+    loop {
+        let remaining = timeout
+            .checked_sub(start.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or("DNS query timed out")?;
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, from) = socket.recv_from(&mut reply)?;
+        // Ignore stray replies, e.g. to an earlier query that timed out
+        if from == nameserver && len >= 12 && u16::from_be_bytes([reply[0], reply[1]]) == id {
+            return Ok(parse_ptr_reply(&reply[..len]));
+        }
+    }
+}
+
+// A recursive query for one PTR record
+fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(12 + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no other records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+// The first PTR answer in a reply, None for errors, NXDOMAIN or no answer
+fn parse_ptr_reply(reply: &[u8]) -> Option<String> {
+    let flags = u16::from_be_bytes([*reply.get(2)?, *reply.get(3)?]);
+    // Not a response, or a non-zero response code
+    if flags & 0x8000 == 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([reply[4], reply[5]]);
+    let answers = u16::from_be_bytes([reply[6], reply[7]]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(reply, offset)?.1 + 4;
+    }
+
+    for _ in 0..answers {
+        let (_, after_name) = read_name(reply, offset)?;
+        let header = reply.get(after_name..after_name + 10)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let length = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let data = after_name + 10;
+        if data + length > reply.len() {
+            return None;
+        }
+
+        if record_type == TYPE_PTR {
+            let (name, _) = read_name(reply, data)?;
+            return Some(name.to_lowercase()).filter(|name| !name.is_empty());
+        }
+        offset = data + length;
+    }
+
+    None
+}
+
+// Decode a possibly compressed name at `offset`. Returns the name without a
+// trailing dot and the offset just past it in the record.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            // Pointer to a name earlier in the message
+            l if l & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                let target = ((l & 0x3f) << 8) | *message.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                offset = target;
+            }
+            l if l & 0xc0 != 0 => return None,
+            l => {
+                let label = message.get(offset + 1..offset + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                offset += 1 + l;
+            }
+        }
+    }
+}
+
+impl ResultDatabase {
+    /// Record a hostname for a host. Returns false if the host isn't in the database.
+    pub fn set_hostname(&self, host: &str, name: &str) -> Result<bool, DatabaseError> {
+        let db = self.writable_db()?;
+        let cfs = self.column_handles(&db)?;
+        let key = host_key(host);
+        if db.get_pinned_cf(cfs[0], &key)?.is_none() {
+            return Ok(false);
+        }
+
+        let name = name.trim().trim_end_matches('.').to_lowercase();
+        let mut batch = WriteBatch::default();
+        IndexManager::new(self, &db)?.put_hostname(&mut batch, host, &name);
+        db.write(batch)?;
+
+        Ok(true)
+    }
+
+    /// Look up the PTR record of every host in the database, a few at a time,
+    /// and store the names found. Hosts whose lookup fails or times out keep
+    /// whatever name they had.
+    pub fn resolve_hostnames(
+        &self,
+        config: &ResolverConfig,
+    ) -> Result<ResolveStats, Box<dyn std::error::Error>> {
+        let nameserver = config
+            .nameserver
+            .or_else(system_nameserver)
+            .ok_or("No nameserver given and none found in /etc/resolv.conf")?;
+
+        let db = self.writable_db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let mut hosts = Vec::new();
+        for item in db.iterator_cf(cfs[0], IteratorMode::Start) {
+            let (key, _) = item?;
+            if let Some(host) = host_from_key(&key)
+                && let Ok(ip) = host.parse::<IpAddr>()
+            {
+                hosts.push(ip);
+            }
+        }
+
+        let start = Instant::now();
+        let mut stats = ResolveStats {
+            looked_up: hosts.len() as u64,
+            ..Default::default()
+        };
+
+        let hosts = Arc::new(Mutex::new(hosts));
+        let (tx, rx) = mpsc::channel();
+        let mut handles = Vec::new();
+        for _ in 0..config.threads.max(1) {
+            let hosts = Arc::clone(&hosts);
+            let tx = tx.clone();
+            let timeout = config.timeout;
+            handles.push(thread::spawn(move || {
+                loop {
+                    let Some(ip) = hosts.lock().unwrap().pop() else {
+                        break;
+                    };
+                    if let Ok(Some(name)) = reverse_lookup(&ip, nameserver, timeout) {
+                        let _ = tx.send((ip, name));
+                    }
+                }
+            }));
+        }
+        drop(tx);
+
+        let indexes = IndexManager::new(self, &db)?;
+        let mut batch = WriteBatch::default();
+        for (ip, name) in rx {
+            indexes.put_hostname(&mut batch, &ip.to_string(), &name);
+            stats.resolved += 1;
+            if batch.len() >= self.batch_size {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            db.write(batch)?;
+        }
+
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        info!(
+            "Resolved {} of {} hosts in {}",
+            stats.resolved,
+            stats.looked_up,
+            humanize_duration(start.elapsed())
+        );
+
+        Ok(stats)
+    }
+
+    /// Rows whose hostname contains `name`, ignoring case
+    pub fn get_rows_by_hostname(&self, name: &str) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.search_substring_in_column("hostnames", &name.to_lowercase())
+    }
+}
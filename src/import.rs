@@ -0,0 +1,325 @@
+use std::{collections::HashMap, error::Error, fs, io::Read, path::Path};
+
+use log::info;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseResult, Responses, ResultDatabase, service_key};
+
+/// Counts from an import
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ImportStats {
+    /// Hosts written to the database
+    pub hosts: u64,
+    /// Open ports written to the database
+    pub ports: u64,
+    /// Hosts skipped because they had no IPv4 or IPv6 address
+    pub skipped_hosts: u64,
+    /// Open ports skipped because they were not TCP (the database only holds TCP ports)
+    pub skipped_ports: u64,
+}
+
+#[derive(Deserialize)]
+struct MasscanRecord {
+    ip: String,
+    #[serde(default)]
+    ports: Vec<MasscanPort>,
+}
+
+#[derive(Deserialize)]
+struct MasscanPort {
+    port: i32,
+    #[serde(default)]
+    proto: String,
+    #[serde(default)]
+    status: String,
+    service: Option<MasscanService>,
+}
+
+#[derive(Deserialize)]
+struct MasscanService {
+    name: String,
+    #[serde(default)]
+    banner: String,
+}
+
+impl ResultDatabase {
+    /// Import hosts, open TCP ports and services from an nmap `-oX` file
+    pub fn import_nmap_xml(&self, path: &Path) -> Result<ImportStats, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let doc = roxmltree::Document::parse_with_options(
+            &text,
+            roxmltree::ParsingOptions {
+                allow_dtd: true,
+                ..Default::default()
+            },
+        )?;
+
+        let mut stats = ImportStats::default();
+        let mut rows = Vec::new();
+
+        for host in doc.descendants().filter(|n| n.has_tag_name("host")) {
+            let Some(address) = host
+                .children()
+                .filter(|n| n.has_tag_name("address"))
+                .find(|n| matches!(n.attribute("addrtype"), Some("ipv4") | Some("ipv6")))
+                .and_then(|n| n.attribute("addr"))
+            else {
+                stats.skipped_hosts += 1;
+                continue;
+            };
+
+            let mut ports = Vec::new();
+            let mut responses = Responses::new();
+
+            for port in host
+                .children()
+                .filter(|n| n.has_tag_name("ports"))
+                .flat_map(|n| n.children())
+                .filter(|n| n.has_tag_name("port"))
+            {
+                let open = port
+                    .children()
+                    .find(|n| n.has_tag_name("state"))
+                    .and_then(|n| n.attribute("state"))
+                    == Some("open");
+                let Some(port_id) = port.attribute("portid").and_then(|p| p.parse::<i32>().ok())
+                else {
+                    continue;
+                };
+                if !open {
+                    continue;
+                }
+                if port.attribute("protocol") != Some("tcp") {
+                    stats.skipped_ports += 1;
+                    continue;
+                }
+
+                ports.push(port_id);
+
+                if let Some(service) = port.children().find(|n| n.has_tag_name("service"))
+                    && let Some(name) = service.attribute("name")
+                {
+                    let detail: Vec<&str> = ["product", "version", "extrainfo"]
+                        .iter()
+                        .filter_map(|attr| service.attribute(*attr))
+                        .collect();
+                    responses.insert(
+                        service_key(port_id, "tcp"),
+                        (name.to_string(), detail.join(" ")),
+                    );
+                }
+            }
+
+            rows.push(host_row(address.to_string(), ports, responses, &mut stats)?);
+        }
+
+        self.upsert_rows(rows)?;
+
+        info!(
+            "Imported {} hosts with {} open ports ({} hosts without an address skipped)",
+            stats.hosts, stats.ports, stats.skipped_hosts
+        );
+
+        Ok(stats)
+    }
+
+    /// Import open TCP ports and banners from masscan `-oJ` output.
+    /// Records are grouped by IP so each host becomes a single row; UDP records
+    /// are skipped and counted in `skipped_ports`.
+    pub fn import_masscan_json(
+        &self,
+        mut reader: impl Read,
+    ) -> Result<ImportStats, Box<dyn Error>> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+
+        let records = parse_masscan_json(&text)?;
+
+        let mut stats = ImportStats::default();
+        let mut hosts: HashMap<String, (Vec<i32>, Responses)> = HashMap::new();
+
+        for record in records {
+            let (ports, responses) = hosts.entry(record.ip).or_default();
+
+            for port in record.ports {
+                // Banner records carry a service but no status
+                if port.status != "open" && port.service.is_none() {
+                    continue;
+                }
+                if port.proto != "tcp" {
+                    stats.skipped_ports += 1;
+                    continue;
+                }
+
+                ports.push(port.port);
+                if let Some(service) = port.service {
+                    responses.insert(
+                        service_key(port.port, "tcp"),
+                        (service.name, service.banner),
+                    );
+                }
+            }
+        }
+
+        let mut rows = Vec::with_capacity(hosts.len());
+        for (ip, (ports, responses)) in hosts {
+            rows.push(host_row(ip, ports, responses, &mut stats)?);
+        }
+
+        self.upsert_rows(rows)?;
+
+        info!(
+            "Imported {} hosts with {} open ports ({} UDP ports skipped)",
+            stats.hosts, stats.ports, stats.skipped_ports
+        );
+
+        Ok(stats)
+    }
+}
+
+/// Build a database row for one imported host and count it in `stats`
+fn host_row(
+    id: String,
+    mut ports: Vec<i32>,
+    responses: Responses,
+    stats: &mut ImportStats,
+) -> Result<DatabaseResult, Box<dyn Error>> {
+    ports.sort();
+    ports.dedup();
+
+    let mut services: Vec<String> = responses.values().map(|s| s.0.clone()).collect();
+    services.sort();
+    services.dedup();
+
+    stats.hosts += 1;
+    stats.ports += ports.len() as u64;
+
+    Ok(DatabaseResult {
+        id,
+        ports,
+        services,
+        responses: if responses.is_empty() {
+            String::new()
+        } else {
+            serde_json::to_string(&responses)?
+        },
+        service_entries: Vec::new(),
+        first_seen: None,
+        last_seen: None,
+        last_run: None,
+        tags: Vec::new(),
+        hostname: None,
+        rtt_ms: None,
+        os_guess: None,
+    })
+}
+
+/// Parse masscan's JSON array, which ends with an unquoted `{finished: 1}`
+/// entry, may have a dangling comma, and lacks the closing bracket if the
+/// scan was interrupted
+fn parse_masscan_json(text: &str) -> Result<Vec<MasscanRecord>, Box<dyn Error>> {
+    let finished = Regex::new(r#"\{\s*"?finished"?\s*:\s*\d+\s*\}"#)?;
+    let text = finished.replace_all(text, "");
+
+    let body = text.trim();
+    let body = body.strip_prefix('[').unwrap_or(body);
+    let body = body.strip_suffix(']').unwrap_or(body).trim_end();
+    let body = body.trim_end_matches(|c: char| c == ',' || c.is_whitespace());
+
+    if body.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(serde_json::from_str(&format!("[{}]", body))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::testing;
+
+    // One host with an open, a closed and a UDP port, one IPv6 host, and
+    // one known only by MAC address
+    const NMAP_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE nmaprun>
+<nmaprun scanner="nmap" args="nmap -sS -sU -sV -oX scan.xml 192.0.2.0/24" version="7.94">
+  <host>
+    <status state="up" reason="arp-response"/>
+    <address addr="192.0.2.10" addrtype="ipv4"/>
+    <address addr="00:11:22:33:44:55" addrtype="mac"/>
+    <ports>
+      <port protocol="tcp" portid="22">
+        <state state="open" reason="syn-ack"/>
+        <service name="ssh" product="OpenSSH" version="9.6p1" extrainfo="Ubuntu"/>
+      </port>
+      <port protocol="tcp" portid="23">
+        <state state="closed" reason="reset"/>
+        <service name="telnet"/>
+      </port>
+      <port protocol="udp" portid="53">
+        <state state="open" reason="udp-response"/>
+        <service name="domain"/>
+      </port>
+      <port protocol="tcp" portid="80">
+        <state state="open" reason="syn-ack"/>
+      </port>
+    </ports>
+  </host>
+  <host>
+    <address addr="2001:db8::5" addrtype="ipv6"/>
+    <ports>
+      <port protocol="tcp" portid="443">
+        <state state="open" reason="syn-ack"/>
+        <service name="https"/>
+      </port>
+    </ports>
+  </host>
+  <host>
+    <address addr="66:77:88:99:aa:bb" addrtype="mac"/>
+    <ports>
+      <port protocol="tcp" portid="8080"><state state="open"/></port>
+    </ports>
+  </host>
+</nmaprun>
+"#;
+
+    #[test]
+    fn imports_nmap_xml() {
+        let (dir, database) = testing::temp_database();
+        let path = dir.path().join("scan.xml");
+        fs::write(&path, NMAP_XML).unwrap();
+
+        let stats = database.import_nmap_xml(&path).unwrap();
+        assert_eq!(stats.hosts, 2);
+        assert_eq!(stats.ports, 3);
+        assert_eq!(stats.skipped_hosts, 1);
+        assert_eq!(stats.skipped_ports, 1);
+
+        let row = database.get_row_by_host("192.0.2.10").unwrap().unwrap();
+        // Closed and UDP ports left out
+        assert_eq!(row.ports, vec![22, 80]);
+        assert_eq!(row.services, vec!["ssh"]);
+        assert_eq!(
+            row.response_map()["22/tcp"],
+            ("ssh".to_string(), "OpenSSH 9.6p1 Ubuntu".to_string())
+        );
+
+        let row = database.get_row_by_host("2001:db8::5").unwrap().unwrap();
+        assert_eq!(row.ports, vec![443]);
+        assert_eq!(row.services, vec!["https"]);
+        assert_eq!(row.response_map()["443/tcp"].1, "");
+
+        assert!(database.get_rows_by_port(8080).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_xml() {
+        let (dir, database) = testing::temp_database();
+        let path = dir.path().join("scan.xml");
+        fs::write(&path, "<nmaprun><host>").unwrap();
+
+        assert!(database.import_nmap_xml(&path).is_err());
+    }
+}
@@ -0,0 +1,1592 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use rocksdb::{
+    BottommostLevelCompaction, ColumnFamily, CompactOptions, DB, Direction, IteratorMode,
+    ReadOptions, WriteBatch,
+};
+use serde::Serialize;
+
+use crate::{
+    database::{
+        BANNER_COLUMN, DatabaseError, DatabaseResult, GC_AFTER_DELETES, GC_STEP_ENTRIES,
+        LEGACY_KEYS_END, LEGACY_KEYS_START, PORT_INDEX_COLUMN, PORT_INDEX_PREFIX_LEN, ROW_FORMAT,
+        ResultDatabase, ServiceEntry, TAG_INDEX_COLUMN, canonical_host, column_handle,
+        host_from_key, host_key, row_from_columns, seek_prefix, split_nums, split_tags,
+    },
+    os_info::OsInfo,
+    progress::{Operation, Progress, ProgressHook},
+    util::{humanize_duration, humanize_size},
+};
+
+/// Counts from a garbage collection pass
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GcStats {
+    pub scanned: u64,
+    pub removed: u64,
+}
+
+/// Where an automatic garbage collection pass stopped: the `gc_stages`
+/// column family it was in, and the last key it scanned there
+#[derive(Debug)]
+pub(crate) struct GcCursor {
+    stage: usize,
+    after: Option<Vec<u8>>,
+}
+
+/// Index entries out of step with the rows, found by
+/// `ResultDatabase::verify_indexes`. `collect_garbage` removes the orphaned
+/// and dangling ones, a reindex adds the missing ones.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct IndexReport {
+    /// Index entries, banners and secondary column entries looked at
+    pub scanned: u64,
+    /// Entries in secondary column families for hosts with no row
+    pub orphaned_columns: u64,
+    /// Port index entries for a host that is gone or no longer has the port
+    pub dangling_ports: u64,
+    /// Ports of a host with no port index entry
+    pub missing_ports: u64,
+    /// Tag index entries for a host that is gone or no longer has the tag
+    pub dangling_tags: u64,
+    /// Tags of a host with no tag index entry
+    pub missing_tags: u64,
+    /// Raw banners for a host that is gone or no longer has the port
+    pub dangling_banners: u64,
+}
+
+impl IndexReport {
+    /// Whether nothing was found out of step
+    pub fn is_consistent(&self) -> bool {
+        self.orphaned_columns == 0
+            && self.dangling_ports == 0
+            && self.missing_ports == 0
+            && self.dangling_tags == 0
+            && self.missing_tags == 0
+            && self.dangling_banners == 0
+    }
+}
+
+/// Keeps every per-host column family, the port and tag indexes, and the
+/// raw banners in step with `default`.
+/// All write and delete paths queue their operations through here, so a new
+/// index only has to be taught to this struct.
+pub(crate) struct IndexManager<'a> {
+    db: &'a DB,
+    cfs: Vec<&'a ColumnFamily>,
+    port_index: &'a ColumnFamily,
+    tag_index: &'a ColumnFamily,
+    pub(crate) banners: &'a ColumnFamily,
+    // Most bytes of a raw banner stored
+    banner_limit: usize,
+    // Writes queued before a batch is flushed
+    batch_size: usize,
+    // Wall clock in unix seconds, recorded as last_seen for every row written
+    now: u64,
+    // Whether the database expires rows, so every column of a row has to be
+    // written again when it's saved
+    refresh_labels: bool,
+    // Hosts `delete_row` has queued since garbage was last collected, kept
+    // by the database so it counts across operations
+    deletes_since_gc: &'a AtomicU64,
+    // Where the automatic garbage collection pass in progress got to, also
+    // kept by the database
+    gc_cursor: &'a Mutex<Option<GcCursor>>,
+    // Told how index rebuilds are getting on
+    progress: Option<ProgressHook>,
+}
+
+impl<'a> IndexManager<'a> {
+    pub(crate) fn new(database: &'a ResultDatabase, db: &'a DB) -> Result<Self, DatabaseError> {
+        Ok(Self {
+            db,
+            cfs: database.column_handles(db)?,
+            port_index: column_handle(db, PORT_INDEX_COLUMN)?,
+            tag_index: column_handle(db, TAG_INDEX_COLUMN)?,
+            banners: column_handle(db, BANNER_COLUMN)?,
+            banner_limit: database.banner_limit,
+            batch_size: database.batch_size,
+            now: database.clock.unix_seconds(),
+            refresh_labels: database.ttl.is_some(),
+            deletes_since_gc: &database.deletes_since_gc,
+            gc_cursor: &database.gc_cursor,
+            progress: database.progress.clone(),
+        })
+    }
+
+    /// Queue the writes for one row across all column families. Tags and
+    /// hostnames are left alone, they aren't found by scans. The default
+    /// column gets the encoded row, with the fields this row doesn't set
+    /// filled in from their columns, so it can be read back in one get.
+    pub(crate) fn put_row(&self, batch: &mut WriteBatch, row: &DatabaseResult) {
+        self.put_row_seen(batch, row, None, false)
+    }
+
+    /// `put_row` for a row copied from another database. Its tags and
+    /// hostname are written too, and its own first_seen and last_seen are
+    /// kept when it has both, since it wasn't seen now.
+    pub(crate) fn put_copied_row(&self, batch: &mut WriteBatch, row: &DatabaseResult) {
+        let key = &host_key(&row.id);
+
+        self.put_tag_entries(batch, key, &row.tags);
+        if let Some(hostname) = &row.hostname {
+            batch.put_cf(self.cfs[7], key, hostname.as_bytes());
+        }
+        self.put_row_seen(batch, row, row.first_seen.zip(row.last_seen), true);
+    }
+
+    fn put_row_seen(
+        &self,
+        batch: &mut WriteBatch,
+        row: &DatabaseResult,
+        seen: Option<(u64, u64)>,
+        with_labels: bool,
+    ) {
+        let key = &host_key(&row.id);
+        let previous = self.stored_row(key);
+
+        // Keep first_seen from an earlier write of this host. If the wall clock
+        // was stepped back since then, don't let last_seen move backwards
+        let (first_seen, last_seen) = match seen.or_else(|| {
+            let previous = previous.as_ref()?;
+            previous
+                .first_seen
+                .zip(previous.last_seen)
+                .map(|(first_seen, last_seen)| {
+                    (first_seen, self.now.max(last_seen).max(first_seen))
+                })
+        }) {
+            Some(seen) => seen,
+            None => (self.now, self.now),
+        };
+        let previous = previous.unwrap_or_default();
+
+        // Drop index entries and banners for ports the host no longer has
+        for &port in &previous.ports {
+            if !row.ports.contains(&port) {
+                delete_port_entry(batch, self.port_index, port, key);
+                if let Some(banner_key) = banner_key(key, port) {
+                    batch.delete_cf(self.banners, banner_key);
+                }
+            }
+        }
+        for port in &row.ports {
+            if let Some(index_key) = port_index_key(*port, key) {
+                batch.put_cf(self.port_index, index_key, vec![]);
+            }
+        }
+
+        let ports = encode_ports(&row.ports);
+        let mut stored = DatabaseResult {
+            id: canonical_host(&row.id),
+            ports: decode_ports(&ports),
+            services: row
+                .services
+                .iter()
+                .filter(|service| !service.is_empty())
+                .cloned()
+                .collect(),
+            responses: String::new(),
+            service_entries: row.service_entries.clone(),
+            first_seen: Some(first_seen),
+            last_seen: Some(last_seen),
+            last_run: row.last_run.clone().or(previous.last_run),
+            tags: if with_labels {
+                row.tags.clone()
+            } else {
+                previous.tags
+            },
+            hostname: if with_labels && row.hostname.is_some() {
+                row.hostname.clone()
+            } else {
+                previous.hostname
+            },
+            rtt_ms: row.rtt_ms.or(previous.rtt_ms),
+            // Only `put_os_info` writes the os column, so the guess always
+            // comes from what's stored
+            os_guess: previous.os_guess,
+        };
+        // Keyed by port and protocol, whatever form the row came in, with
+        // an entry for each service
+        stored.set_response_map(&row.response_map());
+
+        batch.put_cf(self.cfs[0], key, stored.encode());
+        batch.put_cf(self.cfs[1], key, ports);
+        batch.put_cf(self.cfs[2], key, encode_services(&stored.service_entries));
+        batch.put_cf(self.cfs[3], key, stored.responses.as_bytes());
+        batch.put_cf(self.cfs[4], key, encode_timestamps(first_seen, last_seen));
+        // Rows saved outside a scan run keep the run that last saved them
+        if let Some(run) = &row.last_run {
+            batch.put_cf(self.cfs[5], key, run.as_bytes());
+        }
+        // Likewise the last round trip time, port and service scans don't measure one
+        if let Some(rtt_ms) = row.rtt_ms {
+            batch.put_cf(self.cfs[8], key, rtt_ms.to_be_bytes());
+        }
+
+        // With a TTL each value expires a while after it was last written,
+        // so the labels this save leaves alone are written again with what
+        // is stored to keep them as long as the row
+        if self.refresh_labels {
+            self.put_tag_entries(batch, key, &stored.tags);
+            if let Some(run) = &stored.last_run {
+                batch.put_cf(self.cfs[5], key, run.as_bytes());
+            }
+            if let Some(hostname) = &stored.hostname {
+                batch.put_cf(self.cfs[7], key, hostname.as_bytes());
+            }
+            if let Some(rtt_ms) = stored.rtt_ms {
+                batch.put_cf(self.cfs[8], key, rtt_ms.to_be_bytes());
+            }
+            if let Ok(Some(data)) = self.db.get_pinned_cf(self.cfs[9], key) {
+                batch.put_cf(self.cfs[9], key, data);
+            }
+        }
+    }
+
+    /// Queue the deletes for one host across all column families. Counts
+    /// towards the next `collect_garbage_if_due`.
+    pub(crate) fn delete_row(&self, batch: &mut WriteBatch, host: &str) {
+        let key = &host_key(host);
+        self.deletes_since_gc.fetch_add(1, Ordering::Relaxed);
+
+        for port in self.stored_ports(key) {
+            delete_port_entry(batch, self.port_index, port, key);
+        }
+        for tag in self.stored_tags(key) {
+            batch.delete_cf(self.tag_index, tag_index_key(&tag, key));
+        }
+        for (port, _) in self.stored_banners(key) {
+            if let Some(banner_key) = banner_key(key, port as i32) {
+                batch.delete_cf(self.banners, banner_key);
+            }
+        }
+        for cf in &self.cfs {
+            batch.delete_cf(*cf, key);
+        }
+    }
+
+    /// Queue storing the raw banner read from a host's port, cut to the
+    /// banner limit
+    pub(crate) fn put_banner(&self, batch: &mut WriteBatch, host: &str, port: i32, banner: &[u8]) {
+        if let Some(key) = banner_key(&host_key(host), port) {
+            batch.put_cf(
+                self.banners,
+                key,
+                &banner[..banner.len().min(self.banner_limit)],
+            );
+        }
+    }
+
+    /// Queue replacing a host's tags with `tags`, updating the tag index
+    pub(crate) fn put_tags(&self, batch: &mut WriteBatch, host: &str, tags: &[String]) {
+        let key = &host_key(host);
+
+        self.put_tag_entries(batch, key, tags);
+        self.update_stored_row(batch, key, |row| row.tags = tags.to_vec());
+    }
+
+    // Queue the tags column and tag index writes for `put_tags`, leaving
+    // the row in the default column alone
+    fn put_tag_entries(&self, batch: &mut WriteBatch, key: &[u8], tags: &[String]) {
+        for tag in self.stored_tags(key) {
+            if !tags.contains(&tag) {
+                batch.delete_cf(self.tag_index, tag_index_key(&tag, key));
+            }
+        }
+        for tag in tags {
+            batch.put_cf(self.tag_index, tag_index_key(tag, key), vec![]);
+        }
+
+        if tags.is_empty() {
+            batch.delete_cf(self.cfs[6], key);
+        } else {
+            batch.put_cf(self.cfs[6], key, tags.join(",").as_bytes());
+        }
+    }
+
+    /// Queue setting a host's hostname
+    pub(crate) fn put_hostname(&self, batch: &mut WriteBatch, host: &str, name: &str) {
+        let key = &host_key(host);
+
+        batch.put_cf(self.cfs[7], key, name.as_bytes());
+        self.update_stored_row(batch, key, |row| row.hostname = Some(name.to_string()));
+    }
+
+    /// Queue storing what's been guessed about a host's operating system
+    pub(crate) fn put_os_info(&self, batch: &mut WriteBatch, host: &str, info: &OsInfo) {
+        let key = &host_key(host);
+
+        batch.put_cf(self.cfs[9], key, info.encode());
+        self.update_stored_row(batch, key, |row| row.os_guess = Some(info.guess.clone()));
+    }
+
+    // Rewrite the encoded row in the default column, if the host has one,
+    // so it agrees with a column written outside `put_row`
+    fn update_stored_row(
+        &self,
+        batch: &mut WriteBatch,
+        key: &[u8],
+        update: impl FnOnce(&mut DatabaseResult),
+    ) {
+        if let Ok(Some(data)) = self.db.get_pinned_cf(self.cfs[0], key)
+            && let Ok(Some(mut row)) = DatabaseResult::decode(&data)
+        {
+            update(&mut row);
+            batch.put_cf(self.cfs[0], key, row.encode());
+        }
+    }
+
+    /// Hosts tagged with `tag`, according to the tag index
+    pub(crate) fn hosts_with_tag(&self, tag: &str) -> Result<Vec<String>, rocksdb::Error> {
+        let prefix = tag_index_key(tag, &[]);
+
+        let mut hosts = Vec::new();
+        for item in seek_prefix(self.db, self.tag_index, &prefix, 0) {
+            let (key, _) = item?;
+            if let Some(host) = host_from_key(&key[prefix.len()..]) {
+                hosts.push(host);
+            }
+        }
+
+        Ok(hosts)
+    }
+
+    /// Hosts with `port` open, according to the port index
+    pub(crate) fn hosts_with_port(&self, port: i32) -> Result<Vec<String>, rocksdb::Error> {
+        let Ok(port) = u16::try_from(port) else {
+            return Ok(Vec::new());
+        };
+        let prefix = port.to_be_bytes();
+
+        let mut hosts = Vec::new();
+        for item in seek_prefix(self.db, self.port_index, &prefix, PORT_INDEX_PREFIX_LEN) {
+            let (key, _) = item?;
+            if let Some(host) = host_from_key(&key[2..]) {
+                hosts.push(host);
+            }
+        }
+
+        Ok(hosts)
+    }
+
+    /// Hosts with any port from `low` to `high` inclusive, according to the
+    /// port index, each listed once in key order
+    pub(crate) fn hosts_with_port_range(
+        &self,
+        low: u16,
+        high: u16,
+    ) -> Result<Vec<String>, rocksdb::Error> {
+        let mut keys = BTreeSet::new();
+        for item in self.db.iterator_cf_opt(
+            self.port_index,
+            total_order(),
+            IteratorMode::From(&low.to_be_bytes(), Direction::Forward),
+        ) {
+            let (key, _) = item?;
+            let Some(port) = key.get(..2) else {
+                continue;
+            };
+            if u16::from_be_bytes([port[0], port[1]]) > high {
+                break;
+            }
+            keys.insert(key[2..].to_vec());
+        }
+
+        Ok(keys.iter().filter_map(|key| host_from_key(key)).collect())
+    }
+
+    /// Replace the port index with one built from the `ports` column family.
+    /// Returns the number of entries written.
+    pub(crate) fn rebuild_port_index(&self) -> Result<u64, rocksdb::Error> {
+        let mut batch = self.clear(self.port_index)?;
+
+        let progress = Progress::new(
+            self.progress.as_ref(),
+            Operation::Reindex,
+            self.db,
+            self.cfs[1],
+        );
+        let mut entries = 0;
+        for item in self.db.iterator_cf(self.cfs[1], IteratorMode::Start) {
+            let (host, ports) = item?;
+            let ports = decode_ports(&ports);
+            progress.key(!ports.is_empty());
+            for port in ports {
+                if let Some(index_key) = port_index_key(port, &host) {
+                    batch.put_cf(self.port_index, index_key, vec![]);
+                    entries += 1;
+                }
+            }
+            if batch.len() >= self.batch_size {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+        }
+        progress.finish();
+
+        Ok(entries)
+    }
+
+    /// Replace the tag index with one built from the `tags` column family.
+    /// Returns the number of entries written.
+    pub(crate) fn rebuild_tag_index(&self) -> Result<u64, rocksdb::Error> {
+        let mut batch = self.clear(self.tag_index)?;
+
+        let progress = Progress::new(
+            self.progress.as_ref(),
+            Operation::Reindex,
+            self.db,
+            self.cfs[6],
+        );
+        let mut entries = 0;
+        for item in self.db.iterator_cf(self.cfs[6], IteratorMode::Start) {
+            let (host, tags) = item?;
+            let tags = split_tags(&String::from_utf8_lossy(&tags));
+            progress.key(!tags.is_empty());
+            for tag in tags {
+                batch.put_cf(self.tag_index, tag_index_key(&tag, &host), vec![]);
+                entries += 1;
+            }
+            if batch.len() >= self.batch_size {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+        }
+        progress.finish();
+
+        Ok(entries)
+    }
+
+    // Queue deleting every entry of `cf`, writing full batches as it goes.
+    // Returns the batch holding the rest.
+    fn clear(&self, cf: &ColumnFamily) -> Result<WriteBatch, rocksdb::Error> {
+        let mut batch = WriteBatch::default();
+        for item in self
+            .db
+            .iterator_cf_opt(cf, total_order(), IteratorMode::Start)
+        {
+            let (key, _) = item?;
+            batch.delete_cf(cf, &key);
+            if batch.len() >= self.batch_size {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Move every host stored under a text key, as older versions wrote
+    /// them, to its binary key in each per-host column family. A host that
+    /// already has a row under its binary key keeps that row. Returns the
+    /// number of hosts moved.
+    pub(crate) fn migrate_keys(&self) -> Result<u64, rocksdb::Error> {
+        let mut moved = 0;
+
+        for (column, cf) in self.cfs.iter().enumerate() {
+            let mut read_opts = total_order();
+            read_opts.set_iterate_upper_bound(LEGACY_KEYS_END);
+
+            let mut batch = WriteBatch::default();
+            for item in self.db.iterator_cf_opt(
+                *cf,
+                read_opts,
+                IteratorMode::From(&LEGACY_KEYS_START, Direction::Forward),
+            ) {
+                let (key, value) = item?;
+                let Ok(host) = std::str::from_utf8(&key) else {
+                    continue;
+                };
+
+                let new_key = host_key(host);
+                if self.db.get_pinned_cf(*cf, &new_key)?.is_none() {
+                    batch.put_cf(*cf, &new_key, &value);
+                }
+                batch.delete_cf(*cf, &key);
+                if column == 0 {
+                    moved += 1;
+                }
+
+                if batch.len() >= self.batch_size {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+
+            if !batch.is_empty() {
+                self.db.write(batch)?;
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Rewrite rows encoded by older versions in the current format, with
+    /// their responses keyed by port and protocol in the responses column
+    /// too. Rows with no encoding, or one this build can't read, are left
+    /// for the other columns to be read from. Returns the number of rows
+    /// rewritten.
+    pub(crate) fn upgrade_rows(&self) -> Result<u64, rocksdb::Error> {
+        let mut upgraded = 0;
+        let mut batch = WriteBatch::default();
+
+        for item in self
+            .db
+            .iterator_cf_opt(self.cfs[0], total_order(), IteratorMode::Start)
+        {
+            let (key, value) = item?;
+            if DatabaseResult::format_version(&value) == Some(ROW_FORMAT) {
+                continue;
+            }
+            if let Ok(Some(row)) = DatabaseResult::decode(&value) {
+                batch.put_cf(self.cfs[0], &key, row.encode());
+                batch.put_cf(self.cfs[3], &key, row.responses.as_bytes());
+                upgraded += 1;
+
+                if batch.len() >= self.batch_size {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+        }
+
+        Ok(upgraded)
+    }
+
+    /// Remove entries in secondary column families whose host is no longer
+    /// present in `default`, and port and tag index entries and banners that
+    /// no longer match the host, writing deletions in batches
+    pub(crate) fn collect_garbage(&self) -> Result<GcStats, rocksdb::Error> {
+        let mut stats = GcStats::default();
+        self.deletes_since_gc.store(0, Ordering::Relaxed);
+        // A full pass covers whatever an automatic one had left to do
+        *self.gc_cursor.lock().unwrap() = None;
+
+        for stage in 0..self.gc_stages().len() {
+            self.collect_stage(stage, None, u64::MAX, &mut stats)?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Continue the automatic garbage collection pass, scanning at most
+    /// `budget` entries. A pass starts once `GC_AFTER_DELETES` hosts have
+    /// been deleted since the last one, and carries on over the deletes
+    /// after that until it has been through every column family, so no
+    /// single delete pays for a full scan. None if no pass is due.
+    /// Call once the deletes are written.
+    pub(crate) fn collect_garbage_step(
+        &self,
+        budget: u64,
+    ) -> Result<Option<GcStats>, rocksdb::Error> {
+        let mut cursor = self.gc_cursor.lock().unwrap();
+        let (mut stage, mut after) = match cursor.take() {
+            Some(GcCursor { stage, after }) => (stage, after),
+            None if self.deletes_since_gc.load(Ordering::Relaxed) < GC_AFTER_DELETES => {
+                return Ok(None);
+            }
+            None => {
+                // Deletes from here on count towards the next pass
+                self.deletes_since_gc.store(0, Ordering::Relaxed);
+                (0, None)
+            }
+        };
+
+        let mut stats = GcStats::default();
+        while stage < self.gc_stages().len() {
+            if stats.scanned >= budget {
+                *cursor = Some(GcCursor { stage, after });
+                return Ok(Some(stats));
+            }
+            after =
+                self.collect_stage(stage, after.as_deref(), budget - stats.scanned, &mut stats)?;
+            if after.is_none() {
+                stage += 1;
+            }
+        }
+
+        Ok(Some(stats))
+    }
+
+    /// `collect_garbage_step` with the step size automatic collection uses
+    pub(crate) fn collect_garbage_if_due(&self) -> Result<Option<GcStats>, rocksdb::Error> {
+        self.collect_garbage_step(GC_STEP_ENTRIES)
+    }
+
+    // Column families garbage is collected from, in the order a pass visits
+    // them: the per-host ones after `default`, then the port index, the tag
+    // index and the raw banners
+    fn gc_stages(&self) -> Vec<&'a ColumnFamily> {
+        let mut stages = self.cfs[1..].to_vec();
+        stages.extend([self.port_index, self.tag_index, self.banners]);
+        stages
+    }
+
+    // Whether an entry of a `gc_stages` column family still belongs to a row
+    fn gc_entry_live(&self, stage: usize, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        match stage.checked_sub(self.cfs.len() - 1) {
+            None => Ok(self.db.get_pinned_cf(self.cfs[0], key)?.is_some()),
+            Some(0) => self.port_entry_live(key),
+            Some(1) => self.tag_entry_live(key),
+            Some(_) => self.banner_live(key),
+        }
+    }
+
+    // Delete the dead entries among at most `limit` entries of one
+    // `gc_stages` column family, starting after `after`. Returns the last
+    // key scanned if it stopped at the limit, None once it reached the end.
+    fn collect_stage(
+        &self,
+        stage: usize,
+        after: Option<&[u8]>,
+        limit: u64,
+        stats: &mut GcStats,
+    ) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        let cf = self.gc_stages()[stage];
+        let mode = match after {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let mut batch = WriteBatch::default();
+        let mut scanned = 0;
+        let mut last = None;
+        for item in self.db.iterator_cf_opt(cf, total_order(), mode) {
+            let (key, _) = item?;
+            if after == Some(&key[..]) {
+                continue;
+            }
+            if scanned >= limit {
+                break;
+            }
+            scanned += 1;
+
+            if !self.gc_entry_live(stage, &key)? {
+                batch.delete_cf(cf, &key);
+                stats.removed += 1;
+
+                if batch.len() >= self.batch_size {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+            last = Some(key);
+        }
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+        }
+        stats.scanned += scanned;
+
+        // Only a stage cut short has somewhere to resume from
+        Ok(if scanned >= limit {
+            last.map(|key| key.to_vec())
+        } else {
+            None
+        })
+    }
+
+    /// Count what `collect_garbage` would remove, and ports and tags of
+    /// hosts missing from their index, without writing anything
+    pub(crate) fn verify_indexes(&self) -> Result<IndexReport, rocksdb::Error> {
+        let mut report = IndexReport::default();
+
+        for cf in &self.cfs[1..] {
+            for item in self.db.iterator_cf(*cf, IteratorMode::Start) {
+                let (key, _) = item?;
+                report.scanned += 1;
+                if self.db.get_pinned_cf(self.cfs[0], &key)?.is_none() {
+                    report.orphaned_columns += 1;
+                }
+            }
+        }
+
+        for item in self
+            .db
+            .iterator_cf_opt(self.port_index, total_order(), IteratorMode::Start)
+        {
+            let (key, _) = item?;
+            report.scanned += 1;
+            if !self.port_entry_live(&key)? {
+                report.dangling_ports += 1;
+            }
+        }
+        for item in self.db.iterator_cf(self.tag_index, IteratorMode::Start) {
+            let (key, _) = item?;
+            report.scanned += 1;
+            if !self.tag_entry_live(&key)? {
+                report.dangling_tags += 1;
+            }
+        }
+        for item in self.db.iterator_cf(self.banners, IteratorMode::Start) {
+            let (key, _) = item?;
+            report.scanned += 1;
+            if !self.banner_live(&key)? {
+                report.dangling_banners += 1;
+            }
+        }
+
+        // Orphaned entries were counted above, only hosts with a row are
+        // expected in the indexes
+        for item in self.db.iterator_cf(self.cfs[1], IteratorMode::Start) {
+            let (host, ports) = item?;
+            if self.db.get_pinned_cf(self.cfs[0], &host)?.is_none() {
+                continue;
+            }
+            for port in decode_ports(&ports) {
+                if let Some(index_key) = port_index_key(port, &host)
+                    && self.db.get_pinned_cf(self.port_index, index_key)?.is_none()
+                {
+                    report.missing_ports += 1;
+                }
+            }
+        }
+        for item in self.db.iterator_cf(self.cfs[6], IteratorMode::Start) {
+            let (host, tags) = item?;
+            if self.db.get_pinned_cf(self.cfs[0], &host)?.is_none() {
+                continue;
+            }
+            for tag in split_tags(&String::from_utf8_lossy(&tags)) {
+                let index_key = tag_index_key(&tag, &host);
+                if self.db.get_pinned_cf(self.tag_index, index_key)?.is_none() {
+                    report.missing_tags += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    // Whether a port index entry's host has a row with that port
+    fn port_entry_live(&self, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        let port = key
+            .get(..2)
+            .map(|p| u16::from_be_bytes([p[0], p[1]]) as i32);
+        let host = key.get(2..).unwrap_or_default();
+        Ok(self.db.get_pinned_cf(self.cfs[0], host)?.is_some()
+            && port.is_some_and(|port| self.stored_ports(host).contains(&port)))
+    }
+
+    // Whether a tag index entry's host has a row with that tag
+    fn tag_entry_live(&self, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        let Some(split) = key.iter().position(|b| *b == 0) else {
+            return Ok(false);
+        };
+        let host = &key[split + 1..];
+        let tag = String::from_utf8_lossy(&key[..split]);
+        Ok(self.db.get_pinned_cf(self.cfs[0], host)?.is_some()
+            && self.stored_tags(host).iter().any(|t| *t == tag))
+    }
+
+    // Whether a banner's host has a row with the port it was read from
+    fn banner_live(&self, key: &[u8]) -> Result<bool, rocksdb::Error> {
+        let (host, port) = key.split_at(key.len().saturating_sub(2));
+        Ok(port.len() == 2
+            && self.db.get_pinned_cf(self.cfs[0], host)?.is_some()
+            && self
+                .stored_ports(host)
+                .contains(&(u16::from_be_bytes([port[0], port[1]]) as i32)))
+    }
+
+    /// A host's raw banners by port, in port order
+    pub(crate) fn stored_banners(&self, key: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        decode_banners(key, seek_prefix(self.db, self.banners, key, 0))
+    }
+
+    // Everything stored for a host key, from its encoded row when it's in
+    // the current format, so a save reads it in one get. Rows written by an
+    // older version may be missing fields that were added since, so those
+    // are put together from the other columns as a read does.
+    fn stored_row(&self, key: &[u8]) -> Option<DatabaseResult> {
+        let data = self.db.get_pinned_cf(self.cfs[0], key).ok()??;
+        if DatabaseResult::format_version(&data) == Some(ROW_FORMAT)
+            && let Ok(Some(row)) = DatabaseResult::decode(&data)
+        {
+            return Some(row);
+        }
+        Some(row_from_columns(&host_from_key(key)?, |cf| {
+            self.db.get_cf(self.cfs[cf], key).ok().flatten()
+        }))
+    }
+
+    fn stored_ports(&self, key: &[u8]) -> Vec<i32> {
+        match self.db.get_pinned_cf(self.cfs[1], key) {
+            Ok(Some(data)) => decode_ports(&data),
+            _ => Vec::new(),
+        }
+    }
+
+    pub(crate) fn stored_tags(&self, key: &[u8]) -> Vec<String> {
+        match self.db.get_pinned_cf(self.cfs[6], key) {
+            Ok(Some(data)) => split_tags(&String::from_utf8_lossy(&data)),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Read options for walking a column family across the prefixes of its
+// prefix extractor, which would otherwise confine a seek to the prefix the
+// iterator started on
+fn total_order() -> ReadOptions {
+    let mut read_opts = ReadOptions::default();
+    read_opts.set_total_order_seek(true);
+    read_opts
+}
+
+/// Port index key: the port as a big-endian u16 followed by the host, so all
+/// hosts for a port share a prefix. None for ports outside the u16 range.
+fn port_index_key(port: i32, host: &[u8]) -> Option<Vec<u8>> {
+    let port = u16::try_from(port).ok()?;
+    let mut key = Vec::with_capacity(2 + host.len());
+    key.extend_from_slice(&port.to_be_bytes());
+    key.extend_from_slice(host);
+    Some(key)
+}
+
+/// Banner key: the host key followed by the port as a big-endian u16, so a
+/// host's banners are together in port order. None for ports outside the
+/// u16 range.
+pub(crate) fn banner_key(host: &[u8], port: i32) -> Option<Vec<u8>> {
+    let port = u16::try_from(port).ok()?;
+    Some([host, &port.to_be_bytes()].concat())
+}
+
+/// The (port, banner) pairs of a host from an iterator over the banner
+/// column starting at its key
+pub(crate) fn decode_banners<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    key: &[u8],
+    items: impl Iterator<Item = Result<(K, V), rocksdb::Error>>,
+) -> Vec<(u16, Vec<u8>)> {
+    items
+        .filter_map(|item| item.ok())
+        // A name key is also the prefix of longer names' keys
+        .filter(|(banner_key, _)| banner_key.as_ref().len() == key.len() + 2)
+        .map(|(banner_key, banner)| {
+            let port = &banner_key.as_ref()[key.len()..];
+            (
+                u16::from_be_bytes([port[0], port[1]]),
+                banner.as_ref().to_vec(),
+            )
+        })
+        .collect()
+}
+
+/// Tag index key: the tag, a zero byte, then the host. Tags can't contain a
+/// zero byte, so one tag's hosts never share a prefix with a longer tag's.
+fn tag_index_key(tag: &str, host: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(tag.len() + 1 + host.len());
+    key.extend_from_slice(tag.as_bytes());
+    key.push(0);
+    key.extend_from_slice(host);
+    key
+}
+
+fn delete_port_entry(batch: &mut WriteBatch, cf: &ColumnFamily, port: i32, host: &[u8]) {
+    if let Some(index_key) = port_index_key(port, host) {
+        batch.delete_cf(cf, index_key);
+    }
+}
+
+/// Ports column value: a little-endian u32 count, then that many sorted
+/// little-endian u16 ports without duplicates. Ports outside the u16 range
+/// are dropped.
+pub(crate) fn encode_ports(ports: &[i32]) -> Vec<u8> {
+    let mut ports: Vec<u16> = ports
+        .iter()
+        .filter_map(|port| u16::try_from(*port).ok())
+        .collect();
+    ports.sort();
+    ports.dedup();
+
+    let mut data = Vec::with_capacity(4 + ports.len() * 2);
+    data.extend_from_slice(&(ports.len() as u32).to_le_bytes());
+    for port in ports {
+        data.extend_from_slice(&port.to_le_bytes());
+    }
+    data
+}
+
+/// Ports from a ports column value. Values saved before the binary format
+/// are comma separated text, which is read as such and rewritten the next
+/// time the host is saved. Text never has a zero byte, so its first four
+/// bytes can't be a count that matches the value's length.
+pub(crate) fn decode_ports(data: &[u8]) -> Vec<i32> {
+    if let Some(count) = data.get(..4)
+        && let Ok(count) = <[u8; 4]>::try_from(count)
+        && data.len() as u64 == 4 + u32::from_le_bytes(count) as u64 * 2
+    {
+        return data[4..]
+            .chunks_exact(2)
+            .map(|port| u16::from_le_bytes([port[0], port[1]]) as i32)
+            .collect();
+    }
+
+    split_nums(&String::from_utf8_lossy(data), ",")
+}
+
+/// Services column value: the row's service entries as a JSON array, or
+/// nothing if it has none
+pub(crate) fn encode_services(entries: &[ServiceEntry]) -> Vec<u8> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+    // Only strings and numbers, none of which can fail to serialize
+    serde_json::to_vec(entries).expect("service entries always serialize to JSON")
+}
+
+/// Service entries from a services column value. None for values saved
+/// before the entries were stored, which are the service names separated by
+/// commas, and have to be read as such.
+pub(crate) fn decode_services(data: &[u8]) -> Option<Vec<ServiceEntry>> {
+    if !data.starts_with(b"[") {
+        return None;
+    }
+    serde_json::from_slice(data).ok()
+}
+
+/// first_seen and last_seen as two big-endian u64s
+pub(crate) fn encode_timestamps(first_seen: u64, last_seen: u64) -> [u8; 16] {
+    let mut data = [0u8; 16];
+    data[..8].copy_from_slice(&first_seen.to_be_bytes());
+    data[8..].copy_from_slice(&last_seen.to_be_bytes());
+    data
+}
+
+pub(crate) fn decode_timestamps(data: &[u8]) -> Option<(u64, u64)> {
+    let first_seen = u64::from_be_bytes(data.get(..8)?.try_into().ok()?);
+    let last_seen = u64::from_be_bytes(data.get(8..16)?.try_into().ok()?);
+    Some((first_seen, last_seen))
+}
+
+impl ResultDatabase {
+    /// Remove orphaned index entries left behind by removed hosts
+    pub fn collect_garbage(&self) -> Result<GcStats, DatabaseError> {
+        let db = self.writable_db()?;
+        let stats = IndexManager::new(self, &db)?.collect_garbage()?;
+
+        info!(
+            "Garbage collection removed {} of {} index entries",
+            stats.removed, stats.scanned
+        );
+
+        Ok(stats)
+    }
+
+    /// Check that the port and tag indexes, the raw banners and the
+    /// secondary column families agree with the rows, without changing
+    /// anything. Works on a database opened read-only.
+    pub fn verify_indexes(&self) -> Result<IndexReport, DatabaseError> {
+        let db = self.db()?;
+        let report = IndexManager::new(self, &db)?.verify_indexes()?;
+
+        if report.is_consistent() {
+            info!("Checked {} index entries, all consistent", report.scanned);
+        } else {
+            warn!("Checked {} index entries: {:?}", report.scanned, report);
+        }
+
+        Ok(report)
+    }
+
+    /// Regenerate the port index from the stored ports, for databases
+    /// written before the index existed. Returns the number of entries.
+    pub fn rebuild_port_index(&self) -> Result<u64, DatabaseError> {
+        let db = self.writable_db()?;
+        let entries = IndexManager::new(self, &db)?.rebuild_port_index()?;
+
+        info!("Rebuilt port index with {} entries", entries);
+
+        Ok(entries)
+    }
+
+    /// Move hosts saved by versions that keyed them by their address as
+    /// text to the binary keys that sort them numerically, then rebuild the
+    /// port and tag indexes, whose keys hold the host key too, and rewrite
+    /// rows stored in an older format. Returns the number of hosts moved.
+    /// Hosts under text keys aren't found by lookups until this has run.
+    pub fn migrate_keys(&self) -> Result<u64, DatabaseError> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+
+        let moved = indexes.migrate_keys()?;
+        let port_entries = indexes.rebuild_port_index()?;
+        let tag_entries = indexes.rebuild_tag_index()?;
+        let upgraded = indexes.upgrade_rows()?;
+
+        info!(
+            "Migrated {} hosts to binary keys, rebuilt {} port and {} tag index entries, upgraded {} rows",
+            moved, port_entries, tag_entries, upgraded
+        );
+
+        Ok(moved)
+    }
+
+    /// Flush and fully compact every column family, so the space held by
+    /// deleted and overwritten rows is given back, e.g. after a `purge`.
+    /// Returns the size of the database on disk before and after.
+    pub fn compact(&self) -> Result<(u64, u64), DatabaseError> {
+        let db = self.writable_db()?;
+        let before = self.disk_usage()?;
+        let start = self.clock.monotonic();
+
+        // Rewrite the bottom level too, it's where deleted rows end up
+        let mut compact_opts = CompactOptions::default();
+        compact_opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+        for column in &self.columns {
+            let cf = column_handle(&db, column)?;
+            db.flush_cf(cf)?;
+            db.compact_range_cf_opt(cf, None::<&[u8]>, None::<&[u8]>, &compact_opts);
+        }
+
+        let after = self.disk_usage()?;
+        info!(
+            "Compacted {} from {} to {} in {}",
+            self.path,
+            humanize_size(before),
+            humanize_size(after),
+            humanize_duration(self.clock.monotonic().saturating_duration_since(start))
+        );
+
+        Ok((before, after))
+    }
+
+    /// Bytes the database's files take on disk: table files, write-ahead
+    /// logs and metadata
+    pub fn disk_usage(&self) -> Result<u64, std::io::Error> {
+        fn dir_size(path: &Path) -> Result<u64, std::io::Error> {
+            let mut size = 0;
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                size += if metadata.is_dir() {
+                    dir_size(&entry.path())?
+                } else {
+                    metadata.len()
+                };
+            }
+            Ok(size)
+        }
+
+        dir_size(Path::new(&self.path))
+    }
+
+    /// Remove a host from every column family in one batch.
+    /// Returns false if the host wasn't in the database.
+    pub fn delete_host(&self, host: &str) -> Result<bool, DatabaseError> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        if db.get_pinned_cf(indexes.cfs[0], host_key(host))?.is_none() {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::default();
+        indexes.delete_row(&mut batch, host);
+        db.write(batch)?;
+        indexes.collect_garbage_if_due()?;
+
+        Ok(true)
+    }
+
+    /// Remove every host whose last_seen is more than `secs` seconds ago.
+    /// Hosts saved before timestamps were recorded are kept. Returns the
+    /// number of hosts removed.
+    pub fn purge_older_than(&self, secs: u64) -> Result<u64, DatabaseError> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        let cutoff = self.clock.unix_seconds().saturating_sub(secs);
+
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+
+        for item in db.iterator_cf(indexes.cfs[4], IteratorMode::Start) {
+            let (key, value) = item?;
+            if let Some((_, last_seen)) = decode_timestamps(&value)
+                && last_seen < cutoff
+                && let Some(host) = host_from_key(&key)
+            {
+                indexes.delete_row(&mut batch, &host);
+                removed += 1;
+
+                if removed % indexes.batch_size as u64 == 0 {
+                    db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        if !batch.is_empty() {
+            db.write(batch)?;
+        }
+        indexes.collect_garbage_if_due()?;
+
+        info!(
+            "Purged {} hosts not seen in {}",
+            removed,
+            humanize_duration(Duration::from_secs(secs))
+        );
+
+        Ok(removed)
+    }
+
+    /// Remove every host with no open ports and no services, such as the
+    /// hosts a ping sweep found up, or with `older_than` only those last
+    /// seen longer ago than that. Tagged hosts are always kept, and so are
+    /// hosts saved before timestamps were recorded when there's a cutoff.
+    /// Returns the number of hosts removed, or with `dry_run` the number
+    /// that would be, without removing any.
+    pub fn prune_empty_hosts(
+        &self,
+        older_than: Option<Duration>,
+        dry_run: bool,
+    ) -> Result<u64, DatabaseError> {
+        let db = if dry_run {
+            self.db()?
+        } else {
+            self.writable_db()?
+        };
+        let indexes = IndexManager::new(self, &db)?;
+        let cutoff = older_than.map(|age| self.clock.unix_seconds().saturating_sub(age.as_secs()));
+
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+
+        for item in db.iterator_cf(indexes.cfs[1], IteratorMode::Start) {
+            let (key, ports) = item?;
+            if !decode_ports(&ports).is_empty() {
+                continue;
+            }
+            let Some(host) = host_from_key(&key) else {
+                continue;
+            };
+            let old_enough = match cutoff {
+                Some(cutoff) => db
+                    .get_pinned_cf(indexes.cfs[4], &key)?
+                    .and_then(|data| decode_timestamps(&data))
+                    .is_some_and(|(_, last_seen)| last_seen < cutoff),
+                None => true,
+            };
+            if !old_enough
+                || db.get_pinned_cf(indexes.cfs[0], &key)?.is_none()
+                || db
+                    .get_pinned_cf(indexes.cfs[2], &key)?
+                    .is_some_and(|services| !services.is_empty())
+                || !indexes.stored_tags(&key).is_empty()
+            {
+                continue;
+            }
+
+            removed += 1;
+            if dry_run {
+                continue;
+            }
+            indexes.delete_row(&mut batch, &host);
+            if removed % indexes.batch_size as u64 == 0 {
+                db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        if !batch.is_empty() {
+            db.write(batch)?;
+        }
+
+        if dry_run {
+            info!("Would prune {} hosts with no ports or services", removed);
+        } else {
+            info!("Pruned {} hosts with no ports or services", removed);
+            indexes.collect_garbage_if_due()?;
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, atomic::Ordering},
+        time::{Duration, Instant, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::{
+        clock::FakeClock,
+        database::Responses,
+        port_scan::port_scan::PortScanResult,
+        testing::{self, row},
+    };
+
+    // Hosts with ports and a tag each, so deletes have index entries to clear
+    fn fill(database: &ResultDatabase) {
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22, 80], &["ssh", "http"]),
+                row("10.0.0.2", &[443], &["https"]),
+                row("10.0.0.3", &[], &[]),
+                row("2001:db8::1", &[8080], &[]),
+            ])
+            .unwrap();
+        for host in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            database.add_tag(host, "lab").unwrap();
+        }
+    }
+
+    fn assert_consistent(database: &ResultDatabase) {
+        let report = database.verify_indexes().unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+    }
+
+    #[test]
+    fn delete_host_leaves_indexes_consistent() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+
+        assert!(database.delete_host("10.0.0.1").unwrap());
+        assert!(!database.delete_host("10.0.0.1").unwrap());
+
+        assert!(database.get_row_by_host("10.0.0.1").unwrap().is_none());
+        assert!(database.get_rows_by_port(80).unwrap().is_empty());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn purge_leaves_indexes_consistent() {
+        let clock = Arc::new(FakeClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        let (_dir, database) = testing::temp_database();
+        let database = database.with_clock(clock.clone());
+        fill(&database);
+
+        clock.advance(Duration::from_secs(3600));
+        database
+            .save_rows(vec![row("10.0.0.2", &[443], &["https"])])
+            .unwrap();
+        clock.advance(Duration::from_secs(60));
+
+        assert_eq!(database.purge_older_than(1800).unwrap(), 3);
+        assert!(database.get_row_by_host("10.0.0.1").unwrap().is_none());
+        assert!(database.get_row_by_host("10.0.0.2").unwrap().is_some());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn prune_leaves_indexes_consistent() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+        database.save_rows(vec![row("10.0.0.9", &[], &[])]).unwrap();
+
+        assert_eq!(database.prune_empty_hosts(None, true).unwrap(), 1);
+        assert_eq!(database.prune_empty_hosts(None, false).unwrap(), 1);
+        assert!(database.get_row_by_host("10.0.0.9").unwrap().is_none());
+        // Tagged, so kept though it has nothing open
+        assert!(database.get_row_by_host("10.0.0.3").unwrap().is_some());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn removing_bad_rows_leaves_indexes_consistent() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+        {
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            db.put_cf(cfs[0], host_key("10.0.0.1"), b"not a row")
+                .unwrap();
+        }
+
+        let report = database.check_all().unwrap();
+        assert_eq!(report.bad_keys.len(), 1);
+        assert_eq!(report.bad_keys[0].host.as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(database.remove_bad_keys(&report.bad_keys).unwrap(), 1);
+        assert!(database.check_all().unwrap().is_healthy());
+        assert!(database.get_rows_by_port(22).unwrap().is_empty());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn automatic_collection_runs_in_bounded_steps() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+        let db = database.db().unwrap();
+        let indexes = IndexManager::new(&database, &db).unwrap();
+
+        // Garbage a delete didn't clear, in an index and a secondary column
+        let orphan = host_key("10.0.0.200");
+        db.put_cf(indexes.port_index, port_index_key(22, &orphan).unwrap(), [])
+            .unwrap();
+        db.put_cf(indexes.cfs[2], &orphan, b"ssh").unwrap();
+        assert!(!database.verify_indexes().unwrap().is_consistent());
+
+        // Not due yet
+        assert!(indexes.collect_garbage_step(2).unwrap().is_none());
+
+        database
+            .deletes_since_gc
+            .store(GC_AFTER_DELETES, Ordering::Relaxed);
+        let mut steps = 0;
+        let mut removed = 0;
+        while let Some(stats) = indexes.collect_garbage_step(2).unwrap() {
+            assert!(stats.scanned <= 2);
+            removed += stats.removed;
+            steps += 1;
+            if database.gc_cursor.lock().unwrap().is_none() {
+                break;
+            }
+        }
+
+        assert!(steps > 1);
+        assert_eq!(removed, 2);
+        assert_eq!(database.deletes_since_gc.load(Ordering::Relaxed), 0);
+        assert!(indexes.collect_garbage_step(2).unwrap().is_none());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn delete_host_removes_only_that_host() {
+        let (_dir, database) = testing::temp_database();
+        assert!(!database.delete_host("10.0.0.1").unwrap());
+        fill(&database);
+
+        assert!(!database.delete_host("10.0.0.9").unwrap());
+        assert!(database.delete_host("10.0.0.2").unwrap());
+
+        assert!(database.get_row_by_host("10.0.0.2").unwrap().is_none());
+        let kept = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(kept.ports, vec![22, 80]);
+    }
+
+    #[test]
+    fn purge_removes_rows_from_a_ttl_database() {
+        let clock = Arc::new(FakeClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        let (_dir, database) = testing::temp_database();
+        let database = database
+            .with_ttl(Duration::from_secs(86400))
+            .with_clock(clock.clone());
+        fill(&database);
+
+        clock.advance(Duration::from_secs(7200));
+        database
+            .save_rows(vec![row("10.0.0.2", &[443], &["https"])])
+            .unwrap();
+
+        assert_eq!(database.purge_older_than(3600).unwrap(), 3);
+        assert!(database.get_row_by_host("10.0.0.1").unwrap().is_none());
+        assert!(database.get_row_by_host("2001:db8::1").unwrap().is_none());
+        assert!(database.get_row_by_host("10.0.0.2").unwrap().is_some());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn ttl_saves_keep_labels_alive() {
+        let (_dir, database) = testing::temp_database();
+        let database = database.with_ttl(Duration::from_secs(1));
+        fill(&database);
+        database.set_hostname("10.0.0.1", "gateway.lan").unwrap();
+
+        // RocksDB measures the TTL in whole seconds of the system clock
+        std::thread::sleep(Duration::from_millis(2100));
+        database
+            .save_rows(vec![row("10.0.0.1", &[22, 80], &["ssh", "http"])])
+            .unwrap();
+        database.compact().unwrap();
+
+        let saved = database.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(saved.tags, vec!["lab"]);
+        assert_eq!(saved.hostname.as_deref(), Some("gateway.lan"));
+        assert!(database.get_row_by_host("10.0.0.2").unwrap().is_none());
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn merges_and_retired_ports_leave_indexes_consistent() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+
+        database
+            .upsert_rows(vec![row("10.0.0.2", &[8443], &["https-alt"])])
+            .unwrap();
+        database
+            .add_tcp_results(
+                &vec![PortScanResult {
+                    ip: "10.0.0.1".parse().unwrap(),
+                    open_ports: vec![443],
+                    closed_ports: vec![80],
+                    filtered_ports: vec![],
+                    filtered_may_be_open: false,
+                    mss: None,
+                }],
+                None,
+            )
+            .unwrap();
+        database.remove_tag("10.0.0.2", "lab").unwrap();
+        database.add_tag("10.0.0.2", "dmz").unwrap();
+
+        let ports = |host| database.get_row_by_host(host).unwrap().unwrap().ports;
+        assert_eq!(ports("10.0.0.1"), vec![22, 443]);
+        assert_eq!(ports("10.0.0.2"), vec![443, 8443]);
+        assert!(database.get_rows_by_port(80).unwrap().is_empty());
+        assert_eq!(database.get_rows_by_port(8443).unwrap().len(), 1);
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn verify_indexes_reports_broken_entries() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+        assert_consistent(&database);
+
+        let db = database.db().unwrap();
+        let indexes = IndexManager::new(&database, &db).unwrap();
+        let key = host_key("10.0.0.1");
+        db.delete_cf(indexes.port_index, port_index_key(22, &key).unwrap())
+            .unwrap();
+        db.put_cf(indexes.port_index, port_index_key(23, &key).unwrap(), [])
+            .unwrap();
+        db.delete_cf(indexes.tag_index, tag_index_key("lab", &key))
+            .unwrap();
+        db.put_cf(indexes.tag_index, tag_index_key("dmz", &key), [])
+            .unwrap();
+        db.put_cf(indexes.banners, banner_key(&key, 8080).unwrap(), b"x")
+            .unwrap();
+        db.put_cf(indexes.cfs[7], host_key("10.0.0.200"), b"gone.lan")
+            .unwrap();
+
+        let report = database.verify_indexes().unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(report.missing_ports, 1);
+        assert_eq!(report.dangling_ports, 1);
+        assert_eq!(report.missing_tags, 1);
+        assert_eq!(report.dangling_tags, 1);
+        assert_eq!(report.dangling_banners, 1);
+        assert_eq!(report.orphaned_columns, 1);
+
+        // Garbage collection removes what's dangling, a rebuild adds what's missing
+        database.collect_garbage().unwrap();
+        database.migrate_keys().unwrap();
+        assert_consistent(&database);
+    }
+
+    #[test]
+    fn compact_gives_back_the_space_of_deleted_rows() {
+        let (_dir, database) = testing::temp_database();
+
+        // Random banners, so compression can't shrink them away
+        let rows = (0..2000u32)
+            .map(|i| {
+                let mut row = row(&Ipv4Addr::from(0x0a00_0000 + i).to_string(), &[80], &[]);
+                let banner: String = (0..64)
+                    .map(|_| format!("{:016x}", rand::random::<u64>()))
+                    .collect();
+                row.set_response_map(&Responses::from([(
+                    "80/tcp".to_string(),
+                    ("http".to_string(), banner),
+                )]));
+                row
+            })
+            .collect();
+        database.save_rows(rows).unwrap();
+        let (_, full) = database.compact().unwrap();
+        assert!(full > 2000 * 1024, "{} bytes", full);
+
+        for i in 0..1900u32 {
+            database
+                .delete_host(&Ipv4Addr::from(0x0a00_0000 + i).to_string())
+                .unwrap();
+        }
+        let (before, after) = database.compact().unwrap();
+        assert!(before >= after);
+        assert!(after < full / 4, "{} bytes of {}", after, full);
+        assert_eq!(after, database.disk_usage().unwrap());
+
+        let kept = database.get_row_by_host("10.0.7.207").unwrap().unwrap();
+        assert_eq!(kept.response_map()["80/tcp"].1.len(), 1024);
+        assert_consistent(&database);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    fn binary_ports_read_faster_than_text() {
+        const ROWS: u32 = 1_000_000;
+        let (_dir, database) = testing::temp_database();
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+
+        // Each host's ports under both encodings, the text ones under the
+        // IPv4 keys and the binary ones under the same hosts as names
+        let ports = |i: u32| [22, 80, 443, 1024 + (i % 60000) as i32];
+        for chunk in (0..ROWS).collect::<Vec<_>>().chunks(10_000) {
+            let mut batch = WriteBatch::default();
+            for i in chunk {
+                let host = Ipv4Addr::from(0x0a00_0000 + i).to_string();
+                batch.put_cf(
+                    cfs[1],
+                    host_key(&host),
+                    crate::database::join_nums(&ports(*i), ","),
+                );
+                batch.put_cf(
+                    cfs[1],
+                    [&[0xff][..], host.as_bytes()].concat(),
+                    encode_ports(&ports(*i)),
+                );
+            }
+            db.write(batch).unwrap();
+        }
+        db.compact_range_cf(cfs[1], None::<&[u8]>, None::<&[u8]>);
+
+        // Read and decode every value starting with `first`, the key byte
+        // of one encoding
+        let read = |first: u8| {
+            let start = Instant::now();
+            let mut read_opts = ReadOptions::default();
+            read_opts.set_total_order_seek(true);
+            if let Some(end) = first.checked_add(1) {
+                read_opts.set_iterate_upper_bound([end]);
+            }
+            let mut rows = 0;
+            let mut open = 0;
+            for item in db.iterator_cf_opt(
+                cfs[1],
+                read_opts,
+                IteratorMode::From(&[first], Direction::Forward),
+            ) {
+                let (_, value) = item.unwrap();
+                rows += 1;
+                open += decode_ports(&value).len();
+            }
+            assert_eq!(rows, ROWS);
+            assert_eq!(open, ROWS as usize * 4);
+            start.elapsed()
+        };
+
+        // Best of three, so neither gains from the other warming the cache
+        let best = |first: u8| (0..3).map(|_| read(first)).min().unwrap();
+        let text = best(host_key("10.0.0.0")[0]);
+        let binary = best(0xff);
+        println!(
+            "Read ports of {} rows: text {:?} ({:.0} rows/s), binary {:?} ({:.0} rows/s)",
+            ROWS,
+            text,
+            ROWS as f64 / text.as_secs_f64(),
+            binary,
+            ROWS as f64 / binary.as_secs_f64()
+        );
+        assert!(binary < text, "binary {:?}, text {:?}", binary, text);
+    }
+}
@@ -1,6 +1,32 @@
+pub mod backup;
+pub mod banners;
+pub mod check;
+pub mod clock;
 pub mod database;
+pub mod diff;
+pub mod export;
+pub mod host_scan;
+pub mod hostnames;
+pub mod import;
+pub mod index;
+pub mod merge;
+pub mod metrics;
 pub mod online_scan;
+pub mod os_info;
 pub mod parse_ip_range;
+pub mod path_mtu;
 pub mod port_scan;
+pub mod progress;
 pub mod query;
+pub mod resume;
+pub mod rows;
+pub mod runs;
+pub mod sample;
 pub mod service_scan;
+pub mod snapshot;
+pub mod stats;
+pub mod subscriptions;
+pub mod tags;
+#[cfg(test)]
+mod testing;
+pub mod util;
@@ -1,21 +1,149 @@
 use std::{
     cmp::min,
+    collections::HashSet,
     env,
-    net::IpAddr,
+    fs::File,
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
     time::{Duration, Instant},
 };
 
-use parse_ip_range::parse_ip_targets;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{LevelFilter, Log, Metadata, Record};
+use parse_ip_range::{load_targets, parse_ip_targets};
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
 use untitled::{
-    database::ResultDatabase, online_scan, parse_ip_range, port_scan::tcp_scan, query,
-    service_scan::service_scan::scan_services,
+    clock::ntp_offset,
+    database::{DatabaseError, DatabaseOptions, ResultDatabase},
+    host_scan::scan_host,
+    hostnames::ResolverConfig,
+    merge::MergePolicy,
+    online_scan, parse_ip_range,
+    path_mtu::{PrefixPath, prefix_of, prefix_paths_from_scan, probe_path_mtu},
+    port_scan::{
+        TOP_PORTS,
+        parse_ports::parse_ports,
+        port_scan::{ProbeOrder, ScanConfig},
+        tcp_scan::{self, select_interface},
+    },
+    progress::ProgressEvent,
+    query::{self, QueryError},
+    resume::ScanCheckpoint,
+    runs::RunMetadata,
+    service_scan::{
+        service_scan::{
+            DEFAULT_BANNER_CONCURRENCY, ServiceScanResult, quick_service_scan, scan_services,
+        },
+        tls_info::DEFAULT_TLS_PORTS,
+    },
+    util::{humanize_duration, humanize_size, parse_duration},
 };
 
 const BATCH_SIZE: usize = 4096;
+const DATABASE_PATH: &str = "ping_result_database";
+
+/// Prints the library's log records to stderr, keeping stdout for results
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Other crates only get through with warnings and errors
+        metadata.level() <= log::max_level()
+            && (metadata.target().starts_with("untitled") || metadata.level() <= log::Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= log::Level::Warn {
+            eprintln!("{}: {}", record.level(), record.args());
+        } else {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log at the level in RUST_LOG (off, error, warn, info, debug or trace), info by default
+fn init_logging() {
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::Info);
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Draws a bar for long database operations, from their progress events
+fn progress_bar() -> impl Fn(ProgressEvent) + Send + Sync + 'static {
+    let bar: Mutex<Option<ProgressBar>> = Mutex::new(None);
+    move |event| {
+        let mut bar = bar.lock().unwrap();
+        if event.done {
+            if let Some(bar) = bar.take() {
+                bar.finish_and_clear();
+            }
+            return;
+        }
+
+        let bar = bar.get_or_insert_with(|| {
+            ProgressBar::new(0).with_style(
+                ProgressStyle::with_template(
+                    "[{msg}] {wide_bar:.cyan/blue} {pos}/~{len} keys ({eta_precise})",
+                )
+                .unwrap(),
+            )
+        });
+        // The total is an estimate, don't let the bar overflow it
+        bar.set_length(event.estimated_total.unwrap_or(0).max(event.processed));
+        bar.set_position(event.processed);
+        bar.set_message(format!("{:?}, {} matched", event.operation, event.matched));
+    }
+}
+
+/// A flag the first Ctrl-C sets, so a scan can stop and save what it has.
+/// A second Ctrl-C exits straight away.
+fn stop_on_ctrl_c() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    let handler = ctrlc::set_handler(move || {
+        if handler_stop.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("Stopping, saving partial results. Press Ctrl-C again to quit now");
+    });
+    if let Err(e) = handler {
+        eprintln!("Couldn't set a Ctrl-C handler: {}", e);
+    }
+    stop
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_logging();
     let args: Vec<String> = env::args().collect();
-    let database = ResultDatabase::new("ping_result_database");
+    // Commands that only read can run alongside a scan that is writing
+    let database = match args.get(1).map(|a| a.as_str()) {
+        Some(
+            "search" | "query" | "export" | "stats" | "diff" | "runs" | "tagged" | "hostname"
+            | "sample" | "stale" | "verify" | "metrics",
+        ) => ResultDatabase::open_read_only(DATABASE_PATH),
+        Some("check") if !args[2..].iter().any(|flag| flag == "--remove") => {
+            ResultDatabase::open_read_only(DATABASE_PATH)
+        }
+        Some("prune") if args[2..].iter().any(|flag| flag == "--dry-run") => {
+            ResultDatabase::open_read_only(DATABASE_PATH)
+        }
+        _ => ResultDatabase::new(DATABASE_PATH),
+    }
+    .with_progress(progress_bar());
 
     if args.len() <= 1 {
         println!("You must specify a command!");
@@ -25,12 +153,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match args[1].to_lowercase().as_str() {
         "scan" => {
-            if args.len() != 4 {
-                println!("Invalid Usage!");
-                print_help(Some(args[1].as_str()));
+            // `scan resume` runs the scan an unfinished checkpoint recorded again
+            let (scan_args, checkpoint) = if args.get(2).is_some_and(|arg| arg == "resume") {
+                match database.load_checkpoint()? {
+                    Some(checkpoint) => (checkpoint.args.clone(), Some(checkpoint)),
+                    None => {
+                        println!("No unfinished scan to resume");
+                        return Ok(());
+                    }
+                }
+            } else {
+                (args[2..].to_vec(), None)
+            };
+            let Some(mut options) = parse_scan_args(scan_args) else {
                 return Ok(());
-            }
-            let _ = scan(database, args[2].clone(), args[3].clone());
+            };
+            options.config.cancel = Some(stop_on_ctrl_c());
+            scan(database, options, checkpoint)?;
         }
         // "search" => {
         //     if args.len() != 4 {
@@ -40,6 +179,375 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         //     }
         //     search(database, args[2].to_string(), args[3].to_string());
         // }
+        "export" => {
+            if args.len() < 4 || args.len() > 5 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            export(
+                database,
+                &args[2],
+                &args[3],
+                args.get(4).map(|c| c.as_str()),
+            )?;
+        }
+        "host" => {
+            if args.len() < 3 || args.len() > 4 || args.get(3).is_some_and(|a| a != "--json") {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let report = scan_host(args[2].parse()?, TOP_PORTS.to_vec())?;
+            if args.len() == 4 {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report.render());
+            }
+        }
+        "import" => {
+            let bulk = args.get(4).map(|flag| flag.as_str()) == Some("--bulk");
+            if args.len() != 4 && !(args.len() == 5 && bulk) {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let database = if bulk {
+                ResultDatabase::with_options(
+                    DATABASE_PATH,
+                    DatabaseOptions::default().with_bulk_load(true),
+                )
+            } else {
+                database
+            };
+            import(database, &args[2], &args[3])?;
+        }
+        "delete" => {
+            if args.len() < 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            for host in &args[2..] {
+                if database.delete_host(host)? {
+                    println!("Deleted {}", host);
+                } else {
+                    println!("{} is not in the database", host);
+                }
+            }
+        }
+        "tag" | "untag" => {
+            if args.len() < 4 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let host = &args[2];
+            for tag in &args[3..] {
+                let changed = if args[1] == "tag" {
+                    database.add_tag(host, tag)?
+                } else {
+                    database.remove_tag(host, tag)?
+                };
+                match (args[1].as_str(), changed) {
+                    ("tag", true) => println!("Tagged {} with {}", host, tag),
+                    ("tag", false) => println!("{} is not in the database", host),
+                    (_, true) => println!("Removed {} from {}", tag, host),
+                    (_, false) => println!("{} is not tagged with {}", host, tag),
+                }
+            }
+        }
+        "tagged" => {
+            if args.len() != 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            for row in database.get_rows_by_tag(&args[2])? {
+                println!("{}", row);
+            }
+        }
+        "resolve" => {
+            let mut config = ResolverConfig::default();
+            for flag in &args[2..] {
+                if let Some(nameserver) = flag.strip_prefix("--nameserver=") {
+                    let nameserver = nameserver.parse::<SocketAddr>().or_else(|_| {
+                        nameserver
+                            .parse::<IpAddr>()
+                            .map(|ip| SocketAddr::new(ip, 53))
+                    });
+                    match nameserver {
+                        Ok(nameserver) => config.nameserver = Some(nameserver),
+                        Err(_) => {
+                            println!("Invalid nameserver \"{}\"", flag);
+                            return Ok(());
+                        }
+                    }
+                } else if let Some(threads) = flag.strip_prefix("--threads=")
+                    && let Ok(threads) = threads.parse()
+                {
+                    config.threads = threads;
+                } else {
+                    println!("Invalid Usage!");
+                    print_help(Some(args[1].as_str()));
+                    return Ok(());
+                }
+            }
+            database.resolve_hostnames(&config)?;
+        }
+        "hostname" => {
+            if args.len() != 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            for row in database.get_rows_by_hostname(&args[2])? {
+                println!("{} {}", row.id, row.hostname.unwrap_or_default());
+            }
+        }
+        "purge" => {
+            if args.len() != 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            database.purge_older_than(parse_duration(&args[2])?.as_secs())?;
+        }
+        "prune" => {
+            let mut older_than = None;
+            let mut dry_run = false;
+            for arg in &args[2..] {
+                if arg == "--dry-run" {
+                    dry_run = true;
+                } else if older_than.is_none()
+                    && let Ok(age) = parse_duration(arg)
+                {
+                    older_than = Some(age);
+                } else {
+                    println!("Invalid Usage!");
+                    print_help(Some(args[1].as_str()));
+                    return Ok(());
+                }
+            }
+            let pruned = database.prune_empty_hosts(older_than, dry_run)?;
+            if dry_run {
+                println!(
+                    "Would remove {} hosts with no open ports or services",
+                    pruned
+                );
+            } else {
+                println!("Removed {} hosts with no open ports or services", pruned);
+            }
+        }
+        "stale" => {
+            let include_untimed = match args.get(3).map(|flag| flag.as_str()) {
+                None => Some(false),
+                Some("--untimed") => Some(true),
+                Some(_) => None,
+            };
+            let (Some(age), Some(include_untimed)) = (args.get(2), include_untimed) else {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            };
+            for row in database.hosts_older_than(parse_duration(age)?, include_untimed)? {
+                println!("{}", row);
+            }
+        }
+        "backup" => {
+            if args.len() != 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            database.backup_to(Path::new(&args[2]))?;
+        }
+        "restore" => {
+            if args.len() != 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            ResultDatabase::restore_from(Path::new(&args[2]), Path::new(DATABASE_PATH))?;
+        }
+        "diff" => {
+            if args.len() < 3 || args.len() > 4 || args.get(3).is_some_and(|a| a != "--json") {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let diff = database.diff(&ResultDatabase::open_read_only(&args[2]))?;
+            if args.len() == 4 {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print!("{}", diff);
+            }
+        }
+        "merge" => {
+            if args.len() < 3 || args.len() > 4 || args.get(3).is_some_and(|a| a != "--union") {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let policy = if args.len() == 4 {
+                MergePolicy::Union
+            } else {
+                MergePolicy::PreferNewest
+            };
+            let stats = database.merge_from(&ResultDatabase::open_read_only(&args[2]), policy)?;
+            println!(
+                "Merged {} new, {} updated and {} unchanged hosts",
+                stats.new_hosts, stats.updated_hosts, stats.unchanged_hosts
+            );
+        }
+        "stats" => {
+            print!("{}", database.stats()?);
+        }
+        "metrics" => {
+            print!("{}", database.metrics()?.render_prometheus());
+        }
+        "sample" => {
+            let count = args.get(2).and_then(|count| count.parse().ok());
+            let seed = match args.get(3).map(|flag| flag.strip_prefix("--seed=")) {
+                None => Some(None),
+                Some(Some(seed)) => seed.parse().ok().map(Some),
+                Some(None) => None,
+            };
+            let (Some(count), Some(seed)) = (count, seed) else {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            };
+            let rows = match seed {
+                Some(seed) => database.sample_hosts_seeded(count, seed)?,
+                None => database.sample_hosts(count)?,
+            };
+            for row in rows {
+                println!("{}", row);
+            }
+        }
+        "runs" => {
+            for run in database.list_scan_runs()? {
+                println!(
+                    "{}  {:<7} {}  {} results in {}{}",
+                    run.id,
+                    run.scan_type,
+                    run.targets,
+                    run.results_written,
+                    humanize_duration(Duration::from_secs(run.finished_at - run.started_at)),
+                    if run.interrupted {
+                        " (interrupted)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        "gc" => {
+            database.collect_garbage()?;
+        }
+        "verify" => {
+            let report = database.verify_indexes()?;
+            println!("Checked {} entries", report.scanned);
+            for (count, what) in [
+                (
+                    report.orphaned_columns,
+                    "column entries for hosts with no row",
+                ),
+                (
+                    report.dangling_ports,
+                    "port index entries for ports hosts don't have",
+                ),
+                (
+                    report.dangling_tags,
+                    "tag index entries for tags hosts don't have",
+                ),
+                (
+                    report.dangling_banners,
+                    "banners for ports hosts don't have",
+                ),
+                (
+                    report.missing_ports,
+                    "open ports missing from the port index",
+                ),
+                (report.missing_tags, "tags missing from the tag index"),
+            ] {
+                if count > 0 {
+                    println!("- {} {}", count, what);
+                }
+            }
+            if report.is_consistent() {
+                println!("Indexes are consistent");
+            } else {
+                println!("Run gc to remove stale entries, and migrate to rebuild both indexes");
+            }
+        }
+        "check" => {
+            let flags = &args[2..];
+            let report = if flags.iter().any(|flag| flag == "--all") {
+                database.check_all()
+            } else {
+                database.check()
+            };
+            let report = match report {
+                Ok(report) => report,
+                Err(e @ DatabaseError::Corrupted(_)) => {
+                    println!("{}", e);
+                    println!("Run repair to recover what can still be read");
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if report.complete {
+                println!("Checked {} entries", report.scanned);
+            } else {
+                println!(
+                    "Checked {} entries at the start of each column family, use --all to check every one",
+                    report.scanned
+                );
+            }
+            for column in &report.missing_columns {
+                println!("- Missing column family {}", column);
+            }
+            for (column, e) in &report.read_errors {
+                println!("- Couldn't read {}: {}", column, e);
+            }
+            for bad in &report.bad_keys {
+                println!("- Bad entry in {}", bad);
+            }
+
+            if report.is_healthy() {
+                println!("No problems found");
+            } else if flags.iter().any(|flag| flag == "--remove") {
+                let removed = database.remove_bad_keys(&report.bad_keys)?;
+                // Index entries for a removed column entry are left dangling
+                if report.bad_keys.iter().any(|bad| bad.column != "default") {
+                    database.collect_garbage()?;
+                }
+                println!("Removed {} bad entries", removed);
+            } else if !report.bad_keys.is_empty() {
+                println!("Run check --remove to delete the bad entries");
+            }
+        }
+        "repair" => {
+            ResultDatabase::repair(DATABASE_PATH)?;
+            println!("Run check to see what was kept");
+        }
+        "reindex" => {
+            database.rebuild_port_index()?;
+        }
+        "migrate" => {
+            database.migrate_keys()?;
+        }
+        "compact" => {
+            let (before, after) = database.compact()?;
+            println!(
+                "Compacted from {} to {}",
+                humanize_size(before),
+                humanize_size(after)
+            );
+        }
         "help" => {
             if args.len() != 3 {
                 print_help(None);
@@ -54,12 +562,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 if let Ok(results) = results {
                     let len = results.len();
                     for result in results {
-                        println!("{}", result.to_string());
+                        println!("{}", result);
                     }
-                    println!("{} results in {}ms", len, start.elapsed().as_millis());
+                    println!("{} results in {}", len, humanize_duration(start.elapsed()));
                 }
             }
         }
+        "query" => {
+            if args.len() < 3 {
+                println!("Invalid Usage!");
+                print_help(Some(args[1].as_str()));
+                return Ok(());
+            }
+            let expr = args[2..].join(" ");
+            let start = Instant::now();
+            match database.query(&expr) {
+                Ok(results) => {
+                    let len = results.len();
+                    for result in results {
+                        println!("{}", result);
+                    }
+                    println!("{} results in {}", len, humanize_duration(start.elapsed()));
+                }
+                Err(QueryError::Parse {
+                    message, offset, ..
+                }) => {
+                    println!("{}", expr);
+                    println!("{}^", " ".repeat(expr[..offset].chars().count()));
+                    println!("{}", message);
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
         _ => {
             println!("Invalid command!");
             print_help(None);
@@ -69,146 +603,566 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-const PORTS: [i32; 1000] = [
-    25565, 3, 4, 6, 7, 9, 13, 17, 19, 20, 21, 22, 23, 24, 25, 26, 30, 32, 33, 37, 42, 43, 49, 53,
-    70, 79, 80, 81, 82, 83, 84, 85, 88, 89, 90, 99, 100, 106, 109, 110, 111, 113, 119, 125, 135,
-    139, 143, 144, 146, 161, 163, 179, 199, 211, 212, 222, 254, 255, 256, 259, 264, 280, 301, 306,
-    311, 340, 366, 389, 406, 407, 416, 417, 425, 427, 443, 444, 445, 458, 464, 465, 481, 497, 500,
-    512, 513, 514, 515, 524, 541, 543, 544, 545, 548, 554, 555, 563, 587, 593, 616, 617, 625, 631,
-    636, 646, 648, 666, 667, 668, 683, 687, 691, 700, 705, 711, 714, 720, 722, 726, 749, 765, 777,
-    783, 787, 800, 801, 808, 843, 873, 880, 888, 898, 900, 901, 902, 903, 911, 912, 981, 987, 990,
-    992, 993, 995, 999, 1000, 1001, 1002, 1007, 1009, 1010, 1011, 1021, 1022, 1023, 1024, 1025,
-    1026, 1027, 1028, 1029, 1030, 1031, 1032, 1033, 1034, 1035, 1036, 1037, 1038, 1039, 1040, 1041,
-    1042, 1043, 1044, 1045, 1046, 1047, 1048, 1049, 1050, 1051, 1052, 1053, 1054, 1055, 1056, 1057,
-    1058, 1059, 1060, 1061, 1062, 1063, 1064, 1065, 1066, 1067, 1068, 1069, 1070, 1071, 1072, 1073,
-    1074, 1075, 1076, 1077, 1078, 1079, 1080, 1081, 1082, 1083, 1084, 1085, 1086, 1087, 1088, 1089,
-    1090, 1091, 1092, 1093, 1094, 1095, 1096, 1097, 1098, 1099, 1100, 1102, 1104, 1105, 1106, 1107,
-    1108, 1110, 1111, 1112, 1113, 1114, 1117, 1119, 1121, 1122, 1123, 1124, 1126, 1130, 1131, 1132,
-    1137, 1138, 1141, 1145, 1147, 1148, 1149, 1151, 1152, 1154, 1163, 1164, 1165, 1166, 1169, 1174,
-    1175, 1183, 1185, 1186, 1187, 1192, 1198, 1199, 1201, 1213, 1216, 1217, 1218, 1233, 1234, 1236,
-    1244, 1247, 1248, 1259, 1271, 1272, 1277, 1287, 1296, 1300, 1301, 1309, 1310, 1311, 1322, 1328,
-    1334, 1352, 1417, 1433, 1434, 1443, 1455, 1461, 1494, 1500, 1501, 1503, 1521, 1524, 1533, 1556,
-    1580, 1583, 1594, 1600, 1641, 1658, 1666, 1687, 1688, 1700, 1717, 1718, 1719, 1720, 1721, 1723,
-    1755, 1761, 1782, 1783, 1801, 1805, 1812, 1839, 1840, 1862, 1863, 1864, 1875, 1900, 1914, 1935,
-    1947, 1971, 1972, 1974, 1984, 1998, 1999, 2000, 2001, 2002, 2003, 2004, 2005, 2006, 2007, 2008,
-    2009, 2010, 2013, 2020, 2021, 2022, 2030, 2033, 2034, 2035, 2038, 2040, 2041, 2042, 2043, 2045,
-    2046, 2047, 2048, 2049, 2065, 2068, 2099, 2100, 2103, 2105, 2106, 2107, 2111, 2119, 2121, 2126,
-    2135, 2144, 2160, 2161, 2170, 2179, 2190, 2191, 2196, 2200, 2222, 2251, 2260, 2288, 2301, 2323,
-    2366, 2381, 2382, 2383, 2393, 2394, 2399, 2401, 2492, 2500, 2522, 2525, 2557, 2601, 2602, 2604,
-    2605, 2607, 2608, 2638, 2701, 2702, 2710, 2717, 2718, 2725, 2800, 2809, 2811, 2869, 2875, 2909,
-    2910, 2920, 2967, 2968, 2998, 3000, 3001, 3003, 3005, 3006, 3007, 3011, 3013, 3017, 3030, 3031,
-    3052, 3071, 3077, 3128, 3168, 3211, 3221, 3260, 3261, 3268, 3269, 3283, 3300, 3301, 3306, 3322,
-    3323, 3324, 3325, 3333, 3351, 3367, 3369, 3370, 3371, 3372, 3389, 3390, 3404, 3476, 3493, 3517,
-    3527, 3546, 3551, 3580, 3659, 3689, 3690, 3703, 3737, 3766, 3784, 3800, 3801, 3809, 3814, 3826,
-    3827, 3828, 3851, 3869, 3871, 3878, 3880, 3889, 3905, 3914, 3918, 3920, 3945, 3971, 3986, 3995,
-    3998, 4000, 4001, 4002, 4003, 4004, 4005, 4006, 4045, 4111, 4125, 4126, 4129, 4224, 4242, 4279,
-    4321, 4343, 4443, 4444, 4445, 4446, 4449, 4550, 4567, 4662, 4848, 4899, 4900, 4998, 5000, 5001,
-    5002, 5003, 5004, 5009, 5030, 5033, 5050, 5051, 5054, 5060, 5061, 5080, 5087, 5100, 5101, 5102,
-    5120, 5190, 5200, 5214, 5221, 5222, 5225, 5226, 5269, 5280, 5298, 5357, 5405, 5414, 5431, 5432,
-    5440, 5500, 5510, 5544, 5550, 5555, 5560, 5566, 5631, 5633, 5666, 5678, 5679, 5718, 5730, 5800,
-    5801, 5802, 5810, 5811, 5815, 5822, 5825, 5850, 5859, 5862, 5877, 5900, 5901, 5902, 5903, 5904,
-    5906, 5907, 5910, 5911, 5915, 5922, 5925, 5950, 5952, 5959, 5960, 5961, 5962, 5963, 5987, 5988,
-    5989, 5998, 5999, 6000, 6001, 6002, 6003, 6004, 6005, 6006, 6007, 6009, 6025, 6059, 6100, 6101,
-    6106, 6112, 6123, 6129, 6156, 6346, 6389, 6502, 6510, 6543, 6547, 6565, 6566, 6567, 6580, 6646,
-    6666, 6667, 6668, 6669, 6689, 6692, 6699, 6779, 6788, 6789, 6792, 6839, 6881, 6901, 6969, 7000,
-    7001, 7002, 7004, 7007, 7019, 7025, 7070, 7100, 7103, 7106, 7200, 7201, 7402, 7435, 7443, 7496,
-    7512, 7625, 7627, 7676, 7741, 7777, 7778, 7800, 7911, 7920, 7921, 7937, 7938, 7999, 8000, 8001,
-    8002, 8007, 8008, 8009, 8010, 8011, 8021, 8022, 8031, 8042, 8045, 8080, 8081, 8082, 8083, 8084,
-    8085, 8086, 8087, 8088, 8089, 8090, 8093, 8099, 8100, 8180, 8181, 8192, 8193, 8194, 8200, 8222,
-    8254, 8290, 8291, 8292, 8300, 8333, 8383, 8400, 8402, 8443, 8500, 8600, 8649, 8651, 8652, 8654,
-    8701, 8800, 8873, 8888, 8899, 8994, 9000, 9001, 9002, 9003, 9009, 9010, 9011, 9040, 9050, 9071,
-    9080, 9081, 9090, 9091, 9099, 9100, 9101, 9102, 9103, 9110, 9111, 9200, 9207, 9220, 9290, 9415,
-    9418, 9485, 9500, 9502, 9503, 9535, 9575, 9593, 9594, 9595, 9618, 9666, 9876, 9877, 9878, 9898,
-    9900, 9917, 9929, 9943, 9944, 9968, 9998, 9999, 10000, 10001, 10002, 10003, 10004, 10009,
-    10010, 10012, 10024, 10025, 10082, 10180, 10215, 10243, 10566, 10616, 10617, 10621, 10626,
-    10628, 10629, 10778, 11110, 11111, 11967, 12000, 12174, 12265, 12345, 13456, 13722, 13782,
-    13783, 14000, 14238, 14441, 14442, 15000, 15002, 15003, 15004, 15660, 15742, 16000, 16001,
-    16012, 16016, 16018, 16080, 16113, 16992, 16993, 17877, 17988, 18040, 18101, 18988, 19101,
-    19283, 19315, 19350, 19780, 19801, 19842, 20000, 20005, 20031, 20221, 20222, 20828, 21571,
-    22939, 23502, 24444, 24800, 25734, 25735, 26214, 27000, 27352, 27353, 27355, 27356, 27715,
-    28201, 30000, 30718, 30951, 31038, 31337, 32768, 32769, 32770, 32771, 32772, 32773, 32774,
-    32775, 32776, 32777, 32778, 32779, 32780, 32781, 32782, 32783, 32784, 32785, 33354, 33899,
-    34571, 34572, 34573, 35500, 38292, 40193, 40911, 41511, 42510, 44176, 44442, 44443, 44501,
-    45100, 48080, 49152, 49153, 49154, 49155, 49156, 49157, 49158, 49159, 49160, 49161, 49163,
-    49165, 49167, 49175, 49176, 49400, 49999, 50000, 50001, 50002, 50003, 50006, 50300, 50389,
-    50500, 50636, 50800, 51103, 51493, 52673, 52822, 52848, 52869, 54045, 54328, 55055, 55056,
-    55555, 55600, 56737, 56738, 57294, 57797, 58080, 60020, 60443, 61532, 61900, 62078, 63331,
-    64623, 64680, 65000, 65129, 65389,
-];
+/// What to scan and how, from the arguments after `scan`
+struct ScanOptions {
+    search_type: String,
+    targets: String,
+    probe_pmtu: bool,
+    tcp_ping: bool,
+    ntp_server: Option<String>,
+    /// Service probes in flight at once, if given
+    connections: Option<usize>,
+    /// Ports quick scans try TLS on, if given
+    tls_ports: Option<Vec<u16>>,
+    /// Print what would be sent instead of scanning
+    dry_run: bool,
+    config: ScanConfig,
+    /// The arguments these were parsed from, kept in the scan's checkpoint
+    args: Vec<String>,
+}
+
+/// Parse `scan <type> <hosts> (flags)`, without the `scan`. Prints the
+/// usage and returns None if they're invalid.
+fn parse_scan_args(args: Vec<String>) -> Option<ScanOptions> {
+    if args.len() < 2 {
+        println!("Invalid Usage!");
+        print_help(Some("scan"));
+        return None;
+    }
 
+    let mut options = ScanOptions {
+        search_type: args[0].clone(),
+        targets: args[1].clone(),
+        probe_pmtu: false,
+        tcp_ping: false,
+        ntp_server: None,
+        connections: None,
+        tls_ports: None,
+        dry_run: false,
+        config: ScanConfig::default(),
+        args: Vec::new(),
+    };
+    for flag in args.iter().skip(2) {
+        if let Some(server) = flag.strip_prefix("--ntp=") {
+            options.ntp_server = Some(server.to_string());
+        } else if let Some(rate) = flag.strip_prefix("--rate=")
+            && let Ok(rate) = rate.parse()
+        {
+            options.config.rate_pps = rate;
+        } else if let Some(connections) = flag.strip_prefix("--connections=")
+            && let Ok(connections) = connections.parse::<usize>()
+            && connections > 0
+        {
+            options.connections = Some(connections);
+        } else if let Some(ports) = flag.strip_prefix("--tls-ports=") {
+            match parse_ports(ports) {
+                Ok(ports) => {
+                    options.tls_ports = Some(ports.into_iter().map(|port| port as u16).collect())
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    return None;
+                }
+            }
+        } else if let Some(window) = flag.strip_prefix("--window=")
+            && let Ok(window) = window.parse()
+        {
+            options.config.window = window;
+        } else if let Some(mss) = flag.strip_prefix("--mss=")
+            && let Ok(mss) = mss.parse()
+        {
+            options.config.mss = Some(mss);
+        } else if let Some(interface) = flag.strip_prefix("--interface=") {
+            options.config.interface = Some(interface.to_string());
+        } else if let Some(scan_type) = flag.strip_prefix("--scan-type=") {
+            match scan_type.parse() {
+                Ok(scan_type) => options.config.scan_type = scan_type,
+                Err(e) => {
+                    println!("{}", e);
+                    return None;
+                }
+            }
+        } else if flag == "--shuffle" {
+            options.config.probe_order = ProbeOrder::Shuffled(rand::random());
+        } else if let Some(seed) = flag.strip_prefix("--shuffle=")
+            && let Ok(seed) = seed.parse()
+        {
+            options.config.probe_order = ProbeOrder::Shuffled(seed);
+        } else if flag == "--pmtu" {
+            options.probe_pmtu = true;
+        } else if flag == "--tcp-ping" {
+            options.tcp_ping = true;
+        } else if flag == "--dry-run" {
+            options.dry_run = true;
+        } else {
+            println!("Invalid Usage!");
+            print_help(Some("scan"));
+            return None;
+        }
+    }
+    options.args = args;
+    Some(options)
+}
+
+/// Print the probes a scan of `hosts` would send and how long it would take,
+/// without sending any
+fn print_scan_plan(search_type: &str, hosts: &[IpAddr], tcp_ping: bool, config: &ScanConfig) {
+    let discovery = if tcp_ping {
+        let ports: Vec<i32> = online_scan::tcp_ping::DEFAULT_PROBE_PORTS
+            .iter()
+            .map(|port| *port as i32)
+            .collect();
+        tcp_scan::tcp_scan_plan(hosts, &ports, config)
+    } else {
+        online_scan::ping_scanner::ping_scan_plan(hosts)
+    };
+
+    match search_type {
+        "ping" => println!("Ping: {}", discovery),
+        "tcp" | "service" => {
+            println!("Ping: {}", discovery);
+            println!(
+                "Port scan, if every target is up: {}",
+                tcp_scan::tcp_scan_plan(hosts, &TOP_PORTS, config)
+            );
+        }
+        _ => println!("Dry runs can only plan ping, tcp and service scans"),
+    }
+}
+
+/// Run a scan, or carry on with the one `resume` was saved by, skipping the
+/// targets it finished
 fn scan(
     database: ResultDatabase,
-    search_type: String,
-    arg: String,
+    options: ScanOptions,
+    resume: Option<ScanCheckpoint>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Set default targets or use command line input
-    let targets = arg;
+    let ScanOptions {
+        search_type,
+        targets,
+        probe_pmtu,
+        tcp_ping,
+        ntp_server,
+        connections,
+        tls_ports,
+        dry_run,
+        config,
+        args,
+    } = options;
+    let tls_ports = tls_ports.unwrap_or_else(|| DEFAULT_TLS_PORTS.to_vec());
 
-    // Parse the targets into IP addresses
-    let hosts = parse_ip_targets(&targets)?;
+    // A resumed scan shuffles with the same seed, to get the targets in the
+    // order its checkpoint counted them in
+    let seed = resume
+        .as_ref()
+        .map_or_else(rand::random, |resume| resume.seed);
+
+    // Parse the targets into IP addresses, or read them from a file given as @path
+    let hosts = match targets.strip_prefix('@') {
+        Some(path) => {
+            let mut hosts = load_targets(path)?;
+            hosts.shuffle(&mut StdRng::seed_from_u64(seed));
+            hosts
+        }
+        None => parse_ip_targets(&targets)?,
+    };
+
+    if dry_run {
+        print_scan_plan(&search_type, &hosts, tcp_ping, &config);
+        return Ok(());
+    }
+
+    // Find the hosts that are up, by TCP ping if ICMP is dropped
+    let discover =
+        |hosts: Vec<IpAddr>| -> Result<Vec<online_scan::PingResult>, Box<dyn std::error::Error>> {
+            if tcp_ping {
+                Ok(online_scan::tcp_ping::tcp_ping_with(
+                    hosts,
+                    online_scan::tcp_ping::DEFAULT_PROBE_PORTS.to_vec(),
+                    &config,
+                )?)
+            } else {
+                online_scan::ping_scanner::ping_scan_results(hosts, None, config.cancel.clone())
+            }
+        };
+
+    // Fail before pinging anything rather than part way through. Only port
+    // probes need an interface, which ping scans send with --tcp-ping
+    let sends_probes = match search_type.as_str() {
+        "ping" => tcp_ping,
+        "quick" => false,
+        _ => true,
+    };
+    if sends_probes && let Err(e) = select_interface(config.interface.as_deref(), &hosts) {
+        println!("{}", e);
+        return Ok(());
+    }
+
+    let mut run = RunMetadata::new(&search_type, &targets, database.clock().unix_seconds());
+    if let Some(resume) = &resume {
+        run.id = resume.run_id.clone();
+        run.started_at = resume.started_at;
+        run.results_written = resume.results_written;
+    }
+    if let Some(server) = ntp_server {
+        match ntp_offset(database.clock(), &server, Duration::from_secs(2)) {
+            Ok(offset) => {
+                println!("Clock offset from {}: {}ms", server, offset);
+                run.clock_offset_ms = Some(offset);
+            }
+            Err(e) => println!("NTP check against {} failed: {}", server, e),
+        }
+        run.ntp_server = Some(server);
+    }
+
+    if !matches!(search_type.as_str(), "ping" | "arp") {
+        run.ports = TOP_PORTS.to_vec();
+    }
+
+    let mut scanned_prefixes = HashSet::new();
+
+    // Scans that go chunk by chunk can be resumed from the last chunk saved
+    let chunked = matches!(search_type.as_str(), "tcp" | "service" | "quick");
+    let mut checkpoint = ScanCheckpoint {
+        args,
+        run_id: run.id.clone(),
+        started_at: run.started_at,
+        seed,
+        completed: resume.as_ref().map_or(0, |resume| resume.completed),
+        results_written: run.results_written,
+    };
+    if chunked {
+        if resume.is_some() {
+            println!(
+                "Resuming run {}, {} of {} targets already scanned",
+                run.id,
+                checkpoint.completed,
+                hosts.len()
+            );
+        } else if database.load_checkpoint().is_ok_and(|old| old.is_some()) {
+            println!("Replacing the checkpoint of an unfinished scan");
+        }
+    }
 
     match search_type.as_str() {
         "ping" => {
             let length = hosts.len();
+            let mut up = 0;
 
-            let up_hosts: Vec<IpAddr> = online_scan::ping_scanner::ping_scan(hosts).unwrap();
-            println!("Finished! {} Scanned, {} Up", length, up_hosts.len());
-            let _ = database.add_ping_results(&up_hosts);
+            // In chunks like the other scans, saving each one as it finishes
+            for hosts in hosts.chunks(BATCH_SIZE) {
+                let replies = discover(hosts.to_vec())?;
+                database.add_ping_results(&replies, Some(&run.id))?;
+                up += replies.len();
+                run.results_written += replies.len() as u64;
+                if config.cancelled() {
+                    break;
+                }
+            }
+            println!("Finished! {} Scanned, {} Up", length, up);
+        }
+        "arp" => {
+            let interface = select_interface(config.interface.as_deref(), &hosts)?;
+            let up_hosts = online_scan::arp_scan::arp_scan(&interface.name, &targets)?;
+            println!("Finished! {} Scanned, {} Up", hosts.len(), up_hosts.len());
+            database.add_ping_hosts(&up_hosts, Some(&run.id))?;
+            run.results_written += up_hosts.len() as u64;
         }
         "tcp" => {
-            let chunks = hosts.chunks(BATCH_SIZE);
-            let num_chunks = chunks.len();
-            for (i, hosts) in chunks.enumerate() {
-                let hosts = hosts.to_vec();
-                let length = hosts.len();
+            run.results_written += database.scan_from_checkpoint(
+                &mut checkpoint,
+                &hosts,
+                BATCH_SIZE,
+                |hosts,
+                 chunk,
+                 chunks|
+                 -> Result<ControlFlow<u64, u64>, Box<dyn std::error::Error>> {
+                    let hosts = hosts.to_vec();
+                    let length = hosts.len();
 
-                println!("Scanning chunk {}/{} ({} hosts)", i + 1, num_chunks, length);
+                    println!("Scanning chunk {}/{} ({} hosts)", chunk, chunks, length);
 
-                let up_hosts: Vec<IpAddr> = online_scan::ping_scanner::ping_scan(hosts).unwrap();
-                println!(
-                    "Finished Pinging! {} Scanned, {} Up",
-                    length,
-                    up_hosts.len()
-                );
-                let _ = database.add_ping_results(&up_hosts);
+                    let replies = discover(hosts)?;
+                    let up_hosts: Vec<IpAddr> = replies.iter().map(|reply| reply.host).collect();
+                    println!(
+                        "Finished Pinging! {} Scanned, {} Up",
+                        length,
+                        up_hosts.len()
+                    );
+                    database.add_ping_results(&replies, Some(&run.id))?;
 
-                let tcp_results =
-                    tcp_scan::tcp_scan(up_hosts, PORTS.to_vec(), Duration::from_secs(3));
-                println!("Saving Data...");
-                let _ = database.add_tcp_results(&tcp_results);
-            }
+                    let tcp_results = tcp_scan::tcp_scan(up_hosts, TOP_PORTS.to_vec(), &config)?;
+                    println!("Saving Data...");
+                    database.add_tcp_results(&tcp_results, Some(&run.id))?;
+                    database.update_prefix_paths(&prefix_paths_from_scan(&tcp_results))?;
+                    scanned_prefixes.extend(tcp_results.iter().map(|r| prefix_of(&r.ip)));
+
+                    let saved = tcp_results.len() as u64;
+                    Ok(if config.cancelled() {
+                        ControlFlow::Break(saved)
+                    } else {
+                        ControlFlow::Continue(saved)
+                    })
+                },
+            )?;
         }
         "service" => {
-            let chunks = hosts.chunks(BATCH_SIZE);
-            let num_chunks = chunks.len();
-            for (i, hosts) in chunks.enumerate() {
-                let hosts = hosts.to_vec();
-                let length = hosts.len();
+            run.results_written += database.scan_from_checkpoint(
+                &mut checkpoint,
+                &hosts,
+                BATCH_SIZE,
+                |hosts,
+                 chunk,
+                 chunks|
+                 -> Result<ControlFlow<u64, u64>, Box<dyn std::error::Error>> {
+                    let hosts = hosts.to_vec();
+                    let length = hosts.len();
 
-                println!("Scanning chunk {}/{} ({} hosts)", i + 1, num_chunks, length);
+                    println!("Scanning chunk {}/{} ({} hosts)", chunk, chunks, length);
 
-                let up_hosts: Vec<IpAddr> = online_scan::ping_scanner::ping_scan(hosts).unwrap();
-                let up_len = up_hosts.len();
-                println!(
-                    "Finished Pinging! {} Scanned, {} Up",
-                    length,
-                    up_hosts.len()
-                );
-                let _ = database.add_ping_results(&up_hosts);
+                    let replies = discover(hosts)?;
+                    let up_hosts: Vec<IpAddr> = replies.iter().map(|reply| reply.host).collect();
+                    let up_len = up_hosts.len();
+                    println!(
+                        "Finished Pinging! {} Scanned, {} Up",
+                        length,
+                        up_hosts.len()
+                    );
+                    database.add_ping_results(&replies, Some(&run.id))?;
 
-                let tcp_results =
-                    tcp_scan::tcp_scan(up_hosts, PORTS.to_vec(), Duration::from_secs(3));
-                println!("Finished port scan");
-                let _ = database.add_tcp_results(&tcp_results);
+                    let tcp_results = tcp_scan::tcp_scan(up_hosts, TOP_PORTS.to_vec(), &config)?;
+                    println!("Finished port scan");
+                    database.add_tcp_results(&tcp_results, Some(&run.id))?;
+                    database.update_prefix_paths(&prefix_paths_from_scan(&tcp_results))?;
+                    scanned_prefixes.extend(tcp_results.iter().map(|r| prefix_of(&r.ip)));
+                    // The open ports are saved, skip probing their services
+                    if config.cancelled() {
+                        return Ok(ControlFlow::Break(0));
+                    }
 
-                let service_results =
-                    scan_services(tcp_results, min(50, up_len), Duration::from_secs(1));
-                println!("Finished service scan");
-                let _ = database.add_service_results(&service_results);
-            }
+                    let path_mss = database
+                        .get_prefix_paths()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|(prefix, path)| Some((prefix, path.clamp_mss()?)))
+                        .collect();
+
+                    let service_results = scan_services(
+                        tcp_results,
+                        min(connections.unwrap_or(50), up_len),
+                        Duration::from_secs(1),
+                        path_mss,
+                    );
+                    println!("Finished service scan");
+                    database.add_service_results(&service_results, Some(&run.id))?;
+
+                    if probe_pmtu {
+                        probe_stalled_prefixes(&database, &service_results);
+                    }
+
+                    let saved = service_results.len() as u64;
+                    Ok(if config.cancelled() {
+                        ControlFlow::Break(saved)
+                    } else {
+                        ControlFlow::Continue(saved)
+                    })
+                },
+            )?;
+        }
+        "quick" => {
+            let ports: Vec<u16> = TOP_PORTS.iter().map(|port| *port as u16).collect();
+            run.results_written += database.scan_from_checkpoint(
+                &mut checkpoint,
+                &hosts,
+                BATCH_SIZE,
+                |hosts,
+                 chunk,
+                 chunks|
+                 -> Result<ControlFlow<u64, u64>, Box<dyn std::error::Error>> {
+                    println!(
+                        "Scanning chunk {}/{} ({} hosts)",
+                        chunk,
+                        chunks,
+                        hosts.len()
+                    );
+
+                    let service_results = quick_service_scan(
+                        hosts.to_vec(),
+                        ports.clone(),
+                        Duration::from_secs(1),
+                        connections.unwrap_or(DEFAULT_BANNER_CONCURRENCY),
+                        &tls_ports,
+                    );
+                    println!(
+                        "Finished service scan! {} hosts with open ports",
+                        service_results.len()
+                    );
+                    database.add_service_results(&service_results, Some(&run.id))?;
+
+                    let saved = service_results.len() as u64;
+                    Ok(if config.cancelled() {
+                        ControlFlow::Break(saved)
+                    } else {
+                        ControlFlow::Continue(saved)
+                    })
+                },
+            )?;
         }
         _ => {
             println!("Invalid search type!");
+            return Ok(());
+        }
+    }
+
+    if config.cancelled() {
+        println!("Interrupted, {} results saved", run.results_written);
+        if chunked {
+            println!("Run \"scan resume\" to carry on from the last chunk saved");
+        }
+        run.interrupted = true;
+    } else if chunked && let Err(e) = database.clear_checkpoint() {
+        println!("Couldn't remove the scan checkpoint: {}", e);
+    }
+
+    report_prefix_paths(&database, &scanned_prefixes);
+
+    run.finish(database.clock().unix_seconds());
+    database.record_run(&run)?;
+
+    Ok(())
+}
+
+/// Probe the path MTU of prefixes where service probes stalled mid-transfer
+fn probe_stalled_prefixes(database: &ResultDatabase, service_results: &[ServiceScanResult]) {
+    let known = database.get_prefix_paths().unwrap_or_default();
+    let mut probed = HashSet::new();
+    let mut paths = Vec::new();
+
+    for result in service_results {
+        let prefix = prefix_of(&result.ip);
+        if result.stalled_ports.is_empty()
+            || known.get(&prefix).is_some_and(|p| p.path_mtu.is_some())
+            || !probed.insert(prefix.clone())
+        {
+            continue;
+        }
+
+        let IpAddr::V4(ip) = result.ip else {
+            continue;
+        };
+
+        println!("Probing path MTU to {} ({})", prefix, ip);
+        match probe_path_mtu(ip, Duration::from_secs(1)) {
+            Ok(Some(mtu)) => paths.push(PrefixPath {
+                path_mtu: Some(mtu),
+                ..PrefixPath::new(prefix)
+            }),
+            Ok(None) => println!("No probe reached {}", ip),
+            Err(e) => println!("Path MTU probe failed: {}", e),
+        }
+    }
+
+    let _ = database.update_prefix_paths(&paths);
+}
+
+/// Print the scanned prefixes whose path is smaller than standard Ethernet
+fn report_prefix_paths(database: &ResultDatabase, prefixes: &HashSet<String>) {
+    let paths = database.get_prefix_paths().unwrap_or_default();
+    let mut anomalous: Vec<&PrefixPath> = prefixes
+        .iter()
+        .filter_map(|prefix| paths.get(prefix))
+        .filter(|path| path.is_anomalous())
+        .collect();
+
+    if anomalous.is_empty() {
+        return;
+    }
+    anomalous.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+
+    println!("Prefixes with a reduced path MTU:");
+    for path in anomalous {
+        println!(
+            "  {} - MSS {}, path MTU {}",
+            path.prefix,
+            path.min_mss.map_or("?".to_string(), |m| m.to_string()),
+            path.path_mtu.map_or("?".to_string(), |m| m.to_string())
+        );
+    }
+}
+
+fn export(
+    database: ResultDatabase,
+    format: &str,
+    path: &str,
+    columns: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        "csv" => match columns {
+            Some(columns) => {
+                let columns: Vec<&str> = columns.split(',').collect();
+                database.export_csv(File::create(path)?, &columns)?;
+            }
+            None => database.export_csv_file(path)?,
+        },
+        "json" => {
+            database.export_json(path)?;
+        }
+        "sqlite" => export_sqlite(&database, path, columns)?,
+        _ => {
+            println!("Invalid export format!");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn export_sqlite(
+    database: &ResultDatabase,
+    path: &str,
+    flag: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use untitled::export::IfExists;
+
+    let if_exists = match flag {
+        None => IfExists::Fail,
+        Some("--replace") => IfExists::Replace,
+        Some(_) => {
+            println!("Invalid Usage!");
+            print_help(Some("export"));
+            return Ok(());
+        }
+    };
+    let hosts = database.export_sqlite(Path::new(path), if_exists)?;
+    println!("Exported {} hosts to {}", hosts, path);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn export_sqlite(
+    _database: &ResultDatabase,
+    _path: &str,
+    _flag: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("SQLite export needs a build with the sqlite feature: cargo build --features sqlite");
+    Ok(())
+}
+
+fn import(
+    database: ResultDatabase,
+    format: &str,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        "nmap" => {
+            database.import_nmap_xml(Path::new(path))?;
+        }
+        "masscan" => {
+            database.import_masscan_json(File::open(path)?)?;
+        }
+        _ => {
+            println!("Invalid import format!");
         }
     }
 
@@ -254,8 +1208,36 @@ fn print_help(arg: Option<&str>) {
             None => {
                 "rust-scan help menu
 Commands:
-    scan   <type> <hosts> - scan a block of addresses and check for online using icmp echo
+    scan   <type> <hosts> (flags) - scan a block of addresses and check for online using icmp echo
+    host   <address> (--json) - scan a single host in depth and print a report
     search <arguments>    - Search database
+    query  <expression>   - List hosts matching a filter expression
+    export <format> <file> - Export database
+    import <format> <file> - Import results from another scanner
+    delete <addresses>    - Remove hosts from the database
+    tag    <address> <tags> - Label a host
+    untag  <address> <tags> - Remove labels from a host
+    tagged <tag>          - List hosts with a label
+    resolve (flags)       - Look up the hostname of every host
+    hostname <text>       - List hosts whose hostname contains text
+    purge  <age>          - Remove hosts not seen within a duration
+    prune  (<age>) (--dry-run) - Remove hosts with no open ports or services
+    stale  <age> (--untimed) - List hosts not seen within a duration
+    backup <directory>    - Copy the database to a new directory
+    restore <directory>   - Restore the database from a backup
+    diff   <database> (--json) - Show hosts and ports that changed since an older database
+    merge  <database> (--union) - Copy the hosts of another database into this one
+    stats                 - Print host counts and the most common open ports
+    metrics               - Print database size and cache figures for Prometheus
+    sample <count> (--seed=<n>) - Print random hosts
+    runs                  - List past scans and the results each saved
+    gc                    - Remove orphaned index entries
+    verify                - Check the indexes agree with the hosts
+    check  (--all) (--remove) - Check every stored value can be read, optionally deleting those that can't
+    repair                - Recover a database that fails to open after a crash
+    reindex               - Rebuild the port index
+    migrate               - Convert hosts saved by older versions to the current key and row format
+    compact               - Reclaim the disk space of deleted hosts
     help   (command)      - Print help"
             }
             Some("scan") => {
@@ -263,22 +1245,80 @@ Commands:
 
 Example: scan ping 127.0.0.0/8
 Example: scan 12.34.0.0-12.34.56.78,127.0.0.1
+Example: scan tcp @targets.txt
 
 scan a block of addresses using diffrent methods
 
 - scan ping <addresses>
 Scan a block of addresses and check if their online
 
+- scan arp <cidr>
+Broadcast ARP requests to every address of a network on the local link and record the hosts that answer. Finds hosts that drop pings, but only inside a subnet of the interface
+
 - scan tcp <addresses>
 Scan a block of addresses and check if their online, then scan and check what ports are open
 
 - scan service <addresses>
 Scan a block of addresses and check if their online, then scan to check what ports are open, then scan to check what services are running and record responses
 
+- scan quick <addresses>
+Connect to the top ports of every address and record the banners of those that accept. Needs no raw sockets, so it works without root, but skips the ping and is slower than a tcp scan
+
 - scan <addresses>
-Same as scan service"
+Same as scan service
+
+- scan <type> @<file>
+Read the addresses from a file instead, one IP or CIDR per line. Blank lines and # comments are skipped, and invalid lines are reported and skipped
+
+- scan service <addresses> --pmtu
+Also probe the path MTU of networks where service probes stall mid-transfer, and advertise a smaller MSS to them on later scans
+
+- scan <type> <addresses> --tcp-ping
+Find the hosts that are up by sending a SYN to ports 80, 443, 22 and 3389 instead of pinging them, for networks that drop ICMP. A host counts as up if any of them answers, open or closed
+
+- scan <type> <addresses> --rate=<pps>
+Send at most this many port probes per second (default 10000, 0 for no limit)
+
+- scan <type> <addresses> --connections=<n>
+Open at most this many connections at once to identify services (default 50 for service scans, 64 for quick scans)
+
+- scan quick <addresses> --tls-ports=<ports>
+Try a TLS handshake on these ports before grabbing their banner, e.g. 443,993,8443, and record the protocol, cipher and certificate names of those that complete one (default 443, 465, 636, 853, 990, 992-995, 5061, 5986, 6697, 8443 and 9443)
+
+- scan <type> <addresses> --shuffle(=<seed>)
+Send the port probes to every (address, port) pair in a random order instead of one address at a time, so no one network gets a burst of probes. Give a seed to get the same order again
+
+- scan <type> <addresses> --dry-run
+Print how many probes a ping, tcp or service scan would send and about how long it would take, then stop without sending any or touching the database
+
+- scan <type> <addresses> --window=<bytes> --mss=<bytes>
+TCP window size to offer in port probes (default 64240), and an MSS option to send with them (default none). Firewalls and OS fingerprints can treat probes differently depending on these
+
+- scan <type> <addresses> --interface=<name>
+Send port probes from this network interface instead of picking one automatically
+
+- scan <type> <addresses> --scan-type=<syn|null|fin|xmas>
+TCP flags to probe ports with (default syn). NULL, FIN and XMAS probes get a RST from closed ports on most stacks and no answer from open ones, so they can't tell open from filtered and find no open ports to identify services on
+
+- scan <type> <addresses> --ntp=<server>
+Check the local clock against an NTP server before scanning and record the offset with the run
+
+- scan resume
+Carry on with a tcp, service or quick scan that crashed or was stopped, with the same arguments and run id. Targets in the chunks it saved are skipped, and the chunk it was part way through is scanned again from the start. Progress is kept in ping_result_database.checkpoint until the scan finishes
+
+Press Ctrl-C to stop a scan early. Sending stops, the results so far are saved and the run is recorded as interrupted. Press it again to quit without saving"
             }
 
+            Some("host") => {
+                "Usage: host <address> (--json)
+Example: host 10.1.2.3
+Example: host 10.1.2.3 --json
+
+Scan one host in depth without touching the database: ICMP and TCP ping, the top 1000 ports (retrying unanswered ones), service identification and OS hints from banners
+
+- host <address> --json
+Print the report as JSON instead"
+            }
             Some("search") => {
                 "Usage: search <arguments>
 Example: search ssh:raspbian
@@ -297,6 +1337,168 @@ There are four types of equators
 \"!=\" - If the result is exactly not equal to an item
 
 "
+            }
+            Some("query") => {
+                "Usage: query <expression>
+Example: query port:443 AND service:\"nginx\" AND NOT tag:honeypot
+Example: query host:10.0.0.0/8 (port:22 OR port:2222)
+Example: query port:80,8000-8100 seen_after:2024-05-01T00:00:00Z
+
+List hosts matching filters combined with AND, OR, NOT and parentheses. Filters next to each other are ANDed
+
+port:<ports>      - Any of the ports is open, e.g. port:443 or port:80,8000-8100
+service:<text>    - Text is part of a service name or banner
+host:<address>    - Address or hostname is exactly this, or a CIDR such as 10.0.0.0/8
+tag:<tag>         - Host has the label
+seen_after:<time> - Last seen at or after the time, in unix seconds or RFC 3339
+seen_before:<time> - First seen before the time
+
+Quote values with spaces, e.g. service:\"Apache httpd\""
+            }
+            Some("export") => {
+                "Usage: export <format> <file> (columns | --replace)
+Example: export csv results.csv
+Example: export csv results.csv host,ports,services,responses
+Example: export json results.json
+Example: export sqlite results.db --replace
+
+Export every row in the database to a file
+
+- export csv <file> (columns)
+Write a CSV file with a header row. Columns are a comma separated list of host, ports, services, responses, first_seen, last_seen, tags, hostname and banners (default host,ports,services). banners holds each port's raw banner in hex as port:hex
+
+- export json <file>
+Write a JSON array with every field of every row, and each port's raw banner in hex
+
+- export sqlite <file> (--replace)
+Write a SQLite database with hosts(id, last_seen), ports(host, port) and services(host, port, name, banner) tables. Fails if the file exists unless --replace is given. Needs a build with the sqlite feature"
+            }
+            Some("import") => {
+                "Usage: import <format> <file> (--bulk)
+Example: import nmap scan.xml
+Example: import masscan scan.json --bulk
+
+Import results from another scanner into the database
+
+- import nmap <file>
+Read nmap XML output (-oX). Hosts, open TCP ports and service names/versions are imported
+
+- import masscan <file>
+Read masscan JSON output (-oJ). Open TCP ports and banners are imported, UDP records are skipped
+
+- import <format> <file> --bulk
+Write without the write-ahead log and in larger batches, which is much faster for big files. Everything is flushed to disk at the end, but if the import is interrupted any of it may be lost"
+            }
+            Some("delete") => {
+                "Usage: delete <address> (address...)
+Example: delete 10.1.2.3
+Example: delete 10.1.2.3 10.1.2.4
+
+Remove hosts and everything recorded about them from the database"
+            }
+            Some("tag") => {
+                "Usage: tag <address> <tag> (tag...)
+Example: tag 10.1.2.3 dmz
+Example: tag 10.1.2.3 customer-x honeypot
+
+Label a host that is in the database. Tags can't contain commas. List the hosts with a tag using tagged"
+            }
+            Some("untag") => {
+                "Usage: untag <address> <tag> (tag...)
+Example: untag 10.1.2.3 honeypot
+
+Remove labels from a host"
+            }
+            Some("tagged") => {
+                "Usage: tagged <tag>
+Example: tagged dmz
+
+Print every host labelled with the tag"
+            }
+            Some("resolve") => {
+                "Usage: resolve (flags)
+Example: resolve
+Example: resolve --nameserver=10.0.0.53 --threads=64
+
+Look up the PTR record of every host in the database and store the hostnames found. Hosts whose lookup fails keep the name they had
+
+- resolve --nameserver=<address>
+DNS server to ask (default the first nameserver in /etc/resolv.conf)
+
+- resolve --threads=<count>
+Lookups to run at once (default 32)"
+            }
+            Some("hostname") => {
+                "Usage: hostname <text>
+Example: hostname corp.example.com
+
+List hosts whose hostname contains the text, ignoring case. Hostnames are found with resolve"
+            }
+            Some("purge") => {
+                "Usage: purge <age>
+Example: purge 30d
+Example: purge 12h
+
+Remove every host that hasn't been seen by a scan within the given duration. Hosts saved before first/last seen times were recorded are kept"
+            }
+            Some("prune") => {
+                "Usage: prune (<age>) (--dry-run)
+Example: prune
+Example: prune 30d --dry-run
+
+Remove every host with no open ports and no identified services, such as the hosts a ping scan found up. With an age, only those not seen by a scan within it. Tagged hosts are always kept. With --dry-run, count the hosts that would be removed without removing them"
+            }
+            Some("stale") => {
+                "Usage: stale <age> (--untimed)
+Example: stale 7d
+Example: stale 7d --untimed
+
+List every host that hasn't been seen by a scan within the given duration, to find hosts due a rescan
+
+- stale <age> --untimed
+Also list hosts saved before first/last seen times were recorded"
+            }
+            Some("diff") => {
+                "Usage: diff <database> (--json)
+Example: diff backups/2024-06-01
+Example: diff backups/2024-06-01 --json
+
+Compare the database against an older copy (such as a backup) and list new hosts, hosts that are gone, and ports and services that were added or removed on the rest
+
+- diff <database> --json
+Print the changes as JSON instead"
+            }
+            Some("sample") => {
+                "Usage: sample <count> (--seed=<n>)
+Example: sample 20
+Example: sample 20 --seed=42
+
+Print up to count hosts picked at random, for spot checks. Large databases are sampled without reading every host, which picks hosts after wide gaps in the address space a little more often
+
+- sample <count> --seed=<n>
+Pick with a fixed seed, to get the same hosts again from the same database"
+            }
+            Some("merge") => {
+                "Usage: merge <database> (--union)
+Example: merge scanner2/database
+Example: merge scanner2/database --union
+
+Copy every host from another database into this one. Hosts in both keep the ports, services and tags of both. Where both have a response for the same port, the database that saw the host last wins
+
+- merge <database> --union
+Never replace a response already in this database, only add what it's missing"
+            }
+            Some("backup") => {
+                "Usage: backup <directory>
+Example: backup backups/2024-06-01
+
+Write a consistent copy of the database to a new or empty directory. Safe to run while a scan is saving results"
+            }
+            Some("restore") => {
+                "Usage: restore <directory>
+Example: restore backups/2024-06-01
+
+Restore the database from a backup made with the backup command. The current database must be moved out of the way first"
             }
             Some(_) => {
                 print_help(None);
@@ -0,0 +1,342 @@
+use std::collections::{BTreeSet, HashMap};
+
+use log::info;
+use rocksdb::{DB, WriteBatch};
+use serde::Serialize;
+
+use crate::{
+    database::{DatabaseResult, ResultDatabase, column_handle, host_key},
+    index::IndexManager,
+    progress::{Operation, Progress},
+};
+
+/// Counts from a merge
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct MergeStats {
+    /// Hosts that were only in the other database
+    pub new_hosts: u64,
+    /// Hosts in both databases that the merge changed here
+    pub updated_hosts: u64,
+    /// Hosts in both databases that already had everything the other knew
+    pub unchanged_hosts: u64,
+}
+
+/// Which side wins when both databases have a response or banner for the
+/// same port of a host. Ports, services and tags are unioned either way.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The database that saw the host last wins, by last_seen
+    #[default]
+    PreferNewest,
+    /// Nothing here is replaced, the other database only fills in what's missing
+    Union,
+}
+
+// A row to write and the raw banners copied with it
+type MergedRow = (DatabaseResult, Vec<(u16, Vec<u8>)>);
+
+impl ResultDatabase {
+    /// Copy every host from `other` into this database, merging hosts both
+    /// have the way `upsert_rows` does rather than overwriting them. Rows are
+    /// read and written `DatabaseOptions::batch_size` at a time, so neither
+    /// database is loaded whole. Raw banners are copied along with the rows,
+    /// and first_seen and last_seen span what both databases saw.
+    pub fn merge_from(
+        &self,
+        other: &ResultDatabase,
+        policy: MergePolicy,
+    ) -> Result<MergeStats, Box<dyn std::error::Error>> {
+        if other.path == self.path {
+            return Err("Can't merge a database into itself".into());
+        }
+
+        let db = self.writable_db()?;
+        let cfs = self.column_handles(&db)?;
+        let other_db = other.db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        let other_indexes = IndexManager::new(other, &other_db)?;
+        // Held for the whole merge, so a save to the same host between
+        // reading its row here and writing the merged one isn't lost
+        let _writing = self.writing.lock().unwrap();
+
+        let mut stats = MergeStats::default();
+        let mut chunk = Vec::with_capacity(self.batch_size);
+        let progress = Progress::new(
+            self.progress.as_ref(),
+            Operation::Merge,
+            &other_db,
+            column_handle(&other_db, "default")?,
+        );
+
+        for row in other.iter_rows() {
+            let theirs = row?;
+            let key = host_key(&theirs.id);
+            let existing = self.fetch_row(&db, &theirs.id, &cfs)?;
+            let theirs_win = theirs_win(existing.as_ref(), &theirs, policy);
+
+            // Banners for ports this database has none for, and the ones
+            // the other database's responses replace
+            let stored: HashMap<u16, Vec<u8>> = indexes.stored_banners(&key).into_iter().collect();
+            let banners: Vec<(u16, Vec<u8>)> = other_indexes
+                .stored_banners(&key)
+                .into_iter()
+                .filter(|(port, banner)| match stored.get(port) {
+                    Some(stored) => theirs_win && stored != banner,
+                    None => true,
+                })
+                .collect();
+
+            let row = match existing {
+                None => {
+                    stats.new_hosts += 1;
+                    theirs
+                }
+                Some(existing) => match merged_row(&existing, &theirs, theirs_win) {
+                    Some(merged) => {
+                        stats.updated_hosts += 1;
+                        merged
+                    }
+                    None if !banners.is_empty() => {
+                        stats.updated_hosts += 1;
+                        existing
+                    }
+                    None => {
+                        stats.unchanged_hosts += 1;
+                        progress.key(false);
+                        continue;
+                    }
+                },
+            };
+            progress.key(true);
+            chunk.push((row, banners));
+
+            if chunk.len() >= self.batch_size {
+                write_chunk(&db, &indexes, &mut chunk)?;
+            }
+        }
+        write_chunk(&db, &indexes, &mut chunk)?;
+        progress.finish();
+
+        info!(
+            "Merged {}: {} new, {} updated, {} unchanged hosts",
+            other.path, stats.new_hosts, stats.updated_hosts, stats.unchanged_hosts
+        );
+        Ok(stats)
+    }
+}
+
+// Write merged rows and the banners copied with them
+fn write_chunk(
+    db: &DB,
+    indexes: &IndexManager,
+    chunk: &mut Vec<MergedRow>,
+) -> Result<(), rocksdb::Error> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+
+    let mut batch = WriteBatch::default();
+    for (row, banners) in chunk.drain(..) {
+        indexes.put_copied_row(&mut batch, &row);
+        for (port, banner) in banners {
+            indexes.put_banner(&mut batch, &row.id, port as i32, &banner);
+        }
+    }
+    db.write(batch)
+}
+
+// Whether the other database's responses and banners replace this one's
+fn theirs_win(
+    existing: Option<&DatabaseResult>,
+    theirs: &DatabaseResult,
+    policy: MergePolicy,
+) -> bool {
+    match (existing, policy) {
+        (None, _) => true,
+        (Some(_), MergePolicy::Union) => false,
+        (Some(existing), MergePolicy::PreferNewest) => theirs.last_seen > existing.last_seen,
+    }
+}
+
+// The row to store for a host both databases have, or None if the row
+// stored here already has everything
+fn merged_row(
+    existing: &DatabaseResult,
+    theirs: &DatabaseResult,
+    theirs_win: bool,
+) -> Option<DatabaseResult> {
+    let mut merged = if theirs_win {
+        let mut merged = theirs.clone();
+        merged.merge(existing.clone());
+        merged
+    } else {
+        let mut merged = existing.clone();
+        merged.merge(theirs.clone());
+        merged
+    };
+    merged.first_seen = match (existing.first_seen, theirs.first_seen) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    merged.last_seen = existing.last_seen.max(theirs.last_seen);
+
+    (!same_row(existing, &merged)).then_some(merged)
+}
+
+// Rows hold the same data, whatever order their lists are in
+fn same_row(a: &DatabaseResult, b: &DatabaseResult) -> bool {
+    fn set(list: &[String]) -> BTreeSet<&str> {
+        list.iter().map(|s| s.as_str()).collect()
+    }
+
+    a.ports == b.ports
+        && set(&a.services) == set(&b.services)
+        && set(&a.tags) == set(&b.tags)
+        && a.response_map() == b.response_map()
+        && a.first_seen == b.first_seen
+        && a.last_seen == b.last_seen
+        && a.last_run == b.last_run
+        && a.hostname == b.hostname
+        && a.rtt_ms == b.rtt_ms
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        clock::FakeClock,
+        database::{Responses, service_key},
+        testing::{self, row},
+    };
+
+    const START: u64 = 1_700_000_000;
+
+    // A row for `host` with an ssh banner on port 22
+    fn ssh_row(host: &str, ports: &[i32], banner: &str) -> DatabaseResult {
+        let mut row = row(host, ports, &["ssh"]);
+        row.set_response_map(&Responses::from([(
+            service_key(22, "tcp"),
+            ("ssh".to_string(), banner.to_string()),
+        )]));
+        row
+    }
+
+    // Save `rows`, each `after` seconds past START
+    fn save(database: &ResultDatabase, clock: &FakeClock, rows: Vec<(u64, DatabaseResult)>) {
+        for (after, row) in rows {
+            clock.set_wall(UNIX_EPOCH + Duration::from_secs(START + after));
+            database.save_rows(vec![row]).unwrap();
+        }
+    }
+
+    // This database and the one merged into it:
+    // - 10.0.0.1 was seen later there, with another port and a newer banner
+    // - 10.0.0.2 is only here
+    // - 10.0.0.3 is the same in both
+    // - 10.0.0.4 is only there
+    // - 10.0.0.5 was seen later here, with a different banner there
+    fn databases() -> ((TempDir, ResultDatabase), (TempDir, ResultDatabase)) {
+        let clock = Arc::new(FakeClock::new(UNIX_EPOCH + Duration::from_secs(START)));
+        let (ours_dir, ours) = testing::temp_database();
+        let ours = ours.with_clock(clock.clone());
+        save(
+            &ours,
+            &clock,
+            vec![
+                (0, ssh_row("10.0.0.1", &[22], "SSH-2.0-OpenSSH_8.0")),
+                (0, row("10.0.0.2", &[80], &[])),
+                (0, row("10.0.0.3", &[443], &[])),
+                (120, ssh_row("10.0.0.5", &[22], "SSH-2.0-OpenSSH_9.6")),
+            ],
+        );
+
+        let (theirs_dir, theirs) = testing::temp_database();
+        let theirs = theirs.with_clock(clock.clone());
+        save(
+            &theirs,
+            &clock,
+            vec![
+                (60, ssh_row("10.0.0.1", &[22, 80], "SSH-2.0-OpenSSH_9.6")),
+                (0, row("10.0.0.3", &[443], &[])),
+                (60, row("10.0.0.4", &[8080], &[])),
+                (60, ssh_row("10.0.0.5", &[22], "SSH-2.0-dropbear_2022.83")),
+            ],
+        );
+
+        ((ours_dir, ours), (theirs_dir, theirs))
+    }
+
+    fn banner(database: &ResultDatabase, host: &str) -> String {
+        let row = database.get_row_by_host(host).unwrap().unwrap();
+        row.response_map()[&service_key(22, "tcp")].1.clone()
+    }
+
+    #[test]
+    fn newest_responses_win_by_default() {
+        let ((_ours_dir, ours), (_theirs_dir, theirs)) = databases();
+
+        let stats = ours.merge_from(&theirs, MergePolicy::PreferNewest).unwrap();
+        assert_eq!(stats.new_hosts, 1);
+        assert_eq!(stats.updated_hosts, 2);
+        assert_eq!(stats.unchanged_hosts, 1);
+
+        let merged = ours.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(merged.ports, vec![22, 80]);
+        assert_eq!(merged.first_seen, Some(START));
+        assert_eq!(merged.last_seen, Some(START + 60));
+        assert_eq!(banner(&ours, "10.0.0.1"), "SSH-2.0-OpenSSH_9.6");
+
+        // Seen later here, so this database's banner stays
+        let kept = ours.get_row_by_host("10.0.0.5").unwrap().unwrap();
+        assert_eq!(banner(&ours, "10.0.0.5"), "SSH-2.0-OpenSSH_9.6");
+        assert_eq!(kept.first_seen, Some(START + 60));
+        assert_eq!(kept.last_seen, Some(START + 120));
+
+        assert_eq!(
+            ours.get_row_by_host("10.0.0.4").unwrap().unwrap().ports,
+            vec![8080]
+        );
+        assert_eq!(
+            ours.get_row_by_host("10.0.0.2").unwrap().unwrap().ports,
+            vec![80]
+        );
+        assert_eq!(ours.iter_rows().count(), 5);
+    }
+
+    #[test]
+    fn union_only_fills_in_what_is_missing() {
+        let ((_ours_dir, ours), (_theirs_dir, theirs)) = databases();
+
+        let stats = ours.merge_from(&theirs, MergePolicy::Union).unwrap();
+        assert_eq!(stats.new_hosts, 1);
+        assert_eq!(stats.updated_hosts, 2);
+        assert_eq!(stats.unchanged_hosts, 1);
+
+        // Ports are unioned, but the banner here isn't replaced
+        let merged = ours.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(merged.ports, vec![22, 80]);
+        assert_eq!(merged.last_seen, Some(START + 60));
+        assert_eq!(banner(&ours, "10.0.0.1"), "SSH-2.0-OpenSSH_8.0");
+        assert_eq!(banner(&ours, "10.0.0.5"), "SSH-2.0-OpenSSH_9.6");
+
+        // Everything is here now, so merging again changes nothing
+        let stats = ours.merge_from(&theirs, MergePolicy::Union).unwrap();
+        assert_eq!(stats.new_hosts, 0);
+        assert_eq!(stats.updated_hosts, 0);
+        assert_eq!(stats.unchanged_hosts, 4);
+    }
+
+    #[test]
+    fn merging_into_itself_is_an_error() {
+        let (_dir, database) = testing::temp_database();
+        let same = ResultDatabase::new(&database.path);
+        assert!(database.merge_from(&same, MergePolicy::Union).is_err());
+    }
+}
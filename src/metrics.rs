@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use rocksdb::{ColumnFamily, DB};
+use serde::Serialize;
+
+use crate::database::{DatabaseError, ResultDatabase};
+
+// Prefix of every metric `render_prometheus` writes
+const METRIC_PREFIX: &str = "rust_scan_db";
+
+/// Running totals kept by a `ResultDatabase` since it was created
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) rows_written: AtomicU64,
+    pub(crate) write_batches: AtomicU64,
+    pub(crate) rows_read: AtomicU64,
+}
+
+/// How big and how busy the database is, for monitoring. Everything comes
+/// from RocksDB's own bookkeeping or counters kept as rows are saved and
+/// read, so it's cheap to collect however big the database is.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DatabaseMetrics {
+    /// RocksDB's estimate of the keys in each column family. Includes
+    /// overwritten and deleted keys not yet compacted away.
+    pub estimated_keys: BTreeMap<String, u64>,
+    /// Bytes of SST files on disk, over every column family
+    pub sst_bytes: u64,
+    /// Bytes compaction estimates it still has to rewrite
+    pub pending_compaction_bytes: u64,
+    /// Bytes held in the block cache
+    pub block_cache_bytes: u64,
+    /// Rows saved through this instance
+    pub rows_written: u64,
+    /// Write batches those rows were saved in
+    pub write_batches: u64,
+    /// Rows read through this instance, by lookups, queries and exports
+    pub rows_read: u64,
+}
+
+impl DatabaseMetrics {
+    /// The metrics in Prometheus's text exposition format, ready to serve
+    /// from a `/metrics` endpoint
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "estimated_keys",
+            "gauge",
+            "Estimated keys in each column family",
+        );
+        for (column, keys) in &self.estimated_keys {
+            writeln!(
+                out,
+                "{}_estimated_keys{{column=\"{}\"}} {}",
+                METRIC_PREFIX, column, keys
+            )
+            .unwrap();
+        }
+
+        let values = [
+            (
+                "sst_bytes",
+                "gauge",
+                "Bytes of SST files on disk",
+                self.sst_bytes,
+            ),
+            (
+                "pending_compaction_bytes",
+                "gauge",
+                "Bytes compaction estimates it still has to rewrite",
+                self.pending_compaction_bytes,
+            ),
+            (
+                "block_cache_bytes",
+                "gauge",
+                "Bytes held in the block cache",
+                self.block_cache_bytes,
+            ),
+            (
+                "rows_written_total",
+                "counter",
+                "Rows saved since the database was opened",
+                self.rows_written,
+            ),
+            (
+                "write_batches_total",
+                "counter",
+                "Write batches saved since the database was opened",
+                self.write_batches,
+            ),
+            (
+                "rows_read_total",
+                "counter",
+                "Rows read since the database was opened",
+                self.rows_read,
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            header(&mut out, name, kind, help);
+            writeln!(out, "{}_{} {}", METRIC_PREFIX, name, value).unwrap();
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {}_{} {}", METRIC_PREFIX, name, help).unwrap();
+    writeln!(out, "# TYPE {}_{} {}", METRIC_PREFIX, name, kind).unwrap();
+}
+
+// An integer property of `cf`, 0 if RocksDB doesn't report it
+fn int_property(db: &DB, cf: &ColumnFamily, name: &str) -> u64 {
+    db.property_int_value_cf(cf, name)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+impl ResultDatabase {
+    /// Size, compaction and cache figures from RocksDB, and the rows this
+    /// instance has written and read. The counters start at zero each time
+    /// a `ResultDatabase` is created, and don't include other processes
+    /// sharing the path.
+    pub fn metrics(&self) -> Result<DatabaseMetrics, DatabaseError> {
+        let db = self.db()?;
+        let counters = &self.counters;
+        let mut metrics = DatabaseMetrics {
+            rows_written: counters.rows_written.load(Ordering::Relaxed),
+            write_batches: counters.write_batches.load(Ordering::Relaxed),
+            rows_read: counters.rows_read.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+
+        for column in &self.columns {
+            let Some(cf) = db.cf_handle(column) else {
+                continue;
+            };
+            metrics.estimated_keys.insert(
+                column.clone(),
+                int_property(&db, cf, "rocksdb.estimate-num-keys"),
+            );
+            metrics.sst_bytes += int_property(&db, cf, "rocksdb.total-sst-files-size");
+            metrics.pending_compaction_bytes +=
+                int_property(&db, cf, "rocksdb.estimate-pending-compaction-bytes");
+            // Every column family shares one block cache
+            metrics.block_cache_bytes =
+                metrics
+                    .block_cache_bytes
+                    .max(int_property(&db, cf, "rocksdb.block-cache-usage"));
+        }
+
+        Ok(metrics)
+    }
+}
@@ -0,0 +1,249 @@
+use log::info;
+use pnet::datalink::{self, Channel, MacAddr, NetworkInterface};
+use pnet::ipnetwork::IpNetwork;
+use pnet::packet::{
+    Packet,
+    arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket},
+    ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+};
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::parse_ip_range::IpPrefix;
+use crate::util::humanize_duration;
+
+// Hosts on the same link answer within milliseconds
+static TIMEOUT: Duration = Duration::from_secs(1);
+static SEND_DELAY: Duration = Duration::from_micros(50);
+// How long the receiver blocks before checking whether to stop
+static READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ARP_PACKET_LEN: usize = 28;
+/// Length of an ARP request frame built by `arp_request`
+pub const ARP_FRAME_LEN: usize = ETHERNET_HEADER_LEN + ARP_PACKET_LEN;
+
+/// Find the hosts on `interface`'s local network by broadcasting an ARP
+/// request for every host address in `cidr`. Hosts that drop ICMP still
+/// have to answer ARP, so this finds more of a LAN than `ping_scan`, and
+/// faster. `cidr` must be an IPv4 network inside one of the interface's
+/// own, since hosts behind a router never see the requests.
+pub fn arp_scan(interface: &str, cidr: &str) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+    let prefix: IpPrefix = cidr.parse()?;
+
+    let iface = datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface)
+        .ok_or_else(|| format!("No network interface named \"{}\"", interface))?;
+    let source_mac = iface
+        .mac
+        .ok_or_else(|| format!("Network interface {} has no MAC address", interface))?;
+    let source_ip = local_address(&iface, &prefix)?;
+
+    let (mut tx, mut rx) = match datalink::channel(
+        &iface,
+        datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        },
+    )? {
+        Channel::Ethernet(tx, rx) => (tx, rx),
+        _ => return Err(format!("Unsupported channel type on {}", interface).into()),
+    };
+
+    let responders = Arc::new(Mutex::new(BTreeSet::<Ipv4Addr>::new()));
+    let finished_sending = Arc::new(AtomicBool::new(false));
+
+    let recv_responders = Arc::clone(&responders);
+    let recv_finished_sending = Arc::clone(&finished_sending);
+    let receiver_handle = thread::spawn(move || {
+        let mut finish_sending_time: Option<Instant> = None;
+
+        loop {
+            if let Some(finish_sending_time) = finish_sending_time {
+                if finish_sending_time.elapsed() >= TIMEOUT {
+                    break;
+                }
+            } else if recv_finished_sending.load(Ordering::Relaxed) {
+                finish_sending_time = Some(Instant::now());
+                info!("Waiting {} for timeout...", humanize_duration(TIMEOUT));
+            }
+
+            match rx.next() {
+                Ok(frame) => {
+                    if let Some(sender) = arp_reply_sender(frame)
+                        && sender != source_ip
+                        && prefix.contains(&IpAddr::V4(sender))
+                    {
+                        recv_responders.lock().unwrap().insert(sender);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => { /* Timeout, continue */ }
+                Err(_) => break,
+            }
+        }
+    });
+
+    for host in prefix.hosts() {
+        let IpAddr::V4(target) = host else {
+            continue;
+        };
+        if target == source_ip {
+            continue;
+        }
+        if let Some(Err(e)) = tx.send_to(&arp_request(source_mac, source_ip, target), None) {
+            info!("Failed to send ARP request for {}: {}", target, e);
+        }
+        thread::sleep(SEND_DELAY);
+    }
+    finished_sending.store(true, Ordering::Relaxed);
+
+    receiver_handle.join().unwrap();
+
+    let responders = responders.lock().unwrap();
+    Ok(responders.iter().map(|ip| IpAddr::V4(*ip)).collect())
+}
+
+/// The interface's address on the network holding all of `prefix`, which
+/// ARP requests are sent from. An error if there's none.
+fn local_address(
+    iface: &NetworkInterface,
+    prefix: &IpPrefix,
+) -> Result<Ipv4Addr, Box<dyn std::error::Error>> {
+    let IpAddr::V4(network) = prefix.network else {
+        return Err("ARP scans only work on IPv4 networks".into());
+    };
+
+    iface
+        .ips
+        .iter()
+        .find_map(|ip| match ip {
+            IpNetwork::V4(local) if local.prefix() <= prefix.len && local.contains(network) => {
+                Some(local.ip())
+            }
+            _ => None,
+        })
+        .ok_or_else(|| {
+            format!(
+                "{}/{} is outside the subnets of {}",
+                network, prefix.len, iface.name
+            )
+            .into()
+        })
+}
+
+/// A broadcast Ethernet frame asking who has `target`, from the host at
+/// `source_ip` and `source_mac`
+pub fn arp_request(
+    source_mac: MacAddr,
+    source_ip: Ipv4Addr,
+    target: Ipv4Addr,
+) -> [u8; ARP_FRAME_LEN] {
+    let mut frame = [0u8; ARP_FRAME_LEN];
+
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_destination(MacAddr::broadcast());
+    ethernet.set_source(source_mac);
+    ethernet.set_ethertype(EtherTypes::Arp);
+
+    let mut arp = MutableArpPacket::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(source_mac);
+    arp.set_sender_proto_addr(source_ip);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target);
+
+    frame
+}
+
+/// The address that sent `frame`, if it's an ARP reply
+pub fn arp_reply_sender(frame: &[u8]) -> Option<Ipv4Addr> {
+    let ethernet = EthernetPacket::new(frame)?;
+    if ethernet.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+
+    let arp = ArpPacket::new(ethernet.payload())?;
+    (arp.get_operation() == ArpOperations::Reply).then(|| arp.get_sender_proto_addr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_MAC: MacAddr = MacAddr(0x02, 0x00, 0x00, 0xaa, 0xbb, 0xcc);
+    const SOURCE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    const TARGET: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+
+    #[test]
+    fn request_is_a_broadcast_arp_frame() {
+        let frame = arp_request(SOURCE_MAC, SOURCE_IP, TARGET);
+
+        #[rustfmt::skip]
+        let expected: [u8; ARP_FRAME_LEN] = [
+            // Ethernet: broadcast destination, our source, ARP ethertype
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x02, 0x00, 0x00, 0xaa, 0xbb, 0xcc,
+            0x08, 0x06,
+            // ARP: Ethernet and IPv4, 6 and 4 byte addresses, a request
+            0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01,
+            0x02, 0x00, 0x00, 0xaa, 0xbb, 0xcc, 192, 168, 1, 10,
+            0, 0, 0, 0, 0, 0, 192, 168, 1, 20,
+        ];
+        assert_eq!(frame, expected);
+        // A request isn't taken for a reply
+        assert_eq!(arp_reply_sender(&frame), None);
+    }
+
+    #[test]
+    fn reply_sender_is_read_from_the_arp_packet() {
+        let mut frame = arp_request(MacAddr(0x02, 0, 0, 0, 0, 0x20), TARGET, SOURCE_IP);
+        {
+            let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+            ethernet.set_destination(SOURCE_MAC);
+        }
+        let mut arp = MutableArpPacket::new(&mut frame[ETHERNET_HEADER_LEN..]).unwrap();
+        arp.set_operation(ArpOperations::Reply);
+        arp.set_target_hw_addr(SOURCE_MAC);
+
+        assert_eq!(arp_reply_sender(&frame), Some(TARGET));
+        // Cut off or not ARP at all
+        assert_eq!(arp_reply_sender(&frame[..ETHERNET_HEADER_LEN + 8]), None);
+        let mut ipv4 = frame;
+        MutableEthernetPacket::new(&mut ipv4)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+        assert_eq!(arp_reply_sender(&ipv4), None);
+    }
+
+    #[test]
+    fn only_networks_inside_the_interface_subnets_are_scanned() {
+        let iface = NetworkInterface {
+            name: "eth0".to_string(),
+            description: String::new(),
+            index: 2,
+            mac: Some(SOURCE_MAC),
+            ips: vec![
+                "192.168.1.10/24".parse().unwrap(),
+                "fe80::1/64".parse().unwrap(),
+            ],
+            flags: 0,
+        };
+        let local = |cidr: &str| local_address(&iface, &cidr.parse().unwrap());
+
+        assert_eq!(local("192.168.1.0/24").unwrap(), SOURCE_IP);
+        assert_eq!(local("192.168.1.16/28").unwrap(), SOURCE_IP);
+        assert!(local("192.168.0.0/16").is_err());
+        assert!(local("10.0.0.0/24").is_err());
+        assert!(local("fe80::/64").is_err());
+    }
+}
@@ -1,4 +1,7 @@
+pub mod arp_scan;
+#[allow(clippy::module_inception)]
 pub mod online_scan;
 pub mod ping_scanner;
+pub mod tcp_ping;
 
 pub use online_scan::PingResult;
@@ -10,6 +10,8 @@ pub struct PingResult {
     pub host: IpAddr,
     pub is_up: bool,
     pub response_time: Option<Duration>,
+    /// IP TTL of the echo reply
+    pub ttl: Option<u8>,
 }
 
 impl PingResult {
@@ -18,15 +20,44 @@ impl PingResult {
             host: addr,
             is_up: false,
             response_time: None,
+            ttl: None,
         }
     }
 
+    /// Operating system family suggested by the reply TTL. Hosts start from
+    /// 64, 128 or 255 and each hop takes one off, so the smallest of those at
+    /// or above the TTL is the likely starting value.
+    pub fn os_family(&self) -> Option<&'static str> {
+        os_family_from_ttl(self.ttl?)
+    }
+
     pub fn to_database(&self) -> DatabaseResult {
         DatabaseResult {
             id: self.host.to_string(),
             ports: vec![],
             services: Vec::new(),
             responses: String::new(),
+            service_entries: Vec::new(),
+            first_seen: None,
+            last_seen: None,
+            last_run: None,
+            tags: Vec::new(),
+            hostname: None,
+            rtt_ms: self
+                .response_time
+                .filter(|_| self.is_up)
+                .map(|rtt| rtt.as_secs_f64() * 1000.0),
+            os_guess: None,
         }
     }
 }
+
+/// Operating system family for a reply TTL, see `PingResult::os_family`
+pub fn os_family_from_ttl(ttl: u8) -> Option<&'static str> {
+    match ttl {
+        0 => None,
+        1..=64 => Some("Linux/Unix"),
+        65..=128 => Some("Windows"),
+        129..=255 => Some("Network device"),
+    }
+}
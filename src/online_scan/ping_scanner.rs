@@ -1,35 +1,155 @@
 use indicatif::ProgressBar;
+use log::info;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::{
     Packet,
-    icmp::{IcmpTypes, echo_request::MutableEchoRequestPacket},
+    icmp::{IcmpPacket, IcmpTypes, echo_request::MutableEchoRequestPacket},
 };
 use pnet::transport::{
-    TransportChannelType, TransportProtocol, icmp_packet_iter, transport_channel,
+    TransportChannelType, TransportProtocol, ipv4_packet_iter, transport_channel,
 };
 use pnet::util::checksum;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
 use std::net::IpAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use super::PingResult;
+use crate::port_scan::port_scan::{ScanPlan, is_cancelled};
+use crate::util::humanize_duration;
+
 static TIMEOUT: Duration = Duration::from_secs(3);
 // static MAX_PINGS_PER_SECOND: u64 = 10000;
 static SEND_DELAY_NANOS: Duration = Duration::from_micros(10);
 
+/// Counts sent by `ping_scan_results` after each echo request goes out and
+/// each reply comes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanProgress {
+    /// Echo requests sent so far
+    pub sent: usize,
+    /// Hosts that have replied so far
+    pub received: usize,
+    /// Hosts being pinged
+    pub total: usize,
+}
+
+/// Reachability of the hosts in one ping scan, from the same send times as
+/// each `PingResult::response_time`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PingSummary {
+    /// Echo requests sent
+    pub sent: usize,
+    /// Hosts that replied
+    pub received: usize,
+    /// Share of requests that got no reply, from 0 to 100
+    pub loss_percent: f64,
+    /// Round trip times of the replies, None if there were none
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    pub avg_rtt: Option<Duration>,
+}
+
+impl PingSummary {
+    /// Summary of a scan that sent `sent` requests and got `results` back
+    pub fn from_results(sent: usize, results: &[PingResult]) -> Self {
+        let rtts: Vec<Duration> = results
+            .iter()
+            .filter(|result| result.is_up)
+            .filter_map(|result| result.response_time)
+            .collect();
+        let received = results.iter().filter(|result| result.is_up).count();
+
+        PingSummary {
+            sent,
+            received,
+            loss_percent: if sent == 0 {
+                0.0
+            } else {
+                sent.saturating_sub(received) as f64 * 100.0 / sent as f64
+            },
+            min_rtt: rtts.iter().min().copied(),
+            max_rtt: rtts.iter().max().copied(),
+            avg_rtt: (!rtts.is_empty()).then(|| rtts.iter().sum::<Duration>() / rtts.len() as u32),
+        }
+    }
+}
+
+impl fmt::Display for PingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} received, {:.1}% loss",
+            self.sent, self.received, self.loss_percent
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min_rtt, self.avg_rtt, self.max_rtt) {
+            write!(
+                f,
+                ", rtt min/avg/max {:.3}/{:.3}/{:.3} ms",
+                min.as_secs_f64() * 1000.0,
+                avg.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What `ping_scan` would send to `hosts`, one echo request each, without
+/// opening a channel or sending anything
+pub fn ping_scan_plan(hosts: &[IpAddr]) -> ScanPlan {
+    let rate_pps = (Duration::from_secs(1).as_nanos() / SEND_DELAY_NANOS.as_nanos()) as u32;
+    ScanPlan::new(hosts.len(), 1, rate_pps, TIMEOUT)
+}
+
 pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
-    let results = Arc::new(Mutex::new(Vec::<IpAddr>::new()));
+    Ok(ping_scan_results(hosts, None, None)?
+        .into_iter()
+        .map(|result| result.host)
+        .collect())
+}
+
+/// Like `ping_scan`, but with the reply TTL of each host that answered.
+/// With a `progress` sender, counts go there instead of to a progress bar.
+/// Setting `cancel` stops sending and returns the hosts that answered so
+/// far, without waiting out the timeout.
+pub fn ping_scan_results(
+    hosts: Vec<IpAddr>,
+    progress: Option<Sender<ScanProgress>>,
+    cancel: Option<Arc<AtomicBool>>,
+) -> Result<Vec<PingResult>, Box<dyn std::error::Error>> {
+    Ok(ping_scan_summary(hosts, progress, cancel)?.0)
+}
 
-    // Create a receiver channel for ICMP packets
+/// Like `ping_scan_results`, along with the loss and round trip times of
+/// the whole scan
+pub fn ping_scan_summary(
+    hosts: Vec<IpAddr>,
+    progress: Option<Sender<ScanProgress>>,
+    cancel: Option<Arc<AtomicBool>>,
+) -> Result<(Vec<PingResult>, PingSummary), Box<dyn std::error::Error>> {
+    let results = Arc::new(Mutex::new(Vec::<PingResult>::new()));
+
+    let total = hosts.len();
+    let sent = Arc::new(AtomicUsize::new(0));
+    let received = Arc::new(AtomicUsize::new(0));
+
+    // Create a receiver channel for ICMP packets. Layer 3 keeps the IP header,
+    // which has the reply TTL
     let (_, mut rx) = transport_channel(
         1024,
-        TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
+        TransportChannelType::Layer3(IpNextHeaderProtocols::Icmp),
     )?;
 
     // Create a map to store host identifiers
-    let requests: Arc<Mutex<HashMap<u16, IpAddr>>> = Arc::new(Mutex::new(HashMap::new()));
+    // (host, sequence number) -> when its request was sent
+    let requests: Arc<Mutex<HashMap<(IpAddr, u16), Instant>>> =
+        Arc::new(Mutex::new(HashMap::new()));
 
     let finished_sending_time = Arc::new(AtomicBool::new(false));
 
@@ -38,17 +158,25 @@ pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::
     let recv_results = Arc::clone(&results);
     let recv_requests = Arc::clone(&requests);
     let recv_finished_sending_time = Arc::clone(&finished_sending_time);
+    let recv_sent = Arc::clone(&sent);
+    let recv_received = Arc::clone(&received);
+    let recv_progress = progress.clone();
+    let recv_cancel = cancel.clone();
     let receiver_handle = thread::spawn(move || {
-        let mut iter = icmp_packet_iter(&mut rx);
+        let mut iter = ipv4_packet_iter(&mut rx);
         let mut finish_sending_time: Option<Instant> = None;
         // let mut pb: Option<ProgressBar> = None;
 
         // Keep receiving until timeout or all hosts are accounted for
         loop {
+            if is_cancelled(&recv_cancel) {
+                break;
+            }
+
             // Stop reciving loop if timeout is reached
             // let time = finished_sending_time;
-            if finish_sending_time.is_some() {
-                let delay = finish_sending_time.unwrap().elapsed();
+            if let Some(finish_sending_time) = finish_sending_time {
+                let delay = finish_sending_time.elapsed();
                 // pb.as_ref().unwrap().set_position(delay.as_millis() as u64);
                 if delay >= TIMEOUT {
                     // pb.unwrap().finish_and_clear();
@@ -59,7 +187,7 @@ pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::
             {
                 finish_sending_time = Some(Instant::now());
                 // pb = Some(ProgressBar::new(TIMEOUT.as_millis() as u64));
-                println!("Waiting {} seconds for timeout...", TIMEOUT.as_secs())
+                info!("Waiting {} for timeout...", humanize_duration(TIMEOUT))
             }
             // if time.is_some() {
             //     println!("{}", time.unwrap().elapsed().as_millis())
@@ -69,19 +197,34 @@ pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::
             // };
 
             match iter.next_with_timeout(Duration::from_millis(3)) {
-                Ok(Some((packet, _))) => {
-                    if packet.get_icmp_type() == IcmpTypes::EchoReply {
+                Ok(Some((ip_packet, _))) => {
+                    if let Some(packet) = IcmpPacket::new(ip_packet.payload())
+                        && packet.get_icmp_type() == IcmpTypes::EchoReply
+                        && packet.payload().len() >= 4
+                    {
                         let payload = packet.payload();
                         let id = ((payload[2] as u16) << 8) + (payload[3] as u16);
+                        // Only a reply from the host a request went to counts for it
+                        let host = IpAddr::V4(ip_packet.get_source());
 
-                        let host_option = {
-                            let ids = recv_requests.lock().unwrap();
-                            ids.get(&id).cloned()
+                        // Removed so a duplicate reply isn't counted twice
+                        let sent_option = {
+                            let mut ids = recv_requests.lock().unwrap();
+                            ids.remove(&(host, id))
                         };
 
-                        if let Some(host) = host_option {
+                        if let Some(sent_at) = sent_option {
                             let mut results = recv_results.lock().unwrap();
-                            results.push(host);
+                            results.push(PingResult {
+                                host,
+                                is_up: true,
+                                response_time: Some(sent_at.elapsed()),
+                                ttl: Some(ip_packet.get_ttl()),
+                            });
+                            drop(results);
+
+                            recv_received.fetch_add(1, Ordering::Relaxed);
+                            report(&recv_progress, &recv_sent, &recv_received, total);
                             // results.push(PingResult {
                             //     host,
                             //     is_up: true,
@@ -103,22 +246,39 @@ pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::
     let sender_requests = Arc::clone(&requests);
     // let sender_results = Arc::clone(&results);
     let sender_finished_sending_time = Arc::clone(&finished_sending_time);
+    let sender_sent = Arc::clone(&sent);
+    let sender_received = Arc::clone(&received);
     let sender_handle = thread::spawn(move || {
-        let pb = ProgressBar::new(hosts.len() as u64);
+        // A caller listening for progress draws its own
+        let pb = if progress.is_some() {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(hosts.len() as u64)
+        };
         // let mut last_send_time = Instant::now();
         for (i, host) in hosts.iter().enumerate() {
-            let host_clone = host.clone();
+            if is_cancelled(&cancel) {
+                info!("Ping scan cancelled after {} of {} hosts", i, hosts.len());
+                break;
+            }
+            let host_clone = *host;
 
-            // Use the index as a unique identifier for each host
+            // The index as the sequence number. It wraps after 65536 hosts,
+            // but replies are matched on their source too, so only a host
+            // listed twice that far apart would share one.
             let identifier: u16 = i as u16;
 
             // Store the host-identifier mapping
             {
                 let mut ids = sender_requests.lock().unwrap();
-                ids.insert(identifier, host_clone);
+                // Just before sending, so the round trip doesn't include waiting on the lock
+                ids.insert((host_clone, identifier), Instant::now());
             }
 
+            // Counted first, a reply can arrive before send_ping returns
+            sender_sent.fetch_add(1, Ordering::Relaxed);
             let _ = send_ping(host_clone, identifier);
+            report(&progress, &sender_sent, &sender_received, total);
 
             // let now = Instant::now();
             // let delay = MAX_RATE_NANOS - last_send_time.duration_since(now).as_nanos() as u64;
@@ -140,7 +300,26 @@ pub fn ping_scan(hosts: Vec<IpAddr>) -> Result<Vec<IpAddr>, Box<dyn std::error::
     receiver_handle.join().unwrap();
 
     let results = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
-    Ok(results)
+    let summary = PingSummary::from_results(sent.load(Ordering::Relaxed), &results);
+    info!("Ping scan: {}", summary);
+    Ok((results, summary))
+}
+
+// Send the current counts, if anyone is listening. A caller that stopped
+// listening doesn't stop the scan.
+fn report(
+    progress: &Option<Sender<ScanProgress>>,
+    sent: &AtomicUsize,
+    received: &AtomicUsize,
+    total: usize,
+) {
+    if let Some(progress) = progress {
+        let _ = progress.send(ScanProgress {
+            sent: sent.load(Ordering::Relaxed),
+            received: received.load(Ordering::Relaxed),
+            total,
+        });
+    }
 }
 
 fn send_ping(target: IpAddr, identifier: u16) -> Result<(), Box<dyn std::error::Error>> {
@@ -151,7 +330,7 @@ fn send_ping(target: IpAddr, identifier: u16) -> Result<(), Box<dyn std::error::
     )?;
 
     // Create an ICMP packet
-    let mut vec = vec![0; 8];
+    let mut vec = [0; 8];
     let mut echo_packet = MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
 
     // Fill in the ICMP packet details
@@ -166,3 +345,107 @@ fn send_ping(target: IpAddr, identifier: u16) -> Result<(), Box<dyn std::error::
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn reply(last_octet: u8, rtt_ms: Option<u64>) -> PingResult {
+        PingResult {
+            host: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last_octet)),
+            is_up: rtt_ms.is_some(),
+            response_time: rtt_ms.map(Duration::from_millis),
+            ttl: rtt_ms.map(|_| 64),
+        }
+    }
+
+    #[test]
+    fn summary_counts_loss_and_round_trips() {
+        let results = [reply(1, Some(10)), reply(2, Some(30)), reply(3, Some(20))];
+        let summary = PingSummary::from_results(10, &results);
+
+        assert_eq!(summary.sent, 10);
+        assert_eq!(summary.received, 3);
+        assert_eq!(summary.loss_percent, 70.0);
+        assert_eq!(summary.min_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(summary.max_rtt, Some(Duration::from_millis(30)));
+        assert_eq!(summary.avg_rtt, Some(Duration::from_millis(20)));
+        assert_eq!(
+            summary.to_string(),
+            "10 sent, 3 received, 70.0% loss, rtt min/avg/max 10.000/20.000/30.000 ms"
+        );
+    }
+
+    #[test]
+    fn summary_skips_hosts_that_are_down() {
+        let results = [reply(1, Some(5)), reply(2, None)];
+        let summary = PingSummary::from_results(2, &results);
+
+        assert_eq!(summary.received, 1);
+        assert_eq!(summary.loss_percent, 50.0);
+        assert_eq!(summary.min_rtt, Some(Duration::from_millis(5)));
+        assert_eq!(summary.avg_rtt, Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn summary_of_nothing_sent_has_no_loss() {
+        let summary = PingSummary::from_results(0, &[]);
+
+        assert_eq!(summary.received, 0);
+        assert_eq!(summary.loss_percent, 0.0);
+        assert_eq!(summary.min_rtt, None);
+        assert_eq!(summary.max_rtt, None);
+        assert_eq!(summary.avg_rtt, None);
+        assert_eq!(summary.to_string(), "0 sent, 0 received, 0.0% loss");
+    }
+
+    #[test]
+    fn plan_sends_one_echo_request_per_host() {
+        let hosts = vec![IpAddr::V4(Ipv4Addr::LOCALHOST); 1000];
+        let plan = ping_scan_plan(&hosts);
+
+        assert_eq!(plan.targets, 1000);
+        assert_eq!(plan.probes_per_target, 1);
+        assert_eq!(plan.probes, 1000);
+        assert_eq!(plan.rate_pps, 100_000);
+        assert_eq!(plan.estimated_duration, TIMEOUT + Duration::from_millis(10));
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn localhost_replies_carry_the_default_ttl() {
+        // The kernel answers with its own default, unchanged by any hop
+        let default_ttl: u8 = std::fs::read_to_string("/proc/sys/net/ipv4/ip_default_ttl")
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let (results, summary) = ping_scan_summary(vec![loopback], None, None).unwrap();
+        let [result] = &results[..] else {
+            panic!("expected one reply, got {:?}", results);
+        };
+        assert_eq!(result.host, loopback);
+        assert!(result.is_up);
+        assert_eq!(result.ttl, Some(default_ttl));
+        assert!(result.os_family().is_some());
+        assert!(result.response_time.unwrap() < TIMEOUT);
+        assert_eq!((summary.sent, summary.received), (1, 1));
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn cancelled_scan_returns_without_waiting_for_the_timeout() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let hosts = vec![IpAddr::V4(Ipv4Addr::LOCALHOST); 10];
+
+        let start = Instant::now();
+        let (results, summary) = ping_scan_summary(hosts, None, Some(cancel)).unwrap();
+
+        assert!(start.elapsed() < TIMEOUT, "{:?}", start.elapsed());
+        assert!(results.is_empty());
+        assert_eq!(summary.sent, 0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use log::info;
+
+use super::PingResult;
+use crate::port_scan::{
+    port_scan::{ScanConfig, ScanType},
+    tcp_scan::tcp_scan,
+};
+
+/// Ports `scan --tcp-ping` probes: web, SSH and RDP, which hosts that drop
+/// ICMP most often still answer on
+pub const DEFAULT_PROBE_PORTS: [u16; 4] = [80, 443, 22, 3389];
+
+/// Host discovery for networks that drop ICMP. A SYN goes to each of
+/// `probe_ports` on every host, and a host is up if any of them answers,
+/// with a SYN+ACK or a RST, since either proves something is there. Like
+/// `ping_scan_results`, only hosts that answered are returned, though
+/// without a round trip time or TTL. Fails like `tcp_scan`.
+pub fn tcp_ping(
+    hosts: Vec<IpAddr>,
+    probe_ports: Vec<u16>,
+    timeout: Duration,
+) -> io::Result<Vec<PingResult>> {
+    tcp_ping_with(
+        hosts,
+        probe_ports,
+        &ScanConfig {
+            timeout,
+            ..Default::default()
+        },
+    )
+}
+
+/// `tcp_ping` sending from `config`'s interface at its rate, waiting its
+/// timeout for replies. Its scan type is ignored, only SYN probes get an
+/// answer from both open and closed ports.
+pub fn tcp_ping_with(
+    hosts: Vec<IpAddr>,
+    probe_ports: Vec<u16>,
+    config: &ScanConfig,
+) -> io::Result<Vec<PingResult>> {
+    let config = ScanConfig {
+        scan_type: ScanType::Syn,
+        ..config.clone()
+    };
+    let ports = probe_ports.iter().map(|port| *port as i32).collect();
+
+    let results: Vec<PingResult> = tcp_scan(hosts, ports, &config)?
+        .into_iter()
+        .filter(|result| !result.open_ports.is_empty() || !result.closed_ports.is_empty())
+        .map(|result| PingResult {
+            is_up: true,
+            ..PingResult::create(result.ip)
+        })
+        .collect();
+
+    info!("{} hosts answered a TCP ping", results.len());
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn listening_and_closed_ports_both_answer() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        // A FIN would get nothing from the listener, the probes are SYNs anyway
+        let config = ScanConfig {
+            scan_type: ScanType::Fin,
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let results = tcp_ping_with(vec![loopback], vec![open], &config).unwrap();
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        assert_eq!(result.host, loopback);
+        assert!(result.is_up);
+        assert_eq!(result.response_time, None);
+
+        // A reset says the host is there just as well
+        let results = tcp_ping(vec![loopback], vec![closed], Duration::from_millis(500)).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_up);
+    }
+}
@@ -0,0 +1,72 @@
+use rocksdb::WriteBatch;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{DatabaseError, ResultDatabase, host_key},
+    index::IndexManager,
+};
+
+/// What was seen of a host's TCP/IP stack, and the operating system guessed
+/// from it. Stored as JSON in the os column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OsInfo {
+    /// TTL of the host's replies as received, before any hops are counted back
+    #[serde(default)]
+    pub ttl_observed: Option<u8>,
+    /// TCP window size the host offered in its SYN-ACK
+    #[serde(default)]
+    pub window: Option<u16>,
+    /// Name of the operating system, e.g. "Linux" or "Windows"
+    pub guess: String,
+    /// How sure the guess is, from 0 to 100
+    #[serde(default)]
+    pub confidence: u8,
+}
+
+impl OsInfo {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("OsInfo serializes")
+    }
+
+    /// None if `data` isn't an `OsInfo`
+    pub(crate) fn decode(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+}
+
+impl ResultDatabase {
+    /// Record an operating system guess for a host, replacing any earlier
+    /// one. Returns false if the host isn't in the database.
+    pub fn set_os_info(
+        &self,
+        host: &str,
+        info: &OsInfo,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let db = self.writable_db()?;
+        let cfs = self.column_handles(&db)?;
+        if db.get_pinned_cf(cfs[0], host_key(host))?.is_none() {
+            return Ok(false);
+        }
+
+        let info = OsInfo {
+            guess: info.guess.trim().to_string(),
+            confidence: info.confidence.min(100),
+            ..info.clone()
+        };
+        let mut batch = WriteBatch::default();
+        IndexManager::new(self, &db)?.put_os_info(&mut batch, host, &info);
+        db.write(batch)?;
+
+        Ok(true)
+    }
+
+    /// The operating system guess recorded for a host, None if it has none
+    pub fn get_os_info(&self, host: &str) -> Result<Option<OsInfo>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        match db.get_pinned_cf(cfs[9], host_key(host))? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
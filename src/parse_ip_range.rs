@@ -1,8 +1,10 @@
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
 };
 
+use log::warn;
 use rand::{rng, seq::SliceRandom};
 
 // static MAX_HOSTS: u32 = 1024;
@@ -118,3 +120,321 @@ fn parse_ip_range(range: &str, ips: &mut Vec<IpAddr>) -> Result<(), Box<dyn std:
 
     Ok(())
 }
+
+// Most addresses a CIDR in a targets file may expand to, a /8 of IPv4 or a
+// /104 of IPv6. Every address is held in memory, so a larger network such as
+// an IPv6 /64 would exhaust it.
+const MAX_LINE_ADDRESSES: u128 = 1 << 24;
+
+/// A line of a targets file that isn't an address or CIDR, or a CIDR too
+/// large to expand
+#[derive(Debug, Clone)]
+pub struct InvalidTarget {
+    /// Line number, starting at 1
+    pub line: usize,
+    pub text: String,
+    pub error: String,
+}
+
+/// Everything read from a targets file by `load_target_file`
+#[derive(Debug, Default, Clone)]
+pub struct TargetFile {
+    /// Addresses in file order, with CIDRs expanded to their hosts
+    pub targets: Vec<IpAddr>,
+    pub invalid: Vec<InvalidTarget>,
+}
+
+/// Read a file of targets, one IP address or CIDR per line. Blank lines and
+/// lines starting with # are skipped, and anything after a # is a comment.
+/// Lines that don't parse, and CIDRs larger than a /8 of IPv4 or a /104 of
+/// IPv6, are collected in `TargetFile::invalid` rather than failing the load.
+pub fn load_target_file(path: &str) -> Result<TargetFile, io::Error> {
+    let mut file = TargetFile::default();
+
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let target = line.split('#').next().unwrap_or_default().trim();
+        if target.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = parse_target_line(target, &mut file.targets) {
+            file.invalid.push(InvalidTarget {
+                line: number + 1,
+                text: target.to_string(),
+                error: e.to_string(),
+            });
+        }
+    }
+
+    Ok(file)
+}
+
+// Add the address or CIDR hosts of one line of a targets file to `targets`
+fn parse_target_line(
+    target: &str,
+    targets: &mut Vec<IpAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !target.contains('/') {
+        targets.push(IpAddr::from_str(target)?);
+        return Ok(());
+    }
+
+    let prefix: IpPrefix = target.parse()?;
+    let (first, last) = prefix.bounds();
+    if last - first >= MAX_LINE_ADDRESSES {
+        return Err(format!(
+            "{} has more than {} addresses, split it into smaller networks",
+            target, MAX_LINE_ADDRESSES
+        )
+        .into());
+    }
+    targets.extend(prefix.hosts());
+
+    Ok(())
+}
+
+/// The addresses in a targets file, see `load_target_file`. Invalid lines
+/// are logged as warnings and skipped.
+pub fn load_targets(path: &str) -> Result<Vec<IpAddr>, io::Error> {
+    let file = load_target_file(path)?;
+    for invalid in &file.invalid {
+        warn!(
+            "{}:{}: skipping \"{}\": {}",
+            path, invalid.line, invalid.text, invalid.error
+        );
+    }
+    if !file.invalid.is_empty() {
+        warn!(
+            "Skipped {} invalid lines in {}, loaded {} targets",
+            file.invalid.len(),
+            path,
+            file.targets.len()
+        );
+    }
+
+    Ok(file.targets)
+}
+
+/// An IPv4 or IPv6 network such as 192.168.10.0/24 or 2001:db8::/48
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    pub network: IpAddr,
+    pub len: u8,
+}
+
+impl IpPrefix {
+    /// Whether `ip` is inside this network. Addresses of the other family never are.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// First and last address of the network as integers
+    fn bounds(&self) -> (u128, u128) {
+        match self.network {
+            IpAddr::V4(network) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                let first = u32::from(network) & mask;
+                (first as u128, (first | !mask) as u128)
+            }
+            IpAddr::V6(network) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                let first = u128::from(network) & mask;
+                (first, first | !mask)
+            }
+        }
+    }
+
+    /// Every address in the network, including the IPv4 network and broadcast addresses
+    pub fn addresses(&self) -> PrefixAddrs {
+        let (first, last) = self.bounds();
+        PrefixAddrs {
+            next: Some(first),
+            last,
+            ipv4: self.network.is_ipv4(),
+        }
+    }
+
+    /// Addresses that can be assigned to hosts. For IPv4 networks larger than
+    /// a /31 this skips the network and broadcast addresses.
+    pub fn hosts(&self) -> PrefixAddrs {
+        let mut addresses = self.addresses();
+        if addresses.ipv4 && self.len < 31 {
+            addresses.next = addresses.next.map(|first| first + 1);
+            addresses.last -= 1;
+        }
+        addresses
+    }
+}
+
+/// Iterator over the addresses in an `IpPrefix`, generated one at a time so
+/// large networks don't have to fit in memory
+#[derive(Debug, Clone)]
+pub struct PrefixAddrs {
+    next: Option<u128>,
+    last: u128,
+    ipv4: bool,
+}
+
+impl Iterator for PrefixAddrs {
+    type Item = IpAddr;
+
+    fn next(&mut self) -> Option<IpAddr> {
+        let current = self.next?;
+        self.next = current.checked_add(1).filter(|next| *next <= self.last);
+
+        Some(if self.ipv4 {
+            IpAddr::V4(Ipv4Addr::from(current as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(current))
+        })
+    }
+}
+
+/// Host addresses in an IPv4 or IPv6 CIDR such as 10.0.0.0/24 or 2001:db8::/120.
+/// See `IpPrefix::hosts`, and `IpPrefix::addresses` to include the IPv4
+/// network and broadcast addresses.
+pub fn expand_cidr(cidr: &str) -> Result<PrefixAddrs, Box<dyn std::error::Error>> {
+    Ok(cidr.parse::<IpPrefix>()?.hosts())
+}
+
+impl FromStr for IpPrefix {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let Some((ip, len)) = cidr.trim().split_once('/') else {
+            return Err(format!("Invalid CIDR \"{}\": missing prefix length", cidr).into());
+        };
+
+        let network = IpAddr::from_str(ip)?;
+        let len: u8 = len.parse()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        if len > max {
+            return Err(format!("Invalid CIDR prefix length {} (maximum {})", len, max).into());
+        }
+
+        Ok(IpPrefix { network, len })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(cidr: &str) -> Vec<String> {
+        let prefix: IpPrefix = cidr.parse().unwrap();
+        prefix.hosts().map(|ip| ip.to_string()).collect()
+    }
+
+    #[test]
+    fn ipv4_hosts_skip_network_and_broadcast() {
+        assert_eq!(hosts("192.168.1.0/30"), ["192.168.1.1", "192.168.1.2"]);
+        // The network is masked, whichever address in it was given
+        assert_eq!(hosts("192.168.1.3/30"), ["192.168.1.1", "192.168.1.2"]);
+        assert_eq!(hosts("10.0.0.0/31"), ["10.0.0.0", "10.0.0.1"]);
+        assert_eq!(hosts("10.0.0.7/32"), ["10.0.0.7"]);
+        assert_eq!(hosts("10.0.0.0/24").len(), 254);
+    }
+
+    #[test]
+    fn ipv6_hosts_include_every_address() {
+        assert_eq!(hosts("2001:db8::1/128"), ["2001:db8::1"]);
+        assert_eq!(
+            hosts("2001:db8::/126"),
+            ["2001:db8::", "2001:db8::1", "2001:db8::2", "2001:db8::3"]
+        );
+        let last = "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128";
+        assert_eq!(hosts(last), ["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"]);
+    }
+
+    #[test]
+    fn addresses_include_network_and_broadcast() {
+        let prefix: IpPrefix = "192.168.1.0/30".parse().unwrap();
+        assert_eq!(prefix.addresses().count(), 4);
+        assert!(prefix.contains(&"192.168.1.3".parse().unwrap()));
+        assert!(!prefix.contains(&"192.168.1.4".parse().unwrap()));
+        assert!(!prefix.contains(&"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn target_files_skip_comments_and_collect_bad_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("targets.txt");
+        fs::write(
+            &path,
+            "# lab networks\n\
+             10.0.0.1\n\
+             \n\
+             \t10.0.1.0/30  # two hosts\n\
+             not-an-address\n\
+             2001:db8::1\n\
+             10.0.0.0/33\n\
+             2001:db8::/64\n\
+             \x20\x20# indented comment\n\
+             2001:db8::/103\n\
+             2001:db8::100/120\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let file = load_target_file(path).unwrap();
+        let invalid: Vec<(usize, &str)> = file
+            .invalid
+            .iter()
+            .map(|invalid| (invalid.line, invalid.text.as_str()))
+            .collect();
+        assert_eq!(
+            invalid,
+            [
+                (5, "not-an-address"),
+                (7, "10.0.0.0/33"),
+                (8, "2001:db8::/64"),
+                (10, "2001:db8::/103"),
+            ]
+        );
+        assert_eq!(
+            file.invalid[2].error,
+            "2001:db8::/64 has more than 16777216 addresses, split it into smaller networks"
+        );
+
+        let targets = load_targets(path).unwrap();
+        assert_eq!(targets.len(), 1 + 2 + 1 + 256);
+        assert_eq!(
+            targets[..4],
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.1.1".parse().unwrap(),
+                "10.0.1.2".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+            ]
+        );
+        assert_eq!(targets[4], "2001:db8::100".parse::<IpAddr>().unwrap());
+        assert_eq!(targets.last(), Some(&"2001:db8::1ff".parse().unwrap()));
+        assert!(load_targets(&format!("{}.missing", path)).is_err());
+    }
+
+    #[test]
+    fn invalid_prefixes_are_rejected() {
+        for cidr in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0",
+            "10.0.0.0/",
+            "10.0.0/24",
+            "x/8",
+        ] {
+            assert!(cidr.parse::<IpPrefix>().is_err(), "{}", cidr);
+        }
+        let e = "10.0.0.0/33".parse::<IpPrefix>().unwrap_err();
+        assert_eq!(e.to_string(), "Invalid CIDR prefix length 33 (maximum 32)");
+    }
+}
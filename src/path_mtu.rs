@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use pnet::packet::{
+    Packet,
+    icmp::{IcmpTypes, destination_unreachable::IcmpCodes, echo_request::MutableEchoRequestPacket},
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
+};
+use pnet::transport::{
+    TransportChannelType, TransportProtocol, icmp_packet_iter, transport_channel,
+};
+use pnet::util::checksum;
+use rocksdb::{IteratorMode, WriteBatch};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{PREFIX_COLUMN, ResultDatabase, column_handle},
+    port_scan::port_scan::PortScanResult,
+};
+
+/// MTU of a plain Ethernet path
+pub const STANDARD_MTU: u16 = 1500;
+/// MSS a host on a plain Ethernet path offers (MTU minus IPv4 and TCP headers)
+pub const STANDARD_MSS: u16 = STANDARD_MTU - 40;
+
+// Packet sizes tried by the active probe, largest first
+const PROBE_SIZES: [u16; 8] = [1500, 1492, 1480, 1460, 1400, 1280, 1024, 576];
+// Smallest MTU an IPv4 path may have
+const MIN_MTU: u16 = 68;
+
+/// Path characteristics recorded for a network prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixPath {
+    /// e.g. "10.0.0.0/24"
+    pub prefix: String,
+    /// Smallest MSS seen in SYN+ACKs from the prefix
+    pub min_mss: Option<u16>,
+    /// Path MTU found by the active probe
+    pub path_mtu: Option<u16>,
+}
+
+impl PrefixPath {
+    pub fn new(prefix: String) -> Self {
+        PrefixPath {
+            prefix,
+            min_mss: None,
+            path_mtu: None,
+        }
+    }
+
+    /// Smaller than a standard Ethernet path
+    pub fn is_anomalous(&self) -> bool {
+        self.path_mtu.is_some_and(|mtu| mtu < STANDARD_MTU)
+            || self.min_mss.is_some_and(|mss| mss < STANDARD_MSS)
+    }
+
+    /// MSS to advertise so replies fit the discovered path, if it is known to be small
+    pub fn clamp_mss(&self) -> Option<u16> {
+        self.path_mtu
+            .filter(|mtu| *mtu < STANDARD_MTU)
+            .map(|mtu| mtu.saturating_sub(40).max(MIN_MTU))
+    }
+
+    fn merge(&mut self, other: &PrefixPath) {
+        if let Some(mss) = other.min_mss {
+            self.min_mss = Some(self.min_mss.map_or(mss, |m| m.min(mss)));
+        }
+        if other.path_mtu.is_some() {
+            self.path_mtu = other.path_mtu;
+        }
+    }
+}
+
+/// The /24 (IPv4) or /64 (IPv6) network an address belongs to
+pub fn prefix_of(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+/// Group the MSS values from a port scan by prefix
+pub fn prefix_paths_from_scan(results: &[PortScanResult]) -> Vec<PrefixPath> {
+    let mut paths: HashMap<String, PrefixPath> = HashMap::new();
+
+    for result in results {
+        if let Some(mss) = result.mss {
+            let prefix = prefix_of(&result.ip);
+            paths
+                .entry(prefix.clone())
+                .or_insert_with(|| PrefixPath::new(prefix))
+                .merge(&PrefixPath {
+                    prefix: String::new(),
+                    min_mss: Some(mss),
+                    path_mtu: None,
+                });
+        }
+    }
+
+    paths.into_values().collect()
+}
+
+/// What came back for one probe packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ProbeEvent {
+    /// Echo reply, so a packet of the probed size got through
+    Reply,
+    /// ICMP fragmentation needed, with the next-hop MTU if the router sent one
+    FragNeeded(Option<u16>),
+    /// No answer, or the packet was too large to leave this host
+    Timeout,
+}
+
+/// Walks down the probe sizes until a packet gets through
+pub(crate) struct PmtuProbe {
+    sizes: Vec<u16>,
+    index: usize,
+    found: Option<u16>,
+}
+
+impl PmtuProbe {
+    pub(crate) fn new() -> Self {
+        PmtuProbe {
+            sizes: PROBE_SIZES.to_vec(),
+            index: 0,
+            found: None,
+        }
+    }
+
+    /// Size of the next packet to send, or None once finished
+    pub(crate) fn next_size(&self) -> Option<u16> {
+        if self.found.is_some() {
+            None
+        } else {
+            self.sizes.get(self.index).copied()
+        }
+    }
+
+    pub(crate) fn on_event(&mut self, event: ProbeEvent) {
+        let Some(size) = self.next_size() else {
+            return;
+        };
+
+        match event {
+            ProbeEvent::Reply => self.found = Some(size),
+            // Jump straight to the size the router asked for when it is plausible
+            ProbeEvent::FragNeeded(Some(mtu)) if (MIN_MTU..size).contains(&mtu) => {
+                self.sizes.retain(|s| *s < mtu);
+                self.sizes.insert(0, mtu);
+                self.index = 0;
+            }
+            ProbeEvent::FragNeeded(_) | ProbeEvent::Timeout => self.index += 1,
+        }
+    }
+
+    /// Path MTU once a probe got through
+    pub(crate) fn path_mtu(&self) -> Option<u16> {
+        self.found
+    }
+}
+
+/// Find the path MTU to a host by sending DF-flagged echo requests of
+/// decreasing size. Needs raw socket privileges.
+pub fn probe_path_mtu(
+    target: Ipv4Addr,
+    timeout: Duration,
+) -> Result<Option<u16>, Box<dyn std::error::Error>> {
+    let (mut tx, _) = transport_channel(
+        4096,
+        TransportChannelType::Layer3(IpNextHeaderProtocols::Icmp),
+    )?;
+    let (_, mut rx) = transport_channel(
+        4096,
+        TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
+    )?;
+    let mut iter = icmp_packet_iter(&mut rx);
+
+    let identifier: u16 = rand::random();
+    let mut probe = PmtuProbe::new();
+
+    while let Some(size) = probe.next_size() {
+        let mut buffer = vec![0u8; size as usize];
+        build_probe(&mut buffer, target, identifier, size);
+
+        if tx
+            .send_to(Ipv4Packet::new(&buffer).unwrap(), IpAddr::V4(target))
+            .is_err()
+        {
+            // EMSGSIZE: larger than the local interface allows
+            probe.on_event(ProbeEvent::Timeout);
+            continue;
+        }
+
+        let start = Instant::now();
+        let mut event = ProbeEvent::Timeout;
+        while start.elapsed() < timeout {
+            let Ok(Some((packet, addr))) = iter.next_with_timeout(Duration::from_millis(10)) else {
+                continue;
+            };
+            let payload = packet.payload();
+            if payload.len() < 4 {
+                continue;
+            }
+
+            if packet.get_icmp_type() == IcmpTypes::EchoReply
+                && addr == IpAddr::V4(target)
+                && u16::from_be_bytes([payload[0], payload[1]]) == identifier
+                && u16::from_be_bytes([payload[2], payload[3]]) == size
+            {
+                event = ProbeEvent::Reply;
+                break;
+            }
+
+            // Fragmentation needed quotes our IP header after the unused/MTU words
+            if packet.get_icmp_type() == IcmpTypes::DestinationUnreachable
+                && packet.get_icmp_code() == IcmpCodes::FragmentationRequiredAndDFFlagSet
+                && let Some(quoted) = Ipv4Packet::new(&payload[4..])
+                && quoted.get_destination() == target
+            {
+                let mtu = u16::from_be_bytes([payload[2], payload[3]]);
+                event = ProbeEvent::FragNeeded((mtu != 0).then_some(mtu));
+                break;
+            }
+        }
+
+        probe.on_event(event);
+    }
+
+    Ok(probe.path_mtu())
+}
+
+/// Fill `buffer` with a DF-flagged IPv4 echo request of exactly `size` bytes
+fn build_probe(buffer: &mut [u8], target: Ipv4Addr, identifier: u16, size: u16) {
+    {
+        let mut echo = MutableEchoRequestPacket::new(&mut buffer[20..]).unwrap();
+        echo.set_icmp_type(IcmpTypes::EchoRequest);
+        echo.set_identifier(identifier);
+        // The sequence number carries the size so late replies can't be misattributed
+        echo.set_sequence_number(size);
+        let sum = checksum(echo.packet(), 1);
+        echo.set_checksum(sum);
+    }
+
+    let mut ip = MutableIpv4Packet::new(buffer).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length(size);
+    ip.set_ttl(64);
+    ip.set_flags(Ipv4Flags::DontFragment);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    // Left unspecified so the kernel fills in the outgoing address and checksum
+    ip.set_source(Ipv4Addr::UNSPECIFIED);
+    ip.set_destination(target);
+}
+
+impl ResultDatabase {
+    /// Merge path observations into the stored prefix metadata
+    pub fn update_prefix_paths(
+        &self,
+        paths: &[PrefixPath],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let db = self.writable_db()?;
+        let cf = column_handle(&db, PREFIX_COLUMN)?;
+        let mut batch = WriteBatch::default();
+
+        for path in paths {
+            let mut stored = match db.get_cf(cf, path.prefix.as_bytes())? {
+                Some(data) => serde_json::from_slice::<PrefixPath>(&data)?,
+                None => PrefixPath::new(path.prefix.clone()),
+            };
+            stored.merge(path);
+            batch.put_cf(cf, path.prefix.as_bytes(), serde_json::to_vec(&stored)?);
+        }
+
+        db.write(batch)?;
+
+        Ok(())
+    }
+
+    /// Every stored prefix, keyed by prefix
+    pub fn get_prefix_paths(
+        &self,
+    ) -> Result<HashMap<String, PrefixPath>, Box<dyn std::error::Error>> {
+        let db = self.db()?;
+        let cf = column_handle(&db, PREFIX_COLUMN)?;
+        let mut paths = HashMap::new();
+
+        for item in db.iterator_cf(cf, IteratorMode::Start) {
+            let (_, value) = item?;
+            let path: PrefixPath = serde_json::from_slice(&value)?;
+            paths.insert(path.prefix.clone(), path);
+        }
+
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Drive a probe against a path that passes packets up to `mtu`, with
+    // `answer` deciding what comes back for larger ones. Returns the sizes
+    // sent and the MTU found.
+    fn simulate(mtu: u16, answer: impl Fn(u16) -> ProbeEvent) -> (Vec<u16>, Option<u16>) {
+        let mut probe = PmtuProbe::new();
+        let mut sent = Vec::new();
+        while let Some(size) = probe.next_size() {
+            sent.push(size);
+            probe.on_event(if size <= mtu {
+                ProbeEvent::Reply
+            } else {
+                answer(size)
+            });
+        }
+        (sent, probe.path_mtu())
+    }
+
+    #[test]
+    fn jumps_to_the_mtu_a_router_reports() {
+        let (sent, mtu) = simulate(1420, |_| ProbeEvent::FragNeeded(Some(1420)));
+        assert_eq!(sent, vec![1500, 1420]);
+        assert_eq!(mtu, Some(1420));
+    }
+
+    #[test]
+    fn walks_down_without_a_reported_mtu() {
+        let (sent, mtu) = simulate(1400, |_| ProbeEvent::FragNeeded(None));
+        assert_eq!(sent, vec![1500, 1492, 1480, 1460, 1400]);
+        assert_eq!(mtu, Some(1400));
+    }
+
+    #[test]
+    fn ignores_implausible_reported_mtus() {
+        // Larger than the packet that was refused, and below the IPv4 minimum
+        let (sent, mtu) = simulate(1480, |size| ProbeEvent::FragNeeded(Some(size + 100)));
+        assert_eq!(sent, vec![1500, 1492, 1480]);
+        assert_eq!(mtu, Some(1480));
+
+        let (sent, mtu) = simulate(1492, |_| ProbeEvent::FragNeeded(Some(20)));
+        assert_eq!(sent, vec![1500, 1492]);
+        assert_eq!(mtu, Some(1492));
+    }
+
+    #[test]
+    fn gives_up_when_nothing_answers() {
+        let (sent, mtu) = simulate(0, |_| ProbeEvent::Timeout);
+        assert_eq!(sent, PROBE_SIZES.to_vec());
+        assert_eq!(mtu, None);
+    }
+
+    #[test]
+    fn probe_packets_have_the_probed_size() {
+        let target = Ipv4Addr::new(192, 0, 2, 1);
+        let mut buffer = vec![0u8; 1400];
+        build_probe(&mut buffer, target, 0x1234, 1400);
+
+        let ip = Ipv4Packet::new(&buffer).unwrap();
+        assert_eq!(ip.get_total_length(), 1400);
+        assert_eq!(ip.get_flags(), Ipv4Flags::DontFragment);
+        assert_eq!(ip.get_destination(), target);
+
+        let echo = pnet::packet::icmp::echo_request::EchoRequestPacket::new(ip.payload()).unwrap();
+        assert_eq!(echo.get_identifier(), 0x1234);
+        assert_eq!(echo.get_sequence_number(), 1400);
+        assert_eq!(checksum(echo.packet(), 1), echo.get_checksum());
+    }
+}
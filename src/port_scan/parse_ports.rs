@@ -0,0 +1,169 @@
+use std::fmt;
+
+/// Why a port specification was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Nothing between two commas, or an empty spec
+    EmptySegment,
+    /// Not a number
+    InvalidPort(String),
+    /// Outside 1..=65535
+    OutOfRange(String),
+    /// Range start is greater than its end, e.g. "100-50"
+    ReversedRange(i32, i32),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::EmptySegment => write!(f, "Empty port in port list"),
+            ParseError::InvalidPort(port) => write!(f, "Invalid port \"{}\"", port),
+            ParseError::OutOfRange(port) => {
+                write!(f, "Port {} is outside the range 1-65535", port)
+            }
+            ParseError::ReversedRange(start, end) => {
+                write!(
+                    f,
+                    "Invalid port range {}-{}: start is after end",
+                    start, end
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse an nmap style port list such as "1-1024,8080,9000-9100" into
+/// sorted, deduplicated ports
+pub fn parse_ports(spec: &str) -> Result<Vec<i32>, ParseError> {
+    let mut ports = Vec::new();
+
+    for segment in spec.split(',') {
+        let segment = segment.trim();
+        if segment.is_empty() {
+            return Err(ParseError::EmptySegment);
+        }
+
+        match segment.split_once('-') {
+            Some((start, end)) => {
+                let start = parse_port(start)?;
+                let end = parse_port(end)?;
+                if start > end {
+                    return Err(ParseError::ReversedRange(start, end));
+                }
+                ports.extend(start..=end);
+            }
+            None => ports.push(parse_port(segment)?),
+        }
+    }
+
+    ports.sort();
+    ports.dedup();
+
+    Ok(ports)
+}
+
+fn parse_port(port: &str) -> Result<i32, ParseError> {
+    let port = port.trim();
+    if port.is_empty() {
+        return Err(ParseError::EmptySegment);
+    }
+    if !port.chars().all(|c| c.is_ascii_digit()) {
+        return Err(ParseError::InvalidPort(port.to_string()));
+    }
+
+    // Anything too long for a u32 is out of range as well
+    match port.parse::<u32>() {
+        Ok(value @ 1..=65535) => Ok(value as i32),
+        _ => Err(ParseError::OutOfRange(port.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lists_and_ranges() {
+        assert_eq!(parse_ports("22").unwrap(), vec![22]);
+        assert_eq!(parse_ports("1-3,8080").unwrap(), vec![1, 2, 3, 8080]);
+        assert_eq!(parse_ports("65535").unwrap(), vec![65535]);
+        assert_eq!(parse_ports("1-65535").unwrap().len(), 65535);
+    }
+
+    #[test]
+    fn trims_whitespace() {
+        assert_eq!(parse_ports(" 80 , 443 ").unwrap(), vec![80, 443]);
+        assert_eq!(parse_ports("10 - 12").unwrap(), vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn merges_duplicates_and_overlapping_ranges() {
+        assert_eq!(parse_ports("80,80,22").unwrap(), vec![22, 80]);
+        assert_eq!(
+            parse_ports("5-8,1-6,7").unwrap(),
+            (1..=8).collect::<Vec<_>>()
+        );
+        assert_eq!(parse_ports("3-3").unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn rejects_reversed_ranges() {
+        assert_eq!(
+            parse_ports("100-50"),
+            Err(ParseError::ReversedRange(100, 50))
+        );
+        assert_eq!(
+            ParseError::ReversedRange(100, 50).to_string(),
+            "Invalid port range 100-50: start is after end"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_segments() {
+        assert_eq!(parse_ports(""), Err(ParseError::EmptySegment));
+        assert_eq!(parse_ports("80,,443"), Err(ParseError::EmptySegment));
+        assert_eq!(parse_ports("80,"), Err(ParseError::EmptySegment));
+        assert_eq!(parse_ports(" , "), Err(ParseError::EmptySegment));
+        assert_eq!(parse_ports("-80"), Err(ParseError::EmptySegment));
+        assert_eq!(parse_ports("80-"), Err(ParseError::EmptySegment));
+    }
+
+    #[test]
+    fn rejects_ports_out_of_range() {
+        assert_eq!(
+            parse_ports("0"),
+            Err(ParseError::OutOfRange("0".to_string()))
+        );
+        assert_eq!(
+            parse_ports("0-10"),
+            Err(ParseError::OutOfRange("0".to_string()))
+        );
+        assert_eq!(
+            parse_ports("65536"),
+            Err(ParseError::OutOfRange("65536".to_string()))
+        );
+        assert_eq!(
+            parse_ports("1-70000"),
+            Err(ParseError::OutOfRange("70000".to_string()))
+        );
+        assert_eq!(
+            parse_ports("99999999999"),
+            Err(ParseError::OutOfRange("99999999999".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_non_numbers() {
+        assert_eq!(
+            parse_ports("http"),
+            Err(ParseError::InvalidPort("http".to_string()))
+        );
+        assert_eq!(parse_ports("-5-10"), Err(ParseError::EmptySegment));
+        assert_eq!(
+            parse_ports("1-2-3"),
+            Err(ParseError::InvalidPort("2-3".to_string()))
+        );
+    }
+}
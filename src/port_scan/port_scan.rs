@@ -1,11 +1,392 @@
-use std::net::IpAddr;
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::database::DatabaseResult;
+use pnet::packet::tcp::TcpFlags;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{database::DatabaseResult, util::humanize_duration};
+
+/// Probe rate used when none is given, about what the old fixed 100us
+/// sleep between probes managed
+pub const DEFAULT_RATE_PPS: u32 = 10_000;
+/// TCP window offered in probes when none is given, what Linux offers
+/// over Ethernet
+pub const DEFAULT_WINDOW: u16 = 64240;
+// Slowest rate `Pacer::slow_down` goes to
+const MIN_RATE_PPS: u32 = 100;
+// Sends in a row without running out of buffer space before `Pacer` speeds back up
+const RECOVER_AFTER_SENDS: u32 = 1000;
+
+/// TCP flags sent in each probe
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanType {
+    /// Half-open scan. SYN+ACK is open, RST is closed
+    #[default]
+    Syn,
+    /// No flags set. RST is closed, silence is open or filtered
+    Null,
+    /// FIN only. RST is closed, silence is open or filtered
+    Fin,
+    /// FIN, PSH and URG. RST is closed, silence is open or filtered
+    Xmas,
+}
+
+impl ScanType {
+    pub fn flags(&self) -> u8 {
+        match self {
+            ScanType::Syn => TcpFlags::SYN,
+            ScanType::Null => 0,
+            ScanType::Fin => TcpFlags::FIN,
+            ScanType::Xmas => TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG,
+        }
+    }
+}
+
+impl FromStr for ScanType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "syn" => Ok(ScanType::Syn),
+            "null" => Ok(ScanType::Null),
+            "fin" => Ok(ScanType::Fin),
+            "xmas" => Ok(ScanType::Xmas),
+            _ => Err(format!(
+                "Unknown scan type \"{}\" (expected syn, null, fin or xmas)",
+                s
+            )),
+        }
+    }
+}
+
+/// Order a scan sends its probes in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProbeOrder {
+    /// Every port of the first target, then every port of the next
+    #[default]
+    Sequential,
+    /// Every (target, port) pair in a random order, so consecutive probes
+    /// are spread over the targets rather than hitting one network in a
+    /// row. The same seed gives the same order for the same targets and
+    /// ports.
+    Shuffled(u64),
+}
+
+impl ProbeOrder {
+    /// Indexes into the targets and ports of a scan of `targets` × `ports`,
+    /// in the order their probes are sent. Shuffled pairs are worked out
+    /// one at a time rather than held in memory, however big the scan.
+    pub fn pairs(self, targets: usize, ports: usize) -> ProbePairs {
+        let len = targets as u64 * ports as u64;
+        ProbePairs {
+            permutation: match self {
+                ProbeOrder::Sequential => None,
+                ProbeOrder::Shuffled(seed) => Some(Permutation::new(len, seed)),
+            },
+            ports: ports as u64,
+            next: 0,
+            len,
+        }
+    }
+}
+
+/// (target, port) index pairs in the order a `ProbeOrder` sends them
+pub struct ProbePairs {
+    permutation: Option<Permutation>,
+    ports: u64,
+    next: u64,
+    len: u64,
+}
+
+impl Iterator for ProbePairs {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.len {
+            return None;
+        }
+        let index = match &self.permutation {
+            Some(permutation) => permutation.get(self.next),
+            None => self.next,
+        };
+        self.next += 1;
+        Some(((index / self.ports) as usize, (index % self.ports) as usize))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = (self.len - self.next) as usize;
+        (left, Some(left))
+    }
+}
+
+// A random permutation of 0..len, from a Feistel network over the smallest
+// even number of bits that holds len. Indexes it maps past len are mapped
+// again until they land inside, which takes a few rounds at most as the
+// network's range is under four times len.
+struct Permutation {
+    len: u64,
+    half_bits: u32,
+    keys: [u64; 4],
+}
+
+impl Permutation {
+    fn new(len: u64, seed: u64) -> Self {
+        let bits = u64::BITS - len.saturating_sub(1).leading_zeros();
+        Permutation {
+            len,
+            half_bits: bits.div_ceil(2).max(1),
+            keys: StdRng::seed_from_u64(seed).random(),
+        }
+    }
+
+    fn get(&self, index: u64) -> u64 {
+        let mut index = self.feistel(index);
+        while index >= self.len {
+            index = self.feistel(index);
+        }
+        index
+    }
+
+    fn feistel(&self, index: u64) -> u64 {
+        let mask = (1 << self.half_bits) - 1;
+        let (mut left, mut right) = (index >> self.half_bits, index & mask);
+        for key in self.keys {
+            (left, right) = (right, left ^ (mix(right ^ key) & mask));
+        }
+        (left << self.half_bits) | right
+    }
+}
+
+// The splitmix64 finalizer, so neighbouring inputs give unrelated outputs
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Settings for a port scan
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    /// How long to keep listening for replies after the last probe is sent
+    pub timeout: Duration,
+    /// Probes sent per second, 0 for no limit
+    pub rate_pps: u32,
+    /// Network interface to send from, by name. Picked automatically if None
+    pub interface: Option<String>,
+    /// Flags sent in each probe. Ports that stay silent in a NULL, FIN or
+    /// XMAS scan are reported in `filtered_ports`, since they may be open
+    pub scan_type: ScanType,
+    /// Set to stop the scan early, e.g. from a Ctrl-C handler. No more probes
+    /// are sent, and the results for what was probed so far are returned
+    /// without waiting out the timeout.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Order the (target, port) probes are sent in
+    pub probe_order: ProbeOrder,
+    /// TCP window size offered in each probe
+    pub window: u16,
+    /// MSS option sent in each probe. Probes carry no options if None
+    pub mss: Option<u16>,
+}
+
+impl ScanConfig {
+    /// Whether `cancel` has been set
+    pub fn cancelled(&self) -> bool {
+        is_cancelled(&self.cancel)
+    }
+}
+
+/// Whether a cancel flag is present and set
+pub(crate) fn is_cancelled(cancel: &Option<Arc<AtomicBool>>) -> bool {
+    cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            timeout: Duration::from_secs(3),
+            rate_pps: DEFAULT_RATE_PPS,
+            interface: None,
+            scan_type: ScanType::Syn,
+            cancel: None,
+            probe_order: ProbeOrder::Sequential,
+            window: DEFAULT_WINDOW,
+            mss: None,
+        }
+    }
+}
+
+/// What a scan would send, worked out from its targets and settings without
+/// opening a socket, to check a big scan before running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanPlan {
+    /// Addresses that would be probed
+    pub targets: usize,
+    /// Probes sent to each of them
+    pub probes_per_target: usize,
+    /// Probes sent in all
+    pub probes: u64,
+    /// Probes sent per second, 0 for no limit
+    pub rate_pps: u32,
+    /// How long sending takes at that rate, plus the wait for replies after
+    /// the last probe. Sending counts as instant without a limit, so this is
+    /// only a lower bound then.
+    pub estimated_duration: Duration,
+}
+
+impl ScanPlan {
+    pub fn new(targets: usize, probes_per_target: usize, rate_pps: u32, timeout: Duration) -> Self {
+        let probes = targets as u64 * probes_per_target as u64;
+        let sending = match rate_pps {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs_f64(probes as f64 / rate as f64),
+        };
+        ScanPlan {
+            targets,
+            probes_per_target,
+            probes,
+            rate_pps,
+            estimated_duration: sending + timeout,
+        }
+    }
+}
+
+impl fmt::Display for ScanPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} targets x {} probes = {} probes, ",
+            self.targets, self.probes_per_target, self.probes
+        )?;
+        match self.rate_pps {
+            0 => write!(
+                f,
+                "at least {} with no rate limit",
+                humanize_duration(self.estimated_duration)
+            ),
+            rate => write!(
+                f,
+                "about {} at {} probes/s",
+                humanize_duration(self.estimated_duration),
+                rate
+            ),
+        }
+    }
+}
+
+/// Spaces probes out to a target rate. Each probe is due a fixed interval
+/// after the previous one's deadline rather than after it was actually sent,
+/// so oversleeping on one probe is made up on the next and the average rate
+/// stays on target.
+pub(crate) struct Pacer {
+    interval: Option<Duration>,
+    // The interval asked for, which the pacer returns to after slowing down
+    target: Option<Duration>,
+    next: Instant,
+    // Sends since the last slow_down
+    streak: u32,
+}
+
+impl Pacer {
+    // How far behind schedule the pacer may fall before it stops trying to
+    // catch up, so a stalled sender doesn't follow up with a burst
+    const MAX_LAG: Duration = Duration::from_millis(50);
+
+    pub(crate) fn new(rate_pps: u32) -> Self {
+        let interval = (rate_pps > 0).then(|| Duration::from_secs(1) / rate_pps);
+        Pacer {
+            interval,
+            target: interval,
+            next: Instant::now(),
+            streak: 0,
+        }
+    }
+
+    /// Current rate in probes per second, 0 for no limit
+    pub(crate) fn rate_pps(&self) -> u32 {
+        self.interval
+            .map_or(0, |interval| (1.0 / interval.as_secs_f64()).round() as u32)
+    }
+
+    /// Halve the rate, down to MIN_RATE_PPS, after the kernel ran out of
+    /// buffer space for a probe. Without a limit, drop to DEFAULT_RATE_PPS.
+    pub(crate) fn slow_down(&mut self) {
+        let slowest = Duration::from_secs(1) / MIN_RATE_PPS;
+        self.interval = Some(match self.interval {
+            Some(interval) => (interval * 2).min(slowest),
+            None => Duration::from_secs(1) / DEFAULT_RATE_PPS,
+        });
+        self.streak = 0;
+    }
+
+    /// Count a probe that went out first time. After RECOVER_AFTER_SENDS of
+    /// them in a row the rate goes back up by a third, until it reaches the
+    /// one asked for.
+    pub(crate) fn sent(&mut self) {
+        self.streak += 1;
+        if self.streak < RECOVER_AFTER_SENDS || self.interval == self.target {
+            return;
+        }
+        self.streak = 0;
+
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let faster = interval * 3 / 4;
+        self.interval = match self.target {
+            Some(target) => Some(faster.max(target)),
+            None => (faster >= Duration::from_secs(1) / DEFAULT_RATE_PPS).then_some(faster),
+        };
+    }
+
+    /// Block until the next probe is due
+    pub(crate) fn wait(&mut self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else if now - self.next > Self::MAX_LAG {
+            self.next = now;
+        }
+        self.next += interval;
+    }
+}
+
+/// What a probed port answered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    /// Answered with SYN+ACK (TCP) or a datagram (UDP)
+    Open,
+    /// Answered with RST (TCP) or ICMP port unreachable (UDP)
+    Closed,
+    /// No answer before the timeout. For UDP this means open or filtered
+    Filtered,
+}
 
 #[derive(Debug, Clone)]
 pub struct PortScanResult {
     pub ip: IpAddr,
     pub open_ports: Vec<i32>,
+    pub closed_ports: Vec<i32>,
+    pub filtered_ports: Vec<i32>,
+    /// Whether ports in `filtered_ports` may be open rather than known not
+    /// to be, as in UDP scans and NULL, FIN and XMAS scans
+    pub filtered_may_be_open: bool,
+    /// Smallest MSS offered in the host's SYN+ACKs
+    pub mss: Option<u16>,
 }
 
 impl PortScanResult {
@@ -13,15 +394,161 @@ impl PortScanResult {
         PortScanResult {
             ip,
             open_ports: Vec::new(),
+            closed_ports: Vec::new(),
+            filtered_ports: Vec::new(),
+            filtered_may_be_open: false,
+            mss: None,
             // data: HashMap::new(),
         }
     }
+
+    /// State of a port, or None if it was not probed
+    pub fn port_state(&self, port: i32) -> Option<PortState> {
+        if self.open_ports.contains(&port) {
+            Some(PortState::Open)
+        } else if self.closed_ports.contains(&port) {
+            Some(PortState::Closed)
+        } else if self.filtered_ports.contains(&port) {
+            Some(PortState::Filtered)
+        } else {
+            None
+        }
+    }
+
+    /// Probed ports known not to be open: the closed ones, and the filtered
+    /// ones unless `filtered_may_be_open`
+    pub fn not_open_ports(&self) -> Vec<i32> {
+        let mut ports = self.closed_ports.clone();
+        if !self.filtered_may_be_open {
+            ports.extend(&self.filtered_ports);
+            ports.sort();
+        }
+        ports
+    }
+
+    /// Sort the port lists and mark every probed port that never answered as filtered
+    pub(crate) fn finish(&mut self, probed: &[i32]) {
+        self.open_ports.sort();
+        self.open_ports.dedup();
+
+        self.closed_ports.sort();
+        self.closed_ports.dedup();
+        self.closed_ports
+            .retain(|port| self.open_ports.binary_search(port).is_err());
+
+        self.filtered_ports = probed
+            .iter()
+            .filter(|port| {
+                self.open_ports.binary_search(port).is_err()
+                    && self.closed_ports.binary_search(port).is_err()
+            })
+            .cloned()
+            .collect();
+        self.filtered_ports.sort();
+        self.filtered_ports.dedup();
+    }
+
     pub fn to_database(&self) -> DatabaseResult {
         DatabaseResult {
             id: self.ip.to_string(),
             ports: (*self.open_ports).to_vec(),
             services: Vec::new(),
             responses: String::new(),
+            service_entries: Vec::new(),
+            first_seen: None,
+            last_seen: None,
+            last_run: None,
+            tags: Vec::new(),
+            hostname: None,
+            rtt_ms: None,
+            os_guess: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(order: ProbeOrder, targets: usize, ports: usize) -> Vec<(usize, usize)> {
+        order.pairs(targets, ports).collect()
+    }
+
+    #[test]
+    fn sequential_order_goes_one_target_at_a_time() {
+        assert_eq!(
+            pairs(ProbeOrder::Sequential, 2, 3),
+            vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]
+        );
+    }
+
+    #[test]
+    fn pacer_holds_probes_to_the_rate() {
+        // 10 targets × 20 ports at 1000 pps. The first probe goes out at
+        // once, so the last is due 199ms in.
+        let mut pacer = Pacer::new(1000);
+        let start = Instant::now();
+        for _ in ProbeOrder::Sequential.pairs(10, 20) {
+            pacer.wait();
+            pacer.sent();
+        }
+        let elapsed = start.elapsed();
+
+        assert_eq!(pacer.rate_pps(), 1000);
+        assert!(
+            elapsed >= Duration::from_millis(199),
+            "200 probes took {:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < Duration::from_millis(400),
+            "200 probes took {:?}",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_order() {
+        let order = pairs(ProbeOrder::Shuffled(42), 13, 7);
+        assert_eq!(order, pairs(ProbeOrder::Shuffled(42), 13, 7));
+        assert_ne!(order, pairs(ProbeOrder::Shuffled(43), 13, 7));
+        assert_ne!(order, pairs(ProbeOrder::Sequential, 13, 7));
+    }
+
+    #[test]
+    fn shuffled_order_probes_every_pair_once() {
+        // Empty, single, powers of four and sizes just either side of them
+        for (targets, ports) in [
+            (0, 0),
+            (0, 5),
+            (5, 0),
+            (1, 1),
+            (1, 2),
+            (3, 1),
+            (4, 1),
+            (5, 1),
+            (16, 1),
+            (17, 1),
+            (13, 7),
+            (16, 16),
+            (257, 1),
+            (100, 37),
+        ] {
+            for seed in [0, 1, u64::MAX] {
+                let probes = ProbeOrder::Shuffled(seed).pairs(targets, ports);
+                assert_eq!(probes.size_hint(), (targets * ports, Some(targets * ports)));
+
+                let mut order: Vec<(usize, usize)> = probes.collect();
+                order.sort();
+                assert_eq!(
+                    order,
+                    pairs(ProbeOrder::Sequential, targets, ports),
+                    "{} targets × {} ports, seed {}",
+                    targets,
+                    ports,
+                    seed
+                );
+            }
         }
     }
 }
@@ -1,72 +1,155 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::BorrowedFd;
 use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use indicatif::{ProgressBar, ProgressStyle};
-use pnet::datalink::{self};
+use log::{debug, info, trace, warn};
+use pnet::datalink::{self, NetworkInterface};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::tcp::{MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::tcp::{MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumbers, TcpPacket};
 use pnet::packet::{Packet, tcp};
-use pnet::transport::{self, TransportChannelType, TransportSender};
+use pnet::transport::{
+    self, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
+};
 use rand::random_range;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 
-use super::port_scan::PortScanResult;
+use super::port_scan::{Pacer, PortScanResult, ScanConfig, ScanPlan, ScanType, is_cancelled};
+use crate::util::humanize_duration;
 
-fn std_to_pnet_ipv4(previous: &IpAddr) -> Ipv4Addr {
-    Ipv4Addr::from_str(previous.to_string().as_str()).unwrap()
+// Source ports probes are sent from, reserved for the length of the scan
+const SOURCE_PORTS: usize = 64;
+// Attempts at binding a random port before settling for fewer source ports
+const SOURCE_PORT_ATTEMPTS: usize = 1024;
+// Times a probe is resent while the kernel has no buffer space for it, and
+// the wait before the first retry, doubled before each one after
+const MAX_SEND_RETRIES: u32 = 6;
+const FIRST_SEND_BACKOFF: Duration = Duration::from_millis(10);
+// Linux's ENOBUFS, returned while an interface's send queue is full
+const ENOBUFS: i32 = 105;
+// Length of a probe's TCP header without options, and with an MSS option
+const PROBE_LEN: usize = 20;
+const PROBE_WITH_MSS_LEN: usize = 24;
+
+/// Local ports to send probes from. Each is bound by a real socket held
+/// until the scan ends, so nothing else on this machine can use it and have
+/// its connections confused with the scan, and ports already in use are
+/// skipped. Replies to a bound but unconnected socket are still reset by the
+/// kernel, as for a port nobody holds.
+struct SourcePorts {
+    ports: Vec<u16>,
+    _sockets: Vec<Socket>,
 }
 
-// Main scanning function
-pub fn tcp_scan(targets: Vec<IpAddr>, ports: Vec<i32>, timeout: Duration) -> Vec<PortScanResult> {
-    // Search for VPN connection and fall back to regular
-    let interface = datalink::interfaces()
-        .into_iter()
-        .find(|iface| {
-            iface.is_up()
-                && !iface.is_loopback()
-                && !iface.ips.is_empty()
-                && !iface.is_dormant()
-                && iface.is_running()
-                && iface.is_point_to_point()
-        })
-        .or(datalink::interfaces().into_iter().find(|iface| {
-            iface.is_up()
-                && !iface.is_loopback()
-                && !iface.ips.is_empty()
-                && !iface.is_dormant()
-                && iface.is_running()
-                && !iface.is_point_to_point()
-        }))
-        .expect("No valid network interface found");
-
-    let (mut tx, mut rx) = transport::transport_channel(
-        65535,
-        TransportChannelType::Layer4(pnet::transport::TransportProtocol::Ipv4(
-            IpNextHeaderProtocols::Tcp,
-        )),
-    )
-    .expect("Failed to create transport channel");
-
-    let results = Arc::new(Mutex::new(HashMap::<IpAddr, Vec<i32>>::new()));
+impl SourcePorts {
+    /// Reserve ports for IPv4, and for IPv6 too if `ipv6`
+    fn reserve(ipv6: bool) -> Self {
+        let mut ports = Vec::new();
+        let mut sockets = Vec::new();
+        for _ in 0..SOURCE_PORT_ATTEMPTS {
+            if ports.len() == SOURCE_PORTS {
+                break;
+            }
+            let port: u16 = random_range(1024..=65535);
+            if ports.contains(&port) {
+                continue;
+            }
+            let Some(socket) = bind_port(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))) else {
+                continue;
+            };
+            if ipv6 {
+                let Some(socket) = bind_port(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+                else {
+                    continue;
+                };
+                sockets.push(socket);
+            }
+            ports.push(port);
+            sockets.push(socket);
+        }
 
-    {
-        let mut results_map = results.lock().unwrap();
-        for ip in &targets {
-            results_map.insert(*ip, Vec::new());
+        if ports.is_empty() {
+            warn!("Couldn't reserve any source ports, probes may collide with local sockets");
+            ports.push(random_range(1024..=65535));
+        }
+
+        SourcePorts {
+            ports,
+            _sockets: sockets,
         }
     }
+}
 
-    let finished_sending_time = Arc::new(AtomicBool::new(false));
-    let port_count = Arc::new(AtomicU32::new(0));
+// A TCP socket bound to `addr`, IPv6 ones to IPv6 alone so they don't
+// clash with the IPv4 socket on the same port
+fn bind_port(addr: SocketAddr) -> Option<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)).ok()?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true).ok()?;
+    }
+    socket.bind(&addr.into()).ok()?;
+    Some(socket)
+}
+
+/// Sequence numbers for probes, derived from a keyed hash of the probe's
+/// addresses so replies can be matched to the probe that caused them
+/// without remembering every probe sent.
+#[derive(Clone)]
+struct ProbeCookies(RandomState);
+
+impl ProbeCookies {
+    fn sequence(&self, target: &IpAddr, source_port: u16, dest_port: u16) -> u32 {
+        self.0.hash_one((target, source_port, dest_port)) as u32
+    }
+
+    /// Whether `reply` from `addr` answers one of our probes: it comes from
+    /// the probed port, goes to the source port the probe used, and if it
+    /// acknowledges anything, acknowledges the probe's sequence number plus
+    /// the SYN and FIN flags it carried. Replies to a SYN always acknowledge
+    /// it, so for SYN probes one that doesn't is someone else's.
+    fn matches(&self, addr: &IpAddr, reply: &TcpPacket, probe_flags: u8) -> bool {
+        if reply.get_flags() & TcpFlags::ACK == 0 {
+            return probe_flags & TcpFlags::SYN == 0;
+        }
+
+        let sequence = self.sequence(addr, reply.get_destination(), reply.get_source());
+        let consumed =
+            (probe_flags & TcpFlags::SYN != 0) as u32 + (probe_flags & TcpFlags::FIN != 0) as u32;
+        reply.get_acknowledgement() == sequence.wrapping_add(consumed)
+    }
+}
+
+/// What `tcp_scan` would send to `targets` with `config`, without opening
+/// a channel or sending anything
+pub fn tcp_scan_plan(targets: &[IpAddr], ports: &[i32], config: &ScanConfig) -> ScanPlan {
+    ScanPlan::new(targets.len(), ports.len(), config.rate_pps, config.timeout)
+}
 
-    let receiver_results = Arc::clone(&results);
-    let receiver_finished_sending_time = Arc::clone(&finished_sending_time);
-    let receiver_port_count = Arc::clone(&port_count);
-    let receiver_handle = thread::spawn(move || {
+// What a receiver thread needs to match replies to the probes sent and
+// record them, shared by the IPv4 and IPv6 receivers
+#[derive(Clone)]
+struct ReplyReceiver {
+    results: Arc<Mutex<HashMap<IpAddr, PortScanResult>>>,
+    finished_sending_time: Arc<AtomicBool>,
+    port_count: Arc<AtomicU32>,
+    ports: HashSet<i32>,
+    source_ports: HashSet<u16>,
+    cookies: ProbeCookies,
+    scan_type: ScanType,
+    cancel: Option<Arc<AtomicBool>>,
+    timeout: Duration,
+}
+
+impl ReplyReceiver {
+    // Record replies from `rx` until the timeout has passed since the last
+    // probe was sent, or the scan is cancelled
+    fn run(self, mut rx: TransportReceiver) {
         let mut finish_sending_time: Option<Instant> = None;
 
         // let mut tmp_results: Vec<(TcpPacket<'_>, IpAddr)> = Vec::new();
@@ -77,152 +160,1136 @@ pub fn tcp_scan(targets: Vec<IpAddr>, ports: Vec<i32>, timeout: Duration) -> Vec
             //     break;
             // };
 
-            if finish_sending_time.is_some() {
-                let delay = finish_sending_time.unwrap().elapsed();
+            // Replies still on their way are given up on
+            if is_cancelled(&self.cancel) {
+                break;
+            }
+
+            if let Some(finish_sending_time) = finish_sending_time {
+                let delay = finish_sending_time.elapsed();
                 // pb.as_ref().unwrap().set_position(delay.as_millis() as u64);
-                if delay >= timeout {
+                if delay >= self.timeout {
                     // pb.unwrap().finish_and_clear();
                     break;
                 }
             } else if finish_sending_time.is_none()
-                && receiver_finished_sending_time.load(std::sync::atomic::Ordering::Relaxed)
+                && self
+                    .finished_sending_time
+                    .load(std::sync::atomic::Ordering::Relaxed)
             {
                 finish_sending_time = Some(Instant::now());
                 // pb = Some(ProgressBar::new(TIMEOUT.as_millis() as u64));
-                println!("Waiting {} seconds for timeout...", timeout.as_secs())
+                info!("Waiting {} for timeout...", humanize_duration(self.timeout))
             }
 
             // println!("loop");
 
             match iter.next_with_timeout(Duration::from_millis(3)) {
                 Ok(Some((packet, addr))) => {
-                    if let Some(tcp) = TcpPacket::new(packet.packet()) {
-                        // Check for SYN+ACK flags (indicating open port)
-                        if tcp.get_flags() == TcpFlags::SYN | TcpFlags::ACK {
-                            // println!(
-                            //     "Discovered open port {} on {}",
-                            //     tcp.get_source(),
-                            //     addr.to_string()
-                            // );
-                            let mut results_map = receiver_results.lock().unwrap();
-                            if let Some(open_ports) = results_map.get_mut(&addr) {
-                                open_ports.push(tcp.get_source() as i32);
-                            }
-                            receiver_port_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        }
+                    if let Some(tcp) = TcpPacket::new(packet.packet())
+                        // Ignore traffic that isn't a reply to one of our probes,
+                        // such as our own kernel resetting a SYN+ACK on loopback
+                        // or a stray packet from another probed port
+                        && self.ports.contains(&(tcp.get_source() as i32))
+                        && self.source_ports.contains(&tcp.get_destination())
+                        && self.cookies.matches(&addr, &tcp, self.scan_type.flags())
+                    {
+                        self.record(addr, &tcp);
                     }
                 }
+                // Nothing arrived yet, keep listening until the timeout
                 Ok(None) => {}
-                Err(_) => {
-                    break;
+                // A failed read (e.g. interrupted by a signal) loses at most
+                // one packet, only the timeout ends the receiver
+                Err(e) => {
+                    debug!("Error reading TCP replies: {}", e);
+                    thread::sleep(Duration::from_millis(3));
                 }
             }
         }
 
         // for (packet, addr) in tmp_results {}
-    });
+    }
 
-    let pb = ProgressBar::new((targets.len() * ports.len()) as u64).with_style(
-        ProgressStyle::with_template("[{msg}] {wide_bar:.cyan/blue} {pos}/{len} ({eta_precise})")
-            .unwrap(),
-    );
+    fn record(&self, addr: IpAddr, tcp: &TcpPacket) {
+        let flags = tcp.get_flags();
+        trace!(
+            "TCP reply from {}:{} flags {:#04x}",
+            addr,
+            tcp.get_source(),
+            flags
+        );
+
+        // Check for SYN+ACK flags (indicating open port). Only a
+        // SYN probe should get one, other scan types learn
+        // nothing from it
+        if flags == TcpFlags::SYN | TcpFlags::ACK && self.scan_type == ScanType::Syn {
+            info!("Discovered open port {} on {}", tcp.get_source(), addr);
+            let mut results_map = self.results.lock().unwrap();
+            if let Some(result) = results_map.get_mut(&addr) {
+                result.open_ports.push(tcp.get_source() as i32);
+                if let Some(mss) = offered_mss(tcp) {
+                    result.mss = Some(result.mss.map_or(mss, |m| m.min(mss)));
+                }
+            }
+            self.port_count
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        } else if flags & TcpFlags::RST != 0 {
+            // A RST means the host is up but nothing listens on the port
+            let mut results_map = self.results.lock().unwrap();
+            if let Some(result) = results_map.get_mut(&addr) {
+                result.closed_ports.push(tcp.get_source() as i32);
+            }
+        }
+    }
+}
+
+// A channel for TCP probes over IPv4, or IPv6 if `ipv6`. With `device`
+// the channel's socket is bound to that interface, so probes leave through
+// it and only replies arriving on it are read, whatever the routing table says.
+fn tcp_channel(
+    ipv6: bool,
+    device: Option<&str>,
+) -> io::Result<(TransportSender, TransportReceiver)> {
+    let protocol = if ipv6 {
+        TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp)
+    } else {
+        TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)
+    };
+    let (tx, rx) = transport::transport_channel(65535, TransportChannelType::Layer4(protocol))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to create transport channel: {}", e),
+            )
+        })?;
+
+    if let Some(device) = device {
+        // The sender and receiver share one socket, which stays open for as
+        // long as they do
+        let fd = unsafe { BorrowedFd::borrow_raw(tx.socket.fd) };
+        SockRef::from(&fd)
+            .bind_device(Some(device.as_bytes()))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Couldn't bind to network interface {}: {}", device, e),
+                )
+            })?;
+    }
+
+    Ok((tx, rx))
+}
+
+/// Scan `ports` of each target with raw TCP probes, IPv4 and IPv6 targets
+/// alike. Probes to each family are sent from that family's address on the
+/// interface, so IPv6 targets other than ::1 need the interface to have a
+/// global IPv6 address, and IPv4 targets other than loopback an IPv4 one.
+/// A named `config.interface` is the only one probes are sent and replies
+/// read on.
+///
+/// Fails if there is no interface with the addresses to send from or the
+/// raw sockets can't be opened, which needs root or CAP_NET_RAW.
+pub fn tcp_scan(
+    targets: Vec<IpAddr>,
+    ports: Vec<i32>,
+    config: &ScanConfig,
+) -> io::Result<Vec<PortScanResult>> {
+    let timeout = config.timeout;
+
+    // Only has the addresses the targets need, checked here
+    let interface = select_interface(config.interface.as_deref(), &targets)
+        .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
 
     // println!("{:?}", interface.ips);
 
-    let source_ip = std_to_pnet_ipv4(
-        &interface
-            .ips
-            .iter()
-            .find(|ip| ip.is_ipv4())
-            .expect("No IPv4 address found")
-            .ip(),
-    );
+    let source_ipv4 = source_ipv4(&interface);
+    let source_ipv6 = source_ipv6(&interface);
 
     // let source_ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     // let source_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 70, 4));
 
     // println!("Using IP: {}", source_ip.to_string());
 
+    // A channel for each address family there are targets in
+    let has_ipv6 = targets.iter().any(|ip| ip.is_ipv6());
+    let device = config.interface.as_deref();
+    let (mut tx_ipv4, rx_ipv4) = targets
+        .iter()
+        .any(|ip| ip.is_ipv4())
+        .then(|| tcp_channel(false, device))
+        .transpose()?
+        .unzip();
+    let (mut tx_ipv6, rx_ipv6) = has_ipv6
+        .then(|| tcp_channel(true, device))
+        .transpose()?
+        .unzip();
+
+    let results = Arc::new(Mutex::new(HashMap::<IpAddr, PortScanResult>::new()));
+
+    {
+        let mut results_map = results.lock().unwrap();
+        for ip in &targets {
+            results_map.insert(*ip, PortScanResult::new(*ip));
+        }
+    }
+
+    let finished_sending_time = Arc::new(AtomicBool::new(false));
+    let port_count = Arc::new(AtomicU32::new(0));
+
+    let scan_type = config.scan_type;
+    let source_ports = SourcePorts::reserve(has_ipv6);
+    let cookies = ProbeCookies(RandomState::new());
+    let receiver = ReplyReceiver {
+        results: Arc::clone(&results),
+        finished_sending_time: Arc::clone(&finished_sending_time),
+        port_count: Arc::clone(&port_count),
+        ports: ports.iter().cloned().collect(),
+        source_ports: source_ports.ports.iter().cloned().collect(),
+        cookies: cookies.clone(),
+        scan_type,
+        cancel: config.cancel.clone(),
+        timeout,
+    };
+    let receiver_handles: Vec<_> = rx_ipv4
+        .into_iter()
+        .chain(rx_ipv6)
+        .map(|rx| {
+            let receiver = receiver.clone();
+            thread::spawn(move || receiver.run(rx))
+        })
+        .collect();
+
+    let pb = ProgressBar::new((targets.len() * ports.len()) as u64).with_style(
+        ProgressStyle::with_template("[{msg}] {wide_bar:.cyan/blue} {pos}/{len} ({eta_precise})")
+            .unwrap(),
+    );
+
     let sender_finished_sending_time = Arc::clone(&finished_sending_time);
     let sender_port_count = Arc::clone(&port_count);
-    for target in &targets {
-        for port in &ports {
-            // let source_ip = Ipv4Addr::from_bits(random_range(0..=(0xffffffff)));
-            let source_port: u16 = random_range(1..=65535);
-            // println!("{}", source_ip.to_string());
-
-            let mut tcp_buffer = vec![0u8; 20 + 20]; // IP header + TCP header
-            let mut tcp_header = MutableTcpPacket::new(&mut tcp_buffer[0..]).unwrap();
-
-            tcp_header.set_source(source_port);
-            tcp_header.set_destination(*port as u16);
-            tcp_header.set_sequence(rand::random::<u32>());
-            tcp_header.set_acknowledgement(0);
-            tcp_header.set_data_offset(5);
-            tcp_header.set_reserved(0);
-            tcp_header.set_flags(TcpFlags::SYN);
-            tcp_header.set_window(64240);
-            tcp_header.set_urgent_ptr(0);
-            // tcp_header.set_options(&[TcpOption::mss(1460)]);
-
-            // Calculate checksum
-            let checksum = tcp::ipv4_checksum(
-                &tcp_header.to_immutable(),
-                if !target.is_loopback() {
-                    &source_ip
-                } else {
-                    &Ipv4Addr::LOCALHOST
-                },
-                &std_to_pnet_ipv4(&target),
-            );
-            tcp_header.set_checksum(checksum);
+    let mut pacer = Pacer::new(config.rate_pps);
+    let mut probes = 0;
+    let mut cancelled = false;
+    for (i, j) in config.probe_order.pairs(targets.len(), ports.len()) {
+        let (target, port) = (&targets[i], &ports[j]);
+        if config.cancelled() {
+            cancelled = true;
+            break;
+        }
+        pacer.wait();
 
-            send_tcp_packet(&mut tx, tcp_header, target);
+        // let source_ip = Ipv4Addr::from_bits(random_range(0..=(0xffffffff)));
+        let source_port = source_ports.ports[probes % source_ports.ports.len()];
+        probes += 1;
+        // println!("{}", source_ip.to_string());
 
-            pb.set_message(format!(
-                "{} ports",
-                sender_port_count.load(std::sync::atomic::Ordering::Relaxed),
-            ));
-            pb.inc(1);
+        let mut tcp_buffer = [0u8; PROBE_WITH_MSS_LEN];
+        let len = build_probe(
+            &mut tcp_buffer,
+            config,
+            source_port,
+            *port as u16,
+            cookies.sequence(target, source_port, *port as u16),
+        );
+        let mut tcp_header = MutableTcpPacket::new(&mut tcp_buffer[..len]).unwrap();
 
-            thread::sleep(Duration::from_micros(100));
+        // Calculate checksum, over the addresses the IP header will carry.
+        // The kernel fills in neither for raw sockets, IPv6 ones included
+        let (checksum, tx) = match target {
+            IpAddr::V4(target) => {
+                let source = if target.is_loopback() {
+                    Ipv4Addr::LOCALHOST
+                } else {
+                    source_ipv4.expect("interface was chosen with an IPv4 address")
+                };
+                (
+                    tcp::ipv4_checksum(&tcp_header.to_immutable(), &source, target),
+                    tx_ipv4.as_mut(),
+                )
+            }
+            IpAddr::V6(target) => {
+                let source = if target.is_loopback() {
+                    Ipv6Addr::LOCALHOST
+                } else {
+                    source_ipv6.expect("interface was chosen with an IPv6 address")
+                };
+                (
+                    tcp::ipv6_checksum(&tcp_header.to_immutable(), &source, target),
+                    tx_ipv6.as_mut(),
+                )
+            }
+        };
+        tcp_header.set_checksum(checksum);
+
+        // Every target's family has a channel
+        let tx = tx.unwrap();
+        if let Err(e) = send_with_backoff(&mut pacer, || tx.send_to(&tcp_header, *target)) {
+            debug!("Failed to send probe to {}:{}: {}", target, port, e);
         }
+
+        pb.set_message(format!(
+            "{} ports",
+            sender_port_count.load(std::sync::atomic::Ordering::Relaxed),
+        ));
+        pb.inc(1);
     }
 
-    pb.finish_with_message("Finished!");
+    if cancelled {
+        pb.abandon_with_message("Cancelled");
+        info!(
+            "Scan cancelled after {} of {} probes",
+            probes,
+            pb.length().unwrap_or(0)
+        );
+    } else {
+        pb.finish_with_message("Finished!");
+    }
     sender_finished_sending_time.swap(true, std::sync::atomic::Ordering::Relaxed);
-    // Wait for receiver to finish
+    // Wait for receivers to finish
     // thread::sleep(timeout);
-    receiver_handle.join().unwrap();
+    for handle in receiver_handles {
+        handle.join().unwrap();
+    }
 
-    // Convert results to the return format
+    // Convert results to the return format. A cancelled scan leaves out the
+    // targets it never got to, and only counts the ports it probed as
+    // filtered, found by going over the probe order again up to where it
+    // stopped
     let results_map = results.lock().unwrap();
-    targets
+    let mut probed = vec![Vec::new(); targets.len()];
+    if cancelled {
+        for (i, j) in config
+            .probe_order
+            .pairs(targets.len(), ports.len())
+            .take(probes)
+        {
+            probed[i].push(ports[j]);
+        }
+    }
+    Ok(targets
         .iter()
-        .map(|ip| {
-            let mut open_ports = results_map.get(ip).cloned().unwrap_or_default();
-            open_ports.sort();
-            open_ports.dedup();
-            PortScanResult {
-                ip: *ip,
-                open_ports,
-            }
+        .zip(probed)
+        .filter(|(_, probed)| !cancelled || !probed.is_empty())
+        .map(|(ip, probed)| {
+            let mut result = results_map
+                .get(ip)
+                .cloned()
+                .unwrap_or_else(|| PortScanResult::new(*ip));
+            result.finish(if cancelled { &probed } else { &ports });
+            result.filtered_may_be_open = scan_type != ScanType::Syn;
+            result
+        })
+        .collect())
+}
+
+// Write a probe's TCP header into `buffer`, all but the checksum, and
+// return its length. Just the TCP header, the channel adds the IP header.
+// Anything more would go out as payload and be counted in RST
+// acknowledgements. The data offset counts the MSS option when there is
+// one, so it's read as part of the header, and the checksum, worked out
+// afterwards over the header's length, covers it.
+fn build_probe(
+    buffer: &mut [u8; PROBE_WITH_MSS_LEN],
+    config: &ScanConfig,
+    source_port: u16,
+    dest_port: u16,
+    sequence: u32,
+) -> usize {
+    let len = if config.mss.is_some() {
+        PROBE_WITH_MSS_LEN
+    } else {
+        PROBE_LEN
+    };
+    let mut tcp_header = MutableTcpPacket::new(&mut buffer[..len]).unwrap();
+
+    tcp_header.set_source(source_port);
+    tcp_header.set_destination(dest_port);
+    tcp_header.set_sequence(sequence);
+    tcp_header.set_acknowledgement(0);
+    // In 32 bit words. Set before the options, whose room it decides
+    tcp_header.set_data_offset((len / 4) as u8);
+    tcp_header.set_reserved(0);
+    tcp_header.set_flags(config.scan_type.flags());
+    tcp_header.set_window(config.window);
+    tcp_header.set_urgent_ptr(0);
+    if let Some(mss) = config.mss {
+        tcp_header.set_options(&[TcpOption::mss(mss)]);
+    }
+
+    len
+}
+
+/// The interface whose addresses probes to `targets` are sent from: the one
+/// named, or else the first usable VPN connection, falling back to any other
+/// usable interface. Usable means up, running, not loopback, and with an
+/// IPv4 address if there are IPv4 targets and a global IPv6 address if
+/// there are IPv6 ones, so IPv6-only interfaces can scan IPv6 targets.
+/// Loopback targets are probed from loopback addresses and don't count, so
+/// a scan of nothing else can use the loopback interface.
+pub fn select_interface(
+    name: Option<&str>,
+    targets: &[IpAddr],
+) -> Result<NetworkInterface, String> {
+    choose_interface(datalink::interfaces(), name, targets)
+}
+
+// `select_interface` from `interfaces`
+fn choose_interface(
+    interfaces: Vec<NetworkInterface>,
+    name: Option<&str>,
+    targets: &[IpAddr],
+) -> Result<NetworkInterface, String> {
+    let remote = || targets.iter().filter(|ip| !ip.is_loopback());
+    let needs_ipv4 = remote().any(|ip| ip.is_ipv4());
+    let needs_ipv6 = remote().any(|ip| ip.is_ipv6());
+    let missing = |iface: &NetworkInterface| {
+        if needs_ipv4 && source_ipv4(iface).is_none() {
+            Some("IPv4")
+        } else if needs_ipv6 && source_ipv6(iface).is_none() {
+            Some("global IPv6")
+        } else {
+            None
+        }
+    };
+
+    if let Some(name) = name {
+        let iface = interfaces
+            .into_iter()
+            .find(|iface| iface.name == name)
+            .ok_or_else(|| format!("No network interface named \"{}\"", name))?;
+        if let Some(missing) = missing(&iface) {
+            return Err(format!(
+                "Network interface {} has no {} address",
+                name, missing
+            ));
+        }
+        return Ok(iface);
+    }
+
+    let usable = |iface: &&NetworkInterface| {
+        iface.is_up()
+            && iface.is_running()
+            && (!iface.is_loopback() || remote().next().is_none())
+            && !iface.is_dormant()
+            && missing(iface).is_none()
+    };
+
+    interfaces
+        .iter()
+        .filter(usable)
+        .find(|iface| iface.is_point_to_point())
+        .or_else(|| interfaces.iter().find(usable))
+        .cloned()
+        .ok_or_else(|| {
+            let families = match (needs_ipv4, needs_ipv6) {
+                (true, true) => "IPv4 and global IPv6 addresses",
+                (false, true) => "a global IPv6 address",
+                _ => "an IPv4 address",
+            };
+            format!(
+                "No usable network interface found (up, not loopback, with {})",
+                families
+            )
         })
-        .collect()
 }
 
-fn send_tcp_packet(tx: &mut TransportSender, tcp_header: MutableTcpPacket<'_>, target: &IpAddr) {
-    match tx.send_to(&tcp_header, *target) {
-        Ok(_) => {}
-        Err(e) => {
-            if let Some(code) = e.raw_os_error() {
-                if code == 105 {
-                    thread::sleep(Duration::from_millis(500));
-                    send_tcp_packet(tx, tcp_header, target);
+// The address probes to IPv4 targets are sent from
+fn source_ipv4(interface: &NetworkInterface) -> Option<Ipv4Addr> {
+    interface.ips.iter().find_map(|ip| match ip.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    })
+}
+
+// The address probes to IPv6 targets are sent from. Link-local addresses
+// only reach the local link, and only with a scope
+fn source_ipv6(interface: &NetworkInterface) -> Option<Ipv6Addr> {
+    interface.ips.iter().find_map(|ip| match ip.ip() {
+        IpAddr::V6(ip) if !ip.is_unicast_link_local() && !ip.is_loopback() => Some(ip),
+        _ => None,
+    })
+}
+
+/// MSS option value from a SYN+ACK, if present
+fn offered_mss(tcp: &TcpPacket) -> Option<u16> {
+    tcp.get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::MSS)
+        .and_then(|option| {
+            let payload = option.payload();
+            (payload.len() >= 2).then(|| u16::from_be_bytes([payload[0], payload[1]]))
+        })
+}
+
+/// Call `send` until it succeeds. While the kernel has no buffer space,
+/// wait with exponential backoff and retry up to MAX_SEND_RETRIES times,
+/// slowing `pacer` down once per probe that had to wait. Other errors, and
+/// the last one once the retries run out, are returned.
+fn send_with_backoff(
+    pacer: &mut Pacer,
+    mut send: impl FnMut() -> io::Result<usize>,
+) -> io::Result<()> {
+    let mut backoff = FIRST_SEND_BACKOFF;
+    let mut retry = 0;
+    loop {
+        match send() {
+            Ok(_) if retry == 0 => {
+                pacer.sent();
+                return Ok(());
+            }
+            Ok(_) => return Ok(()),
+            Err(e) if send_buffer_full(&e) && retry < MAX_SEND_RETRIES => {
+                if retry == 0 {
+                    pacer.slow_down();
+                    debug!(
+                        "Send buffer full, slowing down to {} probes per second",
+                        pacer.rate_pps()
+                    );
+                }
+                thread::sleep(backoff);
+                backoff *= 2;
+                retry += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// ENOBUFS, or EAGAIN/EWOULDBLOCK on a non-blocking socket
+fn send_buffer_full(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.raw_os_error() == Some(ENOBUFS)
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::ipnetwork::IpNetwork;
+
+    use super::*;
+
+    // Linux interface flags
+    const UP: u32 = 0x1;
+    const LOOPBACK: u32 = 0x8;
+    const POINT_TO_POINT: u32 = 0x10;
+    const RUNNING: u32 = 0x40;
+
+    fn interface(name: &str, flags: u32, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 0,
+            mac: None,
+            ips: ips
+                .iter()
+                .map(|ip| ip.parse::<IpNetwork>().unwrap())
+                .collect(),
+            flags,
+        }
+    }
+
+    const REMOTE_IPV4: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+    const REMOTE_IPV6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7));
+
+    fn chosen(
+        interfaces: Vec<NetworkInterface>,
+        name: Option<&str>,
+        targets: &[IpAddr],
+    ) -> Result<String, String> {
+        choose_interface(interfaces, name, targets).map(|iface| iface.name)
+    }
+
+    #[test]
+    fn prefers_a_usable_vpn_interface() {
+        let interfaces = vec![
+            interface("lo", UP | RUNNING | LOOPBACK, &["127.0.0.1/8"]),
+            interface("eth0", UP | RUNNING, &["192.168.1.10/24"]),
+            interface("tun0", UP | RUNNING | POINT_TO_POINT, &["10.8.0.2/24"]),
+        ];
+        assert_eq!(
+            chosen(interfaces, None, &[REMOTE_IPV4]),
+            Ok("tun0".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_interfaces_that_are_down_or_have_no_ipv4_address() {
+        let interfaces = vec![
+            interface("lo", UP | RUNNING | LOOPBACK, &["127.0.0.1/8"]),
+            interface("tun0", POINT_TO_POINT, &["10.8.0.2/24"]),
+            interface("eth0", UP | RUNNING, &["fe80::1/64"]),
+            interface("eth1", UP | RUNNING, &["192.168.1.10/24"]),
+        ];
+        assert_eq!(
+            chosen(interfaces.clone(), None, &[REMOTE_IPV4]),
+            Ok("eth1".to_string())
+        );
+        assert_eq!(
+            chosen(interfaces[..3].to_vec(), None, &[REMOTE_IPV4]),
+            Err(
+                "No usable network interface found (up, not loopback, with an IPv4 address)"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn ipv6_scans_need_only_a_global_ipv6_address() {
+        let interfaces = vec![
+            interface("lo", UP | RUNNING | LOOPBACK, &["127.0.0.1/8", "::1/128"]),
+            interface("eth0", UP | RUNNING, &["192.168.1.10/24", "fe80::1/64"]),
+            interface("eth1", UP | RUNNING, &["2001:db8::10/64", "fe80::2/64"]),
+        ];
+        assert_eq!(
+            chosen(interfaces.clone(), None, &[REMOTE_IPV6]),
+            Ok("eth1".to_string())
+        );
+        assert_eq!(
+            chosen(interfaces.clone(), None, &[REMOTE_IPV4]),
+            Ok("eth0".to_string())
+        );
+        // Neither has both
+        assert_eq!(
+            chosen(interfaces.clone(), None, &[REMOTE_IPV4, REMOTE_IPV6]),
+            Err("No usable network interface found (up, not loopback, with IPv4 and global IPv6 addresses)".to_string())
+        );
+        assert_eq!(
+            chosen(interfaces, Some("eth0"), &[REMOTE_IPV6]),
+            Err("Network interface eth0 has no global IPv6 address".to_string())
+        );
+    }
+
+    #[test]
+    fn loopback_scans_can_use_the_loopback_interface() {
+        let interfaces = vec![interface(
+            "lo",
+            UP | RUNNING | LOOPBACK,
+            &["127.0.0.1/8", "::1/128"],
+        )];
+        let loopback = [
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ];
+        assert_eq!(
+            chosen(interfaces.clone(), None, &loopback),
+            Ok("lo".to_string())
+        );
+        assert!(chosen(interfaces, None, &[REMOTE_IPV4]).is_err());
+    }
+
+    #[test]
+    fn named_interface_is_used_as_is() {
+        let interfaces = vec![
+            interface("lo", UP | RUNNING | LOOPBACK, &["127.0.0.1/8"]),
+            interface("eth0", UP | RUNNING, &["192.168.1.10/24"]),
+            interface("eth1", UP | RUNNING, &["fe80::1/64"]),
+        ];
+        assert_eq!(
+            chosen(interfaces.clone(), Some("lo"), &[REMOTE_IPV4]),
+            Ok("lo".to_string())
+        );
+        assert_eq!(
+            chosen(interfaces.clone(), Some("wlan0"), &[REMOTE_IPV4]),
+            Err("No network interface named \"wlan0\"".to_string())
+        );
+        assert_eq!(
+            chosen(interfaces, Some("eth1"), &[REMOTE_IPV4]),
+            Err("Network interface eth1 has no IPv4 address".to_string())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn finds_an_open_port_on_ipv6_loopback() {
+        let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+        let open = listener.local_addr().unwrap().port() as i32;
+        let closed = {
+            let listener = std::net::TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap().port() as i32
+        };
+        let config = ScanConfig {
+            timeout: Duration::from_secs(1),
+            ..ScanConfig::default()
+        };
+
+        let results = tcp_scan(
+            vec![IpAddr::V6(Ipv6Addr::LOCALHOST)],
+            vec![open, closed],
+            &config,
+        )
+        .unwrap();
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        assert_eq!(result.ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(result.open_ports, vec![open]);
+        assert_eq!(result.closed_ports, vec![closed]);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn reset_marks_a_port_closed_on_loopback() {
+        // Ports nothing listens on, which the kernel answers with a RST
+        let closed: Vec<i32> = (0..3)
+            .map(|_| {
+                let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+                listener.local_addr().unwrap().port() as i32
+            })
+            .collect();
+        let config = ScanConfig {
+            timeout: Duration::from_millis(500),
+            ..ScanConfig::default()
+        };
+
+        let results = tcp_scan(
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            closed.clone(),
+            &config,
+        )
+        .unwrap();
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let mut expected = closed;
+        expected.sort();
+        expected.dedup();
+        assert!(result.open_ports.is_empty());
+        assert_eq!(result.closed_ports, expected);
+        assert!(result.filtered_ports.is_empty());
+        assert!(!result.filtered_may_be_open);
+        assert_eq!(result.not_open_ports(), expected);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn cancelled_scan_returns_what_it_probed() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        // 10 seconds of probes at this rate
+        let config = ScanConfig {
+            timeout: Duration::from_secs(3),
+            rate_pps: 100,
+            cancel: Some(Arc::clone(&cancel)),
+            ..ScanConfig::default()
+        };
+        let ports: Vec<i32> = (1..=1000).collect();
+        let canceller = {
+            let cancel = Arc::clone(&cancel);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+            })
+        };
+
+        let start = Instant::now();
+        let results = tcp_scan(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], ports, &config).unwrap();
+        canceller.join().unwrap();
+
+        // Neither the rest of the probes nor the timeout are waited for
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let probed =
+            result.open_ports.len() + result.closed_ports.len() + result.filtered_ports.len();
+        assert!(probed > 0 && probed < 100, "{} ports probed", probed);
+        // Only the ports probed so far, in order, are counted
+        assert!(
+            result
+                .closed_ports
+                .iter()
+                .chain(&result.filtered_ports)
+                .all(|port| *port as usize <= probed)
+        );
+
+        // Cancelled before the first probe, there is nothing to report
+        let results =
+            tcp_scan(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], vec![22, 80], &config).unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn plan_counts_probes_without_opening_a_socket() {
+        let targets: Vec<IpAddr> = (0..=255)
+            .map(|i| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)))
+            .collect();
+        let ports: Vec<i32> = (1..=100).collect();
+        let config = ScanConfig {
+            timeout: Duration::from_secs(3),
+            rate_pps: 1000,
+            ..ScanConfig::default()
+        };
+
+        let plan = tcp_scan_plan(&targets, &ports, &config);
+        assert_eq!(plan.targets, 256);
+        assert_eq!(plan.probes_per_target, 100);
+        assert_eq!(plan.probes, 25600);
+        assert_eq!(plan.rate_pps, 1000);
+        // 25.6 seconds of sending, then the timeout
+        assert_eq!(plan.estimated_duration, Duration::from_millis(28600));
+        assert_eq!(
+            plan.to_string(),
+            "256 targets x 100 probes = 25600 probes, about 28s600ms at 1000 probes/s"
+        );
+
+        let unlimited = ScanConfig {
+            rate_pps: 0,
+            ..config.clone()
+        };
+        let plan = tcp_scan_plan(&targets, &ports, &unlimited);
+        assert_eq!(plan.estimated_duration, Duration::from_secs(3));
+        assert_eq!(
+            plan.to_string(),
+            "256 targets x 100 probes = 25600 probes, at least 3s with no rate limit"
+        );
+
+        let plan = tcp_scan_plan(&[], &ports, &config);
+        assert_eq!(plan.probes, 0);
+        assert_eq!(plan.estimated_duration, Duration::from_secs(3));
+    }
+
+    // `send_with_backoff` with a send that fails with `errors` in turn, then
+    // succeeds. Also returns the number of sends tried.
+    fn send_failing(pacer: &mut Pacer, errors: Vec<io::Error>) -> (io::Result<()>, u32) {
+        let mut errors = errors.into_iter();
+        let mut calls = 0;
+        let result = send_with_backoff(pacer, || {
+            calls += 1;
+            errors.next().map_or(Ok(PROBE_LEN), Err)
+        });
+        (result, calls)
+    }
+
+    fn enobufs(times: usize) -> Vec<io::Error> {
+        (0..times)
+            .map(|_| io::Error::from_raw_os_error(ENOBUFS))
+            .collect()
+    }
+
+    #[test]
+    fn full_send_buffers_are_retried_and_slow_the_rate_once() {
+        let mut pacer = Pacer::new(1000);
+
+        let (result, calls) = send_failing(&mut pacer, enobufs(3));
+        assert!(result.is_ok());
+        assert_eq!(calls, 4);
+        assert_eq!(pacer.rate_pps(), 500);
+
+        let (result, calls) = send_failing(&mut pacer, vec![io::ErrorKind::WouldBlock.into()]);
+        assert!(result.is_ok());
+        assert_eq!(calls, 2);
+        assert_eq!(pacer.rate_pps(), 250);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut pacer = Pacer::new(1000);
+
+        let (result, calls) = send_failing(&mut pacer, enobufs(100));
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(ENOBUFS));
+        assert_eq!(calls, MAX_SEND_RETRIES + 1);
+        assert_eq!(pacer.rate_pps(), 500);
+    }
+
+    #[test]
+    fn other_errors_are_returned_at_once() {
+        let mut pacer = Pacer::new(1000);
+
+        let (result, calls) =
+            send_failing(&mut pacer, vec![io::ErrorKind::PermissionDenied.into()]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls, 1);
+        assert_eq!(pacer.rate_pps(), 1000);
+    }
+
+    // Whether a TCP segment's checksum is right, by summing it with the IPv4
+    // pseudo-header in one's complement, which gives all ones when it is
+    fn checksum_verifies(segment: &[u8], source: Ipv4Addr, dest: Ipv4Addr) -> bool {
+        let mut data = [source.octets(), dest.octets()].concat();
+        data.extend_from_slice(&[0, 6]);
+        data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        data.extend_from_slice(segment);
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
+
+    #[test]
+    fn probes_carry_the_mss_option_only_when_set() {
+        let source = Ipv4Addr::new(192, 168, 1, 10);
+        let dest = Ipv4Addr::new(203, 0, 113, 7);
+        for (mss, len, offset) in [(None, PROBE_LEN, 5), (Some(1380), PROBE_WITH_MSS_LEN, 6)] {
+            let config = ScanConfig {
+                window: 1024,
+                mss,
+                ..ScanConfig::default()
+            };
+            let mut buffer = [0u8; PROBE_WITH_MSS_LEN];
+            assert_eq!(
+                build_probe(&mut buffer, &config, 40000, 443, 0xdeadbeef),
+                len
+            );
+
+            let mut probe = MutableTcpPacket::new(&mut buffer[..len]).unwrap();
+            let checksum = tcp::ipv4_checksum(&probe.to_immutable(), &source, &dest);
+            probe.set_checksum(checksum);
+
+            let probe = TcpPacket::new(&buffer[..len]).unwrap();
+            assert_eq!(probe.get_data_offset(), offset);
+            assert_eq!(probe.get_source(), 40000);
+            assert_eq!(probe.get_destination(), 443);
+            assert_eq!(probe.get_sequence(), 0xdeadbeef);
+            assert_eq!(probe.get_flags(), TcpFlags::SYN);
+            assert_eq!(probe.get_window(), 1024);
+            assert!(probe.payload().is_empty());
+            match mss {
+                Some(mss) => {
+                    let [high, low] = mss.to_be_bytes();
+                    assert_eq!(&buffer[20..24], &[2, 4, high, low]);
+                    assert_eq!(offered_mss(&probe), Some(mss));
                 }
-            } else {
-                eprintln!("Failed to send packet: {}", e);
+                None => assert_eq!(offered_mss(&probe), None),
             }
+            assert!(checksum_verifies(&buffer[..len], source, dest));
+
+            // Any byte changed, options included, breaks it
+            let mut corrupted = buffer;
+            corrupted[len - 1] ^= 1;
+            assert!(!checksum_verifies(&corrupted[..len], source, dest));
+        }
+    }
+
+    #[test]
+    fn probes_carry_the_scan_type_flags() {
+        for (scan_type, flags) in [
+            (ScanType::Syn, TcpFlags::SYN),
+            (ScanType::Null, 0),
+            (ScanType::Fin, TcpFlags::FIN),
+            (
+                ScanType::Xmas,
+                TcpFlags::FIN | TcpFlags::PSH | TcpFlags::URG,
+            ),
+        ] {
+            let config = ScanConfig {
+                scan_type,
+                ..ScanConfig::default()
+            };
+            let mut buffer = [0u8; PROBE_WITH_MSS_LEN];
+            let len = build_probe(&mut buffer, &config, 40000, 443, 1);
+            let probe = TcpPacket::new(&buffer[..len]).unwrap();
+            assert_eq!(probe.get_flags(), flags, "{:?}", scan_type);
+        }
+    }
+
+    const TARGET: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+    // A receiver for a scan of TARGET, with nothing recorded yet
+    fn receiver(scan_type: ScanType) -> ReplyReceiver {
+        ReplyReceiver {
+            results: Arc::new(Mutex::new(HashMap::from([(
+                TARGET,
+                PortScanResult::new(TARGET),
+            )]))),
+            finished_sending_time: Arc::new(AtomicBool::new(false)),
+            port_count: Arc::new(AtomicU32::new(0)),
+            ports: HashSet::from([22, 80, 443]),
+            source_ports: HashSet::from([40000]),
+            cookies: ProbeCookies(RandomState::new()),
+            scan_type,
+            cancel: None,
+            timeout: Duration::ZERO,
+        }
+    }
+
+    // A reply from `port` on TARGET to source port 40000
+    fn reply(port: u16, flags: u8) -> Vec<u8> {
+        let mut buffer = vec![0u8; PROBE_LEN];
+        let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp.set_source(port);
+        tcp.set_destination(40000);
+        tcp.set_data_offset(5);
+        tcp.set_flags(flags);
+        buffer
+    }
+
+    // A reply from `port` on TARGET to `source_port`, acknowledging `ack`
+    fn acking(port: u16, source_port: u16, flags: u8, ack: u32) -> Vec<u8> {
+        let mut buffer = reply(port, flags);
+        let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp.set_destination(source_port);
+        tcp.set_acknowledgement(ack);
+        buffer
+    }
+
+    #[test]
+    fn replies_match_only_the_probe_they_answer() {
+        let cookies = ProbeCookies(RandomState::new());
+        let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+        // Two probes to one host, from different source ports
+        let probes = [(40000, 22), (40001, 80)];
+        let sequences =
+            probes.map(|(source_port, port)| cookies.sequence(&TARGET, source_port, port));
+        assert_ne!(sequences[0], sequences[1]);
+
+        for (i, &(source_port, port)) in probes.iter().enumerate() {
+            let (other_source_port, other_port) = probes[1 - i];
+            let ack = sequences[i].wrapping_add(1);
+            let matches = |buffer: Vec<u8>| {
+                cookies.matches(&TARGET, &TcpPacket::new(&buffer).unwrap(), TcpFlags::SYN)
+            };
+
+            assert!(matches(acking(port, source_port, syn_ack, ack)));
+            assert!(matches(acking(
+                port,
+                source_port,
+                TcpFlags::RST | TcpFlags::ACK,
+                ack
+            )));
+            // The other probe's port, source port or sequence number
+            assert!(!matches(acking(other_port, source_port, syn_ack, ack)));
+            assert!(!matches(acking(port, other_source_port, syn_ack, ack)));
+            assert!(!matches(acking(
+                port,
+                source_port,
+                syn_ack,
+                sequences[1 - i].wrapping_add(1)
+            )));
+            // Not acknowledging the SYN, or acknowledging nothing
+            assert!(!matches(acking(port, source_port, syn_ack, sequences[i])));
+            assert!(!matches(acking(port, source_port, TcpFlags::RST, 0)));
+            // From another host
+            let other = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8));
+            let buffer = acking(port, source_port, syn_ack, ack);
+            assert!(!cookies.matches(&other, &TcpPacket::new(&buffer).unwrap(), TcpFlags::SYN));
+        }
+
+        // A FIN probe's sequence number is consumed by its FIN, and a reset
+        // acknowledging nothing could be for a probe without a SYN
+        let fin = cookies.sequence(&TARGET, 40000, 22);
+        for (flags, ack, matches) in [
+            (TcpFlags::RST | TcpFlags::ACK, fin.wrapping_add(1), true),
+            (TcpFlags::RST | TcpFlags::ACK, fin, false),
+            (TcpFlags::RST, 0, true),
+        ] {
+            let buffer = acking(22, 40000, flags, ack);
+            let reply = TcpPacket::new(&buffer).unwrap();
+            assert_eq!(cookies.matches(&TARGET, &reply, TcpFlags::FIN), matches);
+        }
+    }
+
+    #[test]
+    fn stealth_scans_only_learn_from_resets() {
+        for scan_type in [ScanType::Null, ScanType::Fin, ScanType::Xmas] {
+            let receiver = receiver(scan_type);
+            for (port, flags) in [
+                (22, TcpFlags::RST | TcpFlags::ACK),
+                (80, TcpFlags::RST),
+                // Says nothing about a port these probes can't open
+                (443, TcpFlags::SYN | TcpFlags::ACK),
+            ] {
+                receiver.record(TARGET, &TcpPacket::new(&reply(port, flags)).unwrap());
+            }
+
+            let mut result = receiver.results.lock().unwrap()[&TARGET].clone();
+            result.finish(&[22, 80, 443]);
+            assert!(result.open_ports.is_empty(), "{:?}", scan_type);
+            assert_eq!(result.closed_ports, vec![22, 80], "{:?}", scan_type);
+            // Silent, so open or filtered
+            assert_eq!(result.filtered_ports, vec![443], "{:?}", scan_type);
+        }
+
+        let receiver = receiver(ScanType::Syn);
+        receiver.record(
+            TARGET,
+            &TcpPacket::new(&reply(443, TcpFlags::SYN | TcpFlags::ACK)).unwrap(),
+        );
+        receiver.record(TARGET, &TcpPacket::new(&reply(22, TcpFlags::RST)).unwrap());
+        let result = &receiver.results.lock().unwrap()[&TARGET];
+        assert_eq!(result.open_ports, vec![443]);
+        assert_eq!(result.closed_ports, vec![22]);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn replies_after_an_idle_gap_are_recorded() {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let receiver = ReplyReceiver {
+            results: Arc::new(Mutex::new(HashMap::from([(
+                loopback,
+                PortScanResult::new(loopback),
+            )]))),
+            finished_sending_time: Arc::new(AtomicBool::new(true)),
+            timeout: Duration::from_secs(1),
+            ..receiver(ScanType::Syn)
+        };
+        let results = receiver.results.clone();
+        let cookies = receiver.cookies.clone();
+        let (mut tx, rx) = tcp_channel(false, None).unwrap();
+        let start = Instant::now();
+        let listening = thread::spawn(move || receiver.run(rx));
+
+        // Each well after the receiver's reads have started timing out
+        for (delay, port, flags) in [
+            (300, 443, TcpFlags::SYN | TcpFlags::ACK),
+            (700, 80, TcpFlags::RST | TcpFlags::ACK),
+        ] {
+            thread::sleep(Duration::from_millis(delay).saturating_sub(start.elapsed()));
+            let mut buffer = reply(port, flags);
+            let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+            let probe = cookies.sequence(&loopback, 40000, port);
+            tcp.set_acknowledgement(probe.wrapping_add(1));
+            let checksum = tcp::ipv4_checksum(
+                &tcp.to_immutable(),
+                &Ipv4Addr::LOCALHOST,
+                &Ipv4Addr::LOCALHOST,
+            );
+            tcp.set_checksum(checksum);
+            tx.send_to(tcp, loopback).unwrap();
+        }
+
+        listening.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        let result = &results.lock().unwrap()[&loopback];
+        assert_eq!(result.open_ports, vec![443]);
+        assert_eq!(result.closed_ports, vec![80]);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn stealth_scans_of_loopback() {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let open = listener.local_addr().unwrap().port() as i32;
+        let closed = {
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.local_addr().unwrap().port() as i32
+        };
+
+        for scan_type in [ScanType::Null, ScanType::Fin, ScanType::Xmas] {
+            let config = ScanConfig {
+                timeout: Duration::from_millis(500),
+                scan_type,
+                ..ScanConfig::default()
+            };
+            let results = tcp_scan(
+                vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                vec![open, closed],
+                &config,
+            )
+            .unwrap();
+            let [result] = &results[..] else {
+                panic!("expected one host, got {:?}", results);
+            };
+            // A listening socket drops these probes without a word
+            assert!(result.open_ports.is_empty(), "{:?}", scan_type);
+            assert_eq!(result.closed_ports, vec![closed], "{:?}", scan_type);
+            assert_eq!(result.filtered_ports, vec![open], "{:?}", scan_type);
+            assert!(result.filtered_may_be_open, "{:?}", scan_type);
+            assert_eq!(result.not_open_ports(), vec![closed], "{:?}", scan_type);
         }
     }
 }
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+// DNS query for the root NS records
+const DNS_PROBE: [u8; 17] = [
+    0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+    0x01,
+];
+
+// NetBIOS node status request for the wildcard name "*"
+const NETBIOS_PROBE: [u8; 50] = [
+    0x80, 0xf0, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x43, 0x4b, 0x41,
+    0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41,
+    0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x41, 0x00, 0x00, 0x21,
+    0x00, 0x01,
+];
+
+// SNMPv1 get-request for sysDescr with the "public" community
+const SNMP_PROBE: [u8; 40] = [
+    0x30, 0x26, 0x02, 0x01, 0x00, 0x04, 0x06, 0x70, 0x75, 0x62, 0x6c, 0x69, 0x63, 0xa0, 0x19, 0x02,
+    0x01, 0x01, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08, 0x2b, 0x06,
+    0x01, 0x02, 0x01, 0x01, 0x01, 0x00, 0x05, 0x00,
+];
+
+// SSDP search for every device and service
+const SSDP_PROBE: &[u8] = b"M-SEARCH * HTTP/1.1\r\n\
+HOST: 239.255.255.250:1900\r\n\
+MAN: \"ssdp:discover\"\r\n\
+MX: 1\r\n\
+ST: ssdp:all\r\n\r\n";
+
+// memcached "stats", after the 8 byte UDP frame header: request id 1,
+// datagram 0 of 1
+const MEMCACHED_PROBE: &[u8] = b"\x00\x01\x00\x00\x00\x01\x00\x00stats\r\n";
+
+/// Payloads `udp_scan` sends, by destination port. UDP services mostly
+/// ignore datagrams they can't parse, so a port only answers a probe in
+/// its own protocol. Ports without one get an empty datagram, which still
+/// draws an ICMP port unreachable from a closed port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpProbeTable {
+    payloads: HashMap<u16, Vec<u8>>,
+}
+
+impl Default for UdpProbeTable {
+    /// Probes for DNS, NTP, NetBIOS, SNMP, SSDP and memcached on their
+    /// well-known ports
+    fn default() -> Self {
+        // NTP v3 client request
+        let mut ntp = vec![0u8; 48];
+        ntp[0] = 0x1b;
+
+        UdpProbeTable::empty()
+            .with_probe(53, DNS_PROBE)
+            .with_probe(123, ntp)
+            .with_probe(137, NETBIOS_PROBE)
+            .with_probe(161, SNMP_PROBE)
+            .with_probe(1900, SSDP_PROBE)
+            .with_probe(11211, MEMCACHED_PROBE)
+    }
+}
+
+impl UdpProbeTable {
+    /// A table that sends every port an empty datagram
+    pub fn empty() -> Self {
+        UdpProbeTable {
+            payloads: HashMap::new(),
+        }
+    }
+
+    /// Send `payload` to `port`, replacing any probe it had
+    pub fn with_probe(mut self, port: u16, payload: impl Into<Vec<u8>>) -> Self {
+        self.payloads.insert(port, payload.into());
+        self
+    }
+
+    /// The payload for `port`, empty if it has no probe
+    pub fn payload(&self, port: i32) -> &[u8] {
+        u16::try_from(port)
+            .ok()
+            .and_then(|port| self.payloads.get(&port))
+            .map_or(&[], |payload| payload.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([data[offset], data[offset + 1]])
+    }
+
+    #[test]
+    fn dns_probe_is_a_well_formed_query() {
+        let table = UdpProbeTable::default();
+        let probe = table.payload(53);
+
+        // Header: a standard query asking for recursion, with one question
+        // and no other records
+        assert_eq!(u16_at(probe, 2), 0x0100);
+        assert_eq!(u16_at(probe, 4), 1);
+        assert_eq!(&probe[6..12], &[0; 6]);
+
+        // Question: the root name, NS records in class IN, and nothing after
+        assert_eq!(probe[12], 0);
+        assert_eq!(u16_at(probe, 13), 2);
+        assert_eq!(u16_at(probe, 15), 1);
+        assert_eq!(probe.len(), 17);
+    }
+
+    #[test]
+    fn ports_without_a_probe_get_an_empty_datagram() {
+        let table = UdpProbeTable::default();
+        assert_eq!(table.payload(123)[0], 0x1b);
+        assert!(table.payload(9999).is_empty());
+        assert!(table.payload(-1).is_empty());
+        assert!(table.payload(70000).is_empty());
+        assert!(UdpProbeTable::empty().payload(53).is_empty());
+
+        let table = table.with_probe(53, b"custom".to_vec());
+        assert_eq!(table.payload(53), b"custom");
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use pnet::packet::Packet;
+use pnet::packet::icmp::{IcmpTypes, destination_unreachable::IcmpCodes};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::transport::{self, TransportChannelType, TransportProtocol};
+
+use super::{port_scan::PortScanResult, udp_probes::UdpProbeTable};
+use crate::util::humanize_duration;
+
+/// UDP scan of IPv4 targets. A UDP reply marks a port open, an ICMP port
+/// unreachable marks it closed, and silence leaves it in `filtered_ports`
+/// (open|filtered, since UDP services often don't answer unknown input).
+/// Hosts rate limit ICMP errors, so closed ports on large scans may show as filtered.
+/// Each port is sent its probe from the default `UdpProbeTable`.
+///
+/// Fails if a port is outside 1-65535 or the sockets can't be opened. The
+/// raw socket ICMP errors are read on needs root or CAP_NET_RAW.
+pub fn udp_scan(
+    targets: Vec<IpAddr>,
+    ports: Vec<i32>,
+    timeout: Duration,
+) -> io::Result<Vec<PortScanResult>> {
+    udp_scan_with(targets, ports, timeout, &UdpProbeTable::default())
+}
+
+/// `udp_scan` sending the payloads in `probes`
+pub fn udp_scan_with(
+    targets: Vec<IpAddr>,
+    ports: Vec<i32>,
+    timeout: Duration,
+    probes: &UdpProbeTable,
+) -> io::Result<Vec<PortScanResult>> {
+    if let Some(port) = ports.iter().find(|port| !(1..=65535).contains(*port)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Port {} is outside the range 1-65535", port),
+        ));
+    }
+
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind UDP socket: {}", e)))?;
+    socket.set_read_timeout(Some(Duration::from_millis(3)))?;
+    let local_port = socket.local_addr()?.port();
+
+    let (_, mut icmp_rx) = transport::transport_channel(
+        65535,
+        TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
+    )
+    .map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to create transport channel: {}", e),
+        )
+    })?;
+
+    let results = Arc::new(Mutex::new(HashMap::<IpAddr, PortScanResult>::new()));
+
+    {
+        let mut results_map = results.lock().unwrap();
+        for ip in &targets {
+            results_map.insert(*ip, PortScanResult::new(*ip));
+        }
+    }
+
+    let finished_sending_time = Arc::new(AtomicBool::new(false));
+    let port_count = Arc::new(AtomicU32::new(0));
+
+    let receiver_results = Arc::clone(&results);
+    let receiver_finished_sending_time = Arc::clone(&finished_sending_time);
+    let receiver_port_count = Arc::clone(&port_count);
+    let receiver_ports: HashSet<i32> = ports.iter().cloned().collect();
+    let receiver_socket = socket.try_clone()?;
+    let receiver_handle = thread::spawn(move || {
+        let mut finish_sending_time: Option<Instant> = None;
+        let mut iter = transport::icmp_packet_iter(&mut icmp_rx);
+        let mut buffer = [0u8; 65535];
+
+        loop {
+            if let Some(finish_sending_time) = finish_sending_time {
+                if finish_sending_time.elapsed() >= timeout {
+                    break;
+                }
+            } else if receiver_finished_sending_time.load(Ordering::Relaxed) {
+                finish_sending_time = Some(Instant::now());
+                info!("Waiting {} for timeout...", humanize_duration(timeout))
+            }
+
+            // Any reply from a probed port means something is listening
+            if let Ok((_, SocketAddr::V4(from))) = receiver_socket.recv_from(&mut buffer)
+                && receiver_ports.contains(&(from.port() as i32))
+            {
+                let mut results_map = receiver_results.lock().unwrap();
+                if let Some(result) = results_map.get_mut(&IpAddr::V4(*from.ip())) {
+                    result.open_ports.push(from.port() as i32);
+                    receiver_port_count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+
+            match iter.next_with_timeout(Duration::from_millis(3)) {
+                Ok(Some((packet, _))) => {
+                    if packet.get_icmp_type() != IcmpTypes::DestinationUnreachable
+                        || packet.get_icmp_code() != IcmpCodes::DestinationPortUnreachable
+                    {
+                        continue;
+                    }
+
+                    // The payload quotes the IP and UDP headers of our probe after 4 unused bytes
+                    let payload = packet.payload();
+                    if payload.len() < 4 {
+                        continue;
+                    }
+                    if let Some(ip) = Ipv4Packet::new(&payload[4..])
+                        && ip.get_next_level_protocol() == IpNextHeaderProtocols::Udp
+                        && let Some(udp) = UdpPacket::new(ip.payload())
+                        && udp.get_source() == local_port
+                    {
+                        let mut results_map = receiver_results.lock().unwrap();
+                        if let Some(result) = results_map.get_mut(&IpAddr::V4(ip.get_destination()))
+                        {
+                            result.closed_ports.push(udp.get_destination() as i32);
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    break;
+                }
+            }
+        }
+    });
+
+    let pb = ProgressBar::new((targets.len() * ports.len()) as u64).with_style(
+        ProgressStyle::with_template("[{msg}] {wide_bar:.cyan/blue} {pos}/{len} ({eta_precise})")
+            .unwrap(),
+    );
+
+    for target in &targets {
+        for port in &ports {
+            if let Err(e) = socket.send_to(probes.payload(*port), (*target, *port as u16)) {
+                warn!("Failed to send packet: {}", e);
+            }
+
+            pb.set_message(format!("{} ports", port_count.load(Ordering::Relaxed)));
+            pb.inc(1);
+
+            thread::sleep(Duration::from_micros(100));
+        }
+    }
+
+    pb.finish_with_message("Finished!");
+    finished_sending_time.swap(true, Ordering::Relaxed);
+    receiver_handle.join().unwrap();
+
+    let results_map = results.lock().unwrap();
+    Ok(targets
+        .iter()
+        .map(|ip| {
+            let mut result = results_map
+                .get(ip)
+                .cloned()
+                .unwrap_or_else(|| PortScanResult::new(*ip));
+            result.finish(&ports);
+            result.filtered_may_be_open = true;
+            result
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn ports_outside_the_range_are_an_error() {
+        for port in [0, -1, 65536] {
+            let err = udp_scan(
+                vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                vec![53, port],
+                Duration::from_millis(10),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "port {}", port);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs CAP_NET_RAW"]
+    fn finds_a_local_responder() {
+        // Answers the first datagram it gets
+        let responder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        responder
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let open = responder.local_addr().unwrap().port() as i32;
+        let responder = thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            let (_, from) = responder.recv_from(&mut buffer).unwrap();
+            responder.send_to(b"hello", from).unwrap();
+        });
+        let closed = {
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            socket.local_addr().unwrap().port() as i32
+        };
+
+        let results = udp_scan(
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            vec![open, closed],
+            Duration::from_millis(500),
+        )
+        .unwrap();
+        responder.join().unwrap();
+
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        assert_eq!(result.open_ports, vec![open]);
+        assert_eq!(result.closed_ports, vec![closed]);
+        assert!(result.filtered_ports.is_empty());
+        assert!(result.filtered_may_be_open);
+    }
+}
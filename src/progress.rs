@@ -0,0 +1,102 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use rocksdb::{AsColumnFamilyRef, DB};
+
+// Keys processed between two progress events
+const PROGRESS_INTERVAL: u64 = 4096;
+
+/// Long database operations that report their progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Export,
+    Merge,
+    Reindex,
+    Search,
+}
+
+/// How far an operation has got, sent to the hook set with
+/// `ResultDatabase::with_progress` every few thousand keys, and once more
+/// when it finishes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub operation: Operation,
+    /// Keys read so far
+    pub processed: u64,
+    /// Keys that matched a search or were written, so far
+    pub matched: u64,
+    /// RocksDB's estimate of the keys the operation will read. Only a guess,
+    /// `processed` can end up above it.
+    pub estimated_total: Option<u64>,
+    /// Whether this is the last event of the operation
+    pub done: bool,
+}
+
+/// Called with each `ProgressEvent`
+pub type ProgressHook = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Counts keys for one operation, calling the hook every
+/// `PROGRESS_INTERVAL` of them. Can be shared by threads.
+pub(crate) struct Progress<'a> {
+    hook: Option<&'a ProgressHook>,
+    operation: Operation,
+    estimated_total: Option<u64>,
+    processed: AtomicU64,
+    matched: AtomicU64,
+}
+
+impl<'a> Progress<'a> {
+    /// Progress of reading the keys of `cf`
+    pub(crate) fn new(
+        hook: Option<&'a ProgressHook>,
+        operation: Operation,
+        db: &DB,
+        cf: impl AsColumnFamilyRef,
+    ) -> Self {
+        Progress {
+            hook,
+            operation,
+            // Not worth asking RocksDB when there's no one to tell
+            estimated_total: hook.and_then(|_| {
+                db.property_int_value_cf(&cf, "rocksdb.estimate-num-keys")
+                    .ok()
+                    .flatten()
+            }),
+            processed: AtomicU64::new(0),
+            matched: AtomicU64::new(0),
+        }
+    }
+
+    /// Count one key read, and whether it matched
+    pub(crate) fn key(&self, matched: bool) {
+        if self.hook.is_none() {
+            return;
+        }
+        if matched {
+            self.matched.fetch_add(1, Ordering::Relaxed);
+        }
+        let processed = self.processed.fetch_add(1, Ordering::Relaxed) + 1;
+        if processed.is_multiple_of(PROGRESS_INTERVAL) {
+            self.report(false);
+        }
+    }
+
+    /// Send the final counts
+    pub(crate) fn finish(&self) {
+        self.report(true);
+    }
+
+    fn report(&self, done: bool) {
+        if let Some(hook) = self.hook {
+            hook(ProgressEvent {
+                operation: self.operation,
+                processed: self.processed.load(Ordering::Relaxed),
+                matched: self.matched.load(Ordering::Relaxed),
+                estimated_total: self.estimated_total,
+                done,
+            });
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{error::Error, fmt, net::IpAddr, ops::ControlFlow, str::FromStr};
 
+use log::debug;
 use regex::Regex;
 
-use crate::database::{QueryDataType, QueryType, split_nums};
+use crate::{
+    database::{
+        DatabaseError, DatabaseResult, QueryDataType, QueryType, ResultDatabase, split_nums,
+    },
+    parse_ip_range::IpPrefix,
+    port_scan::parse_ports,
+};
 
 pub fn search(query: String) -> Result<Vec<QueryDataType>, Box<dyn std::error::Error>> {
     if let Ok(ip) = IpAddr::from_str(&query) {
@@ -16,7 +23,7 @@ pub fn search(query: String) -> Result<Vec<QueryDataType>, Box<dyn std::error::E
     let mut results = Vec::new();
 
     for query in split {
-        if let Ok(ip) = IpAddr::from_str(&query) {
+        if let Ok(ip) = IpAddr::from_str(query) {
             return Ok(vec![QueryDataType::Host(ip)]);
         }
 
@@ -60,8 +67,782 @@ pub fn search(query: String) -> Result<Vec<QueryDataType>, Box<dyn std::error::E
     }
 
     for result in &results {
-        println!("{:?}", result);
+        debug!("{:?}", result);
     }
 
     Ok(results)
 }
+
+/// Filter parsed from a query expression by `parse_query`
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryExpr {
+    And(Box<QueryExpr>, Box<QueryExpr>),
+    Or(Box<QueryExpr>, Box<QueryExpr>),
+    Not(Box<QueryExpr>),
+    /// Host has any of these ports open
+    Port(Vec<i32>),
+    /// Text is part of the name or banner of one of the host's services,
+    /// ignoring case
+    Service(String),
+    /// Host's address is inside the network
+    Network(IpPrefix),
+    /// Host's address or hostname is exactly this
+    Host(String),
+    /// Host has this label
+    Tag(String),
+    /// Host was last seen at or after this unix time
+    SeenAfter(u64),
+    /// Host was first seen before this unix time
+    SeenBefore(u64),
+}
+
+impl QueryExpr {
+    /// Whether `row` passes the filter. Rows without timestamps never pass
+    /// `SeenAfter` or `SeenBefore`.
+    pub fn matches(&self, row: &DatabaseResult) -> bool {
+        match self {
+            QueryExpr::And(left, right) => left.matches(row) && right.matches(row),
+            QueryExpr::Or(left, right) => left.matches(row) || right.matches(row),
+            QueryExpr::Not(expr) => !expr.matches(row),
+            QueryExpr::Port(ports) => ports.iter().any(|port| row.ports.contains(port)),
+            QueryExpr::Service(text) => row.service_entries.iter().any(|entry| entry.matches(text)),
+            QueryExpr::Network(prefix) => row
+                .id
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| prefix.contains(&ip)),
+            QueryExpr::Host(host) => {
+                row.id == *host
+                    || row
+                        .hostname
+                        .as_deref()
+                        .is_some_and(|hostname| hostname.eq_ignore_ascii_case(host))
+            }
+            QueryExpr::Tag(tag) => row.tags.contains(tag),
+            QueryExpr::SeenAfter(time) => row.last_seen.is_some_and(|seen| seen >= *time),
+            QueryExpr::SeenBefore(time) => row.first_seen.is_some_and(|seen| seen < *time),
+        }
+    }
+}
+
+/// Why `ResultDatabase::query` failed
+#[derive(Debug)]
+pub enum QueryError {
+    /// The expression doesn't parse. `offset` is where `token` starts in it,
+    /// in bytes, and `token` is empty when the expression ended too early.
+    Parse {
+        message: String,
+        offset: usize,
+        token: String,
+    },
+    Database(DatabaseError),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Parse { message, token, .. } if token.is_empty() => {
+                write!(f, "{} at end of query", message)
+            }
+            QueryError::Parse {
+                message,
+                offset,
+                token,
+            } => write!(f, "{} at \"{}\" (offset {})", message, token, offset),
+            QueryError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+impl From<DatabaseError> for QueryError {
+    fn from(e: DatabaseError) -> Self {
+        QueryError::Database(e)
+    }
+}
+
+impl ResultDatabase {
+    /// Rows matching a query expression such as
+    /// `port:443 AND service:"nginx" AND NOT tag:honeypot`. See `parse_query`.
+    pub fn query(&self, expr: &str) -> Result<Vec<DatabaseResult>, QueryError> {
+        let query = parse_query(expr)?;
+        let mut rows = Vec::new();
+        self.for_each_row(|row| {
+            if query.matches(&row) {
+                rows.push(row);
+            }
+            ControlFlow::Continue(())
+        })?;
+        Ok(rows)
+    }
+}
+
+/// Parse a query expression. Filters are `field:value`:
+///
+/// - `port:443`, or a list such as `port:80,443,8000-8100` for any of them
+/// - `service:nginx`, part of a service name or banner, in any case
+/// - `host:10.0.0.0/8` for a network, `host:10.1.2.3` or `host:example.com`
+///   for an exact address or hostname
+/// - `tag:honeypot`
+/// - `seen_after:<time>` and `seen_before:<time>`, unix seconds or RFC 3339
+///   such as `2024-05-01T12:00:00Z`. A host matches if it was last seen at or
+///   after, or first seen before, the time.
+///
+/// Filters combine with `AND`, `OR`, `NOT` and parentheses, with NOT binding
+/// tightest and OR loosest. Filters next to each other are ANDed. Values with
+/// spaces or parentheses can be quoted: `service:"Apache httpd"`.
+pub fn parse_query(expr: &str) -> Result<QueryExpr, QueryError> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        position: 0,
+    };
+    if parser.peek().kind == TokenKind::End {
+        return Err(parse_error("Empty query", parser.peek()));
+    }
+
+    let query = parser.parse_or()?;
+    let token = parser.peek();
+    if token.kind != TokenKind::End {
+        return Err(parse_error("Unexpected token", token));
+    }
+    Ok(query)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    /// A filter, with quotes removed
+    Word(String),
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    offset: usize,
+    /// The token as written, for error messages
+    text: String,
+}
+
+fn parse_error(message: &str, token: &Token) -> QueryError {
+    QueryError::Parse {
+        message: message.to_string(),
+        offset: token.offset,
+        token: token.text.clone(),
+    }
+}
+
+// Split on whitespace and parentheses, outside of double quotes
+fn tokenize(expr: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '(' || c == ')' {
+            chars.next();
+            tokens.push(Token {
+                kind: if c == '(' {
+                    TokenKind::Open
+                } else {
+                    TokenKind::Close
+                },
+                offset: start,
+                text: c.to_string(),
+            });
+            continue;
+        }
+
+        let mut value = String::new();
+        let mut quoted = false;
+        let mut end = start;
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() || c == '(' || c == ')' {
+                break;
+            }
+            chars.next();
+            end = i + c.len_utf8();
+            if c != '"' {
+                value.push(c);
+                continue;
+            }
+
+            quoted = true;
+            let unterminated = || QueryError::Parse {
+                message: "Unterminated quote".to_string(),
+                offset: i,
+                token: expr[i..].to_string(),
+            };
+            loop {
+                match chars.next() {
+                    Some((i, '"')) => {
+                        end = i + 1;
+                        break;
+                    }
+                    Some((_, '\\')) => {
+                        let (_, escaped) = chars.next().ok_or_else(unterminated)?;
+                        value.push(escaped);
+                    }
+                    Some((_, c)) => value.push(c),
+                    None => return Err(unterminated()),
+                }
+            }
+        }
+
+        let kind = match value.to_ascii_uppercase().as_str() {
+            "AND" if !quoted => TokenKind::And,
+            "OR" if !quoted => TokenKind::Or,
+            "NOT" if !quoted => TokenKind::Not,
+            _ => TokenKind::Word(value),
+        };
+        tokens.push(Token {
+            kind,
+            offset: start,
+            text: expr[start..end].to_string(),
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::End,
+        offset: expr.len(),
+        text: String::new(),
+    });
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        // Stay on End once it's reached
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    // or := and (OR and)*
+    fn parse_or(&mut self) -> Result<QueryExpr, QueryError> {
+        let mut expr = self.parse_and()?;
+        while self.peek().kind == TokenKind::Or {
+            self.next();
+            let right = self.parse_and()?;
+            expr = QueryExpr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    // and := unary (AND? unary)*
+    fn parse_and(&mut self) -> Result<QueryExpr, QueryError> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            match self.peek().kind {
+                TokenKind::And => {
+                    self.next();
+                }
+                TokenKind::Not | TokenKind::Open | TokenKind::Word(_) => {}
+                _ => break,
+            }
+            let right = self.parse_unary()?;
+            expr = QueryExpr::And(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    // unary := NOT unary | "(" or ")" | filter
+    fn parse_unary(&mut self) -> Result<QueryExpr, QueryError> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Not => Ok(QueryExpr::Not(Box::new(self.parse_unary()?))),
+            TokenKind::Open => {
+                let expr = self.parse_or()?;
+                let close = self.next();
+                match close.kind {
+                    TokenKind::Close => Ok(expr),
+                    TokenKind::End => Err(parse_error("Unclosed parenthesis", &token)),
+                    _ => Err(parse_error("Expected )", &close)),
+                }
+            }
+            TokenKind::Word(value) => parse_filter(value, &token),
+            TokenKind::Close => Err(parse_error("Unmatched )", &token)),
+            TokenKind::And | TokenKind::Or => Err(parse_error(
+                &format!("Expected a filter before {}", token.text),
+                &token,
+            )),
+            TokenKind::End => {
+                // Blame the operator left dangling, e.g. the AND in "port:22 AND"
+                let previous = &self.tokens[self.position.saturating_sub(1)];
+                Err(parse_error(
+                    &format!("Expected a filter after {}", previous.text),
+                    previous,
+                ))
+            }
+        }
+    }
+}
+
+fn parse_filter(value: &str, token: &Token) -> Result<QueryExpr, QueryError> {
+    let Some((field, arg)) = value.split_once(':') else {
+        return Err(parse_error(
+            "Expected a field:value filter, such as port:443",
+            token,
+        ));
+    };
+    if arg.is_empty() {
+        return Err(parse_error(
+            &format!("Missing value after {}:", field),
+            token,
+        ));
+    }
+
+    match field.to_ascii_lowercase().as_str() {
+        "port" => parse_ports(arg)
+            .map(QueryExpr::Port)
+            .map_err(|e| parse_error(&e.to_string(), token)),
+        "service" => Ok(QueryExpr::Service(arg.to_string())),
+        "host" if arg.contains('/') => arg
+            .parse::<IpPrefix>()
+            .map(QueryExpr::Network)
+            .map_err(|e| parse_error(&e.to_string(), token)),
+        "host" => Ok(QueryExpr::Host(match arg.parse::<IpAddr>() {
+            // Rows are keyed by the address in its usual form
+            Ok(ip) => ip.to_string(),
+            Err(_) => arg.to_string(),
+        })),
+        "tag" => Ok(QueryExpr::Tag(arg.to_string())),
+        "seen_after" => parse_time(arg, token).map(QueryExpr::SeenAfter),
+        "seen_before" => parse_time(arg, token).map(QueryExpr::SeenBefore),
+        _ => Err(parse_error(
+            &format!(
+                "Unknown field \"{}\", expected port, service, host, tag, seen_after or seen_before",
+                field
+            ),
+            token,
+        )),
+    }
+}
+
+// Unix seconds or an RFC 3339 time
+fn parse_time(text: &str, token: &Token) -> Result<u64, QueryError> {
+    let time = if text.bytes().all(|b| b.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        parse_rfc3339(text)
+    };
+    time.ok_or_else(|| {
+        parse_error(
+            "Expected unix seconds or an RFC 3339 time such as 2024-05-01T12:00:00Z",
+            token,
+        )
+    })
+}
+
+// "2024-05-01T12:00:00Z", optionally with fractional seconds and a "+02:00"
+// style offset. None for anything before 1970.
+fn parse_rfc3339(text: &str) -> Option<u64> {
+    let number = |start: usize, end: usize| -> Option<i64> {
+        let digits = text.get(start..end)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let bytes = text.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+
+    let (year, month, day) = (number(0, 4)?, number(5, 7)?, number(8, 10)?);
+    let (hour, minute, second) = (number(11, 13)?, number(14, 16)?, number(17, 19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let mut rest = &text[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            let start = text.len() - 6;
+            let (hours, minutes) = (number(start + 1, start + 3)?, number(start + 4, start + 6)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    let seconds =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(seconds).ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days from 1970-01-01 to a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count years from March so the leap day falls at the end
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Responses, service_key};
+    use crate::testing::{self, row};
+
+    fn kinds(expr: &str) -> Vec<(TokenKind, usize)> {
+        tokenize(expr)
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.kind, token.offset))
+            .collect()
+    }
+
+    fn word(text: &str) -> TokenKind {
+        TokenKind::Word(text.to_string())
+    }
+
+    fn port(port: i32) -> QueryExpr {
+        QueryExpr::Port(vec![port])
+    }
+
+    fn tag(tag: &str) -> QueryExpr {
+        QueryExpr::Tag(tag.to_string())
+    }
+
+    fn and(left: QueryExpr, right: QueryExpr) -> QueryExpr {
+        QueryExpr::And(Box::new(left), Box::new(right))
+    }
+
+    fn or(left: QueryExpr, right: QueryExpr) -> QueryExpr {
+        QueryExpr::Or(Box::new(left), Box::new(right))
+    }
+
+    fn not(expr: QueryExpr) -> QueryExpr {
+        QueryExpr::Not(Box::new(expr))
+    }
+
+    // Message, offset and token of the error parsing `expr`
+    fn error(expr: &str) -> (String, usize, String) {
+        match parse_query(expr) {
+            Err(QueryError::Parse {
+                message,
+                offset,
+                token,
+            }) => (message, offset, token),
+            other => panic!("{:?} parsed as {:?}", expr, other),
+        }
+    }
+
+    #[test]
+    fn tokens_split_on_spaces_and_parentheses() {
+        assert_eq!(
+            kinds("(port:22 or\ttag:a)AND not x"),
+            vec![
+                (TokenKind::Open, 0),
+                (word("port:22"), 1),
+                (TokenKind::Or, 9),
+                (word("tag:a"), 12),
+                (TokenKind::Close, 17),
+                (TokenKind::And, 18),
+                (TokenKind::Not, 22),
+                (word("x"), 26),
+                (TokenKind::End, 27),
+            ]
+        );
+        assert_eq!(kinds("  "), vec![(TokenKind::End, 2)]);
+    }
+
+    #[test]
+    fn quotes_keep_spaces_parentheses_and_escapes() {
+        assert_eq!(
+            kinds(r#"service:"Apache (httpd)" "AND" tag:"a\"b\\c""#),
+            vec![
+                (word("service:Apache (httpd)"), 0),
+                // Quoted, so a filter rather than an operator
+                (word("AND"), 25),
+                (word("tag:a\"b\\c"), 31),
+                (TokenKind::End, 44),
+            ]
+        );
+        assert_eq!(
+            parse_query(r#"service:"Apache httpd""#).unwrap(),
+            QueryExpr::Service("Apache httpd".to_string())
+        );
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_and_and_than_or() {
+        assert_eq!(
+            parse_query("port:1 OR port:2 AND NOT port:3").unwrap(),
+            or(port(1), and(port(2), not(port(3))))
+        );
+        assert_eq!(
+            parse_query("NOT port:1 AND port:2 OR port:3").unwrap(),
+            or(and(not(port(1)), port(2)), port(3))
+        );
+        assert_eq!(
+            parse_query("NOT (port:1 OR port:2)").unwrap(),
+            not(or(port(1), port(2)))
+        );
+        assert_eq!(
+            parse_query("(port:1 OR port:2) AND port:3").unwrap(),
+            and(or(port(1), port(2)), port(3))
+        );
+    }
+
+    #[test]
+    fn filters_next_to_each_other_are_anded() {
+        assert_eq!(
+            parse_query("port:1 tag:a OR NOT tag:b tag:c").unwrap(),
+            or(and(port(1), tag("a")), and(not(tag("b")), tag("c")))
+        );
+        assert_eq!(
+            parse_query("port:1 and tag:a").unwrap(),
+            parse_query("port:1 tag:a").unwrap()
+        );
+    }
+
+    #[test]
+    fn filters_parse_their_values() {
+        assert_eq!(
+            parse_query("port:80,443,8000-8002").unwrap(),
+            QueryExpr::Port(vec![80, 443, 8000, 8001, 8002])
+        );
+        assert_eq!(
+            parse_query("HOST:10.0.0.0/8").unwrap(),
+            QueryExpr::Network("10.0.0.0/8".parse().unwrap())
+        );
+        assert_eq!(
+            parse_query("host:::0001").unwrap(),
+            QueryExpr::Host("::1".to_string())
+        );
+        assert_eq!(
+            parse_query("host:Gateway.lan").unwrap(),
+            QueryExpr::Host("Gateway.lan".to_string())
+        );
+        assert_eq!(
+            parse_query("seen_after:1714564800").unwrap(),
+            QueryExpr::SeenAfter(1714564800)
+        );
+        assert_eq!(
+            parse_query("seen_before:2024-05-01T12:00:00Z").unwrap(),
+            QueryExpr::SeenBefore(1714564800)
+        );
+    }
+
+    #[test]
+    fn rfc3339_times() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00Z"), Some(1714564800));
+        assert_eq!(parse_rfc3339("2024-05-01t12:00:00z"), Some(1714564800));
+        assert_eq!(parse_rfc3339("2024-05-01 12:00:00Z"), Some(1714564800));
+
+        // Offsets are subtracted to get UTC
+        assert_eq!(parse_rfc3339("2024-05-01T14:30:00+02:30"), Some(1714564800));
+        assert_eq!(parse_rfc3339("2024-05-01T07:00:00-05:00"), Some(1714564800));
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00+0200"), None);
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00+24:00"), None);
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00"), None);
+
+        // Fractions of a second are dropped
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00.999Z"), Some(1714564800));
+        assert_eq!(
+            parse_rfc3339("2024-05-01T12:00:00.5+00:00"),
+            Some(1714564800)
+        );
+        assert_eq!(parse_rfc3339("2024-05-01T12:00:00.Z"), None);
+
+        // Leap days only in leap years
+        assert_eq!(parse_rfc3339("2024-02-29T00:00:00Z"), Some(1709164800));
+        assert_eq!(parse_rfc3339("2000-02-29T23:59:59Z"), Some(951868799));
+        assert_eq!(parse_rfc3339("2023-02-29T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("1900-02-29T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2024-04-31T00:00:00Z"), None);
+
+        assert_eq!(parse_rfc3339("1969-12-31T23:59:59Z"), None);
+        assert_eq!(parse_rfc3339("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2024-05-01T24:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2024-05-01"), None);
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_token() {
+        let cases = [
+            ("", "Empty query", 0, ""),
+            ("   ", "Empty query", 3, ""),
+            (
+                r#"port:22 service:"nginx"#,
+                "Unterminated quote",
+                16,
+                "\"nginx",
+            ),
+            (r#"tag:"a\"#, "Unterminated quote", 4, "\"a\\"),
+            (") port:22", "Unmatched )", 0, ")"),
+            ("port:22 )", "Unexpected token", 8, ")"),
+            ("port:22 AND (tag:a", "Unclosed parenthesis", 12, "("),
+            ("(port:22 tag:a", "Unclosed parenthesis", 0, "("),
+            ("port:22 AND", "Expected a filter after AND", 8, "AND"),
+            ("port:22 OR NOT", "Expected a filter after NOT", 11, "NOT"),
+            ("OR port:22", "Expected a filter before OR", 0, "OR"),
+            (
+                "port:22 AND AND tag:a",
+                "Expected a filter before AND",
+                12,
+                "AND",
+            ),
+            (
+                "port:22 colour:red",
+                "Unknown field \"colour\", expected port, service, host, tag, seen_after or seen_before",
+                8,
+                "colour:red",
+            ),
+            ("port:22 tag:", "Missing value after tag:", 8, "tag:"),
+            (
+                "nginx",
+                "Expected a field:value filter, such as port:443",
+                0,
+                "nginx",
+            ),
+            (
+                "port:22 seen_after:yesterday",
+                "Expected unix seconds or an RFC 3339 time such as 2024-05-01T12:00:00Z",
+                8,
+                "seen_after:yesterday",
+            ),
+        ];
+        for (expr, message, offset, token) in cases {
+            assert_eq!(
+                error(expr),
+                (message.to_string(), offset, token.to_string()),
+                "{:?}",
+                expr
+            );
+        }
+
+        assert_eq!(
+            parse_query("port:22 AND").unwrap_err().to_string(),
+            "Expected a filter after AND at \"AND\" (offset 8)"
+        );
+        assert_eq!(
+            parse_query("").unwrap_err().to_string(),
+            "Empty query at end of query"
+        );
+    }
+
+    #[test]
+    fn query_finds_matching_rows() {
+        let (_dir, database) = testing::temp_database();
+
+        let mut web = row("10.0.0.1", &[22, 80], &["ssh", "http"]);
+        web.set_response_map(&Responses::from([
+            (
+                service_key(22, "tcp"),
+                ("ssh".to_string(), "SSH-2.0-OpenSSH_9.6".to_string()),
+            ),
+            (
+                service_key(80, "tcp"),
+                (
+                    "http".to_string(),
+                    "HTTP/1.1 200 OK\r\nServer: nginx/1.24.0".to_string(),
+                ),
+            ),
+        ]));
+        let mut trap = row("10.0.0.2", &[443], &["https"]);
+        trap.set_response_map(&Responses::from([(
+            service_key(443, "tcp"),
+            ("https".to_string(), "Apache httpd".to_string()),
+        )]));
+        database
+            .save_rows(vec![web, trap, row("192.168.1.5", &[22], &[])])
+            .unwrap();
+        database.add_tag("10.0.0.1", "lab").unwrap();
+        database.add_tag("10.0.0.2", "honeypot").unwrap();
+        database.set_hostname("192.168.1.5", "printer.lan").unwrap();
+
+        let hosts = |expr: &str| -> Vec<String> {
+            let mut hosts: Vec<String> = database
+                .query(expr)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.id)
+                .collect();
+            hosts.sort();
+            hosts
+        };
+
+        assert_eq!(
+            hosts("port:22 AND NOT tag:honeypot"),
+            vec!["10.0.0.1", "192.168.1.5"]
+        );
+        assert_eq!(hosts("service:NGINX"), vec!["10.0.0.1"]);
+        assert_eq!(hosts(r#"service:"apache httpd""#), vec!["10.0.0.2"]);
+        assert_eq!(
+            hosts("host:10.0.0.0/24 (port:443 OR tag:lab)"),
+            vec!["10.0.0.1", "10.0.0.2"]
+        );
+        assert_eq!(hosts("host:PRINTER.lan"), vec!["192.168.1.5"]);
+        assert_eq!(hosts("seen_after:0").len(), 3);
+        assert!(hosts("seen_before:1").is_empty());
+        assert!(hosts("port:8080").is_empty());
+
+        assert!(matches!(
+            database.query("port:22 AND"),
+            Err(QueryError::Parse { offset: 8, .. })
+        ));
+    }
+}
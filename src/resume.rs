@@ -0,0 +1,197 @@
+use std::{fs, io, ops::ControlFlow, path::PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::database::ResultDatabase;
+
+/// How far a chunked scan has got, kept in a small JSON file next to the
+/// database so `scan resume` can carry on after a crash or Ctrl-C. Only
+/// advanced once a chunk's results are saved, so a chunk that was cut off
+/// part way, and any host in it that was only half scanned, is scanned
+/// again from the start.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanCheckpoint {
+    /// The arguments after `scan`, to run the same scan again with
+    pub args: Vec<String>,
+    /// Id of the run the scan records, kept by the resumed scan
+    pub run_id: String,
+    /// Unix seconds
+    pub started_at: u64,
+    /// Seed targets read from a file are shuffled with, so a resumed scan
+    /// visits them in the same order
+    pub seed: u64,
+    /// Targets at the start of the list whose results are all saved
+    pub completed: usize,
+    /// Host results saved so far
+    pub results_written: u64,
+}
+
+impl ResultDatabase {
+    /// Where the checkpoint of a scan into this database is kept, beside
+    /// the database directory rather than in it so backups don't copy it
+    pub fn checkpoint_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.checkpoint", self.path))
+    }
+
+    /// The checkpoint of an unfinished scan, if there is one
+    pub fn load_checkpoint(&self) -> Result<Option<ScanCheckpoint>, Box<dyn std::error::Error>> {
+        match fs::read(self.checkpoint_path()) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the checkpoint. Written to a temporary file and renamed over
+    /// the old one, so a crash part way through leaves the old one whole.
+    pub fn save_checkpoint(&self, checkpoint: &ScanCheckpoint) -> io::Result<()> {
+        let path = self.checkpoint_path();
+        let temp = path.with_extension("checkpoint.tmp");
+        fs::write(&temp, serde_json::to_vec(checkpoint)?)?;
+        fs::rename(temp, path)
+    }
+
+    /// Scan the targets `checkpoint` hasn't got to yet, `chunk_size` at a
+    /// time. `scan_chunk` gets each chunk with its number, from 1, and the
+    /// number of chunks left to scan, scans it, saves the results and
+    /// returns how many it saved. `ControlFlow::Continue` means the chunk is
+    /// done, and the checkpoint is moved past it and saved. `Break` stops
+    /// the scan, e.g. on Ctrl-C, and leaves the chunk to be scanned again
+    /// from the start on resume, as does an error. A checkpoint that can't
+    /// be saved is logged rather than stopping the scan, which is still
+    /// worth finishing even if it can't be resumed. Returns the number of
+    /// results saved by this call.
+    pub fn scan_from_checkpoint<T, E>(
+        &self,
+        checkpoint: &mut ScanCheckpoint,
+        targets: &[T],
+        chunk_size: usize,
+        mut scan_chunk: impl FnMut(&[T], usize, usize) -> Result<ControlFlow<u64, u64>, E>,
+    ) -> Result<u64, E> {
+        let save = |checkpoint: &ScanCheckpoint| {
+            if let Err(e) = self.save_checkpoint(checkpoint) {
+                warn!("Couldn't save the scan checkpoint: {}", e);
+            }
+        };
+
+        let remaining = &targets[checkpoint.completed.min(targets.len())..];
+        let chunk_size = chunk_size.max(1);
+        let chunks = remaining.len().div_ceil(chunk_size);
+        // Saved before the first chunk too, so a scan that stops in it can
+        // be resumed with its arguments
+        save(checkpoint);
+
+        let mut written = 0;
+        for (i, chunk) in remaining.chunks(chunk_size).enumerate() {
+            match scan_chunk(chunk, i + 1, chunks)? {
+                ControlFlow::Continue(saved) => {
+                    written += saved;
+                    checkpoint.completed += chunk.len();
+                    checkpoint.results_written += saved;
+                    save(checkpoint);
+                }
+                ControlFlow::Break(saved) => {
+                    written += saved;
+                    break;
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Remove the checkpoint once its scan is finished
+    pub fn clear_checkpoint(&self) -> io::Result<()> {
+        match fs::remove_file(self.checkpoint_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, ops::ControlFlow};
+
+    use super::*;
+    use crate::testing;
+
+    fn checkpoint() -> ScanCheckpoint {
+        ScanCheckpoint {
+            args: vec!["tcp".to_string(), "10.0.0.0/28".to_string()],
+            run_id: "run-1".to_string(),
+            started_at: 1_700_000_000,
+            seed: 7,
+            completed: 0,
+            results_written: 0,
+        }
+    }
+
+    #[test]
+    fn resumed_scan_skips_saved_chunks() {
+        let (_dir, database) = testing::temp_database();
+        let targets: Vec<u32> = (0..10).collect();
+
+        // Stopped part way through the second chunk, after saving one result
+        let mut first = checkpoint();
+        let mut scanned = Vec::new();
+        let written = database
+            .scan_from_checkpoint(&mut first, &targets, 4, |chunk, _, _| {
+                scanned.extend_from_slice(chunk);
+                Ok::<_, Infallible>(if scanned.len() > 4 {
+                    ControlFlow::Break(1)
+                } else {
+                    ControlFlow::Continue(chunk.len() as u64)
+                })
+            })
+            .unwrap();
+        assert_eq!(scanned, (0..8).collect::<Vec<_>>());
+        assert_eq!(written, 5);
+        let saved = database.load_checkpoint().unwrap().unwrap();
+        assert_eq!(saved.completed, 4);
+        assert_eq!(saved.results_written, 4);
+        assert_eq!(saved.args, first.args);
+
+        // The first chunk isn't scanned again, the cut off one is
+        let mut resumed = saved;
+        let mut scanned = Vec::new();
+        let mut numbers = Vec::new();
+        database
+            .scan_from_checkpoint(&mut resumed, &targets, 4, |chunk, number, chunks| {
+                scanned.extend_from_slice(chunk);
+                numbers.push((number, chunks));
+                Ok::<_, Infallible>(ControlFlow::Continue(chunk.len() as u64))
+            })
+            .unwrap();
+        assert_eq!(scanned, (4..10).collect::<Vec<_>>());
+        assert_eq!(numbers, [(1, 2), (2, 2)]);
+        let saved = database.load_checkpoint().unwrap().unwrap();
+        assert_eq!(saved.completed, 10);
+        assert_eq!(saved.results_written, 10);
+
+        database.clear_checkpoint().unwrap();
+        assert!(database.load_checkpoint().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_chunk_is_scanned_again() {
+        let (_dir, database) = testing::temp_database();
+        let targets: Vec<u32> = (0..6).collect();
+
+        let mut checkpoint = checkpoint();
+        let result = database.scan_from_checkpoint(&mut checkpoint, &targets, 3, |chunk, _, _| {
+            if chunk[0] == 3 {
+                Err("couldn't save")
+            } else {
+                Ok(ControlFlow::Continue(3))
+            }
+        });
+        assert_eq!(result, Err("couldn't save"));
+        assert_eq!(checkpoint.completed, 3);
+        assert_eq!(database.load_checkpoint().unwrap().unwrap().completed, 3);
+
+        // Kept beside the temporary directory, not in it
+        database.clear_checkpoint().unwrap();
+    }
+}
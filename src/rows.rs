@@ -0,0 +1,267 @@
+use std::{collections::VecDeque, ops::ControlFlow, sync::Arc};
+
+use rocksdb::{DB, Direction, IteratorMode, ReadOptions, Snapshot};
+
+use crate::database::{DatabaseError, DatabaseResult, ResultDatabase, host_from_key};
+
+/// Lazily walks every row in key order, holding at most `DatabaseOptions::batch_size` rows
+/// in memory. Created by `ResultDatabase::iter_rows`.
+///
+/// Each batch is read from its own RocksDB snapshot, so a batch is always
+/// consistent across column families. Rows written while iterating may or
+/// may not be returned depending on which batch they land in, but no row
+/// is returned twice. Use `ResultDatabase::for_each_row` or
+/// `DbSnapshot::iter_rows` to see a single snapshot of the whole database.
+pub struct RowIter<'a> {
+    database: &'a ResultDatabase,
+    db: Option<Arc<DB>>,
+    // Snapshot every batch is read from, instead of a new one per batch
+    pinned: Option<&'a Snapshot<'a>>,
+    // Error from opening the database, returned by the first call to next
+    open_error: Option<DatabaseError>,
+    buffer: VecDeque<Result<DatabaseResult, DatabaseError>>,
+    // Last key read, the next batch starts after it
+    last_key: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl RowIter<'_> {
+    // Read the next batch of rows into the buffer
+    fn fill(&mut self) {
+        let Some(db) = &self.db else {
+            self.done = true;
+            return;
+        };
+        let cfs = match self.database.column_handles(db) {
+            Ok(cfs) => cfs,
+            Err(e) => {
+                self.buffer.push_back(Err(e));
+                self.done = true;
+                return;
+            }
+        };
+
+        let fresh;
+        let snapshot = match self.pinned {
+            Some(snapshot) => snapshot,
+            None => {
+                fresh = db.snapshot();
+                &fresh
+            }
+        };
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(snapshot);
+
+        let mode = match &self.last_key {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        // Resuming seeks past the host key prefix the batch stopped in
+        let mut iter_opts = ReadOptions::default();
+        iter_opts.set_total_order_seek(true);
+
+        let mut read = 0;
+        for item in snapshot.iterator_cf_opt(cfs[0], iter_opts, mode) {
+            let key_bytes = match item {
+                Ok((key_bytes, _)) => key_bytes,
+                Err(e) => {
+                    self.buffer.push_back(Err(e.into()));
+                    break;
+                }
+            };
+            if self.last_key.as_deref() == Some(&key_bytes[..]) {
+                continue;
+            }
+
+            if let Some(key_str) = host_from_key(&key_bytes) {
+                match self.database.fetch_row_opt(db, &key_str, &cfs, &read_opts) {
+                    Ok(Some(row)) => self.buffer.push_back(Ok(row)),
+                    Ok(None) => {}
+                    Err(e) => {
+                        self.buffer.push_back(Err(e));
+                        break;
+                    }
+                }
+            }
+
+            self.last_key = Some(key_bytes.to_vec());
+            read += 1;
+            if read >= self.database.batch_size {
+                return;
+            }
+        }
+
+        self.done = true;
+    }
+}
+
+impl Iterator for RowIter<'_> {
+    type Item = Result<DatabaseResult, DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.open_error.take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+
+        while self.buffer.is_empty() && !self.done {
+            self.fill();
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+impl ResultDatabase {
+    /// Every row, read in batches as the iterator advances instead of all at
+    /// once
+    pub fn iter_rows(&self) -> RowIter<'_> {
+        self.iter_rows_at(None)
+    }
+
+    // `iter_rows` reading every batch from `pinned` when given
+    pub(crate) fn iter_rows_at<'a>(&'a self, pinned: Option<&'a Snapshot<'a>>) -> RowIter<'a> {
+        let (db, open_error) = match self.db() {
+            Ok(db) => (Some(db), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        RowIter {
+            database: self,
+            db,
+            pinned,
+            open_error,
+            buffer: VecDeque::new(),
+            last_key: None,
+            done: false,
+        }
+    }
+
+    /// Call `f` with every row in key order until it returns `ControlFlow::Break`.
+    /// Rows come from one RocksDB snapshot taken before the first row, so
+    /// writes made while this runs are not seen.
+    pub fn for_each_row(
+        &self,
+        mut f: impl FnMut(DatabaseResult) -> ControlFlow<()>,
+    ) -> Result<(), DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let snapshot = db.snapshot();
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(&snapshot);
+
+        for item in snapshot.iterator_cf(cfs[0], IteratorMode::Start) {
+            let (key_bytes, _) = item?;
+            if let Some(key_str) = host_from_key(&key_bytes)
+                && let Some(row) = self.fetch_row_opt(&db, &key_str, &cfs, &read_opts)?
+                && f(row).is_break()
+            {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use crate::{
+        database::{DatabaseOptions, ResultDatabase},
+        testing::{self, row},
+    };
+
+    // Saved out of order, and read back sorted by address, IPv4 first
+    const HOSTS: [&str; 7] = [
+        "10.0.0.10",
+        "printer.lan",
+        "2001:db8::1",
+        "10.0.0.2",
+        "::1",
+        "192.168.1.1",
+        "10.0.0.9",
+    ];
+    const SORTED: [&str; 7] = [
+        "10.0.0.2",
+        "10.0.0.9",
+        "10.0.0.10",
+        "192.168.1.1",
+        "::1",
+        "2001:db8::1",
+        "printer.lan",
+    ];
+
+    fn fill(database: &ResultDatabase) {
+        database
+            .save_rows(HOSTS.iter().map(|host| row(host, &[22], &[])).collect())
+            .unwrap();
+    }
+
+    #[test]
+    fn iterates_every_row_in_key_order_across_batches() {
+        let (_dir, database) =
+            testing::temp_database_with(DatabaseOptions::default().with_batch_size(3));
+        fill(&database);
+
+        let hosts: Vec<String> = database.iter_rows().map(|row| row.unwrap().id).collect();
+        assert_eq!(hosts, SORTED);
+    }
+
+    #[test]
+    fn rows_written_while_iterating_are_not_returned_twice() {
+        let (_dir, database) =
+            testing::temp_database_with(DatabaseOptions::default().with_batch_size(2));
+        fill(&database);
+
+        let mut hosts = Vec::new();
+        for row in database.iter_rows() {
+            let row = row.unwrap();
+            // Rewrite every row, and add one ahead of the iterator
+            database.save_rows(vec![row.clone()]).unwrap();
+            if row.id == "10.0.0.2" {
+                database
+                    .save_rows(vec![testing::row("172.16.0.1", &[80], &[])])
+                    .unwrap();
+            }
+            hosts.push(row.id);
+        }
+
+        let mut expected: Vec<&str> = SORTED.to_vec();
+        expected.insert(3, "172.16.0.1");
+        assert_eq!(hosts, expected);
+    }
+
+    #[test]
+    fn for_each_row_stops_on_break() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database);
+
+        let mut hosts = Vec::new();
+        database
+            .for_each_row(|row| {
+                hosts.push(row.id);
+                if hosts.len() == 3 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            })
+            .unwrap();
+        assert_eq!(hosts, SORTED[..3]);
+    }
+
+    #[test]
+    fn open_errors_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("not-a-database");
+        std::fs::write(&file, "").unwrap();
+        let database = ResultDatabase::new(&file.to_string_lossy());
+
+        let mut rows = database.iter_rows();
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.next().is_none());
+    }
+}
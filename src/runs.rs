@@ -0,0 +1,84 @@
+use rocksdb::IteratorMode;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{DatabaseError, RUN_COLUMN, ResultDatabase, column_handle};
+
+/// What was scanned and when, kept for forensics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunMetadata {
+    /// Start time then a random suffix, so ids sort by start time. Rows saved
+    /// by the run store it as their `last_run`
+    #[serde(default)]
+    pub id: String,
+    pub scan_type: String,
+    pub targets: String,
+    /// Ports probed, empty for ping scans
+    #[serde(default)]
+    pub ports: Vec<i32>,
+    /// Unix seconds
+    pub started_at: u64,
+    /// Unix seconds, never before `started_at`
+    pub finished_at: u64,
+    /// Host results saved by the run
+    #[serde(default)]
+    pub results_written: u64,
+    /// Local clock offset from `ntp_server` in milliseconds, if it was checked
+    pub clock_offset_ms: Option<i64>,
+    pub ntp_server: Option<String>,
+    /// Whether the run was stopped early, with only part of its targets saved
+    #[serde(default)]
+    pub interrupted: bool,
+}
+
+impl RunMetadata {
+    pub fn new(scan_type: &str, targets: &str, started_at: u64) -> Self {
+        RunMetadata {
+            // Random suffix so runs started in the same second don't collide
+            id: format!("{:020}-{:08x}", started_at, rand::random::<u32>()),
+            scan_type: scan_type.to_string(),
+            targets: targets.to_string(),
+            ports: Vec::new(),
+            started_at,
+            finished_at: started_at,
+            results_written: 0,
+            clock_offset_ms: None,
+            ntp_server: None,
+            interrupted: false,
+        }
+    }
+
+    /// Set the finish time, clamped so the run can't have a negative length
+    pub fn finish(&mut self, finished_at: u64) {
+        self.finished_at = finished_at.max(self.started_at);
+    }
+}
+
+impl ResultDatabase {
+    pub fn record_run(&self, run: &RunMetadata) -> Result<(), DatabaseError> {
+        let db = self.writable_db()?;
+        let cf = column_handle(&db, RUN_COLUMN)?;
+
+        db.put_cf(cf, run.id.as_bytes(), serde_json::to_vec(run)?)?;
+
+        Ok(())
+    }
+
+    /// Every recorded run, oldest first
+    pub fn list_scan_runs(&self) -> Result<Vec<RunMetadata>, DatabaseError> {
+        let db = self.db()?;
+        let cf = column_handle(&db, RUN_COLUMN)?;
+
+        let mut runs = Vec::new();
+        for item in db.iterator_cf(cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let mut run: RunMetadata = serde_json::from_slice(&value)?;
+            // Runs recorded before ids were stored are keyed by what their id would have been
+            if run.id.is_empty() {
+                run.id = String::from_utf8_lossy(&key).to_string();
+            }
+            runs.push(run);
+        }
+
+        Ok(runs)
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::BTreeSet;
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rocksdb::{ColumnFamily, DB, DEFAULT_COLUMN_FAMILY_NAME, ReadOptions};
+
+use crate::database::{DatabaseError, DatabaseResult, ResultDatabase, host_from_key};
+
+// Below this many rows per sample the whole column is read instead, as
+// most draws would land on a row already picked
+const MIN_ROWS_PER_SAMPLE: u64 = 4;
+// Rounds of drawing more rows, when rows counted in more than one span
+// were picked twice, before reading the whole column instead
+const MAX_DRAW_ROUNDS: usize = 4;
+
+// Keys from `start` to `end`, and about how many rows are between them
+struct KeySpan {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    rows: u64,
+}
+
+impl ResultDatabase {
+    /// Up to `n` random rows, for spot checks or seeding another scan, in
+    /// key order. All of them if there are no more than `n`.
+    pub fn sample_hosts(&self, n: usize) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        self.sample_hosts_seeded(n, rand::random())
+    }
+
+    /// `sample_hosts` with the choice made by a generator seeded with
+    /// `seed`, so the same seed picks the same hosts from the same database.
+    ///
+    /// A large database isn't read whole. RocksDB knows the key range and
+    /// row count of each of its files, so a file is picked in proportion to
+    /// its rows, then a row of it at random, reached by walking from the
+    /// file's first key. Walks into the same file are made together, so a
+    /// small sample only reads part of the files it lands in. Row counts
+    /// include overwritten rows not yet compacted away, so the choice is
+    /// only roughly uniform until the database is compacted. Databases with
+    /// few rows per sample are read whole and sampled exactly.
+    pub fn sample_hosts_seeded(
+        &self,
+        n: usize,
+        seed: u64,
+    ) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let spans = key_spans(&db, cfs[0]);
+        let rows: u64 = spans.iter().map(|span| span.rows).sum();
+        let keys = if n == 0 {
+            Vec::new()
+        } else if rows < n as u64 * MIN_ROWS_PER_SAMPLE || n >= spans.len() {
+            // Nearly every file would be walked anyway
+            reservoir_sample(&db, cfs[0], n, &mut rng)
+        } else {
+            walk_sample(&db, cfs[0], &spans, rows, n, &mut rng)
+                .unwrap_or_else(|| reservoir_sample(&db, cfs[0], n, &mut rng))
+        };
+
+        self.fetch_rows(&db, keys.iter().filter_map(|key| host_from_key(key)), &cfs)
+    }
+}
+
+// Iterate every key of `cf`, whatever its prefix
+fn total_order() -> ReadOptions {
+    let mut read_opts = ReadOptions::default();
+    read_opts.set_total_order_seek(true);
+    read_opts
+}
+
+// Where the rows of `cf` are, from the key range and entry count of each of
+// its files. Rows still in memory could be anywhere, so they're counted as
+// a span of the whole column.
+fn key_spans(db: &DB, cf: &ColumnFamily) -> Vec<KeySpan> {
+    let mut spans: Vec<KeySpan> = db
+        .live_files()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|file| file.column_family_name == DEFAULT_COLUMN_FAMILY_NAME)
+        .filter_map(|file| {
+            Some(KeySpan {
+                start: file.start_key?,
+                end: file.end_key?,
+                rows: file.num_entries.saturating_sub(file.num_deletions),
+            })
+        })
+        .collect();
+
+    let in_files: u64 = spans.iter().map(|span| span.rows).sum();
+    let estimated = db
+        .property_int_value_cf(cf, "rocksdb.estimate-num-keys")
+        .ok()
+        .flatten()
+        .unwrap_or(0);
+    let mut iter = db.raw_iterator_cf_opt(cf, total_order());
+    iter.seek_to_first();
+    let first = iter.key().map(|key| key.to_vec());
+    iter.seek_to_last();
+    let last = iter.key().map(|key| key.to_vec());
+    if let (Some(start), Some(end)) = (first, last)
+        && estimated > in_files
+    {
+        spans.push(KeySpan {
+            start,
+            end,
+            rows: estimated - in_files,
+        });
+    }
+    spans
+}
+
+// Keys of `n` distinct rows, picked by drawing rows across `spans` in
+// proportion to their counts and walking to each. None if spans overlap so
+// much that draws keep finding rows already picked.
+fn walk_sample(
+    db: &DB,
+    cf: &ColumnFamily,
+    spans: &[KeySpan],
+    rows: u64,
+    n: usize,
+    rng: &mut StdRng,
+) -> Option<Vec<Vec<u8>>> {
+    let mut iter = db.raw_iterator_cf_opt(cf, total_order());
+    let mut drawn: Vec<BTreeSet<u64>> = vec![BTreeSet::new(); spans.len()];
+    let mut picked = BTreeSet::new();
+
+    for _ in 0..MAX_DRAW_ROUNDS {
+        // Offsets into each span, new this round
+        let mut offsets: Vec<BTreeSet<u64>> = vec![BTreeSet::new(); spans.len()];
+        let mut new = 0;
+        while picked.len() + new < n {
+            let mut row = rng.random_range(0..rows);
+            let (i, span) = spans
+                .iter()
+                .enumerate()
+                .find(|(_, span)| {
+                    if row < span.rows {
+                        return true;
+                    }
+                    row -= span.rows;
+                    false
+                })
+                .unwrap_or((0, &spans[0]));
+            if row < span.rows && drawn[i].insert(row) {
+                offsets[i].insert(row);
+                new += 1;
+            }
+        }
+
+        for (span, offsets) in spans.iter().zip(offsets) {
+            let Some(&last) = offsets.last() else {
+                continue;
+            };
+            iter.seek(&span.start);
+            for index in 0..=last {
+                match iter.key() {
+                    Some(key) if key <= span.end.as_slice() => {
+                        if offsets.contains(&index) {
+                            picked.insert(key.to_vec());
+                        }
+                    }
+                    // Fewer rows than counted, overwritten ones the file
+                    // still holds
+                    _ => break,
+                }
+                iter.next();
+            }
+        }
+
+        if picked.len() >= n {
+            return Some(picked.into_iter().take(n).collect());
+        }
+    }
+    None
+}
+
+// Keys of `n` rows picked uniformly by reading every key once, all of them
+// if there are no more
+fn reservoir_sample(db: &DB, cf: &ColumnFamily, n: usize, rng: &mut StdRng) -> Vec<Vec<u8>> {
+    let mut reservoir: Vec<Vec<u8>> = Vec::with_capacity(n);
+    let mut iter = db.raw_iterator_cf_opt(cf, total_order());
+    iter.seek_to_first();
+
+    let mut seen = 0;
+    while let Some(key) = iter.key() {
+        if reservoir.len() < n {
+            reservoir.push(key.to_vec());
+        } else {
+            let slot = rng.random_range(0..=seen);
+            if slot < n {
+                reservoir[slot] = key.to_vec();
+            }
+        }
+        seen += 1;
+        iter.next();
+    }
+
+    reservoir.sort();
+    reservoir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, row};
+
+    fn hosts(rows: &[DatabaseResult]) -> Vec<&str> {
+        rows.iter().map(|row| row.id.as_str()).collect()
+    }
+
+    // `files` table files of `per_file` rows each, none overlapping
+    fn fill(database: &ResultDatabase, files: u8, per_file: u8) {
+        for file in 0..files {
+            let rows = (0..per_file)
+                .map(|i| row(&format!("10.0.{}.{}", file, i), &[80], &[]))
+                .collect();
+            database.save_rows(rows).unwrap();
+            let db = database.db().unwrap();
+            let cfs = database.column_handles(&db).unwrap();
+            db.flush_cf(cfs[0]).unwrap();
+        }
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_hosts() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database, 8, 50);
+
+        let first = database.sample_hosts_seeded(5, 7).unwrap();
+        assert_eq!(first.len(), 5);
+        assert_eq!(
+            hosts(&first),
+            hosts(&database.sample_hosts_seeded(5, 7).unwrap())
+        );
+        // In key order, and different for most other seeds
+        let mut sorted = first.clone();
+        sorted.sort_by_key(|row| crate::database::host_key(&row.id));
+        assert_eq!(hosts(&first), hosts(&sorted));
+        assert!((8..16).any(|seed| {
+            hosts(&database.sample_hosts_seeded(5, seed).unwrap()) != hosts(&first)
+        }));
+    }
+
+    #[test]
+    fn sample_is_capped_at_the_hosts_there_are() {
+        let (_dir, database) = testing::temp_database();
+        assert!(database.sample_hosts(3).unwrap().is_empty());
+
+        fill(&database, 1, 10);
+        assert!(database.sample_hosts(0).unwrap().is_empty());
+        let all = database.sample_hosts(25).unwrap();
+        assert_eq!(all.len(), 10);
+        assert_eq!(
+            hosts(&all),
+            hosts(&database.get_rows_by_cidr("10.0.0.0/24").unwrap())
+        );
+        assert_eq!(database.sample_hosts(10).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn walks_pick_distinct_rows_of_a_compacted_database() {
+        let (_dir, database) = testing::temp_database();
+        fill(&database, 8, 250);
+        database.compact().unwrap();
+
+        let db = database.db().unwrap();
+        let cfs = database.column_handles(&db).unwrap();
+        let spans = key_spans(&db, cfs[0]);
+        let rows: u64 = spans.iter().map(|span| span.rows).sum();
+        assert_eq!(rows, 2000);
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let keys = walk_sample(&db, cfs[0], &spans, rows, 100, &mut rng).unwrap();
+            assert_eq!(keys.len(), 100);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(keys.iter().all(|key| host_from_key(key).is_some()));
+        }
+    }
+}
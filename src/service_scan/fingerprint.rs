@@ -0,0 +1,418 @@
+use std::{error::Error, fs, path::Path};
+
+use lazy_static::lazy_static;
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    /// Table built into the binary from fingerprints.txt
+    pub static ref BUILTIN_FINGERPRINTS: FingerprintTable =
+        FingerprintTable::parse(include_str!("fingerprints.txt"))
+            .expect("Built in fingerprint table is invalid");
+}
+
+// Ports each service usually listens on, used to choose between equally
+// good matches, e.g. a "220 ..." greeting on port 21 or 25
+const WELL_KNOWN_PORTS: &[(&str, &[u16])] = &[
+    ("ftp", &[21, 2121]),
+    ("ssh", &[22, 2222]),
+    ("telnet", &[23]),
+    ("smtp", &[25, 465, 587]),
+    ("http", &[80, 8000, 8008, 8080, 8888]),
+    ("pop3", &[110]),
+    ("imap", &[143]),
+    ("ssl", &[443, 993, 995]),
+    ("mqtt", &[1883]),
+    ("mysql", &[3306]),
+    ("rdp", &[3389]),
+    ("postgresql", &[5432]),
+    ("amqp", &[5672]),
+    ("vnc", &[5900, 5901]),
+    ("redis", &[6379]),
+    ("irc", &[6667]),
+    ("mongodb", &[27017]),
+];
+
+/// Service identified from a banner
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ServiceMatch {
+    /// Service name, e.g. "ssh"
+    pub service: String,
+    /// Software, e.g. "OpenSSH"
+    pub product: Option<String>,
+    /// Software version, e.g. "8.9p1"
+    pub version: Option<String>,
+    /// Anything else the banner gave away
+    pub info: Option<String>,
+    /// Operating system, when the banner names one
+    pub os: Option<String>,
+    /// Whether this came from a softmatch, which names the service but is
+    /// too loose to trust for anything else
+    pub soft: bool,
+}
+
+#[derive(Debug)]
+struct Fingerprint {
+    service: String,
+    pattern: Regex,
+    soft: bool,
+    product: Option<String>,
+    version: Option<String>,
+    info: Option<String>,
+    os: Option<String>,
+}
+
+impl Fingerprint {
+    fn to_match(&self, captures: &Captures) -> ServiceMatch {
+        let fill = |template: &Option<String>| {
+            template
+                .as_deref()
+                .map(|template| substitute(template, captures))
+                .filter(|value| !value.is_empty())
+        };
+
+        ServiceMatch {
+            service: self.service.clone(),
+            product: fill(&self.product),
+            version: fill(&self.version),
+            info: fill(&self.info),
+            os: fill(&self.os),
+            soft: self.soft,
+        }
+    }
+}
+
+/// Banner patterns in the nmap-service-probes `match`/`softmatch` syntax.
+/// See fingerprints.txt for the format.
+#[derive(Debug, Default)]
+pub struct FingerprintTable {
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl FingerprintTable {
+    /// Parse a table. Probe, port and other nmap directives are skipped, as
+    /// are fields other than p, v, i and o.
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut fingerprints = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let soft = match directive {
+                "match" => false,
+                "softmatch" => true,
+                _ => continue,
+            };
+            let fingerprint = parse_fingerprint(rest, soft)
+                .map_err(|e| format!("Fingerprint line {}: {}", number + 1, e))?;
+            fingerprints.push(fingerprint);
+        }
+
+        Ok(FingerprintTable { fingerprints })
+    }
+
+    /// Read and parse a table from a file
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.fingerprints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fingerprints.is_empty()
+    }
+
+    /// Best match for a banner read from `port`. A match beats a softmatch,
+    /// then a service that usually runs on `port` beats one that doesn't,
+    /// then the earlier line wins. The banner may be binary or cut short.
+    pub fn identify(&self, port: u16, banner: &[u8]) -> Option<ServiceMatch> {
+        if banner.is_empty() {
+            return None;
+        }
+
+        self.fingerprints
+            .iter()
+            .enumerate()
+            .filter_map(|(index, fingerprint)| {
+                let captures = fingerprint.pattern.captures(banner)?;
+                let rank = (
+                    fingerprint.soft,
+                    !usual_port(&fingerprint.service, port),
+                    index,
+                );
+                Some((rank, fingerprint, captures))
+            })
+            .min_by_key(|(rank, _, _)| *rank)
+            .map(|(_, fingerprint, captures)| fingerprint.to_match(&captures))
+    }
+}
+
+/// Identify the service behind a banner with the built in table
+pub fn identify_service(port: u16, banner: &[u8]) -> Option<ServiceMatch> {
+    BUILTIN_FINGERPRINTS.identify(port, banner)
+}
+
+fn usual_port(service: &str, port: u16) -> bool {
+    WELL_KNOWN_PORTS
+        .iter()
+        .any(|(name, ports)| *name == service && ports.contains(&port))
+}
+
+// "<service> m|<regex>|<flags> <fields>"
+fn parse_fingerprint(rest: &str, soft: bool) -> Result<Fingerprint, Box<dyn Error>> {
+    let (service, rest) = rest
+        .trim()
+        .split_once(' ')
+        .ok_or("Missing pattern after service name")?;
+    let rest = rest
+        .trim_start()
+        .strip_prefix('m')
+        .ok_or("Pattern must start with m")?;
+    let (pattern, rest) = delimited(rest).ok_or("Unterminated pattern")?;
+
+    let flags: String = rest.chars().take_while(|c| !c.is_whitespace()).collect();
+    let mut rest = &rest[flags.len()..];
+
+    let pattern = RegexBuilder::new(&nmap_escapes(pattern))
+        // Match raw bytes, so "." and \xNN stand for a single byte
+        .unicode(false)
+        .case_insensitive(flags.contains('i'))
+        .dot_matches_new_line(flags.contains('s'))
+        .build()?;
+
+    let mut fingerprint = Fingerprint {
+        service: service.to_string(),
+        pattern,
+        soft,
+        product: None,
+        version: None,
+        info: None,
+        os: None,
+    };
+
+    loop {
+        rest = rest.trim_start();
+        let mut chars = rest.chars();
+        let Some(field) = chars.next() else {
+            break;
+        };
+        // cpe:/.../ has a longer name than the other fields
+        let after_name = rest.strip_prefix("cpe:").unwrap_or(chars.as_str());
+        let (value, after) =
+            delimited(after_name).ok_or(format!("Unterminated {} field", field))?;
+        // A trailing "a" on cpe:/.../a and similar flags
+        rest = after.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+
+        let value = Some(value.to_string());
+        match field {
+            'p' => fingerprint.product = value,
+            'v' => fingerprint.version = value,
+            'i' => fingerprint.info = value,
+            'o' => fingerprint.os = value,
+            _ => {}
+        }
+    }
+
+    Ok(fingerprint)
+}
+
+// Split "|text|rest" on its first character, returning ("text", "rest")
+fn delimited(text: &str) -> Option<(&str, &str)> {
+    let delimiter = text.chars().next()?;
+    let body = &text[delimiter.len_utf8()..];
+    let end = body.find(delimiter)?;
+    Some((&body[..end], &body[end + delimiter.len_utf8()..]))
+}
+
+// The regex crate has no octal escapes, but nmap patterns use \0 for a NUL byte
+fn nmap_escapes(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') if !chars.peek().is_some_and(|c| c.is_ascii_digit()) => out += r"\x00",
+            Some(escaped) => {
+                out.push('\\');
+                out.push(escaped);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// Replace $1 to $9 in a field with the matching capture group
+fn substitute(template: &str, captures: &Captures) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$'
+            && let Some(group) = chars.peek().and_then(|d| d.to_digit(10))
+        {
+            chars.next();
+            if let Some(capture) = captures.get(group as usize) {
+                out += &String::from_utf8_lossy(capture.as_bytes());
+            }
+            continue;
+        }
+        out.push(c);
+    }
+    out.trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(found: &Option<ServiceMatch>) -> Option<(&str, bool)> {
+        found
+            .as_ref()
+            .map(|found| (found.service.as_str(), found.soft))
+    }
+
+    #[test]
+    fn builtin_table_has_every_line() {
+        let lines = include_str!("fingerprints.txt")
+            .lines()
+            .filter(|line| line.starts_with("match ") || line.starts_with("softmatch "))
+            .count();
+        assert!(!BUILTIN_FINGERPRINTS.is_empty());
+        assert_eq!(BUILTIN_FINGERPRINTS.len(), lines);
+    }
+
+    #[test]
+    fn identifies_common_banners() {
+        let ssh = identify_service(22, b"SSH-2.0-OpenSSH_8.9p1").unwrap();
+        assert_eq!(ssh.service, "ssh");
+        assert_eq!(ssh.product.as_deref(), Some("OpenSSH"));
+        assert_eq!(ssh.version.as_deref(), Some("8.9p1"));
+        // An empty capture leaves the field unset
+        assert_eq!(ssh.info, None);
+        assert!(!ssh.soft);
+
+        let ssh = identify_service(2222, b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n").unwrap();
+        assert_eq!(ssh.version.as_deref(), Some("9.6p1"));
+        assert_eq!(ssh.info.as_deref(), Some("Ubuntu-3ubuntu13"));
+
+        let http = identify_service(
+            80,
+            b"HTTP/1.1 200 OK\r\nDate: Mon, 01 Jan 2024 00:00:00 GMT\r\nServer: nginx/1.24.0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(http.service, "http");
+        assert_eq!(http.product.as_deref(), Some("nginx"));
+        assert_eq!(http.version.as_deref(), Some("1.24.0"));
+
+        let http = identify_service(
+            8080,
+            b"HTTP/1.0 404 Not Found\r\nServer: Apache/2.4.58 (Debian)\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(http.product.as_deref(), Some("Apache httpd"));
+        assert_eq!(http.version.as_deref(), Some("2.4.58"));
+        assert_eq!(http.os.as_deref(), Some("Debian"));
+
+        let smtp =
+            identify_service(25, b"220 mail.example.com ESMTP Postfix (Debian)\r\n").unwrap();
+        assert_eq!(smtp.service, "smtp");
+        assert_eq!(smtp.product.as_deref(), Some("Postfix smtpd"));
+        assert_eq!(smtp.info.as_deref(), Some("mail.example.com"));
+    }
+
+    #[test]
+    fn binary_and_cut_off_banners() {
+        // A MySQL greeting, NUL bytes and all
+        let mysql = identify_service(3306, b"J\0\0\0\x0a8.0.36\0\x08\0\0\0\x7f\x1a").unwrap();
+        assert_eq!(mysql.service, "mysql");
+        assert_eq!(mysql.product.as_deref(), Some("MySQL"));
+        assert_eq!(mysql.version.as_deref(), Some("8.0.36"));
+
+        // Bytes that aren't UTF-8 after the part that matched
+        let ssh = identify_service(22, b"SSH-2.0-OpenSSH_8.9p1 \xff\xfe\r\n").unwrap();
+        assert_eq!(ssh.version.as_deref(), Some("8.9p1"));
+
+        assert_eq!(identify_service(22, b""), None);
+        assert_eq!(identify_service(9999, b"\0\xff\xfe\x01\x80garbage"), None);
+        assert_eq!(identify_service(3306, b"J\0\0"), None);
+        assert_eq!(identify_service(80, b"HTT"), None);
+
+        // Cut off before the details, only the softmatch still fits
+        assert_eq!(
+            service(&identify_service(22, b"SSH-2.0-Open")),
+            Some(("ssh", true))
+        );
+        assert_eq!(
+            service(&identify_service(80, b"HTTP/1.1 200")),
+            Some(("http", true))
+        );
+    }
+
+    #[test]
+    fn match_beats_softmatch_then_usual_port_then_line() {
+        let table = FingerprintTable::parse(
+            "# Comments and other directives are skipped\n\
+             Probe TCP NULL q||\n\
+             softmatch ftp m|^220|\n\
+             match smtp m|^220 (\\S+)| i/$1/\n\
+             match ftp m|^220 (\\S+)| i/$1/\n",
+        )
+        .unwrap();
+        assert_eq!(table.len(), 3);
+
+        // The softmatch comes first but loses to either match
+        assert_eq!(
+            service(&table.identify(21, b"220 host ready")),
+            Some(("ftp", false))
+        );
+        assert_eq!(
+            service(&table.identify(25, b"220 host ready")),
+            Some(("smtp", false))
+        );
+        // Neither port is usual, so the earlier line wins
+        assert_eq!(
+            service(&table.identify(9999, b"220 host ready")),
+            Some(("smtp", false))
+        );
+        // Only the softmatch fits
+        assert_eq!(
+            service(&table.identify(25, b"220\r\n")),
+            Some(("ftp", true))
+        );
+        assert_eq!(
+            table.identify(21, b"220 host").unwrap().info.as_deref(),
+            Some("host")
+        );
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let err = FingerprintTable::parse("match ssh m|^SSH|\nmatch ssh m|^SSH-\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Fingerprint line 2: Unterminated pattern");
+
+        let err = FingerprintTable::parse("match ssh m|^SSH| p/OpenSSH")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Fingerprint line 1: Unterminated p field");
+
+        assert!(FingerprintTable::parse("match ssh m|(|").is_err());
+    }
+
+    #[test]
+    fn nul_escapes_match_single_bytes() {
+        let table = FingerprintTable::parse(r"match test m|^\0\x01\0(\d)| v/$1/").unwrap();
+        let found = table.identify(1, b"\0\x01\x007").unwrap();
+        assert_eq!(found.version.as_deref(), Some("7"));
+        assert_eq!(table.identify(1, b"0\x0107"), None);
+    }
+}
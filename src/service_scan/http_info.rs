@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+/// What a web server said about itself in reply to `GET /`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HttpInfo {
+    /// Status code, e.g. 200
+    pub status: u16,
+    /// The Server header, e.g. "nginx/1.24.0"
+    pub server: Option<String>,
+    /// Text of the page's `<title>`, with runs of whitespace made one space
+    pub title: Option<String>,
+    /// Where a redirect points, from the Location header. Not followed.
+    pub location: Option<String>,
+}
+
+impl HttpInfo {
+    /// Pick the status, headers and title out of a raw response. None if it
+    /// doesn't start with an HTTP status line, so anything else a web port
+    /// sends is left to the banner. A response cut off part way still gives
+    /// whatever arrived.
+    pub fn parse(response: &[u8]) -> Option<HttpInfo> {
+        let (head, body) = split_head(response);
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.lines();
+
+        let status = parse_status_line(lines.next()?)?;
+        let mut info = HttpInfo {
+            status,
+            server: None,
+            title: None,
+            location: None,
+        };
+        let mut chunked = false;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            match name.trim().to_ascii_lowercase().as_str() {
+                "server" => info.server = Some(value.to_string()),
+                "location" => info.location = Some(value.to_string()),
+                "transfer-encoding" => {
+                    chunked = value.to_ascii_lowercase().contains("chunked");
+                }
+                _ => {}
+            }
+        }
+
+        info.title = if chunked {
+            find_title(&dechunk(body))
+        } else {
+            find_title(body)
+        };
+        Some(info)
+    }
+}
+
+// The status code of a line like "HTTP/1.1 200 OK"
+fn parse_status_line(line: &str) -> Option<u16> {
+    let mut parts = line.split_whitespace();
+    parts.next()?.strip_prefix("HTTP/")?;
+    let code = parts.next()?;
+    if code.len() != 3 {
+        return None;
+    }
+    code.parse().ok()
+}
+
+// Headers and body, split at the first blank line. All headers if the
+// response ended before one.
+fn split_head(response: &[u8]) -> (&[u8], &[u8]) {
+    for (i, window) in response.windows(2).enumerate() {
+        // Servers that end lines with a bare \n are common enough to allow
+        if window == b"\n\n" {
+            return (&response[..i], &response[i + 2..]);
+        }
+        if window == b"\n\r" && response.get(i + 2) == Some(&b'\n') {
+            return (&response[..i], &response[i + 3..]);
+        }
+    }
+    (response, &[])
+}
+
+// Join the chunks of a chunked body. Stops at the last chunk, or at the
+// first one that is malformed or cut off, keeping what came before it.
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut joined = Vec::new();
+    while let Some(line_end) = body.iter().position(|&b| b == b'\n') {
+        let size_line = String::from_utf8_lossy(&body[..line_end]);
+        // Chunk extensions follow a ';'
+        let size = size_line.split(';').next().unwrap_or("").trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            break;
+        };
+        if size == 0 {
+            break;
+        }
+        body = &body[line_end + 1..];
+        let take = size.min(body.len());
+        joined.extend_from_slice(&body[..take]);
+        if take < size {
+            break;
+        }
+        body = &body[take..];
+        // The chunk's trailing line break
+        body = body.strip_prefix(b"\r").unwrap_or(body);
+        body = body.strip_prefix(b"\n").unwrap_or(body);
+    }
+    joined
+}
+
+// Text between the first <title ...> and the </title> after it. A title cut
+// off by the end of the response is kept up to there.
+fn find_title(body: &[u8]) -> Option<String> {
+    let lower = body.to_ascii_lowercase();
+    let open = find(&lower, b"<title")?;
+    let start = open + lower[open..].iter().position(|&b| b == b'>')? + 1;
+    let end = find(&lower[start..], b"</title")
+        .map(|end| start + end)
+        .unwrap_or(body.len());
+
+    let title = String::from_utf8_lossy(&body[start..end])
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_headers_and_title() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            Server: nginx/1.24.0\r\n\
+            Content-Type: text/html\r\n\
+            \r\n\
+            <html><head><TITLE class=\"x\">\n  Welcome   home\n</TITLE></head></html>";
+
+        let info = HttpInfo::parse(response).unwrap();
+        assert_eq!(info.status, 200);
+        assert_eq!(info.server.as_deref(), Some("nginx/1.24.0"));
+        assert_eq!(info.title.as_deref(), Some("Welcome home"));
+        assert_eq!(info.location, None);
+    }
+
+    #[test]
+    fn keeps_a_redirect_location() {
+        let response = b"HTTP/1.0 301 Moved Permanently\nlocation: https://example.com/\n\n";
+
+        let info = HttpInfo::parse(response).unwrap();
+        assert_eq!(info.status, 301);
+        assert_eq!(info.location.as_deref(), Some("https://example.com/"));
+        assert_eq!(info.title, None);
+    }
+
+    #[test]
+    fn joins_chunked_bodies() {
+        let response = b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            7\r\n<title>\r\n\
+            6;ext=1\r\nRouter\r\n\
+            8\r\n</title>\r\n\
+            0\r\n\r\n";
+
+        let info = HttpInfo::parse(response).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Router"));
+    }
+
+    #[test]
+    fn keeps_what_arrived_of_a_cut_off_response() {
+        let info = HttpInfo::parse(b"HTTP/1.1 200 OK\r\nServer: Apache").unwrap();
+        assert_eq!(info.server.as_deref(), Some("Apache"));
+
+        let info = HttpInfo::parse(b"HTTP/1.1 200 OK\r\n\r\n<title>Admin pa").unwrap();
+        assert_eq!(info.title.as_deref(), Some("Admin pa"));
+    }
+
+    #[test]
+    fn rejects_anything_that_isnt_http() {
+        assert_eq!(HttpInfo::parse(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(HttpInfo::parse(b"HTTP/1.1 2000 OK\r\n"), None);
+        assert_eq!(HttpInfo::parse(b""), None);
+    }
+}
@@ -1,5 +1,9 @@
+pub mod fingerprint;
+pub mod http_info;
+#[allow(clippy::module_inception)]
 pub mod service_scan;
 pub mod services;
 pub mod tcp_http;
 pub mod tcp_https;
 pub mod tcp_minecraft;
+pub mod tls_info;
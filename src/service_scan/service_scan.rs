@@ -2,37 +2,85 @@ use std::{
     collections::HashMap,
     io::{Read, Write},
     net::{IpAddr, SocketAddr, TcpStream},
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
 
 use indicatif::{ProgressBar, ProgressStyle};
+use openssl::ssl::SslStream;
 use rand::seq::SliceRandom;
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
-    database::DatabaseResult, port_scan::port_scan::PortScanResult, service_scan::tcp_http,
+    database::{DatabaseResult, Responses, ServiceEntry, service_key, version_from_match},
+    path_mtu::prefix_of,
+    port_scan::port_scan::PortScanResult,
+    service_scan::tcp_http,
 };
 
-use super::{services::SERVICE_PATTERNS, tcp_https, tcp_minecraft};
+use super::{
+    fingerprint::{ServiceMatch, identify_service},
+    http_info::HttpInfo,
+    services::SERVICE_PATTERNS,
+    tcp_https, tcp_minecraft,
+    tls_info::{self, TlsInfo},
+};
+
+/// Most bytes of a response kept by `service_scan`
+pub const MAX_BANNER_BYTES: usize = 16 * 1024;
+/// Connections `service_scan` has open at once when not told otherwise
+pub const DEFAULT_BANNER_CONCURRENCY: usize = 64;
+// Services that send a banner before the client says anything
+const BANNER_FIRST_PORTS: [u16; 10] = [21, 22, 23, 25, 110, 143, 587, 3306, 5900, 6667];
+// Sent to ports that stay quiet and aren't known to want anything else
+const GENERIC_PROBE: &[u8] = b"\r\n\r\n";
+// How long a port gets to send a banner before the generic probe is tried
+const BANNER_WAIT: Duration = Duration::from_millis(1500);
 
 #[derive(Debug, Clone)]
 pub struct ServiceScanResult {
     pub ip: IpAddr,
     pub open_ports: Vec<i32>,
     pub services: HashMap<i32, (String, String)>,
+    /// Ports where the connection stalled after data started arriving, a sign of a path MTU black hole
+    pub stalled_ports: Vec<i32>,
+    /// Software and version identified from each port's banner
+    pub matches: HashMap<i32, ServiceMatch>,
+    /// Each port's banner exactly as it was read, for ports whose banner came
+    /// off the socket rather than out of a protocol client
+    pub banners: HashMap<i32, Vec<u8>>,
+    /// Status, Server header, page title and redirect target of each port
+    /// that answered in HTTP
+    pub http: HashMap<i32, HttpInfo>,
+    /// Protocol, cipher and certificate names of each port that completed
+    /// a TLS handshake
+    pub tls: HashMap<i32, TlsInfo>,
 }
 
+// Service name and banner text, the raw banner when a plain socket read one,
+// and whether the transfer stalled
+type Identified = ((String, String), Option<Vec<u8>>, bool);
+
 impl ServiceScanResult {
     fn new(ip: IpAddr) -> Self {
         ServiceScanResult {
             ip,
             open_ports: Vec::new(),
             services: HashMap::new(),
+            stalled_ports: Vec::new(),
+            matches: HashMap::new(),
+            banners: HashMap::new(),
+            http: HashMap::new(),
+            tls: HashMap::new(),
         }
     }
     pub fn to_database(&self) -> DatabaseResult {
-        let data = serde_json::to_string(&self.services).unwrap_or(String::new());
+        let responses: Responses = self
+            .services
+            .iter()
+            .map(|(port, service)| (service_key(*port, "tcp"), service.clone()))
+            .collect();
 
         let mut services = Vec::new();
 
@@ -43,17 +91,51 @@ impl ServiceScanResult {
         services.sort();
         services.dedup();
 
-        // println!("{}", data);
-        DatabaseResult {
+        // Versions come from the fingerprints matched during the scan, so
+        // banners aren't matched again when the row is saved
+        let service_entries = self
+            .services
+            .iter()
+            .map(|(port, (name, banner))| ServiceEntry {
+                port: *port as u16,
+                protocol: "tcp".to_string(),
+                name: name.clone(),
+                banner: banner.clone(),
+                version: version_from_match(self.matches.get(port), banner),
+            })
+            .collect();
+
+        let mut row = DatabaseResult {
             id: self.ip.to_string(),
             ports: self.open_ports.clone(),
             services,
-            responses: data,
-        }
+            responses: String::new(),
+            service_entries,
+            first_seen: None,
+            last_seen: None,
+            last_run: None,
+            tags: Vec::new(),
+            hostname: None,
+            rtt_ms: None,
+            os_guess: None,
+        };
+        row.set_response_map(&responses);
+        row
     }
 }
 
 pub fn identify(ip: IpAddr, port: &i32, timeout: Duration) -> (String, String) {
+    identify_on_path(ip, port, timeout, None).0
+}
+
+/// Identify the service while advertising `mss`, if the path to the host is
+/// known to be small. Also reports whether the transfer stalled.
+fn identify_on_path(ip: IpAddr, port: &i32, timeout: Duration, mss: Option<u16>) -> Identified {
+    if mss.is_some() {
+        // The HTTP clients can't have their MSS clamped, so use a plain socket instead
+        return clamped_identify(ip, port, timeout, mss);
+    }
+
     let e = || {
         // // println!("secondary1");
         // let (service, data) =
@@ -68,7 +150,7 @@ pub fn identify(ip: IpAddr, port: &i32, timeout: Duration) -> (String, String) {
         //     _ => None,
         // })
         // .unwrap_or((service, data))
-        basic_identify(ip, port, timeout).unwrap_or(("tcp".to_string(), "".to_string()))
+        basic_identify(ip, port, timeout, None)
     };
 
     // println!("primary");
@@ -89,10 +171,35 @@ pub fn identify(ip: IpAddr, port: &i32, timeout: Duration) -> (String, String) {
 
         _ => None,
     })
-    .unwrap_or(e())
+    .map(|service| (service, None, false))
+    .unwrap_or_else(e)
     // basic_identify(ip, port, timeout).unwrap_or(("tcp".to_string(), "".to_string()))
 }
 
+fn clamped_identify(ip: IpAddr, port: &i32, timeout: Duration, mss: Option<u16>) -> Identified {
+    if matches!(port, 80 | 8080..=8089)
+        && let Some((response, stalled)) = try_connect(
+            ip,
+            port,
+            timeout,
+            format!("GET / HTTP/1.0\r\nHost: {}\r\n\r\n", ip).as_bytes(),
+            mss,
+        )
+        && !response.is_empty()
+    {
+        return (
+            (
+                "http".to_string(),
+                String::from_utf8_lossy(&response).to_string(),
+            ),
+            Some(response),
+            stalled,
+        );
+    }
+
+    basic_identify(ip, port, timeout, mss)
+}
+
 fn tuple_or_none(
     tag: &str,
     data: Result<String, Box<dyn std::error::Error>>,
@@ -104,18 +211,23 @@ fn tuple_or_none(
     }
 }
 
+/// Scan the open ports for services, on `num_threads` threads that each
+/// probe one port at a time. `path_mss` maps a prefix (see
+/// `path_mtu::prefix_of`) to the MSS to advertise for hosts in it.
 pub fn scan_services(
     port_scan_results: Vec<PortScanResult>,
     num_threads: usize,
     timeout: Duration,
+    path_mss: HashMap<String, u16>,
 ) -> Vec<ServiceScanResult> {
+    let path_mss = Arc::new(path_mss);
     let mut host_port_count: u64 = 0;
-    let results: Arc<Mutex<Vec<ServiceScanResult>>> = Arc::new(Mutex::new(
+    let results: Arc<Mutex<HashMap<IpAddr, ServiceScanResult>>> = Arc::new(Mutex::new(
         port_scan_results
             .iter()
             .map(|result| {
                 host_port_count += result.open_ports.len() as u64;
-                ServiceScanResult::new(result.ip)
+                (result.ip, ServiceScanResult::new(result.ip))
             })
             .collect(),
     ));
@@ -123,7 +235,7 @@ pub fn scan_services(
     let mut host_port: Vec<(IpAddr, i32)> = Vec::with_capacity(host_port_count as usize);
     for host in &port_scan_results {
         for port in &host.open_ports {
-            host_port.push((host.ip, port.clone()));
+            host_port.push((host.ip, *port));
         }
     }
 
@@ -143,19 +255,20 @@ pub fn scan_services(
 
     // Create a thread for each chunk of IPs
     // let chunks = split_ips_into_chunks(port_scan_results, num_threads);
-    for i in 0..=num_threads {
+    for _ in 0..num_threads.max(1) {
         // println!("Thread {},{}", i, chunk.len());
         // let chunk_hosts = chunk.clone();
         let thread_hosts = Arc::clone(&host_port);
         let thread_results = Arc::clone(&results);
         let thread_timeout = timeout;
         let thread_pb = Arc::clone(&pb);
+        let thread_path_mss = Arc::clone(&path_mss);
         handles.push(thread::spawn(move || {
             loop {
                 let mut hosts = thread_hosts.lock().unwrap();
                 // println!("{}, {}, {}", i, hosts.len(), total_count);
 
-                if hosts.len() == 0 {
+                if hosts.is_empty() {
                     // println!("Break thread {} A", i);
                     break;
                 }
@@ -173,17 +286,32 @@ pub fn scan_services(
                 let ip = host.0;
                 let port = host.1;
 
-                println!("{}, {}, {}", i, ip, port);
+                // println!("{}, {}, {}", i, ip, port);
 
                 // Try to identify the service on the port
                 // println!("Thread {} stall 2", i);
-                let (service_name, banner) = identify(ip, &port, thread_timeout);
+                let mss = thread_path_mss.get(&prefix_of(&ip)).copied();
+                let ((service_name, banner), raw, stalled) =
+                    identify_on_path(ip, &port, thread_timeout, mss);
                 // println!("Thread {} stall 3", i);
 
                 let mut results_guard = thread_results.lock().unwrap();
-                if let Some(result) = results_guard.iter_mut().find(|r| r.ip == ip) {
+                if let Some(result) = results_guard.get_mut(&ip) {
                     result.open_ports.push(port);
+                    let raw_or_text = raw.as_deref().unwrap_or(banner.as_bytes());
+                    if let Some(found) = identify_service(port as u16, raw_or_text) {
+                        result.matches.insert(port, found);
+                    }
+                    if let Some(http) = HttpInfo::parse(raw_or_text) {
+                        result.http.insert(port, http);
+                    }
                     result.services.insert(port, (service_name, banner));
+                    if let Some(raw) = raw {
+                        result.banners.insert(port, raw);
+                    }
+                    if stalled {
+                        result.stalled_ports.push(port);
+                    }
                 }
                 // println!("Thread {} stall 4", i);
 
@@ -203,101 +331,336 @@ pub fn scan_services(
 
     pb.clone().finish_with_message("Finished!");
 
-    Arc::try_unwrap(results)
+    let mut results = Arc::try_unwrap(results)
+        .expect("Arc still has multiple owners")
+        .into_inner()
+        .expect("Mutex poisoned");
+    // In the order the hosts were given
+    port_scan_results
+        .iter()
+        .filter_map(|result| results.remove(&result.ip))
+        .collect()
+}
+
+/// Grab the banner from each (host, port): connect, send a probe suited to
+/// the port (nothing for services like SSH, FTP and SMTP that speak first)
+/// and keep up to `MAX_BANNER_BYTES` of the reply. Ports that can't be
+/// connected to are left out. Replies in HTTP are also parsed into
+/// `ServiceScanResult::http`. Results feed `ResultDatabase::add_service_results`.
+///
+/// Ports in `tls_ports` are tried with a TLS handshake first, recorded in
+/// `ServiceScanResult::tls`, and their banner is read through it. Ports
+/// that don't complete one are grabbed in plain text instead.
+///
+/// A pool of `concurrency` worker threads takes targets off a shared list,
+/// each holding one connection at a time, so no more than `concurrency`
+/// sockets are open at once however many targets there are. Results are
+/// in address order.
+pub fn service_scan(
+    targets: Vec<(IpAddr, u16)>,
+    timeout: Duration,
+    concurrency: usize,
+    tls_ports: &[u16],
+) -> Vec<ServiceScanResult> {
+    let results: HashMap<IpAddr, ServiceScanResult> = targets
+        .iter()
+        .map(|(ip, _)| (*ip, ServiceScanResult::new(*ip)))
+        .collect();
+    let results = Arc::new(Mutex::new(results));
+
+    let threads = targets.len().clamp(1, concurrency.max(1));
+    let targets = Arc::new(Mutex::new(targets));
+    let tls_ports = Arc::new(tls_ports.to_vec());
+    let mut handles = Vec::new();
+    for _ in 0..threads {
+        let targets = Arc::clone(&targets);
+        let results = Arc::clone(&results);
+        let tls_ports = Arc::clone(&tls_ports);
+        handles.push(thread::spawn(move || {
+            loop {
+                let Some((ip, port)) = targets.lock().unwrap().pop() else {
+                    break;
+                };
+                let tls = tls_ports.contains(&port);
+                let Some((banner, stalled, tls)) = grab_banner(ip, port, timeout, tls) else {
+                    continue;
+                };
+
+                let mut service = identify_service_from_response(&banner).unwrap_or("tcp");
+                if tls.is_some() {
+                    service = match service {
+                        "http" => "https",
+                        "tcp" => "ssl/tls",
+                        service => service,
+                    };
+                }
+                let mut results = results.lock().unwrap();
+                if let Some(result) = results.get_mut(&ip) {
+                    if let Some(found) = identify_service(port, &banner) {
+                        result.matches.insert(port as i32, found);
+                    }
+                    if let Some(http) = HttpInfo::parse(&banner) {
+                        result.http.insert(port as i32, http);
+                    }
+                    let port = port as i32;
+                    result.open_ports.push(port);
+                    result.services.insert(
+                        port,
+                        (
+                            service.to_string(),
+                            String::from_utf8_lossy(&banner).to_string(),
+                        ),
+                    );
+                    result.banners.insert(port, banner);
+                    if let Some(tls) = tls {
+                        result.tls.insert(port, tls);
+                    }
+                    if stalled {
+                        result.stalled_ports.push(port);
+                    }
+                }
+            }
+        }));
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut results: Vec<ServiceScanResult> = Arc::try_unwrap(results)
         .expect("Arc still has multiple owners")
         .into_inner()
         .expect("Mutex poisoned")
-    // .into_iter()
-    // .map(|a| {
-    //     println!("{:?}", a);
-    //     a
-    // })
-    // .collect()
+        .into_values()
+        .collect();
+    results.sort_by_key(|result| result.ip);
+    for result in &mut results {
+        result.open_ports.sort();
+    }
+    results
+}
+
+/// Connect scan `ports` on every target and grab a banner from each port that
+/// accepts, without ping or raw sockets so it runs unprivileged. Each connect
+/// and read is bounded by `timeout`, banners are cut at `MAX_BANNER_BYTES`,
+/// at most `concurrency` connections are open at once, and only hosts with
+/// an open port are returned. Ports in `tls_ports` are tried in TLS first.
+pub fn quick_service_scan(
+    targets: Vec<IpAddr>,
+    ports: Vec<u16>,
+    timeout: Duration,
+    concurrency: usize,
+    tls_ports: &[u16],
+) -> Vec<ServiceScanResult> {
+    let mut pairs: Vec<(IpAddr, u16)> = targets
+        .iter()
+        .flat_map(|ip| ports.iter().map(move |port| (*ip, *port)))
+        .collect();
+    // Spread the connects over hosts rather than hitting one host's ports in a row
+    pairs.shuffle(&mut rand::rng());
+
+    service_scan(pairs, timeout, concurrency, tls_ports)
+        .into_iter()
+        .filter(|result| !result.open_ports.is_empty())
+        .collect()
+}
+
+/// The probe `service_scan` opens with on `port`, empty to wait for a banner
+pub fn probe_for_port(ip: IpAddr, port: u16) -> Vec<u8> {
+    match port {
+        80 | 443 | 8000 | 8008 | 8080..=8089 | 8443 | 8888 => {
+            format!("GET / HTTP/1.0\r\nHost: {}\r\n\r\n", ip).into_bytes()
+        }
+        _ => Vec::new(),
+    }
+}
+
+// A connection a banner can be read over, in plain text or TLS
+trait BannerStream: Read + Write {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl BannerStream for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl BannerStream for SslStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
+
+// Connect to a port and read its banner, through TLS if `tls` and the port
+// completes a handshake, with what the handshake gave away. None if the
+// connection failed.
+fn grab_banner(
+    ip: IpAddr,
+    port: u16,
+    timeout: Duration,
+    tls: bool,
+) -> Option<(Vec<u8>, bool, Option<TlsInfo>)> {
+    let addr = SocketAddr::new(ip, port);
+    if tls {
+        let stream = TcpStream::connect_timeout(&addr, timeout).ok()?;
+        let _ = stream.set_read_timeout(Some(timeout));
+        let _ = stream.set_write_timeout(Some(timeout));
+        if let Some((mut stream, info)) = tls_info::handshake(stream) {
+            let (banner, stalled) = exchange(&mut stream, ip, port, timeout)?;
+            return Some((banner, stalled, Some(info)));
+        }
+        // Not TLS after all, so try again in plain text
+    }
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).ok()?;
+    let (banner, stalled) = exchange(&mut stream, ip, port, timeout)?;
+    Some((banner, stalled, None))
 }
 
-// Helper function to split the IPs into roughly equal chunks for threading
-fn split_ips_into_chunks(ips: Vec<PortScanResult>, num_chunks: usize) -> Vec<Vec<PortScanResult>> {
-    let chunk_size = (ips.len() + num_chunks - 1) / num_chunks;
-    let mut chunks = Vec::new();
+// Send the port's probe and read the reply. Quiet ports that don't normally
+// speak first are sent the generic probe. None if the probe couldn't be sent.
+fn exchange(
+    stream: &mut impl BannerStream,
+    ip: IpAddr,
+    port: u16,
+    timeout: Duration,
+) -> Option<(Vec<u8>, bool)> {
+    let _ = stream.tcp().set_write_timeout(Some(timeout));
+
+    let probe = probe_for_port(ip, port);
+    if !probe.is_empty() {
+        stream.write_all(&probe).ok()?;
+        let _ = stream.tcp().set_read_timeout(Some(timeout));
+        return Some(read_response(stream, MAX_BANNER_BYTES));
+    }
 
-    for chunk_idx in 0..num_chunks {
-        let start = chunk_idx * chunk_size;
-        if start >= ips.len() {
-            break;
+    let banner_first = BANNER_FIRST_PORTS.contains(&port);
+    let wait = if banner_first {
+        timeout
+    } else {
+        timeout.min(BANNER_WAIT)
+    };
+    let _ = stream.tcp().set_read_timeout(Some(wait));
+    let (banner, stalled) = read_response(stream, MAX_BANNER_BYTES);
+    if !banner.is_empty() || banner_first {
+        return Some((banner, stalled));
+    }
+
+    if stream.write_all(GENERIC_PROBE).is_err() {
+        return Some((banner, stalled));
+    }
+    let _ = stream.tcp().set_read_timeout(Some(timeout));
+    Some(read_response(stream, MAX_BANNER_BYTES))
+}
+
+// Read until the peer stops sending or `cap` bytes have arrived. Returns the
+// bytes and whether the read timed out after data had started arriving.
+fn read_response(stream: &mut impl Read, cap: usize) -> (Vec<u8>, bool) {
+    let mut buffer = [0; 4096];
+    let mut response = Vec::new();
+    let mut stalled = false;
+
+    // Try to read multiple times to get a complete banner
+    while response.len() < cap {
+        match stream.read(&mut buffer) {
+            Ok(0) => break, // End of stream
+            Ok(bytes_read) => {
+                let keep = bytes_read.min(cap - response.len());
+                response.extend_from_slice(&buffer[..keep]);
+                if bytes_read < buffer.len() {
+                    break; // Likely got all data if we read less than buffer size
+                }
+            }
+            Err(e) => {
+                stalled = !response.is_empty()
+                    && matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    );
+                break;
+            }
         }
-        let end = (start + chunk_size).min(ips.len());
-        chunks.push(ips[start..end].to_vec());
+
+        // Small delay between reads
+        thread::sleep(Duration::from_millis(50));
     }
 
-    chunks
+    (response, stalled)
 }
 
-// Connect to an IP:port and send a probe
-fn try_connect(ip: IpAddr, port: &i32, timeout: Duration, probe: &[u8]) -> Option<Vec<u8>> {
+// Connect, optionally advertising a smaller MSS
+fn connect(addr: &SocketAddr, timeout: Duration, mss: Option<u16>) -> std::io::Result<TcpStream> {
+    let Some(mss) = mss else {
+        return TcpStream::connect_timeout(addr, timeout);
+    };
+
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_tcp_mss(mss as u32)?;
+    socket.connect_timeout(&(*addr).into(), timeout)?;
+    Ok(socket.into())
+}
+
+// Connect to an IP:port and send a probe. Returns the response and whether
+// the read timed out after data had started arriving
+fn try_connect(
+    ip: IpAddr,
+    port: &i32,
+    timeout: Duration,
+    probe: &[u8],
+    mss: Option<u16>,
+) -> Option<(Vec<u8>, bool)> {
     let addr = SocketAddr::new(ip, *port as u16);
 
-    match TcpStream::connect_timeout(&addr, timeout) {
+    match connect(&addr, timeout, mss) {
         Ok(mut stream) => {
             // Set read/write timeouts
             let _ = stream.set_read_timeout(Some(timeout));
             let _ = stream.set_write_timeout(Some(timeout));
 
             // Send the probe if it's not empty
-            if !probe.is_empty() {
-                if stream.write(probe).is_err() {
-                    return None;
-                }
+            if !probe.is_empty() && stream.write(probe).is_err() {
+                return None;
             }
 
             // Read the response
-            let mut buffer = [0; 4096]; // Larger buffer for service banners
-            let mut response = Vec::new();
-
-            // Try to read multiple times to get a complete banner
-            for _ in 0..3 {
-                match stream.read(&mut buffer) {
-                    Ok(0) => break, // End of stream
-                    Ok(bytes_read) => {
-                        response.extend_from_slice(&buffer[0..bytes_read]);
-                        if bytes_read < buffer.len() {
-                            break; // Likely got all data if we read less than buffer size
-                        }
-                    }
-                    Err(_) => break, // Error reading
-                }
-
-                // Small delay between reads
-                thread::sleep(Duration::from_millis(50));
-            }
-
-            Some(response)
+            Some(read_response(&mut stream, MAX_BANNER_BYTES))
         }
         Err(_) => None, // Connection failed
     }
 }
 
-fn basic_identify(ip: IpAddr, port: &i32, timeout: Duration) -> Option<(String, String)> {
+fn basic_identify(ip: IpAddr, port: &i32, timeout: Duration, mss: Option<u16>) -> Identified {
     // println!("Start try_connect");
     // Try a simple connection with no probe as last resort
-    if let Some(response) = try_connect(ip, port, timeout, b"\x00\n") {
-        if !response.is_empty() {
-            if let Some(service_name) = identify_service_from_response(&response) {
-                return Some((
+    if let Some((response, stalled)) = try_connect(ip, port, timeout, b"\x00\n", mss) {
+        if !response.is_empty()
+            && let Some(service_name) = identify_service_from_response(&response)
+        {
+            return (
+                (
                     service_name.to_string(),
                     String::from_utf8_lossy(response.as_slice()).to_string(),
-                ));
-            }
+                ),
+                Some(response),
+                stalled,
+            );
         }
 
         // println!("End try_connect1");
 
-        // Port is open but service couldn't be identified
-        return Some(("tcp".to_string(), "".to_string()));
+        // Port is open but service couldn't be identified, keep whatever it sent
+        let raw = (!response.is_empty()).then_some(response);
+        return (("tcp".to_string(), "".to_string()), raw, stalled);
     }
 
     // println!("Start try_connect2");
 
-    None
+    (("tcp".to_string(), "".to_string()), None, false)
 }
 
 fn identify_service_from_response(response: &[u8]) -> Option<&str> {
@@ -334,3 +697,281 @@ fn identify_service_from_response(response: &[u8]) -> Option<&str> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        net::{Ipv4Addr, TcpListener},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        ssl::{SslAcceptor, SslMethod},
+        x509::{X509, X509NameBuilder, extension::SubjectAlternativeName},
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // A listener on a free local port that writes `response` to every
+    // connection as soon as it's accepted, then closes it
+    fn serve(response: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(response);
+            }
+        });
+        port
+    }
+
+    // A listener like `serve` that waits for a request first, as web
+    // servers do
+    fn serve_after_request(response: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let _ = stream.write_all(response);
+            }
+        });
+        port
+    }
+
+    // A local port nothing is listening on
+    fn closed_port() -> u16 {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn grabs_a_banner_sent_on_connect() {
+        const BANNER: &[u8] = b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n";
+        let port = serve(BANNER);
+        let closed = closed_port();
+
+        let results = service_scan(
+            vec![(LOCALHOST, port), (LOCALHOST, closed)],
+            TIMEOUT,
+            4,
+            &[],
+        );
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let port = port as i32;
+        assert_eq!(result.open_ports, vec![port]);
+        assert_eq!(result.banners[&port], BANNER);
+        assert_eq!(result.services[&port].0, "ssh");
+        assert_eq!(
+            result.services[&port].1,
+            String::from_utf8_lossy(BANNER).to_string()
+        );
+        assert!(result.stalled_ports.is_empty());
+    }
+
+    #[test]
+    fn quick_scan_reads_an_http_status_line() {
+        const RESPONSE: &[u8] = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        let port = serve(RESPONSE);
+        let closed = closed_port();
+
+        let results = quick_service_scan(vec![LOCALHOST], vec![port, closed], TIMEOUT, 4, &[]);
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let port = port as i32;
+        assert_eq!(result.open_ports, vec![port]);
+        assert_eq!(result.services[&port].0, "http");
+        assert!(
+            result.services[&port]
+                .1
+                .starts_with("HTTP/1.1 404 Not Found")
+        );
+        assert_eq!(result.http[&port].status, 404);
+
+        // Hosts with nothing open are left out
+        assert!(quick_service_scan(vec![LOCALHOST], vec![closed], TIMEOUT, 4, &[]).is_empty());
+    }
+
+    #[test]
+    fn concurrency_bounds_open_connections() {
+        const BANNER: &[u8] = b"220 ready\r\n";
+        const CONCURRENCY: usize = 10;
+
+        // Every accepted connection is kept until the scanner closes its end,
+        // so the ones still open when a new one arrives are the scanner's
+        let open: Arc<Mutex<Vec<TcpStream>>> = Arc::default();
+        let most_open = Arc::new(AtomicUsize::new(0));
+        let mut ports = Vec::new();
+        for _ in 0..100 {
+            let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+            ports.push(listener.local_addr().unwrap().port());
+            let (open, most_open) = (Arc::clone(&open), Arc::clone(&most_open));
+            thread::spawn(move || {
+                for mut stream in listener.incoming().flatten() {
+                    let _ = stream.write_all(BANNER);
+                    stream.set_nonblocking(true).unwrap();
+                    let mut open = open.lock().unwrap();
+                    open.retain(|stream| match stream.peek(&mut [0]) {
+                        Ok(0) => false,
+                        Ok(_) => true,
+                        Err(e) => e.kind() == ErrorKind::WouldBlock,
+                    });
+                    open.push(stream);
+                    most_open.fetch_max(open.len(), Ordering::Relaxed);
+                }
+            });
+        }
+
+        let results = service_scan(
+            ports.iter().map(|port| (LOCALHOST, *port)).collect(),
+            TIMEOUT,
+            CONCURRENCY,
+            &[],
+        );
+
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let mut expected: Vec<i32> = ports.iter().map(|port| *port as i32).collect();
+        expected.sort();
+        assert_eq!(result.open_ports, expected);
+        assert!(result.banners.values().all(|banner| banner == BANNER));
+        let most_open = most_open.load(Ordering::Relaxed);
+        assert!(
+            (1..=CONCURRENCY).contains(&most_open),
+            "{} connections open at once",
+            most_open
+        );
+    }
+
+    #[test]
+    fn reads_the_title_and_server_of_a_web_server() {
+        const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\n\
+            Server: nginx/1.24.0\r\n\
+            Content-Type: text/html\r\n\
+            Connection: close\r\n\r\n\
+            <html><head><title>\n  Router   Login\n</title></head><body></body></html>";
+        let port = serve_after_request(RESPONSE);
+
+        let results = service_scan(vec![(LOCALHOST, port)], TIMEOUT, 4, &[]);
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+        let port = port as i32;
+        assert_eq!(result.services[&port].0, "http");
+        assert_eq!(
+            result.http[&port],
+            HttpInfo {
+                status: 200,
+                server: Some("nginx/1.24.0".to_string()),
+                title: Some("Router Login".to_string()),
+                location: None,
+            }
+        );
+        assert!(result.tls.is_empty());
+    }
+
+    // A listener that completes a TLS handshake with a new self-signed
+    // certificate for `name`, then writes `response` inside it
+    fn serve_tls(name: &str, response: &'static [u8]) -> u16 {
+        let key = PKey::from_ec_key(
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        certificate.set_subject_name(&subject).unwrap();
+        certificate.set_issuer_name(&subject).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(name)
+            .ip("127.0.0.1")
+            .build(&certificate.x509v3_context(None, None))
+            .unwrap();
+        certificate.append_extension(san).unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = certificate.build();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&certificate).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(mut stream) = acceptor.accept(stream) {
+                    let _ = stream.write_all(response);
+                    let _ = stream.shutdown();
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn reads_the_certificate_of_a_tls_port() {
+        const RESPONSE: &[u8] = b"HTTP/1.0 200 OK\r\nServer: test\r\n\r\n";
+        const BANNER: &[u8] = b"SSH-2.0-OpenSSH_9.6\r\n";
+        let tls_port = serve_tls("scan-test.local", RESPONSE);
+        let plain_port = serve(BANNER);
+
+        let results = service_scan(
+            vec![(LOCALHOST, tls_port), (LOCALHOST, plain_port)],
+            TIMEOUT,
+            4,
+            &[tls_port, plain_port],
+        );
+        let [result] = &results[..] else {
+            panic!("expected one host, got {:?}", results);
+        };
+
+        let (tls_port, plain_port) = (tls_port as i32, plain_port as i32);
+        let tls = &result.tls[&tls_port];
+        assert_eq!(tls.subject_cn.as_deref(), Some("scan-test.local"));
+        assert_eq!(tls.san, vec!["scan-test.local", "127.0.0.1"]);
+        assert!(tls.version.starts_with("TLSv1."), "{}", tls.version);
+        assert!(!tls.cipher.is_empty());
+        assert_eq!(result.services[&tls_port].0, "https");
+        assert_eq!(result.banners[&tls_port], RESPONSE);
+
+        // Not TLS, so grabbed in plain text instead
+        assert!(!result.tls.contains_key(&plain_port));
+        assert_eq!(result.services[&plain_port].0, "ssh");
+        assert_eq!(result.banners[&plain_port], BANNER);
+    }
+}
@@ -17,7 +17,7 @@ pub fn scan(
         .connect_timeout(timeout)
         .build()
         .unwrap()
-        .get(format!("http://{}:{}", ip.to_string(), port))
+        .get(format!("http://{}:{}", ip, port))
         .send()?;
 
     // println!("HTTP reading");
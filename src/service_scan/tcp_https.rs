@@ -18,7 +18,7 @@ pub fn scan(
         .connect_timeout(timeout)
         .build()
         .unwrap()
-        .get(format!("https://{}:{}", ip.to_string(), port))
+        .get(format!("https://{}:{}", ip, port))
         .send()?;
 
     // println!("https read");
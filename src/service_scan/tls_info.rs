@@ -0,0 +1,87 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+
+use openssl::{
+    nid::Nid,
+    ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode},
+    x509::X509Ref,
+};
+use serde::{Deserialize, Serialize};
+
+/// Ports `service_scan` tries a TLS handshake on when not told otherwise
+pub const DEFAULT_TLS_PORTS: [u16; 14] = [
+    443, 465, 636, 853, 990, 992, 993, 994, 995, 5061, 5986, 6697, 8443, 9443,
+];
+
+/// What a port's TLS handshake gave away
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Protocol version negotiated, e.g. "TLSv1.3"
+    pub version: String,
+    /// Cipher suite negotiated, e.g. "TLS_AES_256_GCM_SHA384"
+    pub cipher: String,
+    /// Common name of the certificate's subject
+    pub subject_cn: Option<String>,
+    /// DNS names and IP addresses the certificate's subject alternative
+    /// names list
+    pub san: Vec<String>,
+}
+
+/// Handshake over `stream`, bounded by its timeouts. The certificate isn't
+/// checked, since a scan has nothing to check it against, and old protocol
+/// versions and ciphers are allowed so old servers are still seen. None if
+/// the port doesn't speak TLS.
+pub fn handshake(stream: TcpStream) -> Option<(SslStream<TcpStream>, TlsInfo)> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client()).ok()?;
+    builder.set_verify(SslVerifyMode::NONE);
+    builder.set_min_proto_version(None).ok()?;
+    builder.set_security_level(0);
+    let stream = builder
+        .build()
+        .configure()
+        .ok()?
+        .use_server_name_indication(false)
+        .verify_hostname(false)
+        .connect("", stream)
+        .ok()?;
+
+    let ssl = stream.ssl();
+    let certificate = ssl.peer_certificate();
+    let info = TlsInfo {
+        version: ssl.version_str().to_string(),
+        cipher: ssl
+            .current_cipher()
+            .map(|cipher| cipher.name().to_string())
+            .unwrap_or_default(),
+        subject_cn: certificate.as_deref().and_then(subject_cn),
+        san: certificate.as_deref().map(san).unwrap_or_default(),
+    };
+    Some((stream, info))
+}
+
+fn subject_cn(certificate: &X509Ref) -> Option<String> {
+    let entry = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()?;
+    entry.data().to_string().ok()
+}
+
+// Other kinds of name, such as email addresses, are left out
+fn san(certificate: &X509Ref) -> Vec<String> {
+    let Some(names) = certificate.subject_alt_names() else {
+        return Vec::new();
+    };
+    names
+        .iter()
+        .filter_map(|name| {
+            if let Some(dns) = name.dnsname() {
+                return Some(dns.to_string());
+            }
+            let ip = match name.ipaddress()? {
+                &[a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                octets => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?)),
+            };
+            Some(ip.to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,117 @@
+use rocksdb::{ColumnFamily, DB, Direction, IteratorMode, ReadOptions, Snapshot};
+
+use crate::{
+    database::{
+        BANNER_COLUMN, DatabaseError, DatabaseResult, ResultDatabase, host_key, prefix_read_opts,
+    },
+    index::decode_banners,
+    progress::{Operation, Progress},
+    rows::RowIter,
+};
+
+/// A point-in-time view of a database. Every read through it sees the rows
+/// as they were when `ResultDatabase::snapshot` was called, whatever a
+/// running scan writes after that. The snapshot is released on drop, and
+/// holding it keeps RocksDB from freeing data changed since, so don't keep
+/// one longer than the reads need.
+pub struct DbSnapshot<'a> {
+    pub(crate) database: &'a ResultDatabase,
+    pub(crate) db: &'a DB,
+    pub(crate) snapshot: Snapshot<'a>,
+}
+
+impl ResultDatabase {
+    /// Pin the current state of the database for consistent reads, e.g. an
+    /// export taken while a scan is saving results
+    pub fn snapshot(&self) -> Result<DbSnapshot<'_>, DatabaseError> {
+        let db = self.db_ref()?;
+        Ok(DbSnapshot {
+            database: self,
+            db,
+            snapshot: db.snapshot(),
+        })
+    }
+}
+
+impl<'a> DbSnapshot<'a> {
+    /// `ResultDatabase::get_row_by_host` as of the snapshot
+    pub fn get_row_by_host(&self, host: &str) -> Result<Option<DatabaseResult>, DatabaseError> {
+        let cfs = self.database.column_handles(self.db)?;
+        self.database
+            .fetch_row_opt(self.db, host, &cfs, &self.read_opts())
+    }
+
+    /// `ResultDatabase::iter_rows` as of the snapshot. Rows are still read a
+    /// batch at a time, but all from this one snapshot.
+    pub fn iter_rows(&'a self) -> RowIter<'a> {
+        self.database.iter_rows_at(Some(&self.snapshot))
+    }
+
+    /// `ResultDatabase::get_banners` as of the snapshot
+    pub fn get_banners(&self, host: &str) -> Vec<(u16, Vec<u8>)> {
+        self.banners_at_key(&host_key(host))
+    }
+
+    pub(crate) fn banners_at_key(&self, key: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let Some(banners) = self.db.cf_handle(BANNER_COLUMN) else {
+            return Vec::new();
+        };
+        decode_banners(
+            key,
+            self.snapshot.iterator_cf_opt(
+                banners,
+                prefix_read_opts(key, 0),
+                IteratorMode::From(key, Direction::Forward),
+            ),
+        )
+    }
+
+    // Progress of an operation reading every key of `cf`
+    pub(crate) fn progress(&self, operation: Operation, cf: &ColumnFamily) -> Progress<'a> {
+        Progress::new(self.database.progress.as_ref(), operation, self.db, cf)
+    }
+
+    // Read options for a get as of the snapshot
+    pub(crate) fn read_opts(&self) -> ReadOptions {
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_snapshot(&self.snapshot);
+        read_opts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, row};
+
+    #[test]
+    fn snapshot_doesnt_see_later_writes() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+
+        let snapshot = database.snapshot().unwrap();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[80], &[]),
+                row("10.0.0.2", &[443], &[]),
+            ])
+            .unwrap();
+        assert!(database.delete_host("10.0.0.1").unwrap());
+
+        let old = snapshot.get_row_by_host("10.0.0.1").unwrap().unwrap();
+        assert_eq!(old.ports, vec![22]);
+        assert!(snapshot.get_row_by_host("10.0.0.2").unwrap().is_none());
+        let hosts: Vec<String> = snapshot.iter_rows().map(|row| row.unwrap().id).collect();
+        assert_eq!(hosts, vec!["10.0.0.1"]);
+
+        assert!(database.get_row_by_host("10.0.0.1").unwrap().is_none());
+        let new = database.get_row_by_host("10.0.0.2").unwrap().unwrap();
+        assert_eq!(new.ports, vec![443]);
+        // A snapshot taken now sees them
+        drop(snapshot);
+        let snapshot = database.snapshot().unwrap();
+        assert!(snapshot.get_row_by_host("10.0.0.1").unwrap().is_none());
+        assert!(snapshot.get_row_by_host("10.0.0.2").unwrap().is_some());
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashMap, fmt};
+
+use rocksdb::IteratorMode;
+use serde::Serialize;
+
+use crate::{
+    database::{DatabaseError, ResultDatabase},
+    index::decode_ports,
+};
+
+// Ports listed by the Display impl
+const TOP_PORTS_SHOWN: usize = 20;
+
+/// Counts over the whole database
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DatabaseStats {
+    pub hosts: u64,
+    /// Hosts with at least one open port
+    pub hosts_with_ports: u64,
+    /// Open ports summed over every host
+    pub open_ports: u64,
+    /// Hosts with at least one identified service
+    pub hosts_with_services: u64,
+    /// Number of hosts with each port open
+    pub port_histogram: HashMap<i32, u64>,
+}
+
+impl DatabaseStats {
+    /// Ports by number of hosts, most common first
+    pub fn top_ports(&self) -> Vec<(i32, u64)> {
+        let mut ports: Vec<(i32, u64)> = self
+            .port_histogram
+            .iter()
+            .map(|(port, count)| (*port, *count))
+            .collect();
+        ports.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ports
+    }
+
+    /// The `n` most common ports, as in `top_ports`
+    pub fn top_n_ports(&self, n: usize) -> Vec<(i32, u64)> {
+        let mut ports = self.top_ports();
+        ports.truncate(n);
+        ports
+    }
+}
+
+impl fmt::Display for DatabaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Hosts: {}", self.hosts)?;
+        writeln!(f, "Hosts with open ports: {}", self.hosts_with_ports)?;
+        writeln!(f, "Open ports: {}", self.open_ports)?;
+        writeln!(f, "Hosts with services: {}", self.hosts_with_services)?;
+
+        let ports = self.top_ports();
+        if !ports.is_empty() {
+            writeln!(f, "Most common ports:")?;
+            for (port, count) in ports.iter().take(TOP_PORTS_SHOWN) {
+                writeln!(f, "    {:>5}  {}", port, count)?;
+            }
+            if ports.len() > TOP_PORTS_SHOWN {
+                writeln!(f, "    ({} more)", ports.len() - TOP_PORTS_SHOWN)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ResultDatabase {
+    /// Number of hosts in the database, counted from the keys alone
+    pub fn count_hosts(&self) -> Result<u64, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let mut hosts = 0;
+        let mut iter = db.raw_iterator_cf(cfs[0]);
+        iter.seek_to_first();
+        while iter.valid() {
+            hosts += 1;
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(hosts)
+    }
+
+    /// Host counts and a histogram of open ports, from one pass over each
+    /// of the default, ports and services column families. An empty
+    /// database gives all zeros.
+    pub fn stats(&self) -> Result<DatabaseStats, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let mut stats = DatabaseStats {
+            hosts: self.count_hosts()?,
+            ..Default::default()
+        };
+
+        for item in db.iterator_cf(cfs[1], IteratorMode::Start) {
+            let (_, value) = item?;
+            let ports = decode_ports(&value);
+            if !ports.is_empty() {
+                stats.hosts_with_ports += 1;
+            }
+            stats.open_ports += ports.len() as u64;
+            for port in ports {
+                *stats.port_histogram.entry(port).or_default() += 1;
+            }
+        }
+
+        for item in db.iterator_cf(cfs[2], IteratorMode::Start) {
+            let (_, value) = item?;
+            if !value.is_empty() {
+                stats.hosts_with_services += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, row};
+
+    #[test]
+    fn empty_database_has_zero_stats() {
+        let (_dir, database) = testing::temp_database();
+
+        let stats = database.stats().unwrap();
+        assert_eq!(database.count_hosts().unwrap(), 0);
+        assert_eq!(stats.hosts, 0);
+        assert_eq!(stats.hosts_with_ports, 0);
+        assert_eq!(stats.open_ports, 0);
+        assert!(stats.top_ports().is_empty());
+    }
+
+    #[test]
+    fn counts_hosts_and_ranks_ports() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![
+                row("10.0.0.1", &[22, 80, 443], &["ssh", "http"]),
+                row("10.0.0.2", &[22, 443], &[]),
+                row("10.0.0.3", &[443], &["https"]),
+                row("10.0.0.4", &[], &[]),
+            ])
+            .unwrap();
+
+        let stats = database.stats().unwrap();
+        assert_eq!(database.count_hosts().unwrap(), 4);
+        assert_eq!(stats.hosts, 4);
+        assert_eq!(stats.hosts_with_ports, 3);
+        assert_eq!(stats.open_ports, 6);
+        assert_eq!(stats.hosts_with_services, 2);
+        assert_eq!(stats.top_ports(), vec![(443, 3), (22, 2), (80, 1)]);
+        assert_eq!(stats.top_n_ports(2), vec![(443, 3), (22, 2)]);
+    }
+}
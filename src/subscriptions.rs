@@ -0,0 +1,177 @@
+use std::sync::{
+    Mutex,
+    mpsc::{self, Receiver, SyncSender, TrySendError},
+};
+
+use crate::database::{DatabaseResult, ResultDatabase};
+
+/// Events each subscriber's channel holds before later ones are dropped
+pub const SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// Sent to subscribers as rows are saved
+#[derive(Debug, Clone)]
+pub enum RowEvent {
+    /// A row as it was written, its ports unioned with the stored ones
+    Saved(Box<DatabaseResult>),
+    /// This many rows were dropped because the channel was full, sent
+    /// before the next row that fits
+    Lagged(u64),
+}
+
+/// The live subscribers of a `ResultDatabase`
+#[derive(Default)]
+pub(crate) struct Subscribers(Mutex<Vec<Subscriber>>);
+
+struct Subscriber {
+    sender: SyncSender<RowEvent>,
+    // Rows dropped since the last event that was sent
+    dropped: u64,
+}
+
+impl Subscribers {
+    /// Whether anyone is listening, so rows needn't be kept to send
+    pub(crate) fn active(&self) -> bool {
+        !self.0.lock().unwrap().is_empty()
+    }
+
+    /// Send each row to every subscriber without waiting on any of them,
+    /// and forget those whose receiver has been dropped
+    pub(crate) fn publish(&self, rows: &[DatabaseResult]) {
+        let mut subscribers = self.0.lock().unwrap();
+        subscribers.retain_mut(|subscriber| rows.iter().all(|row| subscriber.send(row)));
+    }
+}
+
+impl Subscriber {
+    // Queue `row` after a Lagged event if rows were dropped before it.
+    // False once the receiver is gone.
+    fn send(&mut self, row: &DatabaseResult) -> bool {
+        if self.dropped > 0 {
+            match self.sender.try_send(RowEvent::Lagged(self.dropped)) {
+                Ok(()) => self.dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        match self.sender.try_send(RowEvent::Saved(Box::new(row.clone()))) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+impl ResultDatabase {
+    /// Receive every row saved through this instance from now on, once
+    /// the batch it was written in is committed, e.g. to start a service
+    /// scan of hosts as they're found. Rows saved by other processes
+    /// aren't seen, and neither are tags, hostnames and other fields set
+    /// on their own.
+    ///
+    /// Saving never waits on a subscriber. Once `SUBSCRIBER_CAPACITY`
+    /// events are waiting in its channel, further rows are dropped for it
+    /// and counted in a `RowEvent::Lagged` sent when there's room again.
+    /// Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Receiver<RowEvent> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers
+            .0
+            .lock()
+            .unwrap()
+            .push(Subscriber { sender, dropped: 0 });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+    use crate::testing::{self, row};
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn saved_host(event: RowEvent) -> String {
+        match event {
+            RowEvent::Saved(row) => row.id,
+            RowEvent::Lagged(n) => panic!("expected a row, got Lagged({})", n),
+        }
+    }
+
+    #[test]
+    fn subscriber_sees_rows_saved_after_it_subscribed() {
+        let (_dir, database) = testing::temp_database();
+        database
+            .save_rows(vec![row("10.0.0.1", &[80], &[])])
+            .unwrap();
+
+        let receiver = database.subscribe();
+        let subscriber = thread::spawn(move || receiver.recv_timeout(WAIT).unwrap());
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &["ssh"])])
+            .unwrap();
+
+        let RowEvent::Saved(saved) = subscriber.join().unwrap() else {
+            panic!("expected the saved row");
+        };
+        assert_eq!(saved.id, "10.0.0.1");
+        // As written, with the ports saved before
+        assert_eq!(saved.ports, vec![22, 80]);
+        assert_eq!(saved.services, vec!["ssh"]);
+    }
+
+    #[test]
+    fn full_channels_drop_rows_and_report_the_lag() {
+        let (_dir, database) = testing::temp_database();
+        let receiver = database.subscribe();
+
+        let rows: Vec<DatabaseResult> = (0..SUBSCRIBER_CAPACITY + 5)
+            .map(|i| row(&format!("10.{}.{}.1", i / 256, i % 256), &[22], &[]))
+            .collect();
+        database.save_rows(rows).unwrap();
+
+        for _ in 0..SUBSCRIBER_CAPACITY {
+            saved_host(receiver.try_recv().unwrap());
+        }
+        assert!(receiver.try_recv().is_err());
+
+        database
+            .save_rows(vec![row("192.168.0.1", &[22], &[])])
+            .unwrap();
+        assert!(matches!(
+            receiver.recv_timeout(WAIT),
+            Ok(RowEvent::Lagged(5))
+        ));
+        assert_eq!(
+            saved_host(receiver.recv_timeout(WAIT).unwrap()),
+            "192.168.0.1"
+        );
+    }
+
+    #[test]
+    fn dropping_the_receiver_unsubscribes() {
+        let (_dir, database) = testing::temp_database();
+        let kept = database.subscribe();
+        drop(database.subscribe());
+        assert!(database.subscribers.active());
+
+        database
+            .save_rows(vec![row("10.0.0.1", &[22], &[])])
+            .unwrap();
+        assert_eq!(database.subscribers.0.lock().unwrap().len(), 1);
+        assert_eq!(saved_host(kept.try_recv().unwrap()), "10.0.0.1");
+
+        drop(kept);
+        database
+            .save_rows(vec![row("10.0.0.2", &[22], &[])])
+            .unwrap();
+        assert!(!database.subscribers.active());
+    }
+}
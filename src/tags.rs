@@ -0,0 +1,83 @@
+use rocksdb::WriteBatch;
+
+use crate::{
+    database::{DatabaseError, DatabaseResult, ResultDatabase, column_handle, host_key},
+    index::IndexManager,
+};
+
+/// Whether `tag` can be stored. Tags are kept comma separated and the tag
+/// index separates tag from host with a zero byte, so neither may appear.
+pub fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && !tag.contains(',') && !tag.chars().any(char::is_control)
+}
+
+impl ResultDatabase {
+    /// Label a host. Returns false if the host isn't in the database.
+    pub fn add_tag(&self, host: &str, tag: &str) -> Result<bool, DatabaseError> {
+        if !valid_tag(tag) {
+            return Err(DatabaseError::Encoding(format!(
+                "invalid tag \"{}\"",
+                tag.escape_debug()
+            )));
+        }
+
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        let key = host_key(host);
+        // Held until the new tags are written, so a tag added alongside
+        // isn't lost
+        let _writing = self.writing.lock().unwrap();
+        if db
+            .get_pinned_cf(column_handle(&db, "default")?, &key)?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        let mut tags = indexes.stored_tags(&key);
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+            let mut batch = WriteBatch::default();
+            indexes.put_tags(&mut batch, host, &tags);
+            db.write(batch)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Remove a label from a host. Returns false if the host didn't have it.
+    pub fn remove_tag(&self, host: &str, tag: &str) -> Result<bool, DatabaseError> {
+        let db = self.writable_db()?;
+        let indexes = IndexManager::new(self, &db)?;
+        let key = host_key(host);
+        let _writing = self.writing.lock().unwrap();
+
+        let mut tags = indexes.stored_tags(&key);
+        let count = tags.len();
+        tags.retain(|t| t != tag);
+        if tags.len() == count {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::default();
+        indexes.put_tags(&mut batch, host, &tags);
+        db.write(batch)?;
+
+        Ok(true)
+    }
+
+    /// A host's labels, in the order they were added
+    pub fn get_tags(&self, host: &str) -> Result<Vec<String>, DatabaseError> {
+        let db = self.db()?;
+        Ok(IndexManager::new(self, &db)?.stored_tags(&host_key(host)))
+    }
+
+    /// Rows labelled `tag`, looked up through the tag index
+    pub fn get_rows_by_tag(&self, tag: &str) -> Result<Vec<DatabaseResult>, DatabaseError> {
+        let db = self.db()?;
+        let cfs = self.column_handles(&db)?;
+
+        let hosts = IndexManager::new(self, &db)?.hosts_with_tag(tag)?;
+        self.fetch_rows(&db, &hosts, &cfs)
+    }
+}
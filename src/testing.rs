@@ -0,0 +1,36 @@
+//! Helpers shared by the unit tests
+
+use tempfile::TempDir;
+
+use crate::database::{DatabaseOptions, DatabaseResult, ResultDatabase};
+
+/// An empty database in a new temporary directory, removed when the
+/// `TempDir` is dropped. Keep it alive for as long as the database is used.
+pub(crate) fn temp_database() -> (TempDir, ResultDatabase) {
+    temp_database_with(DatabaseOptions::default())
+}
+
+/// `temp_database` with `opts`
+pub(crate) fn temp_database_with(opts: DatabaseOptions) -> (TempDir, ResultDatabase) {
+    let dir = tempfile::tempdir().unwrap();
+    let database = ResultDatabase::with_options(&dir.path().to_string_lossy(), opts);
+    (dir, database)
+}
+
+/// A row with only its host, ports and services set
+pub(crate) fn row(host: &str, ports: &[i32], services: &[&str]) -> DatabaseResult {
+    DatabaseResult {
+        id: host.to_string(),
+        ports: ports.to_vec(),
+        services: services.iter().map(|s| s.to_string()).collect(),
+        responses: String::new(),
+        service_entries: Vec::new(),
+        first_seen: None,
+        last_seen: None,
+        last_run: None,
+        tags: Vec::new(),
+        hostname: None,
+        rtt_ms: None,
+        os_guess: None,
+    }
+}
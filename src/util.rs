@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+const DURATION_FORMS: &str =
+    "a number followed by us, ms, s, m, h or d, e.g. \"500ms\", \"2m30s\", \"1h\"";
+const SIZE_FORMS: &str = "a number optionally followed by B, K, M, G or T (KB/KiB etc. also accepted), e.g. \"512MB\", \"1.5G\"";
+
+const DURATION_UNITS: [(&str, f64); 6] = [
+    ("us", 0.000_001),
+    ("ms", 0.001),
+    ("s", 1.0),
+    ("m", 60.0),
+    ("h", 3600.0),
+    ("d", 86400.0),
+];
+
+const SIZE_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Parse a duration such as "500ms", "2m30s", "1.5h" or "0"
+pub fn parse_duration(input: &str) -> Result<Duration, Box<dyn std::error::Error>> {
+    let value = input.trim();
+    let invalid = |reason: &str| -> Box<dyn std::error::Error> {
+        format!("Invalid duration \"{input}\": {reason} (expected {DURATION_FORMS})").into()
+    };
+
+    if value.is_empty() {
+        return Err(invalid("empty value"));
+    }
+    if value == "0" {
+        return Ok(Duration::ZERO);
+    }
+
+    let mut rest = value;
+    let mut seconds = 0.0;
+
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest);
+        if number.is_empty() {
+            return Err(invalid("missing number"));
+        }
+        let number: f64 = number.parse().map_err(|_| invalid("bad number"))?;
+
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let unit = unit.trim();
+        if unit.is_empty() {
+            return Err(invalid("missing unit"));
+        }
+
+        let scale = DURATION_UNITS
+            .iter()
+            .find(|(name, _)| unit.eq_ignore_ascii_case(name))
+            .map(|(_, scale)| *scale)
+            .ok_or_else(|| invalid(&format!("unknown unit \"{unit}\"")))?;
+
+        seconds += number * scale;
+        rest = after.trim_start();
+    }
+
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid("out of range"))
+}
+
+/// Format a duration the way `parse_duration` reads it, e.g. "2m30s" or "500ms"
+pub fn humanize_duration(duration: Duration) -> String {
+    let total_ms = duration.as_millis();
+    if total_ms == 0 {
+        return if duration.is_zero() {
+            "0s".to_string()
+        } else {
+            format!("{}us", duration.as_micros())
+        };
+    }
+
+    let mut out = String::new();
+    let mut remaining = total_ms;
+    for (name, ms) in [
+        ("d", 86_400_000),
+        ("h", 3_600_000),
+        ("m", 60_000),
+        ("s", 1000),
+    ] {
+        if remaining >= ms {
+            out.push_str(&format!("{}{}", remaining / ms, name));
+            remaining %= ms;
+        }
+    }
+    if remaining > 0 {
+        out.push_str(&format!("{remaining}ms"));
+    }
+
+    out
+}
+
+/// Parse a byte size such as "512MB", "1.5G" or "4096". Units are powers of 1024.
+pub fn parse_size(input: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let value = input.trim();
+    let invalid = |reason: &str| -> Box<dyn std::error::Error> {
+        format!("Invalid size \"{input}\": {reason} (expected {SIZE_FORMS})").into()
+    };
+
+    let (number, unit) = split_number(value);
+    if number.is_empty() {
+        return Err(invalid(if value.is_empty() {
+            "empty value"
+        } else {
+            "missing number"
+        }));
+    }
+    let number: f64 = number.parse().map_err(|_| invalid("bad number"))?;
+
+    let unit = unit.trim();
+    let exponent = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return Err(invalid(&format!("unknown unit \"{unit}\""))),
+    };
+
+    let bytes = number * 1024f64.powi(exponent);
+    if bytes > u64::MAX as f64 {
+        return Err(invalid("out of range"));
+    }
+
+    Ok(bytes.round() as u64)
+}
+
+/// Format a byte count with the largest fitting unit, e.g. "1.5GiB"
+pub fn humanize_size(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        let formatted = format!("{:.2}", value);
+        let formatted = formatted.trim_end_matches('0').trim_end_matches('.');
+        format!("{}{}", formatted, SIZE_UNITS[unit])
+    }
+}
+
+/// Split a leading decimal number from the rest of the string
+fn split_number(value: &str) -> (&str, &str) {
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    value.split_at(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_round_trip() {
+        for text in ["0s", "500ms", "2m30s", "1h", "3d", "1d1h1m1s1ms", "250us"] {
+            let duration = parse_duration(text).unwrap();
+            assert_eq!(humanize_duration(duration), text);
+        }
+    }
+
+    #[test]
+    fn durations_parse() {
+        assert_eq!(parse_duration("0").unwrap(), Duration::ZERO);
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("0.5s").unwrap(), Duration::from_millis(500));
+        assert_eq!(
+            parse_duration(" 2M 30S ").unwrap(),
+            Duration::from_secs(150)
+        );
+        assert_eq!(parse_duration("1h1h").unwrap(), Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn duration_errors_say_why() {
+        let message = |input| parse_duration(input).unwrap_err().to_string();
+
+        assert!(message("").contains("empty value"));
+        assert!(message("10").contains("missing unit"));
+        assert!(message("ms").contains("missing number"));
+        assert!(message("1.2.3s").contains("bad number"));
+        assert!(message("5y").contains("unknown unit \"y\""));
+        assert!(message("10").starts_with("Invalid duration \"10\""));
+        assert!(message("10").contains("us, ms, s, m, h or d"));
+    }
+
+    #[test]
+    fn sizes_round_trip() {
+        for text in ["0B", "512B", "1KiB", "1.5GiB", "2TiB"] {
+            assert_eq!(humanize_size(parse_size(text).unwrap()), text);
+        }
+    }
+
+    #[test]
+    fn sizes_parse() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("512MB").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("1.5g").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_size(" 2 KiB ").unwrap(), 2048);
+    }
+
+    #[test]
+    fn size_errors_say_why() {
+        let message = |input| parse_size(input).unwrap_err().to_string();
+
+        assert!(message("").contains("empty value"));
+        assert!(message("MB").contains("missing number"));
+        assert!(message("1.2.3M").contains("bad number"));
+        assert!(message("3 bananas").contains("unknown unit \"bananas\""));
+        assert!(message("99999999T").contains("out of range"));
+    }
+}